@@ -35,6 +35,17 @@ fn main() {
         panic!("glib-compile-resources failed");
     }
 
-    // Protobuf compilation (if needed)
-    // prost_build::compile_protos(&["src/proto/signal.proto"], &["src/proto/"]).unwrap();
+    // Compile Signal protobuf definitions
+    println!("cargo:rerun-if-changed=src/proto/signal.proto");
+    println!("cargo:rerun-if-changed=src/proto/storage.proto");
+    println!("cargo:rerun-if-changed=src/proto/groups.proto");
+    prost_build::compile_protos(
+        &[
+            "src/proto/signal.proto",
+            "src/proto/storage.proto",
+            "src/proto/groups.proto",
+        ],
+        &["src/proto/"],
+    )
+    .expect("Failed to compile protobuf definitions. Make sure protoc is installed.");
 }
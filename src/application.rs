@@ -3,16 +3,35 @@
 use gtk4::prelude::*;
 use gtk4::{gio, glib};
 use libadwaita as adw;
+use std::sync::{Arc, OnceLock};
 
 use crate::config;
+use crate::services::{NotificationService, SyncService};
+use crate::signal::types::{MessageStatus, SyncMessage};
+use crate::signal::{
+    unprotected_key_file_allowed, BusMessage, EventBus, SignalClient, SignalEvent,
+};
+use crate::ui::MessageRow;
 use crate::window::SignalYouWindow;
 
+/// Tokio runtime running the Signal client alongside the GTK main loop
+pub fn runtime() -> &'static tokio::runtime::Runtime {
+    static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Runtime::new().expect("Failed to start Tokio runtime")
+    })
+}
+
 mod imp {
     use super::*;
     use adw::subclass::prelude::*;
+    use std::cell::RefCell;
 
-    #[derive(Debug, Default)]
-    pub struct SignalYouApplication {}
+    #[derive(Default)]
+    pub struct SignalYouApplication {
+        /// Keeps the store current with client events while running
+        pub sync: RefCell<Option<SyncService>>,
+    }
 
     #[glib::object_subclass]
     impl ObjectSubclass for SignalYouApplication {
@@ -38,6 +57,7 @@ mod imp {
                 window
             } else {
                 let window = SignalYouWindow::new(application.upcast_ref());
+                application.open_client(&window);
                 window.upcast()
             };
 
@@ -107,6 +127,134 @@ impl SignalYouApplication {
         self.add_action_entries([action_quit, action_about, action_preferences]);
     }
 
+    /// Open the Signal client in the background and hand it to `window`
+    fn open_client(&self, window: &SignalYouWindow) {
+        let data_dir = glib::user_data_dir().join("signal-you-messenger");
+        let (sender, receiver) = async_channel::bounded(1);
+
+        runtime().spawn(async move {
+            let result = async {
+                let client = SignalClient::new(&data_dir, unprotected_key_file_allowed()).await?;
+                let events = client.events();
+                let store = client.store();
+                let client = Arc::new(tokio::sync::Mutex::new(client));
+
+                let mut sync = SyncService::new(client.clone(), events.subscribe("sync"));
+                sync.start().await?;
+                anyhow::Ok((client, store, events, sync))
+            }
+            .await;
+            let _ = sender.send(result).await;
+        });
+
+        glib::spawn_future_local(glib::clone!(@weak self as app, @weak window => async move {
+            match receiver.recv().await {
+                Ok(Ok((client, store, events, sync))) => {
+                    app.imp().sync.replace(Some(sync));
+                    app.show_notifications(&events, &window);
+                    app.follow_configuration(&events);
+                    window.set_client(client, store, &events);
+                }
+                Ok(Err(e)) => {
+                    tracing::error!("Failed to open Signal client: {}", e);
+                    window.show_toast("Could not open the message store");
+                }
+                Err(_) => {}
+            }
+        }));
+    }
+
+    /// Notify of messages arriving in conversations the user is not looking
+    /// at, withdrawing the notification once the conversation is read
+    fn show_notifications(&self, events: &EventBus, window: &SignalYouWindow) {
+        let receiver = events
+            .subscribe("notifications")
+            .forward(runtime().handle());
+        let notifications = NotificationService::new(self.upcast_ref());
+        let window = window.downgrade();
+
+        glib::spawn_future_local(async move {
+            while let Ok(message) = receiver.recv().await {
+                let Some(window) = window.upgrade() else {
+                    break;
+                };
+                // Missed messages stay unread in the chat list
+                let BusMessage::Event(event) = message else {
+                    continue;
+                };
+                let message = match *event {
+                    SignalEvent::MessageReceived(message) => message,
+                    // Read here or on another device
+                    SignalEvent::ConversationUpdated(conversation)
+                        if conversation.unread_count == 0 =>
+                    {
+                        notifications.withdraw(&conversation.id);
+                        continue;
+                    }
+                    _ => continue,
+                };
+                // Transcripts of our own sends arrive already sent
+                if message.status != MessageStatus::Delivered
+                    || window.is_showing_conversation(&message.conversation_id)
+                {
+                    continue;
+                }
+
+                let sender = window.resolver().name(&message.sender);
+                let content = MessageRow::get_content_preview(&message.content);
+
+                if message.conversation_id == message.sender.aci.to_string() {
+                    notifications.notify_message(&sender, &content, &message.conversation_id);
+                } else {
+                    let group = window
+                        .conversation_name(&message.conversation_id)
+                        .unwrap_or_else(|| "Group".to_string());
+                    notifications.notify_group_message(
+                        &group,
+                        &sender,
+                        &content,
+                        &message.conversation_id,
+                    );
+                }
+            }
+        });
+    }
+
+    /// Apply settings changed on the primary device to our own
+    fn follow_configuration(&self, events: &EventBus) {
+        let receiver = events
+            .subscribe("configuration")
+            .forward(runtime().handle());
+        let settings = gio::Settings::new(config::APP_ID);
+
+        glib::spawn_future_local(async move {
+            while let Ok(message) = receiver.recv().await {
+                let BusMessage::Event(event) = message else {
+                    continue;
+                };
+                let SignalEvent::SyncReceived(SyncMessage::Configuration {
+                    read_receipts,
+                    typing_indicators,
+                    link_previews,
+                }) = *event
+                else {
+                    continue;
+                };
+
+                for (key, value) in [
+                    ("send-read-receipts", read_receipts),
+                    ("send-typing-indicators", typing_indicators),
+                    ("link-previews", link_previews),
+                ] {
+                    let Some(value) = value else { continue };
+                    if let Err(e) = settings.set_boolean(key, value) {
+                        tracing::warn!("Failed to apply synced setting {}: {}", key, e);
+                    }
+                }
+            }
+        });
+    }
+
     fn setup_accels(&self) {
         self.set_accels_for_action("app.quit", &["<Control>q"]);
         self.set_accels_for_action("app.preferences", &["<Control>comma"]);
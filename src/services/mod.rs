@@ -9,7 +9,9 @@ mod notifications;
 mod sync;
 mod websocket;
 
+pub use notifications::NotificationService;
+pub use sync::SyncService;
 pub use websocket::{
     IncomingMessage, ProvisioningMessage, ProvisioningSocket, WebSocketCredentials,
-    WebSocketService,
+    WebSocketRequest, WebSocketService,
 };
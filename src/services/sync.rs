@@ -3,23 +3,33 @@
 use anyhow::Result;
 use tokio::sync::mpsc;
 
-use crate::signal::{SignalClient, SignalEvent};
+use crate::signal::types::{FetchLatest, SyncMessage, SyncRequest};
+use crate::signal::{
+    BusMessage, ClientHandle, ConnectionStatus, EventSubscriber, SignalClient, SignalEvent,
+};
+
+/// How often profiles are checked for being stale
+const PROFILE_REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
 
 /// Service for synchronizing messages with Signal servers
+///
+/// Subscribed to the client's event bus, it writes state that arrives as
+/// events, such as read receipts, back to the store. Syncs run on a
+/// [`ClientHandle`], so the UI can use the client while they do.
 pub struct SyncService {
     client: std::sync::Arc<tokio::sync::Mutex<SignalClient>>,
-    event_rx: Option<mpsc::Receiver<SignalEvent>>,
+    events: Option<EventSubscriber>,
     shutdown_tx: Option<mpsc::Sender<()>>,
 }
 
 impl SyncService {
     pub fn new(
         client: std::sync::Arc<tokio::sync::Mutex<SignalClient>>,
-        event_rx: mpsc::Receiver<SignalEvent>,
+        events: EventSubscriber,
     ) -> Self {
         Self {
             client,
-            event_rx: Some(event_rx),
+            events: Some(events),
             shutdown_tx: None,
         }
     }
@@ -29,7 +39,16 @@ impl SyncService {
         let (shutdown_tx, mut shutdown_rx) = mpsc::channel::<()>(1);
         self.shutdown_tx = Some(shutdown_tx);
 
-        let mut event_rx = self.event_rx.take().expect("Event receiver already taken");
+        let mut events = self
+            .events
+            .take()
+            .expect("Event subscription already taken");
+        let client = self.client.clone();
+        // Connecting refreshes profiles too, so the first tick can wait
+        let mut profile_refresh = tokio::time::interval_at(
+            tokio::time::Instant::now() + PROFILE_REFRESH_INTERVAL,
+            PROFILE_REFRESH_INTERVAL,
+        );
 
         tokio::spawn(async move {
             tracing::info!("Sync service started");
@@ -40,11 +59,22 @@ impl SyncService {
                         tracing::info!("Sync service shutting down");
                         break;
                     }
-                    event = event_rx.recv() => {
-                        if let Some(event) = event {
-                            Self::handle_event(event).await;
-                        } else {
-                            tracing::info!("Event channel closed, shutting down sync service");
+                    _ = profile_refresh.tick() => {
+                        let result = async { handle(&client).await?.refresh_profiles().await };
+                        if let Err(e) = result.await {
+                            tracing::error!("Failed to refresh profiles: {}", e);
+                        }
+                    }
+                    message = events.recv() => match message {
+                        Some(BusMessage::Event(event)) => {
+                            if let Err(e) = Self::handle_event(&client, *event).await {
+                                tracing::error!("Failed to handle event: {}", e);
+                            }
+                        }
+                        // A later read receipt covers any dropped with the lag
+                        Some(BusMessage::Lagged(_)) => {}
+                        None => {
+                            tracing::info!("Event bus closed, shutting down sync service");
                             break;
                         }
                     }
@@ -64,54 +94,205 @@ impl SyncService {
     }
 
     /// Handle incoming Signal events
-    async fn handle_event(event: SignalEvent) {
+    ///
+    /// Messages and conversations are stored before their events are
+    /// published; only state carried by the event itself is written here.
+    async fn handle_event(
+        client: &tokio::sync::Mutex<SignalClient>,
+        event: SignalEvent,
+    ) -> Result<()> {
         match event {
-            SignalEvent::MessageReceived(message) => {
-                tracing::info!("Received message: {:?}", message.id);
-                // TODO: Store message and notify UI
-            }
-            SignalEvent::MessageStatusChanged { message_id, status } => {
-                tracing::info!("Message {} status changed to {:?}", message_id, status);
-                // TODO: Update message status in store
-            }
-            SignalEvent::TypingIndicator { conversation_id, sender, action } => {
-                tracing::debug!("Typing indicator in {} from {:?}: {:?}", conversation_id, sender.uuid, action);
-                // TODO: Notify UI of typing status
-            }
-            SignalEvent::ReadReceipt { conversation_id, read_at } => {
+            SignalEvent::ReadReceipt {
+                conversation_id,
+                read_at,
+            } => {
                 tracing::debug!("Read receipt in {} at {}", conversation_id, read_at);
-                // TODO: Update read status
+                handle(client)
+                    .await?
+                    .apply_read_receipt(&conversation_id, read_at)
+                    .await?;
             }
-            SignalEvent::ContactUpdated(contact) => {
-                tracing::info!("Contact updated: {:?}", contact.uuid);
-                // TODO: Update contact in store
-            }
-            SignalEvent::GroupUpdated(group) => {
-                tracing::info!("Group updated: {}", group.id);
-                // TODO: Update group in store
+            // New keys or another device's changes mean the storage
+            // service holds state we have not seen
+            SignalEvent::SyncReceived(
+                SyncMessage::Keys { .. }
+                | SyncMessage::FetchLatest {
+                    kind: FetchLatest::StorageManifest,
+                },
+            ) => {
+                handle(client).await?.sync_storage().await?;
             }
             SignalEvent::SyncReceived(_sync_message) => {
                 tracing::info!("Sync message received");
-                // TODO: Process sync message
             }
             SignalEvent::ConnectionChanged(status) => {
                 tracing::info!("Connection status: {:?}", status);
-                // TODO: Notify UI of connection status
+                if status == ConnectionStatus::Connected {
+                    let client = handle(client).await?;
+                    if let Err(e) = request_initial_sync(&client).await {
+                        tracing::error!("Initial sync request failed: {}", e);
+                    }
+                    if let Err(e) = client.sync_storage().await {
+                        tracing::error!("Storage sync failed: {}", e);
+                    }
+                    // After the storage sync, which may bring new profile keys
+                    client.refresh_profiles().await?;
+                }
             }
             SignalEvent::DeviceLinked(identity) => {
-                tracing::info!("Device linked: {:?}", identity.uuid);
-                // TODO: Complete linking process
+                tracing::info!("Device linked: {}", identity.aci);
+                // Linking usually comes before connecting, in which case
+                // the first connect asks instead
+                let result = async { request_initial_sync(&handle(client).await?).await };
+                if let Err(e) = result.await {
+                    tracing::info!("Initial sync request deferred: {}", e);
+                }
             }
             SignalEvent::Error(error) => {
                 tracing::error!("Signal error: {}", error);
-                // TODO: Handle error
             }
+            // Shown by the UI, with nothing left to store
+            SignalEvent::MessageReceived(_)
+            | SignalEvent::MessageStatusChanged { .. }
+            | SignalEvent::TypingIndicator { .. }
+            | SignalEvent::ContactUpdated(_)
+            | SignalEvent::GroupUpdated(_)
+            | SignalEvent::ConversationUpdated(_) => {}
         }
+
+        Ok(())
     }
 
     /// Request a full sync from primary device
     pub async fn request_full_sync(&self) -> Result<()> {
-        let client = self.client.lock().await;
-        client.request_sync().await
+        request_full_sync(&handle(&self.client).await?).await
+    }
+}
+
+/// Take a handle on the client, holding its lock no longer than that
+async fn handle(client: &tokio::sync::Mutex<SignalClient>) -> Result<ClientHandle> {
+    client.lock().await.handle()
+}
+
+/// What requesting syncs needs of the client, so tests can stand in for it
+trait SyncRequester {
+    /// Ask the primary device to send us its state of `kind`
+    async fn request_sync(&self, kind: SyncRequest) -> Result<()>;
+
+    /// Whether the primary device was asked for its state since we linked
+    async fn initial_sync_requested(&self) -> Result<bool>;
+
+    /// Record that the primary device was asked for its state
+    async fn set_initial_sync_requested(&self) -> Result<()>;
+}
+
+impl SyncRequester for ClientHandle {
+    async fn request_sync(&self, kind: SyncRequest) -> Result<()> {
+        ClientHandle::request_sync(self, kind).await
+    }
+
+    async fn initial_sync_requested(&self) -> Result<bool> {
+        ClientHandle::initial_sync_requested(self).await
+    }
+
+    async fn set_initial_sync_requested(&self) -> Result<()> {
+        ClientHandle::set_initial_sync_requested(self).await
+    }
+}
+
+/// Ask the primary device for everything a linked device keeps
+async fn request_full_sync(client: &impl SyncRequester) -> Result<()> {
+    for kind in [
+        SyncRequest::Contacts,
+        SyncRequest::Blocked,
+        SyncRequest::Configuration,
+        SyncRequest::Keys,
+    ] {
+        client.request_sync(kind).await?;
+    }
+    Ok(())
+}
+
+/// Request a full sync, once after linking
+///
+/// Only recorded as done once every request went out, so a sync that
+/// fails is asked for again on the next connect.
+async fn request_initial_sync(client: &impl SyncRequester) -> Result<()> {
+    if client.initial_sync_requested().await? {
+        return Ok(());
+    }
+
+    request_full_sync(client).await?;
+    client.set_initial_sync_requested().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::cell::{Cell, RefCell};
+
+    /// Records the requests it is asked to send while connected
+    #[derive(Default)]
+    struct MockRequester {
+        connected: Cell<bool>,
+        sent: RefCell<Vec<SyncRequest>>,
+        requested: Cell<bool>,
+    }
+
+    impl SyncRequester for MockRequester {
+        async fn request_sync(&self, kind: SyncRequest) -> Result<()> {
+            if !self.connected.get() {
+                return Err(anyhow!("WebSocket not connected"));
+            }
+            self.sent.borrow_mut().push(kind);
+            Ok(())
+        }
+
+        async fn initial_sync_requested(&self) -> Result<bool> {
+            Ok(self.requested.get())
+        }
+
+        async fn set_initial_sync_requested(&self) -> Result<()> {
+            self.requested.set(true);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_initial_sync_requested_once() {
+        let client = MockRequester {
+            connected: Cell::new(true),
+            ..Default::default()
+        };
+
+        request_initial_sync(&client).await.unwrap();
+        request_initial_sync(&client).await.unwrap();
+
+        assert_eq!(
+            *client.sent.borrow(),
+            [
+                SyncRequest::Contacts,
+                SyncRequest::Blocked,
+                SyncRequest::Configuration,
+                SyncRequest::Keys,
+            ]
+        );
+        assert!(client.requested.get());
+    }
+
+    #[tokio::test]
+    async fn test_initial_sync_waits_for_connection() {
+        let client = MockRequester::default();
+
+        // Linked, but not connected yet
+        assert!(request_initial_sync(&client).await.is_err());
+        assert!(client.sent.borrow().is_empty());
+        assert!(!client.requested.get());
+
+        client.connected.set(true);
+        request_initial_sync(&client).await.unwrap();
+        assert_eq!(client.sent.borrow().len(), 4);
+        assert!(client.requested.get());
     }
 }
@@ -0,0 +1,214 @@
+//! Attachment encryption and download
+//!
+//! Attachments are stored on the CDN encrypted with AES-256-CBC and
+//! authenticated with HMAC-SHA256 under a random 64-byte key: the cipher
+//! key followed by the MAC key. The stored blob is `iv || ciphertext || mac`,
+//! and the attachment pointer carries the key and the SHA-256 digest of the
+//! whole blob.
+
+use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use super::types::{Attachment, SignalServers};
+
+type Aes256CbcEnc = cbc::Encryptor<aes::Aes256>;
+type Aes256CbcDec = cbc::Decryptor<aes::Aes256>;
+
+/// Size of an attachment key: AES-256 key, then HMAC-SHA256 key
+pub const ATTACHMENT_KEY_SIZE: usize = 64;
+
+const IV_SIZE: usize = 16;
+const MAC_SIZE: usize = 32;
+
+/// Largest attachment we download, in bytes
+const MAX_DOWNLOAD_SIZE: usize = 100 * 1024 * 1024;
+
+/// An encrypted attachment ready for upload
+pub struct EncryptedAttachment {
+    /// `iv || ciphertext || mac`
+    pub blob: Vec<u8>,
+    pub key: Vec<u8>,
+    pub digest: Vec<u8>,
+}
+
+/// Encrypt `plaintext` under a fresh random key
+pub fn encrypt_attachment(plaintext: &[u8]) -> EncryptedAttachment {
+    let mut key = vec![0u8; ATTACHMENT_KEY_SIZE];
+    let mut iv = [0u8; IV_SIZE];
+    rand::rngs::OsRng.fill_bytes(&mut key);
+    rand::rngs::OsRng.fill_bytes(&mut iv);
+
+    let ciphertext = Aes256CbcEnc::new_from_slices(&key[..32], &iv)
+        .expect("Attachment key and IV have valid sizes")
+        .encrypt_padded_vec_mut::<Pkcs7>(plaintext);
+
+    let mut blob = Vec::with_capacity(IV_SIZE + ciphertext.len() + MAC_SIZE);
+    blob.extend_from_slice(&iv);
+    blob.extend_from_slice(&ciphertext);
+    let mac = attachment_mac(&key[32..], &blob);
+    blob.extend_from_slice(&mac);
+
+    let digest = Sha256::digest(&blob).to_vec();
+    EncryptedAttachment { blob, key, digest }
+}
+
+/// Verify and decrypt a downloaded attachment blob
+///
+/// `size` is the plaintext length from the attachment pointer; anything
+/// beyond it is padding. Zero means unknown.
+pub fn decrypt_attachment(blob: &[u8], key: &[u8], digest: &[u8], size: u64) -> Result<Vec<u8>> {
+    if key.len() != ATTACHMENT_KEY_SIZE {
+        return Err(anyhow!("Invalid attachment key length {}", key.len()));
+    }
+    if blob.len() < IV_SIZE + MAC_SIZE {
+        return Err(anyhow!("Attachment too short"));
+    }
+    if digest.is_empty() || Sha256::digest(blob).as_slice() != digest {
+        return Err(anyhow!("Attachment digest mismatch"));
+    }
+
+    let (content, mac) = blob.split_at(blob.len() - MAC_SIZE);
+    let mut verifier =
+        Hmac::<Sha256>::new_from_slice(&key[32..]).expect("HMAC accepts keys of any size");
+    verifier.update(content);
+    verifier
+        .verify_slice(mac)
+        .map_err(|_| anyhow!("Attachment MAC mismatch"))?;
+
+    let (iv, ciphertext) = content.split_at(IV_SIZE);
+    let mut plaintext = Aes256CbcDec::new_from_slices(&key[..32], iv)
+        .expect("Attachment key and IV have valid sizes")
+        .decrypt_padded_vec_mut::<Pkcs7>(ciphertext)
+        .map_err(|_| anyhow!("Attachment padding is invalid"))?;
+
+    if size > 0 {
+        if size > plaintext.len() as u64 {
+            return Err(anyhow!("Attachment shorter than its stated size"));
+        }
+        plaintext.truncate(size as usize);
+    }
+
+    Ok(plaintext)
+}
+
+/// Download an attachment from the CDN and decrypt it
+pub async fn download_attachment(attachment: &Attachment) -> Result<Vec<u8>> {
+    let url = attachment_url(&SignalServers::default(), attachment)?;
+    tracing::debug!(
+        "Downloading attachment {} from CDN {}",
+        attachment.id,
+        attachment.cdn_number
+    );
+
+    let response = reqwest::get(&url).await?.error_for_status()?;
+    if response
+        .content_length()
+        .is_some_and(|length| length as usize > MAX_DOWNLOAD_SIZE)
+    {
+        return Err(anyhow!("Attachment {} is too large", attachment.id));
+    }
+
+    let blob = response.bytes().await?;
+    if blob.len() > MAX_DOWNLOAD_SIZE {
+        return Err(anyhow!("Attachment {} is too large", attachment.id));
+    }
+
+    decrypt_attachment(&blob, &attachment.key, &attachment.digest, attachment.size)
+}
+
+/// CDN location of an attachment
+fn attachment_url(servers: &SignalServers, attachment: &Attachment) -> Result<String> {
+    if attachment.id.is_empty() {
+        return Err(anyhow!("Attachment without a CDN location"));
+    }
+
+    let cdn = match attachment.cdn_number {
+        0 => servers.cdn,
+        2 => servers.cdn2,
+        3 => servers.cdn3,
+        n => return Err(anyhow!("Unknown CDN {}", n)),
+    };
+    Ok(format!("{}/attachments/{}", cdn, attachment.id))
+}
+
+fn attachment_mac(mac_key: &[u8], content: &[u8]) -> [u8; MAC_SIZE] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(mac_key).expect("HMAC accepts keys of any size");
+    mac.update(content);
+    mac.finalize().into_bytes().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attachment_round_trip() {
+        let plaintext = b"contact stream".to_vec();
+        let encrypted = encrypt_attachment(&plaintext);
+
+        assert_eq!(encrypted.key.len(), ATTACHMENT_KEY_SIZE);
+        assert_eq!((encrypted.blob.len() - IV_SIZE - MAC_SIZE) % 16, 0);
+
+        let decrypted =
+            decrypt_attachment(&encrypted.blob, &encrypted.key, &encrypted.digest, 0).unwrap();
+        assert_eq!(decrypted, plaintext);
+
+        // The stated size strips trailing padding
+        let decrypted =
+            decrypt_attachment(&encrypted.blob, &encrypted.key, &encrypted.digest, 7).unwrap();
+        assert_eq!(decrypted, b"contact");
+        assert!(
+            decrypt_attachment(&encrypted.blob, &encrypted.key, &encrypted.digest, 100).is_err()
+        );
+    }
+
+    #[test]
+    fn test_tampered_attachment_is_rejected() {
+        let encrypted = encrypt_attachment(b"avatar");
+
+        // A flipped ciphertext bit no longer matches the digest
+        let mut blob = encrypted.blob.clone();
+        blob[IV_SIZE] ^= 1;
+        assert!(decrypt_attachment(&blob, &encrypted.key, &encrypted.digest, 0).is_err());
+
+        // Nor does it match the MAC, even with a digest of the tampered blob
+        let digest = Sha256::digest(&blob).to_vec();
+        assert!(decrypt_attachment(&blob, &encrypted.key, &digest, 0).is_err());
+
+        // Wrong key
+        let mut key = encrypted.key.clone();
+        key[40] ^= 1;
+        assert!(decrypt_attachment(&encrypted.blob, &key, &encrypted.digest, 0).is_err());
+
+        // Missing digest
+        assert!(decrypt_attachment(&encrypted.blob, &encrypted.key, &[], 0).is_err());
+    }
+
+    #[test]
+    fn test_attachment_url() {
+        let servers = SignalServers::default();
+        let mut attachment = Attachment {
+            id: "abc".to_string(),
+            content_type: "application/octet-stream".to_string(),
+            file_name: None,
+            size: 0,
+            digest: Vec::new(),
+            key: Vec::new(),
+            cdn_number: 2,
+            upload_timestamp: 0,
+            width: None,
+            height: None,
+            thumbnail: None,
+        };
+        assert_eq!(
+            attachment_url(&servers, &attachment).unwrap(),
+            "https://cdn2.signal.org/attachments/abc"
+        );
+
+        attachment.cdn_number = 7;
+        assert!(attachment_url(&servers, &attachment).is_err());
+    }
+}
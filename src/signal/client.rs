@@ -5,17 +5,29 @@
 
 use anyhow::{anyhow, Result};
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
 use std::sync::Arc;
 use tokio::sync::{mpsc, RwLock};
 use uuid::Uuid;
-
-use super::crypto::{DhKeyPair, SignalCipher};
+use zkgroup::profiles::ExpiringProfileKeyCredential;
+use zkgroup::ServerPublicParams;
+
+use super::attachments;
+use super::codec;
+use super::conversations::ConversationService;
+use super::crypto::{DhKeyPair, PreKeyBundle, SignalCipher};
+use super::events::EventBus;
+use super::groups::{
+    self, GroupCredentials, GroupSecretParams, GroupService, InviteLink, ProfileKeyCredentials,
+};
+use super::profiles;
 use super::protocol::{ProtocolAddress, SignalProtocol};
+use super::storage_service::{self, StorageService};
 use super::store::SignalStore;
 use super::types::*;
 use crate::services::{
-    IncomingMessage, ProvisioningSocket, WebSocketCredentials, WebSocketService,
+    IncomingMessage, ProvisioningSocket, WebSocketCredentials, WebSocketRequest, WebSocketService,
 };
 
 /// High-level Signal client
@@ -24,6 +36,8 @@ pub struct SignalClient {
     protocol: Arc<RwLock<SignalProtocol>>,
     /// Encrypted data store
     store: Arc<SignalStore>,
+    /// Keeps conversations in step with stored messages
+    conversations: ConversationService,
     /// WebSocket service for real-time messages
     websocket: Arc<RwLock<WebSocketService>>,
     /// Current identity
@@ -32,8 +46,8 @@ pub struct SignalClient {
     device_password: Option<String>,
     /// Whether device is linked
     is_linked: bool,
-    /// Event sender for UI updates
-    event_tx: mpsc::Sender<SignalEvent>,
+    /// Bus announcing client events to subscribers
+    events: EventBus,
     /// Incoming message receiver
     incoming_rx: Arc<RwLock<mpsc::Receiver<IncomingMessage>>>,
 }
@@ -69,6 +83,8 @@ pub enum SignalEvent {
     ConnectionChanged(ConnectionStatus),
     /// Device linked successfully
     DeviceLinked(SignalIdentity),
+    /// Conversation created or changed, e.g. by a new message
+    ConversationUpdated(Conversation),
     /// Error occurred
     Error(String),
 }
@@ -84,36 +100,50 @@ pub enum ConnectionStatus {
 
 impl SignalClient {
     /// Create a new Signal client
-    pub async fn new(data_dir: &Path) -> Result<Self> {
-        let store = SignalStore::new(data_dir).await?;
-        let (event_tx, _event_rx) = mpsc::channel(100);
+    ///
+    /// Without a Secret Service, its database key is kept in an unprotected
+    /// file only if `allow_key_file`.
+    pub async fn new(data_dir: &Path, allow_key_file: bool) -> Result<Self> {
+        let store = Arc::new(SignalStore::new(data_dir, allow_key_file).await?);
+        let events = EventBus::new();
+        let conversations = ConversationService::new(store.clone(), events.clone());
         let (incoming_tx, incoming_rx) = mpsc::channel(100);
 
         let websocket = WebSocketService::new(incoming_tx);
 
         // Try to load existing identity
-        let (protocol, identity, is_linked) =
-            if let Some((_pub_key, priv_key, reg_id)) = store.get_local_identity().await? {
+        let (protocol, identity, is_linked) = if let Some((_pub_key, priv_key, reg_id)) =
+            store.get_local_identity(ServiceIdKind::Aci).await?
+        {
+            let mut key_bytes = [0u8; 32];
+            key_bytes.copy_from_slice(&priv_key[..32]);
+            let mut protocol = SignalProtocol::from_identity(&key_bytes, reg_id)?;
+
+            if let Some((_pub_key, priv_key, reg_id)) =
+                store.get_local_identity(ServiceIdKind::Pni).await?
+            {
                 let mut key_bytes = [0u8; 32];
                 key_bytes.copy_from_slice(&priv_key[..32]);
-                let protocol = SignalProtocol::from_identity(&key_bytes, reg_id)?;
+                protocol = protocol.with_pni_identity(&key_bytes, reg_id)?;
+            }
 
-                // Load identity from store
-                let identity = store.get_identity().await?;
-                let is_linked = identity.is_some();
-                (protocol, identity, is_linked)
-            } else {
-                (SignalProtocol::new()?, None, false)
-            };
+            // Load identity from store
+            let identity = store.get_identity().await?;
+            let is_linked = identity.is_some();
+            (protocol, identity, is_linked)
+        } else {
+            (SignalProtocol::new()?, None, false)
+        };
 
         Ok(Self {
             protocol: Arc::new(RwLock::new(protocol)),
-            store: Arc::new(store),
+            store,
+            conversations,
             websocket: Arc::new(RwLock::new(websocket)),
             identity,
             device_password: None,
             is_linked,
-            event_tx,
+            events,
             incoming_rx: Arc::new(RwLock::new(incoming_rx)),
         })
     }
@@ -128,9 +158,14 @@ impl SignalClient {
         self.identity.as_ref()
     }
 
-    /// Get event sender for subscribing to events
-    pub fn event_sender(&self) -> mpsc::Sender<SignalEvent> {
-        self.event_tx.clone()
+    /// Bus announcing client events; subscribe to it to receive them
+    pub fn events(&self) -> EventBus {
+        self.events.clone()
+    }
+
+    /// Store, for reads that should not wait on the client
+    pub fn store(&self) -> Arc<SignalStore> {
+        self.store.clone()
     }
 
     /// Generate a device linking URI for QR code
@@ -194,7 +229,7 @@ impl SignalClient {
                     self.is_linked = true;
 
                     // Emit event
-                    let _ = self.event_tx.send(SignalEvent::DeviceLinked(identity.clone())).await;
+                    self.events.publish(SignalEvent::DeviceLinked(identity.clone()));
 
                     return Ok(identity);
                 }
@@ -243,7 +278,8 @@ impl SignalClient {
 
         // Create identity from provisioning data
         let identity = SignalIdentity {
-            uuid: prov_data.uuid,
+            aci: prov_data.aci,
+            pni: prov_data.pni,
             phone_number: Some(prov_data.phone_number),
             device_id: 2, // Linked devices start at 2
             registration_id: {
@@ -257,43 +293,62 @@ impl SignalClient {
             let protocol = self.protocol.read().await;
             self.store
                 .store_local_identity(
+                    ServiceIdKind::Aci,
                     &protocol.identity_public_key().as_bytes(),
                     &protocol.identity_private_key(),
                     protocol.registration_id(),
                 )
                 .await?;
+            self.store
+                .store_local_identity(
+                    ServiceIdKind::Pni,
+                    &protocol.pni_identity_public_key().as_bytes(),
+                    &protocol.pni_identity_private_key(),
+                    protocol.pni_registration_id(),
+                )
+                .await?;
         }
+        self.store.store_account(&identity).await?;
 
-        // Generate and store pre-keys
+        // Generate and store pre-keys for both identities
         {
             let mut protocol = self.protocol.write().await;
-            let pre_keys = protocol.generate_pre_keys(100)?;
-
-            // Convert to storage format
-            let storage_keys: Vec<(u32, Vec<u8>, Vec<u8>)> = pre_keys
-                .iter()
-                .map(|(id, pub_key)| (*id, pub_key.clone(), vec![]))
-                .collect();
-
-            self.store.store_pre_keys(&storage_keys).await?;
-
-            // Generate signed pre-key
-            let (spk_pub, spk_sig) = protocol.generate_signed_pre_key(1)?;
-            self.store
-                .store_signed_pre_key(
-                    1,
-                    &spk_pub,
-                    &[], // Private key handled by protocol
-                    &spk_sig,
-                    chrono::Utc::now().timestamp(),
-                )
-                .await?;
+            for kind in [ServiceIdKind::Aci, ServiceIdKind::Pni] {
+                let (pre_keys, (spk_pub, spk_sig)) = match kind {
+                    ServiceIdKind::Aci => (
+                        protocol.generate_pre_keys(100)?,
+                        protocol.generate_signed_pre_key(1)?,
+                    ),
+                    ServiceIdKind::Pni => (
+                        protocol.generate_pni_pre_keys(100)?,
+                        protocol.generate_pni_signed_pre_key(1)?,
+                    ),
+                };
+
+                // Convert to storage format
+                let storage_keys: Vec<(u32, Vec<u8>, Vec<u8>)> = pre_keys
+                    .iter()
+                    .map(|(id, pub_key)| (*id, pub_key.clone(), vec![]))
+                    .collect();
+
+                self.store.store_pre_keys(kind, &storage_keys).await?;
+                self.store
+                    .store_signed_pre_key(
+                        kind,
+                        1,
+                        &spk_pub,
+                        &[], // Private key handled by protocol
+                        &spk_sig,
+                        chrono::Utc::now().timestamp(),
+                    )
+                    .await?;
+            }
         }
 
         // Store device password for WebSocket auth
         self.device_password = Some(prov_data.provisioning_code.clone());
 
-        tracing::info!("Device linking complete: {:?}", identity.uuid);
+        tracing::info!("Device linking complete: {}", identity.aci);
 
         Ok(identity)
     }
@@ -316,14 +371,11 @@ impl SignalClient {
 
         tracing::info!("Connecting to Signal servers");
 
-        let _ = self
-            .event_tx
-            .send(SignalEvent::ConnectionChanged(ConnectionStatus::Connecting))
-            .await;
+        self.events.publish(SignalEvent::ConnectionChanged(ConnectionStatus::Connecting));
 
         // Create credentials
         let credentials = WebSocketCredentials::from_device(
-            &identity.uuid.to_string(),
+            &identity.aci.to_string(),
             identity.device_id,
             password,
         );
@@ -334,35 +386,36 @@ impl SignalClient {
             ws.connect(&credentials).await?;
         }
 
-        let _ = self
-            .event_tx
-            .send(SignalEvent::ConnectionChanged(ConnectionStatus::Connected))
-            .await;
+        self.events.publish(SignalEvent::ConnectionChanged(ConnectionStatus::Connected));
 
         // Start message receive loop
-        self.start_message_loop();
+        self.start_message_loop(identity.clone());
 
         Ok(())
     }
 
     /// Start the background message processing loop
-    fn start_message_loop(&self) {
+    fn start_message_loop(&self, local: SignalIdentity) {
         let incoming_rx = self.incoming_rx.clone();
-        let event_tx = self.event_tx.clone();
-        let protocol = self.protocol.clone();
-        let store = self.store.clone();
+        let context = MessageContext {
+            protocol: self.protocol.clone(),
+            store: self.store.clone(),
+            conversations: self.conversations.clone(),
+            websocket: self.websocket.clone(),
+            events: self.events.clone(),
+            local,
+        };
 
         tokio::spawn(async move {
             let mut rx = incoming_rx.write().await;
+            let mut failures = HashMap::new();
 
             while let Some(msg) = rx.recv().await {
                 match msg {
                     IncomingMessage::Envelope(envelope) => {
-                        if let Err(e) =
-                            Self::process_envelope(&envelope, &protocol, &store, &event_tx).await
-                        {
+                        if let Err(e) = context.process_envelope(&envelope, &mut failures).await {
                             tracing::error!("Failed to process envelope: {}", e);
-                            let _ = event_tx.send(SignalEvent::Error(e.to_string())).await;
+                            context.events.publish(SignalEvent::Error(e.to_string()));
                         }
                     }
                     IncomingMessage::QueueEmpty => {
@@ -370,86 +423,37 @@ impl SignalClient {
                     }
                     IncomingMessage::Disconnected => {
                         tracing::warn!("WebSocket disconnected");
-                        let _ = event_tx
-                            .send(SignalEvent::ConnectionChanged(ConnectionStatus::Disconnected))
-                            .await;
+                        let status = ConnectionStatus::Disconnected;
+                        context.events.publish(SignalEvent::ConnectionChanged(status));
                     }
                 }
             }
         });
     }
 
-    /// Process an incoming message envelope
-    async fn process_envelope(
-        envelope: &[u8],
-        protocol: &Arc<RwLock<SignalProtocol>>,
-        store: &Arc<SignalStore>,
-        event_tx: &mpsc::Sender<SignalEvent>,
-    ) -> Result<()> {
-        // Parse envelope header
-        // Format: [source_uuid:36][device_id:4][timestamp:8][type:1][content...]
-        if envelope.len() < 49 {
-            return Err(anyhow!("Envelope too short"));
-        }
-
-        let source_uuid_str = String::from_utf8(envelope[0..36].to_vec())?;
-        let source_uuid: Uuid = source_uuid_str.parse()?;
-        let device_id = u32::from_be_bytes(envelope[36..40].try_into()?);
-        let timestamp = i64::from_be_bytes(envelope[40..48].try_into()?);
-        let msg_type = envelope[48];
-        let content = &envelope[49..];
-
-        let sender_address = ProtocolAddress::new(source_uuid.to_string(), device_id);
-
-        // Decrypt the content based on message type
-        let plaintext = match msg_type {
-            1 => {
-                // Pre-key message (initial message)
-                let mut proto = protocol.write().await;
-                proto.decrypt_initial(&sender_address, content).await?
-            }
-            2 => {
-                // Regular message
-                let proto = protocol.read().await;
-                proto.decrypt(&sender_address, content).await?
-            }
-            _ => {
-                return Err(anyhow!("Unknown message type: {}", msg_type));
-            }
-        };
-
-        // Parse the decrypted content as a message
-        let content: MessageContent = serde_json::from_slice(&plaintext)
-            .unwrap_or(MessageContent::Text {
-                body: String::from_utf8_lossy(&plaintext).to_string(),
-            });
-
-        // Create message object
-        let message = Message {
-            id: Uuid::new_v4().to_string(),
-            conversation_id: source_uuid.to_string(),
-            sender: SignalIdentity {
-                uuid: source_uuid,
-                phone_number: None,
-                device_id,
-                registration_id: 0,
-            },
-            timestamp,
-            received_timestamp: Some(chrono::Utc::now().timestamp_millis()),
-            content,
-            status: MessageStatus::Delivered,
-            quote: None,
-            reactions: Vec::new(),
-            expires_at: None,
-        };
-
-        // Store the message
-        store.store_message(&message).await?;
-
-        // Emit event
-        let _ = event_tx.send(SignalEvent::MessageReceived(message)).await;
+    /// Handle for long-running work such as syncs, to use without
+    /// holding the client
+    pub fn handle(&self) -> Result<ClientHandle> {
+        Ok(ClientHandle {
+            context: self.context()?,
+        })
+    }
 
-        Ok(())
+    /// Handles for sending on behalf of the linked identity
+    fn context(&self) -> Result<MessageContext> {
+        let local = self
+            .identity
+            .clone()
+            .ok_or_else(|| anyhow!("No local identity"))?;
+
+        Ok(MessageContext {
+            protocol: self.protocol.clone(),
+            store: self.store.clone(),
+            conversations: self.conversations.clone(),
+            websocket: self.websocket.clone(),
+            events: self.events.clone(),
+            local,
+        })
     }
 
     /// Disconnect from Signal servers
@@ -461,10 +465,7 @@ impl SignalClient {
             ws.disconnect().await?;
         }
 
-        let _ = self
-            .event_tx
-            .send(SignalEvent::ConnectionChanged(ConnectionStatus::Disconnected))
-            .await;
+        self.events.publish(SignalEvent::ConnectionChanged(ConnectionStatus::Disconnected));
 
         Ok(())
     }
@@ -475,56 +476,32 @@ impl SignalClient {
         recipient: &SignalIdentity,
         content: &str,
     ) -> Result<Message> {
-        tracing::info!("Sending message to {:?}", recipient.uuid);
-
-        let recipient_address =
-            ProtocolAddress::new(recipient.uuid.to_string(), recipient.device_id);
+        tracing::info!("Sending message to {}", recipient.aci);
 
         // Create message content
         let msg_content = MessageContent::Text {
             body: content.to_string(),
         };
-        let content_bytes = serde_json::to_vec(&msg_content)?;
-
-        // Encrypt the message
-        let ciphertext = {
-            let protocol = self.protocol.read().await;
-
-            if protocol.has_session(&recipient_address).await {
-                protocol.encrypt(&recipient_address, &content_bytes).await?
-            } else {
-                // Need to fetch pre-key bundle and establish session
-                // For now, return error - real implementation would fetch from server
-                return Err(anyhow!(
-                    "No session with recipient. Pre-key bundle fetch not implemented."
-                ));
-            }
-        };
 
-        // Build envelope and send via WebSocket
         let timestamp = chrono::Utc::now().timestamp_millis();
-        let mut envelope = Vec::with_capacity(49 + ciphertext.len());
-
-        if let Some(identity) = &self.identity {
-            envelope.extend_from_slice(identity.uuid.to_string().as_bytes());
-            envelope.extend_from_slice(&identity.device_id.to_be_bytes());
-        } else {
-            return Err(anyhow!("No local identity"));
-        }
-
-        envelope.extend_from_slice(&timestamp.to_be_bytes());
-        envelope.push(2); // Regular message type
-        envelope.extend_from_slice(&ciphertext);
-
-        {
-            let ws = self.websocket.read().await;
-            ws.send_message(&envelope).await?;
-        }
+        let content_bytes = codec::encode(&SignalContent::Data(Box::new(DataMessage {
+            timestamp,
+            content: Some(msg_content.clone()),
+            ..Default::default()
+        })))?;
+        self.context()?
+            .deliver(recipient, &content_bytes, timestamp, true)
+            .await?;
+
+        // Keep the content around in case the recipient asks for a resend
+        self.store
+            .store_recent_send(&recipient.aci.to_string(), timestamp, &content_bytes)
+            .await?;
 
         // Create message object
         let message = Message {
             id: Uuid::new_v4().to_string(),
-            conversation_id: recipient.uuid.to_string(),
+            conversation_id: recipient.aci.to_string(),
             sender: self.identity.clone().unwrap(),
             timestamp,
             received_timestamp: None,
@@ -533,10 +510,29 @@ impl SignalClient {
             quote: None,
             reactions: Vec::new(),
             expires_at: None,
+            envelope: None,
         };
 
-        // Store the message
-        self.store.store_message(&message).await?;
+        // Store the message in its conversation
+        self.conversations
+            .record_outgoing(&message, recipient, None)
+            .await?;
+        let recipients = [SentRecipient {
+            identity: recipient.clone(),
+            unidentified: false,
+        }];
+        self.store
+            .store_message_recipients(&message.id, &recipients, MessageStatus::Sent)
+            .await?;
+
+        // Show the message on our other devices too
+        let context = self.context()?;
+        if let Err(e) = context
+            .send_transcript(&message, recipient, &recipients)
+            .await
+        {
+            tracing::warn!("Failed to send transcript of {}: {}", message.id, e);
+        }
 
         Ok(message)
     }
@@ -548,7 +544,7 @@ impl SignalClient {
         file_path: &Path,
         caption: Option<&str>,
     ) -> Result<Message> {
-        tracing::info!("Sending attachment to {:?}: {:?}", recipient.uuid, file_path);
+        tracing::info!("Sending attachment to {}: {:?}", recipient.aci, file_path);
 
         // Read file
         let file_data = tokio::fs::read(file_path).await?;
@@ -616,20 +612,46 @@ impl SignalClient {
         recipient: &SignalIdentity,
         action: TypingAction,
     ) -> Result<()> {
-        tracing::debug!("Sending typing indicator to {:?}: {:?}", recipient.uuid, action);
+        tracing::debug!("Sending typing indicator to {}: {:?}", recipient.aci, action);
 
-        // Create typing message
-        let typing_msg = serde_json::json!({
-            "type": "typing",
-            "action": if matches!(action, TypingAction::Started) { "started" } else { "stopped" },
-            "timestamp": chrono::Utc::now().timestamp_millis()
-        });
+        let timestamp = chrono::Utc::now().timestamp_millis();
+        let typing = TypingMessage {
+            action,
+            timestamp,
+            group_id: None,
+        };
+        let content_bytes = codec::encode(&SignalContent::Typing(typing))?;
 
-        // TODO: Send via WebSocket
-        // For now, just log
-        tracing::debug!("Typing message: {}", typing_msg);
+        // Typing indicators must not wake the recipient's devices
+        self.context()?
+            .deliver(recipient, &content_bytes, timestamp, false)
+            .await
+    }
 
-        Ok(())
+    /// Send a typing indicator to the other party of a conversation, or to
+    /// every other member of a group
+    pub async fn send_conversation_typing(
+        &self,
+        conversation_id: &str,
+        action: TypingAction,
+    ) -> Result<()> {
+        let conversation = self
+            .store
+            .get_conversation(conversation_id)
+            .await?
+            .ok_or_else(|| anyhow!("Unknown conversation {}", conversation_id))?;
+
+        if conversation.is_group {
+            let group_id = conversation.group_id.unwrap_or(conversation.id);
+            let group = self
+                .store
+                .get_group(&group_id)
+                .await?
+                .ok_or_else(|| anyhow!("Unknown group {}", group_id))?;
+            return self.context()?.send_group_typing(&group, action).await;
+        }
+
+        self.send_typing(&conversation.recipient, action).await
     }
 
     /// Mark messages as read
@@ -651,24 +673,336 @@ impl SignalClient {
         self.store.get_conversations().await
     }
 
-    /// Get messages for a conversation
+    /// Clear the unread count of a conversation once it has been seen,
+    /// telling our other devices which messages were read
+    pub async fn mark_conversation_read(&self, conversation_id: &str) -> Result<()> {
+        let read = self.conversations.mark_read(conversation_id).await?;
+        if read.is_empty() {
+            return Ok(());
+        }
+
+        let timestamp = chrono::Utc::now().timestamp_millis();
+        self.context()?
+            .send_sync(
+                SyncMessage::ReadMessages { messages: read },
+                timestamp,
+                false,
+            )
+            .await
+    }
+
+    /// Get the newest messages of a conversation, newest first
     pub async fn get_messages(&self, conversation_id: &str, limit: usize) -> Result<Vec<Message>> {
         self.store.get_messages(conversation_id, limit).await
     }
 
+    /// Get older messages of a conversation, oldest first
+    pub async fn get_messages_before(
+        &self,
+        conversation_id: &str,
+        cursor: &MessageCursor,
+        limit: usize,
+    ) -> Result<Vec<Message>> {
+        self.store
+            .get_messages_before(conversation_id, cursor, limit)
+            .await
+    }
+
+    /// Get newer messages of a conversation, oldest first
+    pub async fn get_messages_after(
+        &self,
+        conversation_id: &str,
+        cursor: &MessageCursor,
+        limit: usize,
+    ) -> Result<Vec<Message>> {
+        self.store
+            .get_messages_after(conversation_id, cursor, limit)
+            .await
+    }
+
+    /// Get the messages around one message, e.g. a quote or search hit
+    pub async fn get_messages_around(
+        &self,
+        conversation_id: &str,
+        message_id: &str,
+        limit: usize,
+    ) -> Result<Option<MessageWindow>> {
+        self.store
+            .get_messages_around(conversation_id, message_id, limit)
+            .await
+    }
+
+    /// Search message text in all conversations, or only in `conversation_id`
+    pub async fn search_messages(
+        &self,
+        query: &str,
+        conversation_id: Option<&str>,
+        paging: SearchPaging,
+    ) -> Result<Vec<SearchHit>> {
+        self.store
+            .search_messages(query, conversation_id, paging)
+            .await
+    }
+
     /// Get contacts
     pub async fn get_contacts(&self) -> Result<Vec<SignalIdentity>> {
         self.store.get_contacts().await
     }
 
-    /// Sync with primary device
-    pub async fn request_sync(&self) -> Result<()> {
-        tracing::info!("Requesting sync from primary device");
+    /// Change our account record in the storage service
+    ///
+    /// Our other devices pick the change up on their next storage sync.
+    pub async fn update_account_record(&self, update: impl Fn(&mut AccountRecord)) -> Result<()> {
+        let is_account = |record: &StorageRecord| matches!(record, StorageRecord::Account(_));
+
+        self.context()?
+            .update_storage_record(storage_service::ACCOUNT_RECORD, is_account, |record| {
+                let mut account = match record {
+                    Some(StorageRecord::Account(account)) => account,
+                    _ => AccountRecord::default(),
+                };
+                update(&mut account);
+                StorageRecord::Account(account)
+            })
+            .await
+    }
+
+    /// Change the storage record of the contact with `aci`, or add one
+    pub async fn update_contact_record(
+        &self,
+        aci: Aci,
+        update: impl Fn(&mut ContactRecord),
+    ) -> Result<()> {
+        let is_contact = |record: &StorageRecord| match record {
+            StorageRecord::Contact(contact) => contact.aci == Some(aci),
+            _ => false,
+        };
 
-        // Send sync request message
-        // TODO: Implement sync request protocol
+        self.context()?
+            .update_storage_record(storage_service::CONTACT_RECORD, is_contact, |record| {
+                let mut contact = match record {
+                    Some(StorageRecord::Contact(contact)) => contact,
+                    _ => ContactRecord {
+                        aci: Some(aci),
+                        ..Default::default()
+                    },
+                };
+                update(&mut contact);
+                StorageRecord::Contact(contact)
+            })
+            .await
+    }
 
-        Ok(())
+    /// Change the storage record of the group with `master_key`, or add one
+    pub async fn update_group_record(
+        &self,
+        master_key: &[u8],
+        update: impl Fn(&mut GroupV2Record),
+    ) -> Result<()> {
+        let is_group = |record: &StorageRecord| match record {
+            StorageRecord::GroupV2(group) => group.master_key == master_key,
+            _ => false,
+        };
+
+        self.context()?
+            .update_storage_record(storage_service::GROUP_V2_RECORD, is_group, |record| {
+                let mut group = match record {
+                    Some(StorageRecord::GroupV2(group)) => group,
+                    _ => GroupV2Record {
+                        master_key: master_key.to_vec(),
+                        ..Default::default()
+                    },
+                };
+                update(&mut group);
+                StorageRecord::GroupV2(group)
+            })
+            .await
+    }
+
+    /// Archive or unarchive a conversation on all our devices
+    pub async fn set_conversation_archived(
+        &self,
+        conversation_id: &str,
+        archived: bool,
+    ) -> Result<()> {
+        let conversation = self.storage_conversation(conversation_id).await?;
+        let local_aci = self.context()?.local.aci;
+
+        match conversation {
+            StorageConversation::Group(master_key) => {
+                self.update_group_record(&master_key, |group| group.archived = archived)
+                    .await
+            }
+            // Note to Self keeps its state in our account record
+            StorageConversation::Contact(aci) if aci == local_aci => {
+                self.update_account_record(|account| account.note_to_self_archived = archived)
+                    .await
+            }
+            StorageConversation::Contact(aci) => {
+                self.update_contact_record(aci, |contact| contact.archived = archived)
+                    .await
+            }
+        }
+    }
+
+    /// Mute a conversation on all our devices until a millisecond
+    /// timestamp, or unmute it
+    pub async fn set_conversation_muted(
+        &self,
+        conversation_id: &str,
+        muted_until: Option<i64>,
+    ) -> Result<()> {
+        match self.storage_conversation(conversation_id).await? {
+            StorageConversation::Group(master_key) => {
+                self.update_group_record(&master_key, |group| group.muted_until = muted_until)
+                    .await
+            }
+            StorageConversation::Contact(aci) => {
+                self.update_contact_record(aci, |contact| contact.muted_until = muted_until)
+                    .await
+            }
+        }
+    }
+
+    /// Pin a conversation to the top of the chat list on all our devices,
+    /// or unpin it
+    pub async fn set_conversation_pinned(&self, conversation_id: &str, pinned: bool) -> Result<()> {
+        let conversation = self.storage_conversation(conversation_id).await?;
+
+        if pinned {
+            let conversations = self.store.get_conversations().await?;
+            let pinned_ids: Vec<&str> = conversations
+                .iter()
+                .filter(|conversation| conversation.pinned.is_some())
+                .map(|conversation| conversation.id.as_str())
+                .collect();
+            if pinned_ids.contains(&conversation_id) {
+                return Ok(());
+            }
+            if pinned_ids.len() >= MAX_PINNED_CONVERSATIONS {
+                return Err(anyhow!(
+                    "Only {} conversations can be pinned",
+                    MAX_PINNED_CONVERSATIONS
+                ));
+            }
+        }
+
+        self.update_account_record(|account| {
+            account
+                .pinned_conversations
+                .retain(|pin| !conversation.is_pinned_by(pin));
+            if pinned {
+                account.pinned_conversations.push(conversation.pin());
+            }
+        })
+        .await
+    }
+
+    /// Change the name on our profile
+    ///
+    /// The profile is uploaded again under our profile key, keeping its
+    /// other fields, and the name goes into our account record in the
+    /// storage service for our other devices.
+    pub async fn set_profile_name(
+        &self,
+        given_name: &str,
+        family_name: Option<&str>,
+    ) -> Result<()> {
+        let given_name = given_name.trim();
+        if given_name.is_empty() {
+            return Err(anyhow!("A profile needs a given name"));
+        }
+        let family_name = family_name
+            .map(str::trim)
+            .filter(|family_name| !family_name.is_empty());
+
+        tracing::info!("Changing profile name");
+        self.context()?
+            .upload_profile_name(given_name, family_name)
+            .await?;
+        self.update_account_record(|account| {
+            account.given_name = Some(given_name.to_string());
+            account.family_name = family_name.map(str::to_string);
+        })
+        .await
+    }
+
+    /// What names a conversation in the storage service
+    async fn storage_conversation(&self, conversation_id: &str) -> Result<StorageConversation> {
+        let conversation = self
+            .store
+            .get_conversation(conversation_id)
+            .await?
+            .ok_or_else(|| anyhow!("Unknown conversation {}", conversation_id))?;
+
+        match &conversation.group_id {
+            Some(group_id) => {
+                let group = self
+                    .store
+                    .get_group(group_id)
+                    .await?
+                    .ok_or_else(|| anyhow!("Unknown group {}", group_id))?;
+                Ok(StorageConversation::Group(group.master_key))
+            }
+            None if conversation.recipient.aci.is_nil() => Err(anyhow!(
+                "Conversation {} has no ACI to name it by",
+                conversation_id
+            )),
+            None => Ok(StorageConversation::Contact(conversation.recipient.aci)),
+        }
+    }
+
+    /// Get a group by its ID
+    pub async fn get_group(&self, group_id: &str) -> Result<Option<Group>> {
+        self.store.get_group(group_id).await
+    }
+
+    /// Create a group with us as its administrator
+    ///
+    /// People whose profile key we have join right away, the others are
+    /// invited. Everyone added is told of the group.
+    pub async fn create_group(
+        &self,
+        name: &str,
+        description: Option<&str>,
+        avatar: Option<&[u8]>,
+        disappearing_timer: Option<u32>,
+        members: &[Aci],
+    ) -> Result<Group> {
+        self.context()?
+            .create_group(name, description, avatar, disappearing_timer, members)
+            .await
+    }
+
+    /// Change the members, roles, access control or attributes of a group
+    ///
+    /// Changes our role or the group's access control does not allow are
+    /// turned down before reaching the server.
+    pub async fn change_group(&self, group_id: &str, update: GroupUpdate) -> Result<Group> {
+        self.context()?.change_group(group_id, &update).await
+    }
+
+    /// Invite link of a group to share, unless its link is turned off
+    pub async fn group_invite_link(&self, group_id: &str) -> Result<Option<String>> {
+        let group = self.store.get_group(group_id).await?;
+        Ok(group
+            .as_ref()
+            .and_then(InviteLink::of_group)
+            .map(|link| link.url()))
+    }
+
+    /// Look up the group of an invite link without joining it
+    pub async fn preview_group_link(&self, url: &str) -> Result<GroupPreview> {
+        self.context()?.preview_group_link(url).await
+    }
+
+    /// Join the group of an invite link, or ask its administrators to let
+    /// us in when the link needs approval
+    ///
+    /// Returns what the link showed of the group; `requires_approval` tells
+    /// whether we wait for an administrator.
+    pub async fn join_group_link(&self, url: &str) -> Result<GroupPreview> {
+        self.context()?.join_group_link(url).await
     }
 
     /// Get safety number for a contact
@@ -676,7 +1010,7 @@ impl SignalClient {
         let identity = self.identity.as_ref().ok_or_else(|| anyhow!("No identity"))?;
 
         let protocol = self.protocol.read().await;
-        protocol.get_safety_number(&identity.uuid.to_string(), contact_id)
+        protocol.get_safety_number(&identity.aci, &contact_id.parse()?)
     }
 
     /// Unlink device and clear all data
@@ -698,25 +1032,1646 @@ impl SignalClient {
     }
 }
 
-/// Device linking session data
-pub struct LinkingSession {
-    /// Provisioning UUID
-    pub provisioning_uuid: String,
-    /// Ephemeral key pair for provisioning encryption
-    pub ephemeral_key: DhKeyPair,
+/// Device ID of an account's primary device
+const PRIMARY_DEVICE_ID: u32 = 1;
+
+/// Number of consecutive decryption failures before a session is reset
+const MAX_DECRYPTION_FAILURES: u32 = 3;
+
+/// Storage service records fetched per request
+const STORAGE_READ_BATCH: usize = 1000;
+
+/// Storage service writes tried before giving up to other devices' writes
+const STORAGE_WRITE_ATTEMPTS: usize = 3;
+
+/// Age after which a fetched profile is fetched again
+const PROFILE_MAX_AGE_SECS: i64 = 24 * 60 * 60;
+
+/// Group changes tried before giving up to other members' changes
+const GROUP_CHANGE_ATTEMPTS: usize = 3;
+
+/// Handle on the client for syncs and other long-running work
+///
+/// It shares the client's store, WebSocket and event bus, so it can be
+/// taken under the client lock and used once the lock is released, leaving
+/// the client free for the UI meanwhile.
+#[derive(Clone)]
+pub struct ClientHandle {
+    context: MessageContext,
 }
 
-use sha2::Digest;
+impl ClientHandle {
+    /// Mark our messages in a conversation as read by the other party up to
+    /// `read_at`, announcing each status change
+    pub async fn apply_read_receipt(&self, conversation_id: &str, read_at: i64) -> Result<()> {
+        let context = &self.context;
+        let read = context
+            .store
+            .mark_sent_messages_read(conversation_id, context.local.aci, read_at)
+            .await?;
+
+        for message_id in read {
+            context.events.publish(SignalEvent::MessageStatusChanged {
+                message_id,
+                status: MessageStatus::Read,
+            });
+        }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::TempDir;
+        Ok(())
+    }
+
+    /// Ask the primary device to send us its state of `kind`
+    ///
+    /// The primary answers with a sync message, handled as it arrives.
+    pub async fn request_sync(&self, kind: SyncRequest) -> Result<()> {
+        tracing::info!("Requesting {:?} sync from primary device", kind);
+
+        if self.context.primary_device().is_none() {
+            return Err(anyhow!("Only linked devices request syncs"));
+        }
+
+        let timestamp = chrono::Utc::now().timestamp_millis();
+        self.context
+            .send_sync(SyncMessage::Request { kind }, timestamp, true)
+            .await
+    }
+
+    /// Whether the primary device was asked for its state since we linked
+    pub async fn initial_sync_requested(&self) -> Result<bool> {
+        self.context.store.initial_sync_requested().await
+    }
+
+    /// Record that the primary device was asked for its state
+    pub async fn set_initial_sync_requested(&self) -> Result<()> {
+        self.context.store.set_initial_sync_requested().await
+    }
+
+    /// Fetch state our other devices changed from the storage service
+    pub async fn sync_storage(&self) -> Result<()> {
+        self.context.sync_storage().await
+    }
+
+    /// Fetch the profiles of contacts that were never fetched, or not
+    /// fetched for a day
+    pub async fn refresh_profiles(&self) -> Result<()> {
+        self.context.refresh_profiles().await
+    }
+}
+
+/// Conversations Signal lets an account pin
+const MAX_PINNED_CONVERSATIONS: usize = 4;
+
+/// Shared handles for processing envelopes and sending content
+///
+/// Used by the background message loop, which has to reply to senders
+/// (resend requests, session resets) without access to the client.
+#[derive(Clone)]
+struct MessageContext {
+    protocol: Arc<RwLock<SignalProtocol>>,
+    store: Arc<SignalStore>,
+    conversations: ConversationService,
+    websocket: Arc<RwLock<WebSocketService>>,
+    events: EventBus,
+    /// Our own identity, the source of everything we send
+    local: SignalIdentity,
+}
+
+impl MessageContext {
+    /// Process an incoming message envelope
+    async fn process_envelope(
+        &self,
+        envelope: &[u8],
+        failures: &mut HashMap<ProtocolAddress, u32>,
+    ) -> Result<()> {
+        let envelope = codec::decode_envelope(envelope)?;
+        let timestamp = envelope.timestamp;
+
+        // Drop envelopes the server delivered more than once
+        if let Some(guid) = &envelope.metadata.server_guid {
+            if self.store.has_server_guid(guid).await? {
+                tracing::debug!("Dropping duplicate envelope {}", guid);
+                return Ok(());
+            }
+        }
+
+        // Messages to our PNI are decrypted with the PNI identity
+        let destination = match envelope.metadata.destination_service_id {
+            Some(ServiceId::Pni(pni)) if self.local.pni == Some(pni) => ServiceIdKind::Pni,
+            Some(ServiceId::Pni(pni)) => {
+                return Err(anyhow!("Envelope addressed to unknown PNI {}", pni));
+            }
+            _ => ServiceIdKind::Aci,
+        };
+
+        let source = match envelope.envelope_type {
+            EnvelopeType::Ciphertext
+            | EnvelopeType::PreKeyBundle
+            | EnvelopeType::PlaintextContent => envelope
+                .source
+                .ok_or_else(|| anyhow!("Envelope without source"))?,
+            EnvelopeType::Receipt => {
+                tracing::debug!("Server delivery receipt for {}", timestamp);
+                return Ok(());
+            }
+            EnvelopeType::UnidentifiedSender => {
+                return Err(anyhow!("Sealed sender envelopes are not supported"));
+            }
+            other => {
+                return Err(anyhow!("Unsupported envelope type: {:?}", other));
+            }
+        };
+        let sender = self.envelope_sender(source, envelope.source_device).await?;
+        let source_aci = sender.aci;
+        // The session is with whichever identity the sender used
+        let sender_address = ProtocolAddress::new(source, sender.device_id);
+
+        if envelope.envelope_type == EnvelopeType::PlaintextContent {
+            return match codec::decode_plaintext_content(&envelope.content)? {
+                SignalContent::DecryptionError(error) => {
+                    self.handle_resend_request(&sender, &error).await
+                }
+                _ => Err(anyhow!("Unexpected plaintext content")),
+            };
+        }
+
+        // Decrypt the content based on envelope type
+        let decrypted = if envelope.envelope_type == EnvelopeType::PreKeyBundle {
+            // Pre-key message (initial message)
+            let mut proto = self.protocol.write().await;
+            proto
+                .decrypt_initial(destination, &sender_address, &envelope.content)
+                .await
+        } else {
+            // Regular message
+            let proto = self.protocol.read().await;
+            proto
+                .decrypt(destination, &sender_address, &envelope.content)
+                .await
+        };
+
+        let plaintext = match decrypted {
+            Ok(plaintext) => {
+                failures.remove(&sender_address);
+                plaintext
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to decrypt envelope from {}: {}",
+                    sender_address.to_string(),
+                    e
+                );
+                return self
+                    .handle_decryption_failure(&sender, &sender_address, &envelope, failures)
+                    .await;
+            }
+        };
+
+        // Blocked senders are decrypted to keep the session in step, then dropped
+        if source_aci != self.local.aci && self.store.is_blocked(&sender).await? {
+            tracing::debug!("Dropping message from blocked sender {}", source_aci);
+            return Ok(());
+        }
+
+        match codec::decode(&plaintext, &sender)? {
+            SignalContent::Data(data) => {
+                let group_id = match &data.group_master_key {
+                    Some(master_key) => {
+                        match self
+                            .message_group(master_key, data.group_revision, source_aci)
+                            .await?
+                        {
+                            Some(group_id) => Some(group_id),
+                            None => return Ok(()),
+                        }
+                    }
+                    None => None,
+                };
+                let conversation_id = group_id.clone().unwrap_or_else(|| source_aci.to_string());
+
+                if let Some(reaction) = &data.reaction {
+                    let sender_uuid = source_aci.to_string();
+                    self.store
+                        .apply_reaction(&conversation_id, &sender_uuid, reaction, timestamp)
+                        .await?;
+                }
+
+                // A new profile key means a profile we have not seen yet
+                if let Some(profile_key) = &data.profile_key {
+                    if profile_key.len() != profiles::PROFILE_KEY_SIZE {
+                        tracing::warn!("Ignoring malformed profile key from {}", source_aci);
+                    } else if self
+                        .store
+                        .store_profile_key(source_aci, profile_key)
+                        .await?
+                    {
+                        let context = self.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = context.refresh_profile(source_aci).await {
+                                tracing::warn!("Failed to fetch profile of {}: {}", source_aci, e);
+                            }
+                        });
+                    }
+                }
+
+                let Some(content) = data.content else {
+                    tracing::debug!("Ignoring data message without displayable content");
+                    return Ok(());
+                };
+
+                // A resent message replaces its decryption failure placeholder
+                self.store
+                    .delete_decryption_placeholder(&source_aci.to_string(), timestamp)
+                    .await?;
+
+                let received_at = chrono::Utc::now().timestamp_millis();
+                let quote = data.quote.map(|quote| {
+                    Box::new(Message::quote_stub(
+                        &conversation_id,
+                        quote.author.into(),
+                        quote.timestamp,
+                    ))
+                });
+                let message = Message {
+                    id: Uuid::new_v4().to_string(),
+                    conversation_id,
+                    sender,
+                    timestamp,
+                    received_timestamp: Some(received_at),
+                    content,
+                    status: MessageStatus::Delivered,
+                    quote,
+                    reactions: Vec::new(),
+                    expires_at: data
+                        .expire_timer
+                        .filter(|timer| *timer > 0)
+                        .map(|timer| received_at + timer as i64 * 1000),
+                    envelope: Some(envelope.metadata),
+                };
+
+                // Store the message, then read it back with its quote resolved
+                self.conversations
+                    .record_incoming(&message, group_id.as_deref())
+                    .await?;
+                let message = self
+                    .store
+                    .get_message(&message.id)
+                    .await?
+                    .unwrap_or(message);
+
+                // Emit event
+                self.events.publish(SignalEvent::MessageReceived(message));
+            }
+            SignalContent::Typing(typing) => {
+                // Typing indicators are never stored
+                let conversation_id = typing.group_id.unwrap_or_else(|| source_aci.to_string());
+                self.events.publish(SignalEvent::TypingIndicator {
+                    conversation_id,
+                    sender,
+                    action: typing.action,
+                });
+            }
+            SignalContent::Receipt(receipt) => {
+                if receipt.receipt_type == ReceiptType::Read {
+                    let read_at = receipt.timestamps.iter().copied().max().unwrap_or(timestamp);
+                    self.events.publish(SignalEvent::ReadReceipt {
+                        conversation_id: source_aci.to_string(),
+                        read_at,
+                    });
+                }
+            }
+            SignalContent::Sync(sync) => {
+                // Only our own devices may sync state to us
+                if source_aci != self.local.aci {
+                    tracing::warn!("Ignoring sync message from {}", source_aci);
+                    return Ok(());
+                }
+
+                match &sync {
+                    SyncMessage::Contacts { blob, .. } => {
+                        self.store_synced_contacts(blob).await?;
+                    }
+                    SyncMessage::SentMessage {
+                        message,
+                        destination,
+                        group_master_key,
+                        recipients,
+                    } => {
+                        self.store_transcript(
+                            message,
+                            destination,
+                            group_master_key.as_deref(),
+                            recipients,
+                        )
+                        .await?;
+                    }
+                    SyncMessage::Blocked {
+                        identities,
+                        group_ids,
+                    } => {
+                        for conversation in self.store.store_blocked(identities, group_ids).await? {
+                            self.events
+                                .publish(SignalEvent::ConversationUpdated(conversation));
+                        }
+                    }
+                    SyncMessage::Keys {
+                        storage_service,
+                        master,
+                    } => {
+                        let keys = AccountKeys {
+                            storage_service: storage_service.clone(),
+                            master: master.clone(),
+                        };
+                        self.store.store_account_keys(&keys).await?;
+                    }
+                    // Viewing media reads its message too
+                    SyncMessage::ReadMessages { messages }
+                    | SyncMessage::ViewedMessages { messages } => {
+                        self.conversations.apply_read_sync(messages).await?;
+                    }
+                    _ => {}
+                }
+
+                self.events.publish(SignalEvent::SyncReceived(sync));
+            }
+            SignalContent::Call => {
+                tracing::debug!("Ignoring call message from {}", source_aci);
+            }
+            SignalContent::Null | SignalContent::DecryptionError(_) => {}
+        }
+
+        Ok(())
+    }
+
+    /// Identity of the sender of an envelope
+    ///
+    /// Envelopes sent from a PNI are attributed to the account the PNI is
+    /// merged with; without a known ACI there is no one to attribute them to.
+    async fn envelope_sender(&self, source: ServiceId, device_id: u32) -> Result<SignalIdentity> {
+        let (aci, pni) = match source {
+            ServiceId::Aci(aci) => (aci, None),
+            ServiceId::Pni(pni) => {
+                let aci = self
+                    .store
+                    .get_recipient(&source)
+                    .await?
+                    .and_then(|recipient| recipient.aci)
+                    .ok_or_else(|| anyhow!("Envelope from PNI {} of an unknown account", pni))?;
+                (aci, Some(pni))
+            }
+        };
+
+        Ok(SignalIdentity {
+            aci,
+            pni,
+            phone_number: None,
+            device_id,
+            registration_id: 0,
+        })
+    }
+
+    /// Record an undecryptable envelope and ask the sender to resend it
+    ///
+    /// After repeated failures the session with `address`, the one the
+    /// envelope was decrypted with, is archived and a null message starts a
+    /// new one from a fresh pre-key bundle.
+    async fn handle_decryption_failure(
+        &self,
+        sender: &SignalIdentity,
+        address: &ProtocolAddress,
+        envelope: &Envelope,
+        failures: &mut HashMap<ProtocolAddress, u32>,
+    ) -> Result<()> {
+        let now = chrono::Utc::now().timestamp_millis();
+
+        // Leave a placeholder so the gap in the conversation is visible
+        let placeholder = Message {
+            id: Uuid::new_v4().to_string(),
+            conversation_id: sender.aci.to_string(),
+            sender: sender.clone(),
+            timestamp: envelope.timestamp,
+            received_timestamp: Some(now),
+            content: MessageContent::DecryptionFailed,
+            status: MessageStatus::Delivered,
+            quote: None,
+            reactions: Vec::new(),
+            expires_at: None,
+            envelope: Some(envelope.metadata.clone()),
+        };
+        self.conversations.record_incoming(&placeholder, None).await?;
+        self.events.publish(SignalEvent::MessageReceived(placeholder));
+
+        // Initial messages carry no ratchet key the sender could match
+        let ratchet_key = match envelope.envelope_type {
+            EnvelopeType::Ciphertext => SignalProtocol::message_ratchet_key(&envelope.content),
+            _ => None,
+        };
+        let request = codec::encode_plaintext_content(&DecryptionErrorMessage {
+            ratchet_key,
+            timestamp: envelope.timestamp,
+            device_id: self.local.device_id,
+        });
+        self.send_envelope(sender, EnvelopeType::PlaintextContent, request, now, true)
+            .await?;
+
+        let count = failures.entry(address.clone()).or_insert(0);
+        *count += 1;
+
+        if *count >= MAX_DECRYPTION_FAILURES {
+            failures.remove(address);
+            tracing::warn!("Resetting session with {}", address.to_string());
+
+            self.protocol.read().await.archive_session(address).await;
+            let null = codec::encode(&SignalContent::Null)?;
+            self.deliver(sender, &null, now, false).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Answer a resend request from the recent-send log
+    async fn handle_resend_request(
+        &self,
+        sender: &SignalIdentity,
+        error: &DecryptionErrorMessage,
+    ) -> Result<()> {
+        let address = ProtocolAddress::new(sender.aci, sender.device_id);
+
+        tracing::info!(
+            "Resend of {} requested by {}",
+            error.timestamp,
+            address.to_string()
+        );
+
+        // The session is broken if the failed message used our current ratchet
+        // key, or was an initial message that never established a session
+        {
+            let protocol = self.protocol.read().await;
+            let current_key = protocol.session_ratchet_key(&address).await;
+
+            if error.ratchet_key.is_none() || error.ratchet_key == current_key {
+                protocol.archive_session(&address).await;
+            }
+        }
+
+        let recent = self
+            .store
+            .get_recent_send(&sender.aci.to_string(), error.timestamp)
+            .await?;
+
+        match recent {
+            Some(content) => self.deliver(sender, &content, error.timestamp, true).await,
+            None => {
+                // Nothing to resend; still make sure a working session exists
+                let now = chrono::Utc::now().timestamp_millis();
+                let null = codec::encode(&SignalContent::Null)?;
+                self.deliver(sender, &null, now, false).await
+            }
+        }
+    }
+
+    /// Our primary device, unless we are it
+    fn primary_device(&self) -> Option<SignalIdentity> {
+        (self.local.device_id != PRIMARY_DEVICE_ID).then(|| SignalIdentity {
+            device_id: PRIMARY_DEVICE_ID,
+            ..self.local.clone()
+        })
+    }
+
+    /// Store a message one of our other devices sent as our own
+    async fn store_transcript(
+        &self,
+        message: &Message,
+        destination: &SignalIdentity,
+        group_master_key: Option<&[u8]>,
+        recipients: &[SentRecipient],
+    ) -> Result<()> {
+        let conversation_id = match group_master_key {
+            Some(master_key) => GroupSecretParams::derive_from_master_key(master_key)?.id(),
+            None => message.conversation_id.clone(),
+        };
+        // Our other device may send the same transcript more than once
+        if self
+            .store
+            .has_message(&conversation_id, &message.sender.aci.to_string(), message.timestamp)
+            .await?
+        {
+            tracing::debug!("Dropping repeated transcript of {}", message.timestamp);
+            return Ok(());
+        }
+
+        if let Some(master_key) = group_master_key {
+            // Any state we have will do to name the conversation
+            if let Err(e) = self.refresh_group(master_key, Some(0)).await {
+                tracing::warn!("Failed to fetch group {}: {}", conversation_id, e);
+            }
+
+            let message = Message {
+                conversation_id,
+                ..message.clone()
+            };
+            self.conversations
+                .record_outgoing(&message, destination, Some(&message.conversation_id))
+                .await?;
+            self.store
+                .store_message_recipients(&message.id, recipients, MessageStatus::Sent)
+                .await?;
+
+            self.events.publish(SignalEvent::MessageReceived(message));
+            return Ok(());
+        }
+
+        // Transcripts tell us whose service ID a number belongs to
+        let aci = Some(destination.aci).filter(|aci| !aci.is_nil());
+        if (aci.is_some() || destination.pni.is_some()) && destination.phone_number.is_some() {
+            self.store
+                .merge_recipient(aci, destination.pni, destination.phone_number.as_deref())
+                .await?;
+        }
+
+        self.conversations
+            .record_outgoing(message, destination, None)
+            .await?;
+        self.store
+            .store_message_recipients(&message.id, recipients, MessageStatus::Sent)
+            .await?;
+
+        self.events
+            .publish(SignalEvent::MessageReceived(message.clone()));
+        Ok(())
+    }
+
+    /// Send the transcript of a message we sent to our other devices
+    async fn send_transcript(
+        &self,
+        message: &Message,
+        destination: &SignalIdentity,
+        recipients: &[SentRecipient],
+    ) -> Result<()> {
+        let transcript = SyncMessage::SentMessage {
+            message: Box::new(message.clone()),
+            destination: destination.clone(),
+            group_master_key: None,
+            recipients: recipients.to_vec(),
+        };
+        self.send_sync(transcript, message.timestamp, false).await
+    }
+
+    /// Send a sync message to our primary device
+    ///
+    /// Only the primary is addressed, as we do not track the account's
+    /// other linked devices. Does nothing on the primary itself.
+    async fn send_sync(&self, sync: SyncMessage, timestamp: i64, urgent: bool) -> Result<()> {
+        let Some(primary) = self.primary_device() else {
+            return Ok(());
+        };
+
+        let content_bytes = codec::encode(&SignalContent::Sync(sync))?;
+        self.deliver(&primary, &content_bytes, timestamp, urgent)
+            .await
+    }
+
+    /// Download the contact list sent by the primary device and store it
+    ///
+    /// A contact that fails to store is logged and skipped so one bad
+    /// entry does not lose the rest of the list.
+    async fn store_synced_contacts(&self, blob: &Attachment) -> Result<()> {
+        let stream = attachments::download_attachment(blob).await?;
+        let contacts = codec::decode_contact_details(&stream)?;
+        tracing::info!("Received {} contacts from primary device", contacts.len());
+
+        for details in &contacts {
+            let identity = match self.store.store_synced_contact(details).await {
+                Ok(identity) => identity,
+                Err(e) => {
+                    tracing::warn!("Failed to store synced contact: {}", e);
+                    continue;
+                }
+            };
+
+            // The contact's conversation may have been renamed
+            let conversation = self
+                .store
+                .get_conversation(&identity.aci.to_string())
+                .await?;
+            self.events.publish(SignalEvent::ContactUpdated(identity));
+            if let Some(conversation) = conversation {
+                self.events
+                    .publish(SignalEvent::ConversationUpdated(conversation));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Connect to the storage service, once the primary device has shared
+    /// the storage key
+    async fn storage_service(&self) -> Result<Option<StorageService>> {
+        let keys = self.store.get_account_keys().await?;
+        let Some(storage_key) = storage_service::storage_key(&keys) else {
+            return Ok(None);
+        };
+
+        let response = {
+            let ws = self.websocket.read().await;
+            ws.send_request(WebSocketRequest::new("GET", "/v1/storage/auth"))
+                .await?
+        };
+        if response.status != 200 {
+            return Err(anyhow!(
+                "Storage credentials fetch failed with status {}",
+                response.status
+            ));
+        }
+
+        let credentials = serde_json::from_slice(&response.body.unwrap_or_default())?;
+        Ok(Some(StorageService::new(storage_key, credentials)))
+    }
+
+    /// Bring local state up to date with the storage service
+    async fn sync_storage(&self) -> Result<()> {
+        let Some(service) = self.storage_service().await? else {
+            tracing::info!("No storage key from the primary device yet, skipping storage sync");
+            return Ok(());
+        };
+
+        let local = self.store.get_storage_manifest().await?;
+        match service.fetch_manifest(local.version).await? {
+            Some(remote) => self.apply_storage_manifest(&service, &local, remote).await,
+            None => {
+                tracing::debug!(
+                    "Storage manifest is up to date at version {}",
+                    local.version
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Adopt `remote` if it is newer than `local`, reading the records we
+    /// do not have yet
+    async fn apply_storage_manifest(
+        &self,
+        service: &StorageService,
+        local: &StorageManifest,
+        remote: StorageManifest,
+    ) -> Result<()> {
+        if remote.version <= local.version {
+            tracing::warn!(
+                "Ignoring storage manifest {} as ours is at version {}",
+                remote.version,
+                local.version
+            );
+            return Ok(());
+        }
+
+        let known: HashSet<&[u8]> = local
+            .identifiers
+            .iter()
+            .map(|id| id.raw.as_slice())
+            .collect();
+        let modeled = [
+            storage_service::CONTACT_RECORD,
+            storage_service::GROUP_V2_RECORD,
+            storage_service::ACCOUNT_RECORD,
+        ];
+        let wanted: Vec<StorageId> = remote
+            .identifiers
+            .iter()
+            .filter(|id| !known.contains(id.raw.as_slice()) && modeled.contains(&id.record_type))
+            .cloned()
+            .collect();
+
+        let mut records = Vec::new();
+        for batch in wanted.chunks(STORAGE_READ_BATCH) {
+            for (id, plaintext) in service.read_records(&remote, batch).await? {
+                match storage_service::decode_record(&plaintext) {
+                    Ok(Some(record)) => records.push((id, plaintext, record)),
+                    Ok(None) => {}
+                    Err(e) => tracing::warn!("Skipping malformed storage record: {}", e),
+                }
+            }
+        }
+
+        let changes = self.store.apply_storage_manifest(&remote, &records).await?;
+        self.publish_storage_changes(changes);
+        Ok(())
+    }
+
+    /// Replace the storage service record `matches` picks out, or add one
+    ///
+    /// `update` is given the current record, if any. When another device
+    /// wrote first, its manifest is merged and the write is tried again on
+    /// top of it, so `update` sees that device's changes instead of
+    /// overwriting them.
+    async fn update_storage_record(
+        &self,
+        record_type: i32,
+        matches: impl Fn(&StorageRecord) -> bool,
+        update: impl Fn(Option<StorageRecord>) -> StorageRecord,
+    ) -> Result<()> {
+        let service = self
+            .storage_service()
+            .await?
+            .ok_or_else(|| anyhow!("No storage key from the primary device"))?;
+
+        for _ in 0..STORAGE_WRITE_ATTEMPTS {
+            let local = self.store.get_storage_manifest().await?;
+            let previous = self
+                .store
+                .get_storage_records(record_type)
+                .await?
+                .into_iter()
+                .find_map(|(id, plaintext)| {
+                    let record = storage_service::decode_record(&plaintext).ok().flatten()?;
+                    matches(&record).then_some((id, plaintext, record))
+                });
+
+            let record = update(previous.as_ref().map(|(_, _, record)| record.clone()));
+            let plaintext = storage_service::encode_record(
+                &record,
+                previous
+                    .as_ref()
+                    .map(|(_, plaintext, _)| plaintext.as_slice()),
+            )?;
+
+            // Records are never changed in place; the new one gets a new ID
+            let id = storage_service::new_storage_id(record_type);
+            let deletes: Vec<StorageId> = previous.into_iter().map(|(id, ..)| id).collect();
+            let manifest = StorageManifest {
+                version: local.version + 1,
+                identifiers: local
+                    .identifiers
+                    .iter()
+                    .filter(|existing| !deletes.contains(existing))
+                    .cloned()
+                    .chain([id.clone()])
+                    .collect(),
+                record_ikm: local.record_ikm.clone(),
+            };
+
+            let inserts = [(id.clone(), plaintext.clone())];
+            match service.write(&manifest, &inserts, &deletes).await? {
+                None => {
+                    let changes = self
+                        .store
+                        .apply_storage_manifest(&manifest, &[(id, plaintext, record)])
+                        .await?;
+                    self.publish_storage_changes(changes);
+
+                    // The primary device need not wait for its next sync
+                    let fetch = SyncMessage::FetchLatest {
+                        kind: FetchLatest::StorageManifest,
+                    };
+                    let timestamp = chrono::Utc::now().timestamp_millis();
+                    if let Err(e) = self.send_sync(fetch, timestamp, false).await {
+                        tracing::warn!("Failed to tell primary device about storage write: {}", e);
+                    }
+                    return Ok(());
+                }
+                Some(remote) => {
+                    tracing::info!(
+                        "Storage manifest moved on to version {}, merging before writing again",
+                        remote.version
+                    );
+                    self.apply_storage_manifest(&service, &local, remote)
+                        .await?;
+                }
+            }
+        }
+
+        Err(anyhow!(
+            "Storage service writes kept conflicting with other devices"
+        ))
+    }
+
+    /// Announce what applying storage service records changed
+    fn publish_storage_changes(&self, changes: StorageChanges) {
+        for master_key in changes.groups {
+            let context = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = context.refresh_group(&master_key, None).await {
+                    tracing::warn!("Failed to fetch group from storage record: {}", e);
+                }
+            });
+        }
+        for identity in changes.contacts {
+            self.events.publish(SignalEvent::ContactUpdated(identity));
+        }
+        for conversation in changes.conversations {
+            self.events
+                .publish(SignalEvent::ConversationUpdated(conversation));
+        }
+
+        // Settings reach subscribers the way a configuration sync does
+        if let Some(account) = changes.account {
+            let configuration = SyncMessage::Configuration {
+                read_receipts: Some(account.read_receipts),
+                typing_indicators: Some(account.typing_indicators),
+                link_previews: Some(account.link_previews),
+            };
+            self.events
+                .publish(SignalEvent::SyncReceived(configuration));
+        }
+    }
+
+    /// Fetch and decrypt the profile of `aci` under `profile_key`, if
+    /// they uploaded one
+    async fn fetch_profile(&self, aci: Aci, profile_key: &[u8]) -> Result<Option<Profile>> {
+        let version = profiles::profile_key_version(profile_key, &aci)?;
+
+        let path = format!("/v1/profile/{}/{}", aci, version);
+        let response = {
+            let ws = self.websocket.read().await;
+            ws.send_request(WebSocketRequest::new("GET", path)).await?
+        };
+        match response.status {
+            200 => {}
+            404 => return Ok(None),
+            status => {
+                return Err(anyhow!(
+                    "Profile fetch for {} failed with status {}",
+                    aci,
+                    status
+                ))
+            }
+        }
+
+        let response: profiles::ProfileResponse =
+            serde_json::from_slice(&response.body.unwrap_or_default())?;
+        profiles::decrypt_profile(profile_key, &response).map(Some)
+    }
+
+    /// Fetch a contact's profile with their profile key and store it
+    async fn refresh_profile(&self, aci: Aci) -> Result<()> {
+        let Some(profile_key) = self.store.get_profile_key(aci).await? else {
+            return Ok(());
+        };
+        let profile = self
+            .fetch_profile(aci, &profile_key)
+            .await?
+            .ok_or_else(|| anyhow!("No profile of {} for their profile key", aci))?;
+
+        // Avatars are uploaded to a new path each time they change
+        let cached_url = self.store.get_profile_avatar_url(aci).await?;
+        let avatar = match &profile.avatar_url {
+            Some(url) if cached_url.as_ref() != Some(url) => {
+                match profiles::download_avatar(&profile_key, url).await {
+                    Ok(avatar) => Some(avatar),
+                    Err(e) => {
+                        tracing::warn!("Failed to download profile avatar of {}: {}", aci, e);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        let conversations = self.store.store_profile(aci, &profile, avatar).await?;
+        let identity = SignalIdentity {
+            aci,
+            pni: None,
+            phone_number: None,
+            device_id: 1,
+            registration_id: 0,
+        };
+        self.events.publish(SignalEvent::ContactUpdated(identity));
+        for conversation in conversations {
+            self.events
+                .publish(SignalEvent::ConversationUpdated(conversation));
+        }
+        Ok(())
+    }
+
+    /// Upload our profile with a new name
+    ///
+    /// The about text, emoji and avatar of the profile we uploaded last are
+    /// kept.
+    async fn upload_profile_name(&self, given_name: &str, family_name: Option<&str>) -> Result<()> {
+        let aci = self.local.aci;
+        let profile_key = self
+            .store
+            .get_own_profile_key()
+            .await?
+            .ok_or_else(|| anyhow!("Our profile key is not known yet"))?;
+        let current = self.fetch_profile(aci, &profile_key).await?;
+        let profile = Profile {
+            given_name: Some(given_name.to_string()),
+            family_name: family_name.map(str::to_string),
+            ..current.unwrap_or_default()
+        };
+
+        let body = serde_json::to_vec(&profiles::encrypt_profile(&profile_key, &aci, &profile)?)?;
+        let request = WebSocketRequest::new("PUT", "/v1/profile")
+            .with_header("Content-Type", "application/json")
+            .with_body(body);
+        let response = {
+            let ws = self.websocket.read().await;
+            ws.send_request(request).await?
+        };
+        if response.status != 200 {
+            return Err(anyhow!(
+                "Profile upload failed with status {}",
+                response.status
+            ));
+        }
+        Ok(())
+    }
+
+    /// Fetch the profiles that were never fetched or have grown stale
+    ///
+    /// A profile that fails to fetch is logged and tried again next time.
+    async fn refresh_profiles(&self) -> Result<()> {
+        let fetched_before = chrono::Utc::now().timestamp() - PROFILE_MAX_AGE_SECS;
+        let stale = self.store.get_stale_profiles(fetched_before).await?;
+        if stale.is_empty() {
+            return Ok(());
+        }
+
+        tracing::info!("Refreshing {} profiles", stale.len());
+        for aci in stale {
+            if let Err(e) = self.refresh_profile(aci).await {
+                tracing::warn!("Failed to refresh profile of {}: {}", aci, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Connect to the group server with today's credential
+    async fn group_service(&self) -> Result<GroupService> {
+        let server_params = groups::server_public_params()?;
+        let now = chrono::Utc::now().timestamp();
+        let response = {
+            let ws = self.websocket.read().await;
+            ws.send_request(WebSocketRequest::new("GET", GroupCredentials::path(now)))
+                .await?
+        };
+        if response.status != 200 {
+            return Err(anyhow!(
+                "Group credentials fetch failed with status {}",
+                response.status
+            ));
+        }
+
+        let credentials: GroupCredentials =
+            serde_json::from_slice(&response.body.unwrap_or_default())?;
+        let pni = credentials.pni;
+        GroupService::new(
+            server_params,
+            &credentials.valid_at(now)?,
+            &self.local.aci,
+            pni,
+        )
+    }
+
+    /// Bring a group up to `revision`, or to the newest revision if unset,
+    /// and store it
+    async fn refresh_group(&self, master_key: &[u8], revision: Option<u32>) -> Result<Group> {
+        let params = GroupSecretParams::derive_from_master_key(master_key)?;
+        // Groups found through the storage service have no state yet
+        let local = self
+            .store
+            .get_group(&params.id())
+            .await?
+            .filter(|group| !group.members.is_empty());
+        let local_revision = local.as_ref().map(|group| group.revision);
+
+        // No credentials needed for a revision we have
+        if let (Some(local), Some(revision)) = (&local, revision) {
+            if revision <= local.revision {
+                return Ok(local.clone());
+            }
+        }
+
+        let service = self.group_service().await?;
+        let group = groups::update_group(&service, &params, local, revision).await?;
+        if local_revision != Some(group.revision) {
+            if let Some(conversation) = self.store.store_group(&group).await? {
+                self.events
+                    .publish(SignalEvent::ConversationUpdated(conversation));
+            }
+        }
+        Ok(group)
+    }
+
+    /// Find the group of an incoming group message, updating it to the
+    /// revision the sender saw if that is newer than ours
+    ///
+    /// Returns `None` for messages to drop: those to blocked groups, with a
+    /// malformed master key, or from someone not in the group.
+    async fn message_group(
+        &self,
+        master_key: &[u8],
+        revision: Option<u32>,
+        sender: Aci,
+    ) -> Result<Option<String>> {
+        let params = match GroupSecretParams::derive_from_master_key(master_key) {
+            Ok(params) => params,
+            Err(e) => {
+                tracing::warn!("Dropping group message from {}: {}", sender, e);
+                return Ok(None);
+            }
+        };
+        let group_id = params.id();
+        if self.store.is_group_blocked(&group_id).await? {
+            tracing::debug!("Dropping message to blocked group {}", group_id);
+            return Ok(None);
+        }
+
+        // A message without a revision is checked against the state we
+        // have, which is only fetched when the sender saw a newer one.
+        // Without the group's state, the message is kept unchecked
+        let revision = revision.unwrap_or(0);
+        let group = match self.refresh_group(master_key, Some(revision)).await {
+            Ok(group) => Some(group),
+            Err(e) => {
+                tracing::warn!("Failed to update group {}: {}", group_id, e);
+                self.store
+                    .get_group(&group_id)
+                    .await?
+                    .filter(|group| !group.members.is_empty())
+            }
+        };
+        if group.is_some_and(|group| !group.is_member(&sender.uuid())) {
+            tracing::debug!("Dropping message from non-member {}", sender);
+            return Ok(None);
+        }
+
+        Ok(Some(group_id))
+    }
+
+    /// Create a group on the server, store it and tell its members
+    async fn create_group(
+        &self,
+        name: &str,
+        description: Option<&str>,
+        avatar: Option<&[u8]>,
+        disappearing_timer: Option<u32>,
+        members: &[Aci],
+    ) -> Result<Group> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(anyhow!("A group needs a name"));
+        }
+        // Members are stored with their profile key, ours included
+        let own_profile_key = self
+            .store
+            .get_own_profile_key()
+            .await?
+            .ok_or_else(|| anyhow!("Our profile key is not known yet"))?;
+
+        let mut master_key = [0u8; groups::GROUP_MASTER_KEY_SIZE];
+        rand::RngCore::fill_bytes(&mut rand::rngs::OsRng, &mut master_key);
+        let params = GroupSecretParams::derive_from_master_key(&master_key)?;
+
+        let local = self.local.aci;
+        let server_params = groups::server_public_params()?;
+        let mut credentials = self
+            .profile_key_credentials(&server_params, members)
+            .await?;
+        let own_credential = self
+            .fetch_profile_key_credential(&server_params, local, &own_profile_key)
+            .await?;
+        credentials.insert(local, own_credential);
+        let now = chrono::Utc::now().timestamp_millis();
+
+        let mut group = Group {
+            id: params.id(),
+            master_key: params.master_key().to_vec(),
+            name: name.to_string(),
+            description: description
+                .map(str::trim)
+                .filter(|description| !description.is_empty())
+                .map(str::to_string),
+            members: vec![GroupMember {
+                uuid: local.uuid(),
+                role: GroupRole::Administrator,
+                joined_at: 0,
+                invited_by: None,
+            }],
+            admins: vec![local.uuid()],
+            disappearing_messages_timer: disappearing_timer.filter(|timer| *timer > 0),
+            access_control: GroupAccessControl {
+                members_can_add_members: true,
+                members_can_edit_group_info: true,
+            },
+            ..Default::default()
+        };
+        let added: HashSet<Aci> = members
+            .iter()
+            .copied()
+            .filter(|aci| *aci != local)
+            .collect();
+        for aci in added {
+            if credentials.contains(&aci) {
+                group.members.push(GroupMember {
+                    uuid: aci.uuid(),
+                    role: GroupRole::Member,
+                    joined_at: 0,
+                    invited_by: None,
+                });
+            } else {
+                group.pending_members.push(GroupMember {
+                    uuid: aci.uuid(),
+                    role: GroupRole::Member,
+                    joined_at: now,
+                    invited_by: Some(local.uuid()),
+                });
+            }
+        }
+
+        let service = self.group_service().await?;
+        let group = groups::create_group(&service, &params, group, &credentials, avatar).await?;
+        tracing::info!("Created group {}", group.id);
+
+        self.store_group_conversation(&group).await?;
+
+        if let Err(e) = self
+            .send_group_update(&group, group_recipients(&group))
+            .await
+        {
+            tracing::warn!("Failed to announce group {}: {}", group.id, e);
+        }
+        Ok(group)
+    }
+
+    /// Make a change to a group on the server, store it and tell everyone
+    /// it concerns
+    ///
+    /// When another member's change takes the revision first, the group is
+    /// brought up to date and the change is checked and built again.
+    async fn change_group(&self, group_id: &str, update: &GroupUpdate) -> Result<Group> {
+        let stored = self
+            .store
+            .get_group(group_id)
+            .await?
+            .ok_or_else(|| anyhow!("Unknown group {}", group_id))?;
+        if stored.master_key.is_empty() {
+            return Err(anyhow!("Group {} has no master key", group_id));
+        }
+        let params = GroupSecretParams::derive_from_master_key(&stored.master_key)?;
+        let server_params = groups::server_public_params()?;
+        let credentials = match update {
+            GroupUpdate::AddMembers(acis) => {
+                self.profile_key_credentials(&server_params, acis).await?
+            }
+            _ => ProfileKeyCredentials::new(server_params),
+        };
+
+        // Groups found through the storage service have no state yet
+        let mut group = if stored.members.is_empty() {
+            self.refresh_group(params.master_key(), None).await?
+        } else {
+            stored
+        };
+        let service = self.group_service().await?;
+        let now = chrono::Utc::now().timestamp_millis();
+
+        for _ in 0..GROUP_CHANGE_ATTEMPTS {
+            let changed = groups::change_group(
+                &service,
+                &params,
+                &group,
+                &self.local.aci,
+                update,
+                &credentials,
+                now,
+            )
+            .await?;
+            let Some(changed) = changed else {
+                tracing::info!("Group {} changed meanwhile, updating", group_id);
+                group = self.refresh_group(params.master_key(), None).await?;
+                continue;
+            };
+
+            tracing::info!(
+                "Changed group {} to revision {}",
+                group_id,
+                changed.revision
+            );
+            if let Some(conversation) = self.store.store_group(&changed).await? {
+                self.events
+                    .publish(SignalEvent::ConversationUpdated(conversation));
+            }
+
+            // Removed members hear of their removal too
+            let recipients = group_recipients(&group)
+                .into_iter()
+                .chain(group_recipients(&changed))
+                .collect();
+            if let Err(e) = self.send_group_update(&changed, recipients).await {
+                tracing::warn!("Failed to announce change to group {}: {}", group_id, e);
+            }
+            return Ok(changed);
+        }
+
+        Err(anyhow!(
+            "Group {} kept changing while we changed it",
+            group_id
+        ))
+    }
+
+    /// Look up the group of an invite link
+    async fn preview_group_link(&self, url: &str) -> Result<GroupPreview> {
+        let link = InviteLink::parse(url)?;
+        groups::preview_group(&self.group_service().await?, &link).await
+    }
+
+    /// Join the group of an invite link, or ask to join
+    ///
+    /// A group we joined is fetched, stored with its conversation and told
+    /// of us. A group we asked to join is stored with what its link shows,
+    /// so it is known once an administrator lets us in.
+    async fn join_group_link(&self, url: &str) -> Result<GroupPreview> {
+        let link = InviteLink::parse(url)?;
+        let group_id = GroupSecretParams::derive_from_master_key(&link.master_key)?.id();
+        let known = self.store.get_group(&group_id).await?;
+        if known
+            .as_ref()
+            .is_some_and(|group| group.is_member(&self.local.aci.uuid()))
+        {
+            return Err(anyhow!("Already a member of group {}", group_id));
+        }
+        let own_profile_key = self
+            .store
+            .get_own_profile_key()
+            .await?
+            .ok_or_else(|| anyhow!("Our profile key is not known yet"))?;
+        let server_params = groups::server_public_params()?;
+        let own_credential = self
+            .fetch_profile_key_credential(&server_params, self.local.aci, &own_profile_key)
+            .await?;
+        let mut credentials = ProfileKeyCredentials::new(server_params);
+        credentials.insert(self.local.aci, own_credential);
+        let service = self.group_service().await?;
+        let now = chrono::Utc::now().timestamp_millis();
+
+        for _ in 0..GROUP_CHANGE_ATTEMPTS {
+            let preview =
+                groups::join_group(&service, &link, &self.local.aci, &credentials, now).await?;
+            let Some(preview) = preview else {
+                tracing::info!("Group changed while joining, trying again");
+                continue;
+            };
+
+            if preview.requires_approval {
+                tracing::info!("Asked to join group {}", preview.id);
+                if known.is_none() {
+                    self.store
+                        .store_group(&Group {
+                            id: preview.id.clone(),
+                            master_key: link.master_key.clone(),
+                            revision: preview.revision,
+                            name: preview.name.clone(),
+                            description: preview.description.clone(),
+                            avatar: preview.avatar.clone(),
+                            ..Default::default()
+                        })
+                        .await?;
+                }
+                return Ok(preview);
+            }
+
+            tracing::info!("Joined group {}", preview.id);
+            let group = self.refresh_group(&link.master_key, None).await?;
+            self.store_group_conversation(&group).await?;
+            if let Err(e) = self
+                .send_group_update(&group, group_recipients(&group))
+                .await
+            {
+                tracing::warn!("Failed to announce joining group {}: {}", group.id, e);
+            }
+            return Ok(preview);
+        }
+
+        Err(anyhow!("The group kept changing while we joined it"))
+    }
+
+    /// Store a group with a conversation for it, and publish the
+    /// conversation
+    async fn store_group_conversation(&self, group: &Group) -> Result<()> {
+        self.store.store_group(group).await?;
+        if self.store.get_conversation(&group.id).await?.is_none() {
+            self.store
+                .store_conversation(&Conversation {
+                    id: group.id.clone(),
+                    recipient: SignalIdentity {
+                        aci: Uuid::nil().into(),
+                        pni: None,
+                        phone_number: None,
+                        device_id: PRIMARY_DEVICE_ID,
+                        registration_id: 0,
+                    },
+                    is_group: true,
+                    group_id: Some(group.id.clone()),
+                    name: group.name.clone(),
+                    last_message: None,
+                    unread_count: 0,
+                    archived: false,
+                    muted_until: None,
+                    blocked: false,
+                    pinned: None,
+                })
+                .await?;
+        }
+        if let Some(conversation) = self.store.get_conversation(&group.id).await? {
+            self.events
+                .publish(SignalEvent::ConversationUpdated(conversation));
+        }
+        Ok(())
+    }
+
+    /// Tell `recipients` of the newest revision of a group, so they fetch
+    /// its changes
+    ///
+    /// Recipients we fail to reach are logged and skipped; they catch up
+    /// with the next message to the group.
+    async fn send_group_update(&self, group: &Group, recipients: HashSet<Uuid>) -> Result<()> {
+        let timestamp = chrono::Utc::now().timestamp_millis();
+        let content_bytes = codec::encode(&SignalContent::Data(Box::new(DataMessage {
+            timestamp,
+            group_master_key: Some(group.master_key.clone()),
+            group_revision: Some(group.revision),
+            ..Default::default()
+        })))?;
+
+        for uuid in recipients {
+            if uuid == self.local.aci.uuid() {
+                continue;
+            }
+            let recipient = SignalIdentity {
+                aci: uuid.into(),
+                pni: None,
+                phone_number: None,
+                device_id: PRIMARY_DEVICE_ID,
+                registration_id: 0,
+            };
+            if let Err(e) = self
+                .deliver(&recipient, &content_bytes, timestamp, false)
+                .await
+            {
+                tracing::warn!(
+                    "Failed to send update of group {} to {}: {}",
+                    group.id,
+                    uuid,
+                    e
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Tell the other members of a group that we started or stopped typing
+    ///
+    /// Like the indicator itself this is best effort: members we fail to
+    /// reach are logged and skipped.
+    async fn send_group_typing(&self, group: &Group, action: TypingAction) -> Result<()> {
+        let timestamp = chrono::Utc::now().timestamp_millis();
+        let content_bytes = codec::encode(&SignalContent::Typing(TypingMessage {
+            action,
+            timestamp,
+            group_id: Some(group.id.clone()),
+        }))?;
+
+        for member in &group.members {
+            if member.uuid == self.local.aci.uuid() {
+                continue;
+            }
+            let recipient = SignalIdentity {
+                aci: member.uuid.into(),
+                pni: None,
+                phone_number: None,
+                device_id: PRIMARY_DEVICE_ID,
+                registration_id: 0,
+            };
+            // Typing indicators must not wake the recipients' devices
+            if let Err(e) = self
+                .deliver(&recipient, &content_bytes, timestamp, false)
+                .await
+            {
+                tracing::debug!(
+                    "Failed to send typing indicator in group {} to {}: {}",
+                    group.id,
+                    member.uuid,
+                    e
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Profile key credentials of `acis`, to add them to a group
+    ///
+    /// People whose profile key we lack, or whose credential fails to
+    /// fetch, are left out, and get invited instead.
+    async fn profile_key_credentials(
+        &self,
+        server_params: &ServerPublicParams,
+        acis: &[Aci],
+    ) -> Result<ProfileKeyCredentials> {
+        let mut credentials = ProfileKeyCredentials::new(server_params.clone());
+        for aci in acis {
+            let Some(profile_key) = self.store.get_profile_key(*aci).await? else {
+                continue;
+            };
+            match self
+                .fetch_profile_key_credential(server_params, *aci, &profile_key)
+                .await
+            {
+                Ok(credential) => credentials.insert(*aci, credential),
+                Err(e) => {
+                    tracing::warn!("Failed to fetch profile key credential of {}: {}", aci, e)
+                }
+            }
+        }
+        Ok(credentials)
+    }
+
+    /// Fetch the expiring profile key credential of `aci` for
+    /// `profile_key`
+    async fn fetch_profile_key_credential(
+        &self,
+        server_params: &ServerPublicParams,
+        aci: Aci,
+        profile_key: &[u8],
+    ) -> Result<ExpiringProfileKeyCredential> {
+        let (path, context) = profiles::credential_request(server_params, profile_key, &aci)?;
+        let response = {
+            let ws = self.websocket.read().await;
+            ws.send_request(WebSocketRequest::new("GET", path)).await?
+        };
+        if response.status != 200 {
+            return Err(anyhow!(
+                "Profile key credential fetch for {} failed with status {}",
+                aci,
+                response.status
+            ));
+        }
+
+        let response: profiles::ProfileResponse =
+            serde_json::from_slice(&response.body.unwrap_or_default())?;
+        let now = chrono::Utc::now().timestamp();
+        profiles::receive_credential(server_params, &context, &response, now)
+    }
+
+    /// Encrypt encoded `Content` for a recipient and push it over the WebSocket
+    ///
+    /// Without a session, the recipient's pre-key bundle is fetched and the
+    /// content is sent as an initial message.
+    async fn deliver(
+        &self,
+        recipient: &SignalIdentity,
+        content_bytes: &[u8],
+        timestamp: i64,
+        urgent: bool,
+    ) -> Result<()> {
+        let recipient_address = ProtocolAddress::new(recipient.aci, recipient.device_id);
+
+        let has_session = {
+            let protocol = self.protocol.read().await;
+            protocol.has_session(&recipient_address).await
+        };
+
+        // Encrypt the content
+        let (envelope_type, ciphertext) = if has_session {
+            let protocol = self.protocol.read().await;
+            let ciphertext = protocol.encrypt(&recipient_address, content_bytes).await?;
+            (EnvelopeType::Ciphertext, ciphertext)
+        } else {
+            let bundle = self.fetch_pre_key_bundle(recipient).await?;
+            bundle.verify()?;
+
+            let protocol = self.protocol.read().await;
+            let ciphertext = protocol
+                .encrypt_initial(&recipient_address, &bundle, content_bytes)
+                .await?;
+            (EnvelopeType::PreKeyBundle, ciphertext)
+        };
+
+        self.send_envelope(recipient, envelope_type, ciphertext, timestamp, urgent)
+            .await
+    }
+
+    /// Fetch a recipient device's pre-key bundle from the server
+    async fn fetch_pre_key_bundle(&self, recipient: &SignalIdentity) -> Result<PreKeyBundle> {
+        let path = format!("/v2/keys/{}/{}", recipient.aci, recipient.device_id);
+
+        let response = {
+            let ws = self.websocket.read().await;
+            ws.send_request(WebSocketRequest::new("GET", path)).await?
+        };
+
+        if response.status != 200 {
+            return Err(anyhow!(
+                "Pre-key bundle fetch failed with status {}",
+                response.status
+            ));
+        }
+
+        PreKeyBundle::deserialize(&response.body.unwrap_or_default())
+    }
+
+    /// Wrap content in an envelope and send it via WebSocket
+    async fn send_envelope(
+        &self,
+        recipient: &SignalIdentity,
+        envelope_type: EnvelopeType,
+        content: Vec<u8>,
+        timestamp: i64,
+        urgent: bool,
+    ) -> Result<()> {
+        let envelope = codec::encode_envelope(&Envelope {
+            envelope_type,
+            source: Some(self.local.aci.into()),
+            source_device: self.local.device_id,
+            timestamp,
+            metadata: EnvelopeMetadata {
+                destination_service_id: Some(recipient.aci.into()),
+                urgent,
+                ..Default::default()
+            },
+            content,
+        });
+
+        let ws = self.websocket.read().await;
+        ws.send_message(&envelope).await
+    }
+}
+
+/// Members and invited members of a group, who hear of its changes
+fn group_recipients(group: &Group) -> HashSet<Uuid> {
+    group
+        .members
+        .iter()
+        .chain(
+            group
+                .pending_members
+                .iter()
+                .filter(|pending| pending.invited_by.is_some()),
+        )
+        .map(|member| member.uuid)
+        .collect()
+}
+
+/// How the storage service knows a conversation
+enum StorageConversation {
+    Contact(Aci),
+    /// A group, by its master key
+    Group(Vec<u8>),
+}
+
+impl StorageConversation {
+    /// Whether `pin` pins this conversation
+    fn is_pinned_by(&self, pin: &PinnedConversation) -> bool {
+        match (self, pin) {
+            (Self::Contact(aci), PinnedConversation::Contact { service_id, .. }) => {
+                *service_id == Some(ServiceId::Aci(*aci))
+            }
+            (Self::Group(master_key), PinnedConversation::Group { master_key: pinned }) => {
+                master_key == pinned
+            }
+            _ => false,
+        }
+    }
+
+    /// Pin for this conversation in our account record
+    fn pin(&self) -> PinnedConversation {
+        match self {
+            Self::Contact(aci) => PinnedConversation::Contact {
+                service_id: Some((*aci).into()),
+                e164: None,
+            },
+            Self::Group(master_key) => PinnedConversation::Group {
+                master_key: master_key.clone(),
+            },
+        }
+    }
+}
+
+/// Device linking session data
+pub struct LinkingSession {
+    /// Provisioning UUID
+    pub provisioning_uuid: String,
+    /// Ephemeral key pair for provisioning encryption
+    pub ephemeral_key: DhKeyPair,
+}
+
+use sha2::Digest;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Client in `dir`, allowed to keep its database key in a file since
+    /// tests may run without a Secret Service
+    async fn test_client(dir: &Path) -> SignalClient {
+        SignalClient::new(dir, true).await.unwrap()
+    }
 
     #[tokio::test]
     async fn test_client_creation() {
         let temp_dir = TempDir::new().unwrap();
-        let client = SignalClient::new(temp_dir.path()).await.unwrap();
+        let client = test_client(temp_dir.path()).await;
 
         assert!(!client.is_linked());
         assert!(client.identity().is_none());
@@ -725,7 +2680,7 @@ mod tests {
     #[tokio::test]
     async fn test_linking_uri_generation() {
         let temp_dir = TempDir::new().unwrap();
-        let client = SignalClient::new(temp_dir.path()).await.unwrap();
+        let client = test_client(temp_dir.path()).await;
 
         let (uri, _session) = client.generate_linking_uri().await.unwrap();
 
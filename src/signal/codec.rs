@@ -0,0 +1,1439 @@
+//! Content codec
+//!
+//! Maps between the `Content` protobuf exchanged with other Signal clients
+//! and the data model in `types`. Decoding tolerates unknown fields and
+//! rejects content that carries nothing we understand instead of surfacing
+//! it as text. Transport padding is handled by `SignalProtocol`.
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use prost::Message as _;
+use uuid::Uuid;
+
+use super::proto;
+use super::types::*;
+
+/// Prefix used when a location is sent as a plain-text map link
+const LOCATION_URL_PREFIX: &str = "https://maps.google.com/maps?q=";
+
+/// First byte of plaintext content, distinguishing it from ciphertext
+const PLAINTEXT_CONTENT_IDENTIFIER: u8 = 0xC0;
+
+/// Terminates plaintext content in place of block padding
+const PLAINTEXT_CONTENT_TERMINATOR: u8 = 0x80;
+
+/// Encode content into a serialized `Content` protobuf
+pub fn encode(content: &SignalContent) -> Result<Vec<u8>> {
+    let mut message = proto::Content::default();
+
+    match content {
+        SignalContent::Data(data) => {
+            message.data_message = Some(encode_data_message(data));
+        }
+        SignalContent::Sync(sync) => {
+            message.sync_message = Some(encode_sync_message(sync)?);
+        }
+        SignalContent::Typing(typing) => {
+            message.typing_message = Some(encode_typing_message(typing)?);
+        }
+        SignalContent::Receipt(receipt) => {
+            message.receipt_message = Some(encode_receipt_message(receipt));
+        }
+        SignalContent::Null => {
+            message.null_message = Some(proto::NullMessage::default());
+        }
+        SignalContent::Call => {
+            return Err(anyhow!("Call messages are not supported"));
+        }
+        SignalContent::DecryptionError(_) => {
+            return Err(anyhow!("Decryption errors are sent as plaintext content"));
+        }
+    }
+
+    Ok(message.encode_to_vec())
+}
+
+/// Encode a resend request as unencrypted plaintext content
+///
+/// This is sent when we cannot decrypt, so it must not depend on the
+/// session with the recipient.
+pub fn encode_plaintext_content(error: &DecryptionErrorMessage) -> Vec<u8> {
+    let error = proto::DecryptionErrorMessage {
+        ratchet_key: error.ratchet_key.clone(),
+        timestamp: Some(error.timestamp as u64),
+        device_id: Some(error.device_id),
+    };
+    let content = proto::Content {
+        decryption_error_message: Some(error.encode_to_vec()),
+        ..Default::default()
+    };
+
+    let mut bytes = vec![PLAINTEXT_CONTENT_IDENTIFIER];
+    content.encode(&mut bytes).expect("Vec has unlimited capacity");
+    bytes.push(PLAINTEXT_CONTENT_TERMINATOR);
+    bytes
+}
+
+/// Decode plaintext content from a `PLAINTEXT_CONTENT` envelope
+///
+/// Only resend requests may travel unencrypted; anything else is rejected.
+pub fn decode_plaintext_content(bytes: &[u8]) -> Result<SignalContent> {
+    let body = match bytes {
+        [PLAINTEXT_CONTENT_IDENTIFIER, body @ .., PLAINTEXT_CONTENT_TERMINATOR] => body,
+        _ => return Err(anyhow!("Malformed plaintext content")),
+    };
+
+    let content =
+        proto::Content::decode(body).map_err(|e| anyhow!("Malformed plaintext content: {}", e))?;
+    let error = content
+        .decryption_error_message
+        .ok_or_else(|| anyhow!("Plaintext content without a decryption error"))?;
+    let error = proto::DecryptionErrorMessage::decode(error.as_slice())
+        .map_err(|e| anyhow!("Malformed decryption error: {}", e))?;
+
+    Ok(SignalContent::DecryptionError(DecryptionErrorMessage {
+        ratchet_key: error.ratchet_key,
+        timestamp: error.timestamp.unwrap_or(0) as i64,
+        device_id: error.device_id.unwrap_or(1),
+    }))
+}
+
+/// Decode a plaintext `Content` protobuf received from `sender`
+///
+/// `sender` is used as the author of sent transcripts, which only ever
+/// arrive from our own linked devices.
+pub fn decode(plaintext: &[u8], sender: &SignalIdentity) -> Result<SignalContent> {
+    let content = proto::Content::decode(plaintext)
+        .map_err(|e| anyhow!("Malformed content: {}", e))?;
+
+    if let Some(data) = content.data_message {
+        return Ok(SignalContent::Data(Box::new(decode_data_message(&data))));
+    }
+    if let Some(sync) = content.sync_message {
+        return Ok(SignalContent::Sync(decode_sync_message(&sync, sender)?));
+    }
+    if let Some(typing) = content.typing_message {
+        return Ok(SignalContent::Typing(decode_typing_message(&typing)));
+    }
+    if let Some(receipt) = content.receipt_message {
+        return Ok(SignalContent::Receipt(decode_receipt_message(&receipt)));
+    }
+    if content.call_message.is_some() {
+        return Ok(SignalContent::Call);
+    }
+    if content.null_message.is_some() {
+        return Ok(SignalContent::Null);
+    }
+
+    Err(anyhow!("Content contains no supported message"))
+}
+
+// ==================== Envelopes ====================
+
+/// Encode an envelope for transport
+pub fn encode_envelope(envelope: &Envelope) -> Vec<u8> {
+    let envelope_type = match envelope.envelope_type {
+        EnvelopeType::Ciphertext => proto::envelope::Type::Ciphertext,
+        EnvelopeType::PreKeyBundle => proto::envelope::Type::PrekeyBundle,
+        EnvelopeType::Receipt => proto::envelope::Type::Receipt,
+        EnvelopeType::UnidentifiedSender => proto::envelope::Type::UnidentifiedSender,
+        EnvelopeType::PlaintextContent => proto::envelope::Type::PlaintextContent,
+        EnvelopeType::Unknown => proto::envelope::Type::Unknown,
+    };
+    let metadata = &envelope.metadata;
+
+    proto::Envelope {
+        r#type: Some(envelope_type as i32),
+        source_service_id: envelope.source.map(|id| id.to_string()),
+        source_device: envelope.source.map(|_| envelope.source_device),
+        timestamp: Some(envelope.timestamp as u64),
+        content: Some(envelope.content.clone()),
+        server_guid: metadata.server_guid.clone(),
+        server_timestamp: metadata.server_timestamp.map(|t| t as u64),
+        destination_service_id: metadata.destination_service_id.map(|id| id.to_string()),
+        urgent: Some(metadata.urgent),
+        story: Some(metadata.story),
+    }
+    .encode_to_vec()
+}
+
+/// Decode an envelope delivered by the server
+pub fn decode_envelope(bytes: &[u8]) -> Result<Envelope> {
+    let envelope =
+        proto::Envelope::decode(bytes).map_err(|e| anyhow!("Malformed envelope: {}", e))?;
+
+    let envelope_type = match envelope.r#type() {
+        proto::envelope::Type::Ciphertext => EnvelopeType::Ciphertext,
+        proto::envelope::Type::PrekeyBundle => EnvelopeType::PreKeyBundle,
+        proto::envelope::Type::Receipt => EnvelopeType::Receipt,
+        proto::envelope::Type::UnidentifiedSender => EnvelopeType::UnidentifiedSender,
+        proto::envelope::Type::PlaintextContent => EnvelopeType::PlaintextContent,
+        proto::envelope::Type::Unknown => EnvelopeType::Unknown,
+    };
+
+    let source = envelope
+        .source_service_id
+        .as_deref()
+        .map(|id| {
+            id.parse()
+                .map_err(|e| anyhow!("Invalid source service ID {}: {}", id, e))
+        })
+        .transpose()?;
+
+    Ok(Envelope {
+        envelope_type,
+        source,
+        source_device: envelope.source_device.unwrap_or(1),
+        timestamp: envelope.timestamp.unwrap_or(0) as i64,
+        metadata: EnvelopeMetadata {
+            server_guid: envelope.server_guid.clone(),
+            server_timestamp: envelope.server_timestamp.map(|t| t as i64),
+            destination_service_id: envelope
+                .destination_service_id
+                .as_deref()
+                .map(|id| {
+                    id.parse()
+                        .map_err(|e| anyhow!("Invalid destination service ID {}: {}", id, e))
+                })
+                .transpose()?,
+            urgent: envelope.urgent(),
+            story: envelope.story(),
+        },
+        content: envelope.content.unwrap_or_default(),
+    })
+}
+
+// ==================== Data Messages ====================
+
+fn encode_data_message(data: &DataMessage) -> proto::DataMessage {
+    let mut message = proto::DataMessage {
+        timestamp: Some(data.timestamp as u64),
+        expire_timer: data.expire_timer,
+        profile_key: data.profile_key.clone(),
+        ..Default::default()
+    };
+
+    if data.end_session {
+        message.flags = Some(proto::data_message::Flags::EndSession as u32);
+    }
+
+    if let Some(master_key) = &data.group_master_key {
+        message.group_v2 = Some(proto::GroupContextV2 {
+            master_key: Some(master_key.clone()),
+            revision: data.group_revision,
+            ..Default::default()
+        });
+    }
+
+    if let Some(quote) = &data.quote {
+        message.quote = Some(proto::data_message::Quote {
+            id: Some(quote.timestamp as u64),
+            author_aci: Some(quote.author.to_string()),
+            text: quote.text.clone(),
+            ..Default::default()
+        });
+    }
+
+    if let Some(reaction) = &data.reaction {
+        message.reaction = Some(proto::data_message::Reaction {
+            emoji: Some(reaction.emoji.clone()),
+            remove: Some(reaction.remove),
+            target_author_aci: Some(reaction.target_author.to_string()),
+            target_sent_timestamp: Some(reaction.target_timestamp as u64),
+        });
+    }
+
+    match &data.content {
+        Some(MessageContent::Text { body }) => {
+            message.body = Some(body.clone());
+        }
+        Some(MessageContent::Image { attachment, caption })
+        | Some(MessageContent::Video { attachment, caption }) => {
+            message.body = caption.clone();
+            message.attachments.push(encode_attachment(attachment, 0));
+        }
+        Some(MessageContent::Audio { attachment }) | Some(MessageContent::File { attachment }) => {
+            message.attachments.push(encode_attachment(attachment, 0));
+        }
+        Some(MessageContent::Voice { attachment, .. }) => {
+            let flags = proto::attachment_pointer::Flags::VoiceMessage as u32;
+            message.attachments.push(encode_attachment(attachment, flags));
+        }
+        Some(MessageContent::Sticker { pack_id, sticker_id }) => {
+            message.sticker = Some(proto::data_message::Sticker {
+                pack_id: hex::decode(pack_id).ok(),
+                sticker_id: Some(*sticker_id),
+                ..Default::default()
+            });
+        }
+        Some(MessageContent::Contact { contact }) => {
+            message.contact.push(encode_contact(contact));
+        }
+        Some(MessageContent::DecryptionFailed)
+        | Some(MessageContent::NumbersMerged { .. })
+        | Some(MessageContent::NotFound) => {}
+        Some(MessageContent::Location { latitude, longitude, name }) => {
+            // Signal has no location message; send a map link like other clients
+            let link = format!("{}{},{}", LOCATION_URL_PREFIX, latitude, longitude);
+            message.body = Some(match name {
+                Some(name) => format!("{}\n{}", name, link),
+                None => link,
+            });
+        }
+        None => {}
+    }
+
+    message
+}
+
+fn decode_data_message(message: &proto::DataMessage) -> DataMessage {
+    let flags = message.flags.unwrap_or(0);
+
+    DataMessage {
+        timestamp: message.timestamp.unwrap_or(0) as i64,
+        content: decode_message_content(message),
+        quote: message.quote.as_ref().and_then(|quote| {
+            Some(QuoteReference {
+                timestamp: quote.id? as i64,
+                author: quote.author_aci.as_deref()?.parse().ok()?,
+                text: quote.text.clone(),
+            })
+        }),
+        reaction: message.reaction.as_ref().and_then(|reaction| {
+            Some(ReactionUpdate {
+                emoji: reaction.emoji.clone()?,
+                remove: reaction.remove.unwrap_or(false),
+                target_author: reaction.target_author_aci.as_deref()?.parse().ok()?,
+                target_timestamp: reaction.target_sent_timestamp? as i64,
+            })
+        }),
+        group_master_key: message
+            .group_v2
+            .as_ref()
+            .and_then(|group| group.master_key.clone()),
+        group_revision: message.group_v2.as_ref().and_then(|group| group.revision),
+        expire_timer: message.expire_timer,
+        profile_key: message.profile_key.clone(),
+        end_session: flags & proto::data_message::Flags::EndSession as u32 != 0,
+    }
+}
+
+fn decode_message_content(message: &proto::DataMessage) -> Option<MessageContent> {
+    let body = message.body.clone().filter(|body| !body.is_empty());
+
+    if let Some(sticker) = &message.sticker {
+        return Some(MessageContent::Sticker {
+            pack_id: hex::encode(sticker.pack_id.as_deref().unwrap_or_default()),
+            sticker_id: sticker.sticker_id.unwrap_or(0),
+        });
+    }
+
+    if let Some(contact) = message.contact.first() {
+        return Some(MessageContent::Contact {
+            contact: decode_contact(contact),
+        });
+    }
+
+    if let Some(pointer) = message.attachments.first() {
+        let is_voice =
+            pointer.flags.unwrap_or(0) & proto::attachment_pointer::Flags::VoiceMessage as u32 != 0;
+        let attachment = decode_attachment(pointer);
+        let content_type = attachment.content_type.clone();
+
+        return Some(if is_voice {
+            MessageContent::Voice {
+                attachment,
+                duration_ms: 0,
+            }
+        } else if content_type.starts_with("image/") {
+            MessageContent::Image {
+                attachment,
+                caption: body,
+            }
+        } else if content_type.starts_with("video/") {
+            MessageContent::Video {
+                attachment,
+                caption: body,
+            }
+        } else if content_type.starts_with("audio/") {
+            MessageContent::Audio { attachment }
+        } else {
+            MessageContent::File { attachment }
+        });
+    }
+
+    body.map(|body| MessageContent::Text { body })
+}
+
+fn encode_attachment(attachment: &Attachment, flags: u32) -> proto::AttachmentPointer {
+    let identifier = match attachment.id.parse::<u64>() {
+        Ok(cdn_id) => proto::attachment_pointer::AttachmentIdentifier::CdnId(cdn_id),
+        Err(_) => proto::attachment_pointer::AttachmentIdentifier::CdnKey(attachment.id.clone()),
+    };
+
+    proto::AttachmentPointer {
+        attachment_identifier: Some(identifier),
+        content_type: Some(attachment.content_type.clone()),
+        key: Some(attachment.key.clone()),
+        size: Some(attachment.size as u32),
+        thumbnail: attachment.thumbnail.clone(),
+        digest: Some(attachment.digest.clone()),
+        file_name: attachment.file_name.clone(),
+        flags: (flags != 0).then_some(flags),
+        width: attachment.width,
+        height: attachment.height,
+        upload_timestamp: Some(attachment.upload_timestamp as u64),
+        cdn_number: Some(attachment.cdn_number),
+        ..Default::default()
+    }
+}
+
+fn decode_attachment(pointer: &proto::AttachmentPointer) -> Attachment {
+    let id = match &pointer.attachment_identifier {
+        Some(proto::attachment_pointer::AttachmentIdentifier::CdnId(id)) => id.to_string(),
+        Some(proto::attachment_pointer::AttachmentIdentifier::CdnKey(key)) => key.clone(),
+        None => String::new(),
+    };
+
+    Attachment {
+        id,
+        content_type: pointer
+            .content_type
+            .clone()
+            .unwrap_or_else(|| "application/octet-stream".to_string()),
+        file_name: pointer.file_name.clone(),
+        size: pointer.size.unwrap_or(0) as u64,
+        digest: pointer.digest.clone().unwrap_or_default(),
+        key: pointer.key.clone().unwrap_or_default(),
+        cdn_number: pointer.cdn_number.unwrap_or(0),
+        upload_timestamp: pointer.upload_timestamp.unwrap_or(0) as i64,
+        width: pointer.width,
+        height: pointer.height,
+        thumbnail: pointer.thumbnail.clone(),
+    }
+}
+
+fn encode_contact(contact: &ContactInfo) -> proto::data_message::Contact {
+    use proto::data_message::contact::{Email, Name, Phone};
+
+    proto::data_message::Contact {
+        name: Some(Name {
+            display_name: Some(contact.name.clone()),
+            ..Default::default()
+        }),
+        number: contact
+            .phone_numbers
+            .iter()
+            .map(|value| Phone {
+                value: Some(value.clone()),
+                ..Default::default()
+            })
+            .collect(),
+        email: contact
+            .emails
+            .iter()
+            .map(|value| Email {
+                value: Some(value.clone()),
+                ..Default::default()
+            })
+            .collect(),
+    }
+}
+
+fn decode_contact(contact: &proto::data_message::Contact) -> ContactInfo {
+    let name = contact
+        .name
+        .as_ref()
+        .map(|name| {
+            name.display_name.clone().unwrap_or_else(|| {
+                [&name.given_name, &name.family_name]
+                    .iter()
+                    .filter_map(|part| part.as_deref())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+        })
+        .unwrap_or_default();
+
+    ContactInfo {
+        name,
+        phone_numbers: contact.number.iter().filter_map(|p| p.value.clone()).collect(),
+        emails: contact.email.iter().filter_map(|e| e.value.clone()).collect(),
+    }
+}
+
+// ==================== Sync Messages ====================
+
+fn encode_sync_message(sync: &SyncMessage) -> Result<proto::SyncMessage> {
+    use proto::sync_message::sent::UnidentifiedDeliveryStatus;
+    use proto::sync_message::{
+        fetch_latest, request, Blocked, Configuration, Contacts, FetchLatest as FetchLatestProto,
+        Keys, Read, Request, Sent, Viewed,
+    };
+
+    let mut message = proto::SyncMessage::default();
+
+    match sync {
+        SyncMessage::SentMessage {
+            message: sent,
+            destination,
+            group_master_key,
+            recipients,
+        } => {
+            let data = DataMessage {
+                timestamp: sent.timestamp,
+                content: Some(sent.content.clone()),
+                group_master_key: group_master_key.clone(),
+                expire_timer: sent
+                    .expires_at
+                    .map(|expires_at| ((expires_at - sent.timestamp) / 1000) as u32),
+                ..Default::default()
+            };
+
+            // Group messages have no single destination
+            let destination = Some(destination).filter(|_| group_master_key.is_none());
+            message.sent = Some(Sent {
+                destination_service_id: destination.map(|d| d.service_id().to_string()),
+                destination_e164: destination.and_then(|d| d.phone_number.clone()),
+                timestamp: Some(sent.timestamp as u64),
+                message: Some(encode_data_message(&data)),
+                unidentified_status: recipients
+                    .iter()
+                    .map(|recipient| UnidentifiedDeliveryStatus {
+                        destination_service_id: Some(recipient.identity.service_id().to_string()),
+                        unidentified: Some(recipient.unidentified),
+                    })
+                    .collect(),
+                ..Default::default()
+            });
+        }
+        SyncMessage::ReadMessages { messages } => {
+            message.read = messages
+                .iter()
+                .map(|(sender, timestamp)| Read {
+                    sender_aci: Some(sender.clone()),
+                    timestamp: Some(*timestamp as u64),
+                })
+                .collect();
+        }
+        SyncMessage::ViewedMessages { messages } => {
+            message.viewed = messages
+                .iter()
+                .map(|(sender, timestamp)| Viewed {
+                    sender_aci: Some(sender.clone()),
+                    timestamp: Some(*timestamp as u64),
+                })
+                .collect();
+        }
+        SyncMessage::Blocked {
+            identities,
+            group_ids,
+        } => {
+            message.blocked = Some(Blocked {
+                acis: identities
+                    .iter()
+                    .filter(|i| !i.aci.is_nil())
+                    .map(|i| i.aci.to_string())
+                    .collect(),
+                numbers: identities
+                    .iter()
+                    .filter_map(|i| i.phone_number.clone())
+                    .collect(),
+                group_ids: group_ids
+                    .iter()
+                    .map(|id| BASE64.decode(id))
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| anyhow!("Invalid group ID: {}", e))?,
+            });
+        }
+        SyncMessage::Configuration {
+            read_receipts,
+            typing_indicators,
+            link_previews,
+        } => {
+            message.configuration = Some(Configuration {
+                read_receipts: *read_receipts,
+                typing_indicators: *typing_indicators,
+                link_previews: *link_previews,
+                ..Default::default()
+            });
+        }
+        SyncMessage::Keys {
+            storage_service,
+            master,
+        } => {
+            message.keys = Some(Keys {
+                storage_service: storage_service.clone(),
+                master: master.clone(),
+            });
+        }
+        SyncMessage::Contacts { blob, complete } => {
+            message.contacts = Some(Contacts {
+                blob: Some(encode_attachment(blob, 0)),
+                complete: Some(*complete),
+            });
+        }
+        SyncMessage::Request { kind } => {
+            let kind = match kind {
+                SyncRequest::Contacts => request::Type::Contacts,
+                SyncRequest::Blocked => request::Type::Blocked,
+                SyncRequest::Configuration => request::Type::Configuration,
+                SyncRequest::Keys => request::Type::Keys,
+            };
+            message.request = Some(Request {
+                r#type: Some(kind as i32),
+            });
+        }
+        SyncMessage::FetchLatest { kind } => {
+            let kind = match kind {
+                FetchLatest::LocalProfile => fetch_latest::Type::LocalProfile,
+                FetchLatest::StorageManifest => fetch_latest::Type::StorageManifest,
+                FetchLatest::SubscriptionStatus => fetch_latest::Type::SubscriptionStatus,
+            };
+            message.fetch_latest = Some(FetchLatestProto {
+                r#type: Some(kind as i32),
+            });
+        }
+        SyncMessage::Groups { .. } => {
+            return Err(anyhow!("Group sync is not supported"));
+        }
+    }
+
+    Ok(message)
+}
+
+fn decode_sync_message(
+    message: &proto::SyncMessage,
+    sender: &SignalIdentity,
+) -> Result<SyncMessage> {
+    if let Some(sent) = &message.sent {
+        let data = sent
+            .message
+            .as_ref()
+            .map(decode_data_message)
+            .ok_or_else(|| anyhow!("Sent transcript without a message"))?;
+        let content = data
+            .content
+            .ok_or_else(|| anyhow!("Sent transcript without displayable content"))?;
+
+        let identity =
+            |service_id: Option<ServiceId>, phone_number: Option<String>| SignalIdentity {
+                aci: service_id
+                    .and_then(|id| id.aci())
+                    .unwrap_or(Aci::from(Uuid::nil())),
+                pni: service_id.and_then(|id| id.pni()),
+                phone_number,
+                device_id: 1,
+                registration_id: 0,
+            };
+        let destination = identity(
+            sent.destination_service_id
+                .as_deref()
+                .and_then(|id| id.parse().ok()),
+            sent.destination_e164.clone(),
+        );
+        let timestamp = sent.timestamp.map(|t| t as i64).unwrap_or(data.timestamp);
+
+        let mut recipients: Vec<SentRecipient> = sent
+            .unidentified_status
+            .iter()
+            .filter_map(|status| {
+                let service_id = status.destination_service_id.as_deref()?.parse().ok()?;
+                Some(SentRecipient {
+                    identity: identity(Some(service_id), None),
+                    unidentified: status.unidentified.unwrap_or(false),
+                })
+            })
+            .collect();
+        if recipients.is_empty() && data.group_master_key.is_none() {
+            recipients.push(SentRecipient {
+                identity: destination.clone(),
+                unidentified: false,
+            });
+        }
+
+        // Group conversations are found by the group master key
+        let conversation_id = match data.group_master_key {
+            Some(_) => String::new(),
+            None => destination.service_id().to_string(),
+        };
+        let message = Message {
+            id: Uuid::new_v4().to_string(),
+            conversation_id,
+            sender: sender.clone(),
+            timestamp,
+            received_timestamp: Some(chrono::Utc::now().timestamp_millis()),
+            content,
+            status: MessageStatus::Sent,
+            quote: None,
+            reactions: Vec::new(),
+            expires_at: data
+                .expire_timer
+                .filter(|timer| *timer > 0)
+                .map(|timer| timestamp + timer as i64 * 1000),
+            envelope: None,
+        };
+
+        return Ok(SyncMessage::SentMessage {
+            message: Box::new(message),
+            destination,
+            group_master_key: data.group_master_key,
+            recipients,
+        });
+    }
+
+    if !message.read.is_empty() {
+        return Ok(SyncMessage::ReadMessages {
+            messages: message
+                .read
+                .iter()
+                .filter_map(|read| Some((read.sender_aci.clone()?, read.timestamp? as i64)))
+                .collect(),
+        });
+    }
+
+    if !message.viewed.is_empty() {
+        return Ok(SyncMessage::ViewedMessages {
+            messages: message
+                .viewed
+                .iter()
+                .filter_map(|viewed| Some((viewed.sender_aci.clone()?, viewed.timestamp? as i64)))
+                .collect(),
+        });
+    }
+
+    if let Some(blocked) = &message.blocked {
+        let by_aci = blocked.acis.iter().filter_map(|aci| {
+            Some(SignalIdentity {
+                aci: aci.parse().ok()?,
+                pni: None,
+                phone_number: None,
+                device_id: 1,
+                registration_id: 0,
+            })
+        });
+        let by_number = blocked.numbers.iter().map(|number| SignalIdentity {
+            aci: Aci::from(Uuid::nil()),
+            pni: None,
+            phone_number: Some(number.clone()),
+            device_id: 1,
+            registration_id: 0,
+        });
+
+        return Ok(SyncMessage::Blocked {
+            identities: by_aci.chain(by_number).collect(),
+            group_ids: blocked.group_ids.iter().map(|id| BASE64.encode(id)).collect(),
+        });
+    }
+
+    if let Some(configuration) = &message.configuration {
+        return Ok(SyncMessage::Configuration {
+            read_receipts: configuration.read_receipts,
+            typing_indicators: configuration.typing_indicators,
+            link_previews: configuration.link_previews,
+        });
+    }
+
+    if let Some(keys) = &message.keys {
+        return Ok(SyncMessage::Keys {
+            storage_service: keys.storage_service.clone(),
+            master: keys.master.clone(),
+        });
+    }
+
+    if let Some(contacts) = &message.contacts {
+        let blob = contacts
+            .blob
+            .as_ref()
+            .map(decode_attachment)
+            .ok_or_else(|| anyhow!("Contact sync without an attachment"))?;
+        return Ok(SyncMessage::Contacts {
+            blob,
+            complete: contacts.complete.unwrap_or(false),
+        });
+    }
+
+    if let Some(request) = &message.request {
+        use proto::sync_message::request::Type;
+
+        let kind = match request.r#type() {
+            Type::Contacts => SyncRequest::Contacts,
+            Type::Blocked => SyncRequest::Blocked,
+            Type::Configuration => SyncRequest::Configuration,
+            Type::Keys => SyncRequest::Keys,
+            Type::Unknown => return Err(anyhow!("Sync request of unknown type")),
+        };
+        return Ok(SyncMessage::Request { kind });
+    }
+
+    if let Some(fetch_latest) = &message.fetch_latest {
+        use proto::sync_message::fetch_latest::Type;
+
+        let kind = match fetch_latest.r#type() {
+            Type::LocalProfile => FetchLatest::LocalProfile,
+            Type::StorageManifest => FetchLatest::StorageManifest,
+            Type::SubscriptionStatus => FetchLatest::SubscriptionStatus,
+            Type::Unknown => return Err(anyhow!("Fetch latest of unknown type")),
+        };
+        return Ok(SyncMessage::FetchLatest { kind });
+    }
+
+    Err(anyhow!("Unsupported sync message"))
+}
+
+/// Decode the decrypted contact sync attachment
+///
+/// The stream holds varint length-delimited `ContactDetails`, each followed
+/// by the bytes of its inline avatar, if any.
+pub fn decode_contact_details(mut stream: &[u8]) -> Result<Vec<ContactDetails>> {
+    let mut contacts = Vec::new();
+
+    while !stream.is_empty() {
+        let details = proto::ContactDetails::decode_length_delimited(&mut stream)?;
+
+        let avatar = match &details.avatar {
+            Some(avatar) => {
+                let length = avatar.length.unwrap_or(0) as usize;
+                if length > stream.len() {
+                    return Err(anyhow!("Contact avatar runs past the end of the stream"));
+                }
+                let (data, rest) = stream.split_at(length);
+                stream = rest;
+
+                (length > 0).then(|| ContactAvatar {
+                    content_type: avatar
+                        .content_type
+                        .clone()
+                        .unwrap_or_else(|| "image/jpeg".to_string()),
+                    data: data.to_vec(),
+                })
+            }
+            None => None,
+        };
+
+        contacts.push(ContactDetails {
+            aci: details.aci.as_deref().and_then(|aci| aci.parse().ok()),
+            phone_number: details.number.filter(|number| !number.is_empty()),
+            name: details.name.filter(|name| !name.is_empty()),
+            avatar,
+            color: details.color,
+            profile_key: details.profile_key,
+            expire_timer: details.expire_timer.unwrap_or(0),
+            inbox_position: details.inbox_position,
+        });
+    }
+
+    Ok(contacts)
+}
+
+/// Encode contacts as a contact sync stream
+pub fn encode_contact_details(contacts: &[ContactDetails]) -> Vec<u8> {
+    let mut stream = Vec::new();
+
+    for contact in contacts {
+        let details = proto::ContactDetails {
+            number: contact.phone_number.clone(),
+            name: contact.name.clone(),
+            avatar: contact
+                .avatar
+                .as_ref()
+                .map(|avatar| proto::contact_details::Avatar {
+                    content_type: Some(avatar.content_type.clone()),
+                    length: Some(avatar.data.len() as u32),
+                }),
+            color: contact.color.clone(),
+            profile_key: contact.profile_key.clone(),
+            expire_timer: (contact.expire_timer > 0).then_some(contact.expire_timer),
+            aci: contact.aci.map(|aci| aci.to_string()),
+            inbox_position: contact.inbox_position,
+        };
+
+        stream.extend_from_slice(&details.encode_length_delimited_to_vec());
+        if let Some(avatar) = &contact.avatar {
+            stream.extend_from_slice(&avatar.data);
+        }
+    }
+
+    stream
+}
+
+// ==================== Typing and Receipts ====================
+
+fn encode_typing_message(typing: &TypingMessage) -> Result<proto::TypingMessage> {
+    let action = match typing.action {
+        TypingAction::Started => proto::typing_message::Action::Started,
+        TypingAction::Stopped => proto::typing_message::Action::Stopped,
+    };
+
+    let group_id = typing
+        .group_id
+        .as_deref()
+        .map(|id| BASE64.decode(id))
+        .transpose()
+        .map_err(|e| anyhow!("Invalid group ID: {}", e))?;
+
+    Ok(proto::TypingMessage {
+        timestamp: Some(typing.timestamp as u64),
+        action: Some(action as i32),
+        group_id,
+    })
+}
+
+fn decode_typing_message(typing: &proto::TypingMessage) -> TypingMessage {
+    let action = match typing.action() {
+        proto::typing_message::Action::Started => TypingAction::Started,
+        proto::typing_message::Action::Stopped => TypingAction::Stopped,
+    };
+
+    TypingMessage {
+        action,
+        timestamp: typing.timestamp.unwrap_or(0) as i64,
+        group_id: typing.group_id.as_ref().map(|id| BASE64.encode(id)),
+    }
+}
+
+fn encode_receipt_message(receipt: &ReceiptMessage) -> proto::ReceiptMessage {
+    let receipt_type = match receipt.receipt_type {
+        ReceiptType::Delivery => proto::receipt_message::Type::Delivery,
+        ReceiptType::Read => proto::receipt_message::Type::Read,
+        ReceiptType::Viewed => proto::receipt_message::Type::Viewed,
+    };
+
+    proto::ReceiptMessage {
+        r#type: Some(receipt_type as i32),
+        timestamp: receipt.timestamps.iter().map(|t| *t as u64).collect(),
+    }
+}
+
+fn decode_receipt_message(receipt: &proto::ReceiptMessage) -> ReceiptMessage {
+    let receipt_type = match receipt.r#type() {
+        proto::receipt_message::Type::Delivery => ReceiptType::Delivery,
+        proto::receipt_message::Type::Read => ReceiptType::Read,
+        proto::receipt_message::Type::Viewed => ReceiptType::Viewed,
+    };
+
+    ReceiptMessage {
+        receipt_type,
+        timestamps: receipt.timestamp.iter().map(|t| *t as i64).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sender() -> SignalIdentity {
+        SignalIdentity {
+            aci: Aci::from(Uuid::new_v4()),
+            pni: None,
+            phone_number: None,
+            device_id: 1,
+            registration_id: 0,
+        }
+    }
+
+    #[test]
+    fn test_text_message_round_trip() {
+        let data = DataMessage {
+            timestamp: 1_700_000_000_000,
+            content: Some(MessageContent::Text {
+                body: "Hello".to_string(),
+            }),
+            expire_timer: Some(3600),
+            group_master_key: Some(vec![5; 32]),
+            group_revision: Some(4),
+            ..Default::default()
+        };
+
+        let bytes = encode(&SignalContent::Data(Box::new(data))).unwrap();
+
+        match decode(&bytes, &sender()).unwrap() {
+            SignalContent::Data(decoded) => {
+                assert_eq!(decoded.timestamp, 1_700_000_000_000);
+                assert_eq!(decoded.expire_timer, Some(3600));
+                assert_eq!(decoded.group_master_key, Some(vec![5; 32]));
+                assert_eq!(decoded.group_revision, Some(4));
+                match decoded.content {
+                    Some(MessageContent::Text { body }) => assert_eq!(body, "Hello"),
+                    other => panic!("Expected text, got {:?}", other),
+                }
+            }
+            other => panic!("Expected data message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_image_attachment_round_trip() {
+        let attachment = Attachment {
+            id: "cdn-key".to_string(),
+            content_type: "image/png".to_string(),
+            file_name: Some("photo.png".to_string()),
+            size: 1024,
+            digest: vec![1, 2, 3],
+            key: vec![4, 5, 6],
+            cdn_number: 2,
+            upload_timestamp: 42,
+            width: Some(640),
+            height: Some(480),
+            thumbnail: None,
+        };
+        let data = DataMessage {
+            timestamp: 1,
+            content: Some(MessageContent::Image {
+                attachment,
+                caption: Some("Look".to_string()),
+            }),
+            ..Default::default()
+        };
+
+        let bytes = encode(&SignalContent::Data(Box::new(data))).unwrap();
+
+        let data = match decode(&bytes, &sender()).unwrap() {
+            SignalContent::Data(data) => data,
+            other => panic!("Expected data message, got {:?}", other),
+        };
+        match data.content {
+            Some(MessageContent::Image {
+                attachment,
+                caption,
+            }) => {
+                assert_eq!(attachment.id, "cdn-key");
+                assert_eq!(attachment.cdn_number, 2);
+                assert_eq!(attachment.width, Some(640));
+                assert_eq!(caption.as_deref(), Some("Look"));
+            }
+            other => panic!("Expected image, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_typing_and_receipt_round_trip() {
+        let typing = TypingMessage {
+            action: TypingAction::Stopped,
+            timestamp: 7,
+            group_id: Some(BASE64.encode([9u8; 32])),
+        };
+        let bytes = encode(&SignalContent::Typing(typing)).unwrap();
+        match decode(&bytes, &sender()).unwrap() {
+            SignalContent::Typing(decoded) => {
+                assert_eq!(decoded.action, TypingAction::Stopped);
+                assert_eq!(decoded.group_id, Some(BASE64.encode([9u8; 32])));
+            }
+            other => panic!("Expected typing, got {:?}", other),
+        }
+
+        let receipt = ReceiptMessage {
+            receipt_type: ReceiptType::Read,
+            timestamps: vec![1, 2, 3],
+        };
+        let bytes = encode(&SignalContent::Receipt(receipt)).unwrap();
+        match decode(&bytes, &sender()).unwrap() {
+            SignalContent::Receipt(decoded) => {
+                assert_eq!(decoded.receipt_type, ReceiptType::Read);
+                assert_eq!(decoded.timestamps, vec![1, 2, 3]);
+            }
+            other => panic!("Expected receipt, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_sent_transcript_round_trip() {
+        let bob = SignalIdentity {
+            aci: Aci::from(Uuid::new_v4()),
+            pni: None,
+            phone_number: Some("+15550002222".to_string()),
+            device_id: 1,
+            registration_id: 0,
+        };
+        let message = Message {
+            id: "local".to_string(),
+            conversation_id: bob.aci.to_string(),
+            sender: sender(),
+            timestamp: 1000,
+            received_timestamp: None,
+            content: MessageContent::Text {
+                body: "Sent from the phone".to_string(),
+            },
+            status: MessageStatus::Sent,
+            quote: None,
+            reactions: Vec::new(),
+            expires_at: Some(61_000),
+            envelope: None,
+        };
+
+        let transcript = SignalContent::Sync(SyncMessage::SentMessage {
+            message: Box::new(message.clone()),
+            destination: bob.clone(),
+            group_master_key: None,
+            recipients: Vec::new(),
+        });
+        match decode(&encode(&transcript).unwrap(), &sender()).unwrap() {
+            SignalContent::Sync(SyncMessage::SentMessage {
+                message: decoded,
+                destination,
+                group_master_key,
+                recipients,
+            }) => {
+                assert_eq!(decoded.conversation_id, bob.aci.to_string());
+                assert_eq!(decoded.timestamp, 1000);
+                assert_eq!(decoded.status, MessageStatus::Sent);
+                assert_eq!(decoded.expires_at, Some(61_000));
+                assert_eq!(destination.aci, bob.aci);
+                assert_eq!(destination.phone_number, bob.phone_number);
+                assert!(group_master_key.is_none());
+                // A 1:1 transcript without delivery statuses lists its destination
+                assert_eq!(recipients.len(), 1);
+                assert_eq!(recipients[0].identity.aci, bob.aci);
+            }
+            other => panic!("Expected sent transcript, got {:?}", other),
+        }
+
+        let carol = Aci::from(Uuid::new_v4());
+        let recipient = |aci: Aci, unidentified| SentRecipient {
+            identity: SignalIdentity {
+                aci,
+                pni: None,
+                phone_number: None,
+                device_id: 1,
+                registration_id: 0,
+            },
+            unidentified,
+        };
+        let group = SignalContent::Sync(SyncMessage::SentMessage {
+            message: Box::new(message),
+            destination: bob.clone(),
+            group_master_key: Some(vec![5; 32]),
+            recipients: vec![recipient(bob.aci, true), recipient(carol, false)],
+        });
+        match decode(&encode(&group).unwrap(), &sender()).unwrap() {
+            SignalContent::Sync(SyncMessage::SentMessage {
+                message: decoded,
+                destination,
+                group_master_key,
+                recipients,
+            }) => {
+                assert!(decoded.conversation_id.is_empty());
+                assert!(destination.aci.is_nil());
+                assert_eq!(group_master_key, Some(vec![5; 32]));
+                assert_eq!(recipients.len(), 2);
+                assert!(recipients[0].unidentified);
+                assert_eq!(recipients[1].identity.aci, carol);
+                assert!(!recipients[1].unidentified);
+            }
+            other => panic!("Expected sent transcript, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_read_sync_round_trip() {
+        let alice = Uuid::new_v4().to_string();
+        let messages = vec![(alice.clone(), 1000), (alice.clone(), 2000)];
+
+        let read = SignalContent::Sync(SyncMessage::ReadMessages {
+            messages: messages.clone(),
+        });
+        match decode(&encode(&read).unwrap(), &sender()).unwrap() {
+            SignalContent::Sync(SyncMessage::ReadMessages { messages: decoded }) => {
+                assert_eq!(decoded, messages);
+            }
+            other => panic!("Expected read sync, got {:?}", other),
+        }
+
+        let viewed = SignalContent::Sync(SyncMessage::ViewedMessages {
+            messages: messages.clone(),
+        });
+        match decode(&encode(&viewed).unwrap(), &sender()).unwrap() {
+            SignalContent::Sync(SyncMessage::ViewedMessages { messages: decoded }) => {
+                assert_eq!(decoded, messages);
+            }
+            other => panic!("Expected viewed sync, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_settings_sync_round_trip() {
+        let blocked_aci = Aci::from(Uuid::new_v4());
+        let blocked = SignalContent::Sync(SyncMessage::Blocked {
+            identities: vec![
+                SignalIdentity {
+                    aci: blocked_aci,
+                    pni: None,
+                    phone_number: None,
+                    device_id: 1,
+                    registration_id: 0,
+                },
+                SignalIdentity {
+                    aci: Aci::from(Uuid::nil()),
+                    pni: None,
+                    phone_number: Some("+15550003333".to_string()),
+                    device_id: 1,
+                    registration_id: 0,
+                },
+            ],
+            group_ids: vec![BASE64.encode([3u8; 32])],
+        });
+        match decode(&encode(&blocked).unwrap(), &sender()).unwrap() {
+            SignalContent::Sync(SyncMessage::Blocked {
+                identities,
+                group_ids,
+            }) => {
+                assert_eq!(identities.len(), 2);
+                assert_eq!(identities[0].aci, blocked_aci);
+                assert_eq!(identities[1].phone_number.as_deref(), Some("+15550003333"));
+                assert_eq!(group_ids, vec![BASE64.encode([3u8; 32])]);
+            }
+            other => panic!("Expected blocked sync, got {:?}", other),
+        }
+
+        // Settings the primary leaves out stay unset
+        let configuration = SignalContent::Sync(SyncMessage::Configuration {
+            read_receipts: Some(false),
+            typing_indicators: None,
+            link_previews: Some(true),
+        });
+        match decode(&encode(&configuration).unwrap(), &sender()).unwrap() {
+            SignalContent::Sync(SyncMessage::Configuration {
+                read_receipts,
+                typing_indicators,
+                link_previews,
+            }) => {
+                assert_eq!(read_receipts, Some(false));
+                assert_eq!(typing_indicators, None);
+                assert_eq!(link_previews, Some(true));
+            }
+            other => panic!("Expected configuration sync, got {:?}", other),
+        }
+
+        let keys = SignalContent::Sync(SyncMessage::Keys {
+            storage_service: None,
+            master: Some(vec![8; 32]),
+        });
+        match decode(&encode(&keys).unwrap(), &sender()).unwrap() {
+            SignalContent::Sync(SyncMessage::Keys {
+                storage_service,
+                master,
+            }) => {
+                assert!(storage_service.is_none());
+                assert_eq!(master, Some(vec![8; 32]));
+            }
+            other => panic!("Expected keys sync, got {:?}", other),
+        }
+
+        let fetch_latest = SignalContent::Sync(SyncMessage::FetchLatest {
+            kind: FetchLatest::StorageManifest,
+        });
+        match decode(&encode(&fetch_latest).unwrap(), &sender()).unwrap() {
+            SignalContent::Sync(SyncMessage::FetchLatest { kind }) => {
+                assert_eq!(kind, FetchLatest::StorageManifest);
+            }
+            other => panic!("Expected fetch latest sync, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_contact_sync_round_trip() {
+        let request = SignalContent::Sync(SyncMessage::Request {
+            kind: SyncRequest::Contacts,
+        });
+        match decode(&encode(&request).unwrap(), &sender()).unwrap() {
+            SignalContent::Sync(SyncMessage::Request { kind }) => {
+                assert_eq!(kind, SyncRequest::Contacts);
+            }
+            other => panic!("Expected sync request, got {:?}", other),
+        }
+
+        let blob = Attachment {
+            id: "1234".to_string(),
+            content_type: "application/octet-stream".to_string(),
+            file_name: None,
+            size: 100,
+            digest: vec![1; 32],
+            key: vec![2; 64],
+            cdn_number: 0,
+            upload_timestamp: 0,
+            width: None,
+            height: None,
+            thumbnail: None,
+        };
+        let contacts = SignalContent::Sync(SyncMessage::Contacts {
+            blob,
+            complete: true,
+        });
+        match decode(&encode(&contacts).unwrap(), &sender()).unwrap() {
+            SignalContent::Sync(SyncMessage::Contacts { blob, complete }) => {
+                assert_eq!(blob.id, "1234");
+                assert_eq!(blob.key, vec![2; 64]);
+                assert!(complete);
+            }
+            other => panic!("Expected contact sync, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_contact_details_stream() {
+        let alice = ContactDetails {
+            aci: Some(Aci::from(Uuid::new_v4())),
+            phone_number: Some("+15550001111".to_string()),
+            name: Some("Alice".to_string()),
+            avatar: Some(ContactAvatar {
+                content_type: "image/png".to_string(),
+                data: vec![0x89, b'P', b'N', b'G'],
+            }),
+            color: Some("ultramarine".to_string()),
+            expire_timer: 3600,
+            ..Default::default()
+        };
+        let bob = ContactDetails {
+            phone_number: Some("+15550002222".to_string()),
+            ..Default::default()
+        };
+
+        let stream = encode_contact_details(&[alice.clone(), bob]);
+        let decoded = decode_contact_details(&stream).unwrap();
+
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].aci, alice.aci);
+        assert_eq!(decoded[0].name.as_deref(), Some("Alice"));
+        assert_eq!(decoded[0].color.as_deref(), Some("ultramarine"));
+        assert_eq!(decoded[0].expire_timer, 3600);
+        let avatar = decoded[0].avatar.as_ref().unwrap();
+        assert_eq!(avatar.content_type, "image/png");
+        assert_eq!(avatar.data, vec![0x89, b'P', b'N', b'G']);
+
+        // The avatar bytes are not mistaken for the next entry
+        assert_eq!(decoded[1].aci, None);
+        assert_eq!(decoded[1].phone_number.as_deref(), Some("+15550002222"));
+        assert!(decoded[1].avatar.is_none());
+
+        assert!(decode_contact_details(&[]).unwrap().is_empty());
+
+        // A stream cut off inside an avatar is rejected
+        let first_entry = encode_contact_details(&[alice]);
+        assert!(decode_contact_details(&first_entry[..first_entry.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_decode_tolerates_unknown_fields() {
+        let data = DataMessage {
+            timestamp: 5,
+            content: Some(MessageContent::Text {
+                body: "from the future".to_string(),
+            }),
+            ..Default::default()
+        };
+        let mut bytes = encode(&SignalContent::Data(Box::new(data))).unwrap();
+
+        // Unknown top-level field 99 (varint) as sent by newer clients
+        bytes.extend_from_slice(&[0x98, 0x06, 0x01]);
+
+        let data = match decode(&bytes, &sender()).unwrap() {
+            SignalContent::Data(data) => data,
+            other => panic!("Expected data message, got {:?}", other),
+        };
+        match data.content {
+            Some(MessageContent::Text { body }) => assert_eq!(body, "from the future"),
+            other => panic!("Expected text, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decode_rejects_garbage() {
+        assert!(decode(b"{\"type\":\"Text\",\"body\":\"hi\"}", &sender()).is_err());
+        assert!(decode(&[], &sender()).is_err());
+    }
+
+    #[test]
+    fn test_envelope_round_trip() {
+        let source = sender();
+        let envelope = Envelope {
+            envelope_type: EnvelopeType::PreKeyBundle,
+            source: Some(source.aci.into()),
+            source_device: 3,
+            timestamp: 1_700_000_000_000,
+            metadata: EnvelopeMetadata {
+                server_guid: Some(Uuid::new_v4().to_string()),
+                server_timestamp: Some(1_700_000_000_123),
+                destination_service_id: Some(Pni::from(Uuid::new_v4()).into()),
+                urgent: false,
+                story: true,
+            },
+            content: vec![1, 2, 3],
+        };
+
+        let decoded = decode_envelope(&encode_envelope(&envelope)).unwrap();
+
+        assert_eq!(decoded.envelope_type, EnvelopeType::PreKeyBundle);
+        assert_eq!(decoded.source, Some(source.aci.into()));
+        assert_eq!(decoded.source_device, 3);
+        assert_eq!(decoded.timestamp, envelope.timestamp);
+        assert_eq!(decoded.metadata, envelope.metadata);
+        assert_eq!(decoded.content, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_envelope_from_pni() {
+        let pni = Pni::from(Uuid::new_v4());
+        let bytes = proto::Envelope {
+            r#type: Some(proto::envelope::Type::Ciphertext as i32),
+            source_service_id: Some(ServiceId::from(pni).to_string()),
+            source_device: Some(2),
+            ..Default::default()
+        }
+        .encode_to_vec();
+
+        let decoded = decode_envelope(&bytes).unwrap();
+        assert_eq!(decoded.source, Some(ServiceId::Pni(pni)));
+        assert_eq!(decoded.source_device, 2);
+
+        let bytes = proto::Envelope {
+            source_service_id: Some("PNI:not-a-uuid".to_string()),
+            ..Default::default()
+        }
+        .encode_to_vec();
+        assert!(decode_envelope(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_envelope_defaults_to_urgent() {
+        // Sealed sender envelope without source or flags
+        let bytes = proto::Envelope {
+            r#type: Some(proto::envelope::Type::UnidentifiedSender as i32),
+            content: Some(vec![9]),
+            ..Default::default()
+        }
+        .encode_to_vec();
+
+        let decoded = decode_envelope(&bytes).unwrap();
+
+        assert_eq!(decoded.envelope_type, EnvelopeType::UnidentifiedSender);
+        assert!(decoded.source.is_none());
+        assert!(decoded.metadata.urgent);
+        assert!(!decoded.metadata.story);
+        assert_eq!(decoded.metadata, EnvelopeMetadata::default());
+    }
+
+    #[test]
+    fn test_plaintext_content_round_trip() {
+        let error = DecryptionErrorMessage {
+            ratchet_key: Some(vec![5; 32]),
+            timestamp: 1_700_000_000_000,
+            device_id: 2,
+        };
+
+        let bytes = encode_plaintext_content(&error);
+        assert_eq!(bytes[0], 0xC0);
+        assert_eq!(*bytes.last().unwrap(), 0x80);
+
+        match decode_plaintext_content(&bytes).unwrap() {
+            SignalContent::DecryptionError(decoded) => assert_eq!(decoded, error),
+            other => panic!("Expected decryption error, got {:?}", other),
+        }
+
+        // Regular content may not be smuggled in unencrypted
+        let mut data = vec![0xC0];
+        data.extend(encode(&SignalContent::Null).unwrap());
+        data.push(0x80);
+        assert!(decode_plaintext_content(&data).is_err());
+        assert!(decode_plaintext_content(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,169 @@
+//! Conversation bookkeeping
+//!
+//! Every stored message belongs to a conversation. The conversation service
+//! creates a conversation with its first message and keeps its name, unread
+//! count and last message current, announcing each change with
+//! `SignalEvent::ConversationUpdated`.
+
+use anyhow::Result;
+use std::sync::Arc;
+
+use super::client::SignalEvent;
+use super::events::EventBus;
+use super::store::SignalStore;
+use super::types::*;
+
+/// Stores messages into their conversations
+#[derive(Clone)]
+pub struct ConversationService {
+    store: Arc<SignalStore>,
+    events: EventBus,
+}
+
+impl ConversationService {
+    pub fn new(store: Arc<SignalStore>, events: EventBus) -> Self {
+        Self { store, events }
+    }
+
+    /// Store a message received from its sender, counting it as unread
+    ///
+    /// `group_id` is set for messages sent to a group.
+    pub async fn record_incoming(
+        &self,
+        message: &Message,
+        group_id: Option<&str>,
+    ) -> Result<Conversation> {
+        self.record(message, &message.sender, group_id, true).await
+    }
+
+    /// Store a message we sent to `recipient` or a group
+    pub async fn record_outgoing(
+        &self,
+        message: &Message,
+        recipient: &SignalIdentity,
+        group_id: Option<&str>,
+    ) -> Result<Conversation> {
+        self.record(message, recipient, group_id, false).await
+    }
+
+    /// Clear the unread count of a conversation once it has been seen
+    ///
+    /// Returns the author and sent timestamp of each message that was
+    /// unread.
+    pub async fn mark_read(&self, conversation_id: &str) -> Result<Vec<(String, i64)>> {
+        match self.store.mark_conversation_read(conversation_id).await? {
+            Some((conversation, read)) => {
+                self.notify(conversation);
+                Ok(read)
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Apply messages read on another device
+    pub async fn apply_read_sync(&self, reads: &[(String, i64)]) -> Result<()> {
+        for conversation in self.store.apply_read_sync(reads).await? {
+            self.notify(conversation);
+        }
+        Ok(())
+    }
+
+    async fn record(
+        &self,
+        message: &Message,
+        recipient: &SignalIdentity,
+        group_id: Option<&str>,
+        unread: bool,
+    ) -> Result<Conversation> {
+        let conversation = self
+            .store
+            .store_conversation_message(message, recipient, group_id, unread)
+            .await?;
+
+        self.notify(conversation.clone());
+        Ok(conversation)
+    }
+
+    fn notify(&self, conversation: Conversation) {
+        self.events
+            .publish(SignalEvent::ConversationUpdated(conversation));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signal::events::{BusMessage, EventSubscriber};
+    use crate::signal::keystore::{KeyStore, KEY_FILE};
+    use tempfile::TempDir;
+
+    async fn next_update(subscriber: &mut EventSubscriber) -> Conversation {
+        match subscriber.recv().await {
+            Some(BusMessage::Event(event)) => match *event {
+                SignalEvent::ConversationUpdated(conversation) => conversation,
+                other => panic!("unexpected event {:?}", other),
+            },
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_updates_are_announced() {
+        let temp_dir = TempDir::new().unwrap();
+        let key_store = KeyStore::File {
+            path: temp_dir.path().join(KEY_FILE),
+            wrapping_key: [7; 32],
+        };
+        let store = SignalStore::with_key_store(temp_dir.path(), key_store)
+            .await
+            .unwrap();
+        let events = EventBus::new();
+        let mut subscriber = events.subscribe("test");
+        let service = ConversationService::new(Arc::new(store), events);
+
+        let sender = SignalIdentity {
+            aci: uuid::Uuid::new_v4().into(),
+            pni: None,
+            phone_number: None,
+            device_id: 1,
+            registration_id: 0,
+        };
+        let message = Message {
+            id: "msg-1".to_string(),
+            conversation_id: sender.aci.to_string(),
+            sender: sender.clone(),
+            timestamp: 1000,
+            received_timestamp: None,
+            content: MessageContent::Text {
+                body: "Hi".to_string(),
+            },
+            status: MessageStatus::Delivered,
+            quote: None,
+            reactions: Vec::new(),
+            expires_at: None,
+            envelope: None,
+        };
+
+        service.record_incoming(&message, None).await.unwrap();
+        let conversation = next_update(&mut subscriber).await;
+        assert_eq!(conversation.id, message.conversation_id);
+        assert_eq!(conversation.unread_count, 1);
+
+        let read = service.mark_read(&message.conversation_id).await.unwrap();
+        assert_eq!(read, [(sender.aci.to_string(), 1000)]);
+        assert_eq!(next_update(&mut subscriber).await.unread_count, 0);
+
+        // A read on another device of a message already read changes nothing
+        service.record_incoming(&message, None).await.unwrap();
+        assert_eq!(next_update(&mut subscriber).await.unread_count, 0);
+        service
+            .apply_read_sync(&[(sender.aci.to_string(), 1000)])
+            .await
+            .unwrap();
+
+        // Nothing to announce for an unknown conversation
+        service.mark_read("missing").await.unwrap();
+        drop(service);
+        assert!(subscriber.recv().await.is_none());
+    }
+}
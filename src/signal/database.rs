@@ -0,0 +1,531 @@
+//! SQLite connection handling for the store
+//!
+//! All writes go through one connection owned by a dedicated worker thread,
+//! which serializes them without blocking the async runtime. Reads run on
+//! the blocking thread pool using a small pool of read-only connections;
+//! with WAL journaling they proceed in parallel with writes.
+
+use anyhow::{anyhow, Result};
+use rusqlite::{params, Connection, DatabaseName, OpenFlags};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use tokio::sync::oneshot;
+
+use super::keystore::DatabaseKey;
+
+/// First bytes of every unencrypted SQLite database
+const PLAINTEXT_HEADER: &[u8; 16] = b"SQLite format 3\0";
+
+/// Prepared statements cached per connection
+const STATEMENT_CACHE_CAPACITY: usize = 64;
+
+/// Idle read connections kept open for reuse
+const MAX_IDLE_READERS: usize = 4;
+
+/// Work queued for the writer thread
+type Job = Box<dyn FnOnce(&mut Connection) + Send>;
+
+/// Async handle to the store database
+#[derive(Clone)]
+pub struct Database {
+    /// Queue of the writer thread
+    writer: mpsc::Sender<Job>,
+    /// Read-only connections
+    readers: Arc<ReaderPool>,
+}
+
+/// Read-only connections, opened on demand
+struct ReaderPool {
+    path: PathBuf,
+    state: Mutex<ReaderState>,
+}
+
+struct ReaderState {
+    key: Option<DatabaseKey>,
+    /// Bumped on rekey so connections opened with the old key are dropped
+    generation: u64,
+    idle: Vec<Connection>,
+}
+
+impl Database {
+    /// Open the database at `path`, unlocking it with `key` if encrypted
+    pub fn open(path: &Path, key: Option<&DatabaseKey>) -> Result<Self> {
+        let connection = Connection::open(path)?;
+        configure(&connection, key)?;
+
+        let journal_mode: String =
+            connection.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get(0))?;
+        if !journal_mode.eq_ignore_ascii_case("wal") {
+            tracing::warn!("Database is using {} journaling instead of WAL", journal_mode);
+        }
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+
+        let (writer, jobs) = mpsc::channel::<Job>();
+        std::thread::Builder::new()
+            .name("signal-store".to_string())
+            .spawn(move || {
+                let mut connection = connection;
+                for job in jobs {
+                    job(&mut connection);
+                }
+                tracing::debug!("Database writer stopped");
+            })?;
+
+        Ok(Self {
+            writer,
+            readers: Arc::new(ReaderPool {
+                path: path.to_path_buf(),
+                state: Mutex::new(ReaderState {
+                    key: key.cloned(),
+                    generation: 0,
+                    idle: Vec::new(),
+                }),
+            }),
+        })
+    }
+
+    /// Run `f` on the write connection
+    ///
+    /// Writes run one at a time, in the order they were submitted.
+    pub async fn write<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (reply, response) = oneshot::channel();
+
+        self.writer
+            .send(Box::new(move |connection| {
+                let _ = reply.send(f(connection));
+            }))
+            .map_err(|_| anyhow!("Database writer has stopped"))?;
+
+        response
+            .await
+            .map_err(|_| anyhow!("Database writer has stopped"))?
+    }
+
+    /// Run `f` on a read-only connection
+    pub async fn read<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Connection) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let readers = self.readers.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let (generation, connection) = readers.take()?;
+            let result = f(&connection);
+            readers.put_back(generation, connection);
+            result
+        })
+        .await?
+    }
+
+    /// Re-encrypt the database with a new key
+    pub async fn rekey(&self, key: &DatabaseKey) -> Result<()> {
+        let new_key = key.clone();
+        self.write(move |db| {
+            db.pragma_update(None, "rekey", new_key.to_sql().as_str())?;
+            Ok(())
+        })
+        .await?;
+
+        let mut state = self.readers.state.lock().unwrap();
+        state.key = Some(key.clone());
+        state.generation += 1;
+        state.idle.clear();
+
+        Ok(())
+    }
+}
+
+impl ReaderPool {
+    /// Take an idle connection, or open a new one
+    fn take(&self) -> Result<(u64, Connection)> {
+        let (generation, key) = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(connection) = state.idle.pop() {
+                return Ok((state.generation, connection));
+            }
+            (state.generation, state.key.clone())
+        };
+
+        let connection = Connection::open_with_flags(
+            &self.path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        configure(&connection, key.as_ref())?;
+
+        Ok((generation, connection))
+    }
+
+    /// Return a connection for reuse
+    fn put_back(&self, generation: u64, connection: Connection) {
+        let mut state = self.state.lock().unwrap();
+        if state.generation == generation && state.idle.len() < MAX_IDLE_READERS {
+            state.idle.push(connection);
+        }
+    }
+}
+
+/// Whether `path` holds an unencrypted SQLite database
+pub fn is_plaintext(path: &Path) -> Result<bool> {
+    let mut header = [0u8; 16];
+
+    match std::fs::File::open(path).and_then(|mut file| file.read_exact(&mut header)) {
+        Ok(()) => Ok(&header == PLAINTEXT_HEADER),
+        Err(e)
+            if matches!(
+                e.kind(),
+                std::io::ErrorKind::NotFound | std::io::ErrorKind::UnexpectedEof
+            ) =>
+        {
+            Ok(false)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Encrypt the unencrypted database at `path` in place
+///
+/// The contents are exported into a new database encrypted with `key`,
+/// which then replaces the original.
+pub fn encrypt_plaintext(path: &Path, key: &DatabaseKey) -> Result<()> {
+    let mut encrypted_path = path.as_os_str().to_owned();
+    encrypted_path.push(".encrypting");
+    let encrypted_path = PathBuf::from(encrypted_path);
+
+    // Left behind by an interrupted earlier attempt
+    if encrypted_path.exists() {
+        std::fs::remove_file(&encrypted_path)?;
+    }
+
+    {
+        let connection = Connection::open(path)?;
+        let user_version: u32 =
+            connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+        connection.execute(
+            "ATTACH DATABASE ?1 AS encrypted KEY ?2",
+            params![
+                encrypted_path
+                    .to_str()
+                    .ok_or_else(|| anyhow!("Database path is not valid UTF-8"))?,
+                key.to_sql().as_str()
+            ],
+        )?;
+        connection.query_row("SELECT sqlcipher_export('encrypted')", [], |_| Ok(()))?;
+        // The export does not carry over the schema version
+        connection.pragma_update(
+            Some(DatabaseName::Attached("encrypted")),
+            "user_version",
+            user_version,
+        )?;
+        connection.execute_batch("DETACH DATABASE encrypted")?;
+    }
+
+    // Closing the connection checkpointed any WAL into the plaintext file
+    for suffix in ["-wal", "-shm"] {
+        let mut sidecar = path.as_os_str().to_owned();
+        sidecar.push(suffix);
+        let sidecar = PathBuf::from(sidecar);
+        if sidecar.exists() {
+            std::fs::remove_file(sidecar)?;
+        }
+    }
+    std::fs::rename(&encrypted_path, path)?;
+
+    tracing::info!("Encrypted plaintext database {:?}", path);
+    Ok(())
+}
+
+/// Per-connection setup shared by the writer and readers
+fn configure(connection: &Connection, key: Option<&DatabaseKey>) -> Result<()> {
+    if let Some(key) = key {
+        // Set SQLCipher encryption key
+        connection.pragma_update(None, "key", key.to_sql().as_str())?;
+
+        // Verify encryption is working
+        connection
+            .query_row("SELECT count(*) FROM sqlite_master;", [], |_| Ok(()))
+            .map_err(|_| anyhow!("Failed to verify database encryption"))?;
+    }
+
+    // Let INSERT OR REPLACE fire delete triggers, which keep the search index in sync
+    connection.pragma_update(None, "recursive_triggers", true)?;
+    connection.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+    connection.busy_timeout(std::time::Duration::from_secs(5))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_reads_do_not_wait_for_writes() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = Database::open(&temp_dir.path().join("test.db"), None).unwrap();
+
+        db.write(|db| {
+            db.execute_batch("CREATE TABLE items (value INTEGER); INSERT INTO items VALUES (1);")?;
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        // Hold a write transaction open for a while
+        let writer = db.clone();
+        let slow_write = tokio::spawn(async move {
+            writer
+                .write(|db| {
+                    let tx = db.transaction()?;
+                    tx.execute("INSERT INTO items VALUES (2)", [])?;
+                    std::thread::sleep(Duration::from_millis(300));
+                    tx.commit()?;
+                    Ok(())
+                })
+                .await
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let started = Instant::now();
+        let count: i64 = db
+            .read(|db| Ok(db.query_row("SELECT COUNT(*) FROM items", [], |row| row.get(0))?))
+            .await
+            .unwrap();
+
+        // The read sees the last committed state without waiting
+        assert_eq!(count, 1);
+        assert!(started.elapsed() < Duration::from_millis(200));
+
+        slow_write.await.unwrap().unwrap();
+        let count: i64 = db
+            .read(|db| Ok(db.query_row("SELECT COUNT(*) FROM items", [], |row| row.get(0))?))
+            .await
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_writes() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = Database::open(&temp_dir.path().join("test.db"), None).unwrap();
+
+        db.write(|db| Ok(db.execute_batch("CREATE TABLE log (value INTEGER)")?))
+            .await
+            .unwrap();
+
+        let writes: Vec<_> = (0..100)
+            .map(|i| {
+                let db = db.clone();
+                tokio::spawn(async move {
+                    db.write(move |db| Ok(db.execute("INSERT INTO log VALUES (?)", [i])?))
+                        .await
+                })
+            })
+            .collect();
+        for write in writes {
+            write.await.unwrap().unwrap();
+        }
+
+        let count: i64 = db
+            .read(|db| Ok(db.query_row("SELECT COUNT(*) FROM log", [], |row| row.get(0))?))
+            .await
+            .unwrap();
+        assert_eq!(count, 100);
+    }
+
+    const BURST_SCHEMA: &str = "CREATE TABLE messages (
+            id INTEGER PRIMARY KEY,
+            conversation_id TEXT NOT NULL,
+            body TEXT NOT NULL,
+            timestamp INTEGER NOT NULL
+        );";
+    const BURST_INSERT: &str =
+        "INSERT INTO messages (conversation_id, body, timestamp) VALUES (?1, ?2, ?3)";
+    /// Stands in for a search or a long conversation being loaded
+    const BURST_SCAN: &str = "SELECT COUNT(*) FROM messages WHERE body LIKE '%needle%'";
+    const BURST_MESSAGES: i64 = 500;
+
+    fn fill_messages(db: &mut Connection) -> Result<()> {
+        db.execute_batch(BURST_SCHEMA)?;
+        let tx = db.transaction()?;
+        for i in 0..50_000i64 {
+            tx.execute(
+                BURST_INSERT,
+                params![format!("chat-{}", i % 20), "x".repeat(200), i],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn scan(db: &Connection) -> Result<i64> {
+        Ok(db.query_row(BURST_SCAN, [], |row| row.get(0))?)
+    }
+
+    /// 99th percentile of `latencies`
+    fn p99(mut latencies: Vec<Duration>) -> Duration {
+        latencies.sort();
+        latencies[(latencies.len() - 1) * 99 / 100]
+    }
+
+    /// Storing a burst of messages while the UI keeps reading is faster at
+    /// the 99th percentile than with a single connection behind an async
+    /// mutex, as the store had before
+    ///
+    /// Timing depends on the machine, so this is not run by default:
+    /// `cargo test --release burst_latency -- --ignored`
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[ignore]
+    async fn test_burst_latency() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let temp_dir = TempDir::new().unwrap();
+
+        // Before: every query holds the one connection
+        let mut connection = Connection::open(temp_dir.path().join("mutex.db")).unwrap();
+        fill_messages(&mut connection).unwrap();
+        let connection = Arc::new(tokio::sync::Mutex::new(connection));
+
+        let done = Arc::new(AtomicBool::new(false));
+        let reader = tokio::spawn({
+            let connection = connection.clone();
+            let done = done.clone();
+            async move {
+                while !done.load(Ordering::Relaxed) {
+                    scan(&*connection.lock().await).unwrap();
+                    tokio::task::yield_now().await;
+                }
+            }
+        });
+        let mut before = Vec::new();
+        for i in 0..BURST_MESSAGES {
+            let started = Instant::now();
+            connection
+                .lock()
+                .await
+                .execute(BURST_INSERT, params!["chat-0", "burst", 100_000 + i])
+                .unwrap();
+            before.push(started.elapsed());
+        }
+        done.store(true, Ordering::Relaxed);
+        reader.await.unwrap();
+
+        // After: writer thread and read pool
+        let db = Database::open(&temp_dir.path().join("pool.db"), None).unwrap();
+        db.write(fill_messages).await.unwrap();
+
+        let done = Arc::new(AtomicBool::new(false));
+        let reader = tokio::spawn({
+            let db = db.clone();
+            let done = done.clone();
+            async move {
+                while !done.load(Ordering::Relaxed) {
+                    db.read(scan).await.unwrap();
+                }
+            }
+        });
+        let mut after = Vec::new();
+        for i in 0..BURST_MESSAGES {
+            let started = Instant::now();
+            db.write(move |db| {
+                Ok(db.execute(BURST_INSERT, params!["chat-0", "burst", 100_000 + i])?)
+            })
+            .await
+            .unwrap();
+            after.push(started.elapsed());
+        }
+        done.store(true, Ordering::Relaxed);
+        reader.await.unwrap();
+
+        let (before, after) = (p99(before), p99(after));
+        assert!(
+            after < before,
+            "p99 with the writer thread {:?}, with the mutex {:?}",
+            after,
+            before
+        );
+    }
+
+    /// Count the rows of `items` through a fresh connection using `key`
+    fn count_items(path: &Path, key: &DatabaseKey) -> Result<i64> {
+        let connection = Connection::open(path)?;
+        configure(&connection, Some(key))?;
+        Ok(connection.query_row("SELECT COUNT(*) FROM items", [], |row| row.get(0))?)
+    }
+
+    #[test]
+    fn test_encrypt_plaintext() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("test.db");
+        {
+            let connection = Connection::open(&path).unwrap();
+            connection
+                .execute_batch(
+                    "PRAGMA journal_mode = WAL;
+                     CREATE TABLE items (value INTEGER);
+                     INSERT INTO items VALUES (1), (2);
+                     PRAGMA user_version = 3;",
+                )
+                .unwrap();
+        }
+        assert!(is_plaintext(&path).unwrap());
+
+        let key = DatabaseKey::generate();
+        encrypt_plaintext(&path, &key).unwrap();
+        assert!(!is_plaintext(&path).unwrap());
+
+        let connection = Connection::open(&path).unwrap();
+        configure(&connection, Some(&key)).unwrap();
+        let user_version: u32 = connection
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(user_version, 3);
+        assert_eq!(count_items(&path, &key).unwrap(), 2);
+
+        assert!(count_items(&path, &DatabaseKey::generate()).is_err());
+        assert!(Connection::open(&path)
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM sqlite_master", [], |_| Ok(()))
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rekey() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("test.db");
+        let old_key = DatabaseKey::generate();
+        let db = Database::open(&path, Some(&old_key)).unwrap();
+
+        db.write(|db| {
+            db.execute_batch("CREATE TABLE items (value INTEGER); INSERT INTO items VALUES (1);")?;
+            Ok(())
+        })
+        .await
+        .unwrap();
+        // Leave an idle reader holding the old key
+        db.read(|db| Ok(db.query_row("SELECT COUNT(*) FROM items", [], |row| row.get::<_, i64>(0))?))
+            .await
+            .unwrap();
+
+        let new_key = DatabaseKey::generate();
+        db.rekey(&new_key).await.unwrap();
+
+        let count: i64 = db
+            .read(|db| Ok(db.query_row("SELECT COUNT(*) FROM items", [], |row| row.get(0))?))
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+
+        drop(db);
+        assert_eq!(count_items(&path, &new_key).unwrap(), 1);
+        assert!(count_items(&path, &old_key).is_err());
+    }
+}
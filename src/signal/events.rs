@@ -0,0 +1,176 @@
+//! Event bus
+//!
+//! The client publishes `SignalEvent`s on a broadcast bus. The sync service,
+//! notifications and UI widgets each subscribe and receive every event in
+//! order. A subscriber that falls more than `EVENT_BUS_CAPACITY` events
+//! behind loses the oldest ones and is told how many it missed, so it can
+//! reload its state instead of showing stale data.
+
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use super::client::SignalEvent;
+
+/// Events buffered per subscriber before it starts lagging
+pub const EVENT_BUS_CAPACITY: usize = 256;
+
+/// Broadcasts client events to any number of subscribers
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<SignalEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUS_CAPACITY);
+        Self { sender }
+    }
+
+    /// Deliver `event` to all current subscribers
+    pub fn publish(&self, event: SignalEvent) {
+        // Without subscribers there is nobody to tell
+        let _ = self.sender.send(event);
+    }
+
+    /// Receive the events published from now on
+    ///
+    /// `name` identifies the subscriber in lag warnings.
+    pub fn subscribe(&self, name: &'static str) -> EventSubscriber {
+        EventSubscriber {
+            name,
+            receiver: self.sender.subscribe(),
+        }
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What a subscriber receives from the bus
+#[derive(Debug, Clone)]
+pub enum BusMessage {
+    /// The next event
+    Event(Box<SignalEvent>),
+    /// This many events were dropped because the subscriber fell behind
+    Lagged(u64),
+}
+
+/// One subscription to the event bus
+pub struct EventSubscriber {
+    name: &'static str,
+    receiver: broadcast::Receiver<SignalEvent>,
+}
+
+impl EventSubscriber {
+    /// Wait for the next message, or `None` once the bus is gone
+    pub async fn recv(&mut self) -> Option<BusMessage> {
+        match self.receiver.recv().await {
+            Ok(event) => Some(BusMessage::Event(Box::new(event))),
+            Err(RecvError::Lagged(missed)) => {
+                tracing::warn!("Event subscriber {} missed {} events", self.name, missed);
+                Some(BusMessage::Lagged(missed))
+            }
+            Err(RecvError::Closed) => None,
+        }
+    }
+
+    /// Forward this subscription into a channel that can be awaited on the
+    /// GLib main context
+    ///
+    /// The forwarding task runs on `runtime`. The channel holds at most
+    /// `EVENT_BUS_CAPACITY` messages; while it is full the subscription
+    /// backs up and lags instead of blocking publishers.
+    pub fn forward(
+        mut self,
+        runtime: &tokio::runtime::Handle,
+    ) -> async_channel::Receiver<BusMessage> {
+        let (sender, receiver) = async_channel::bounded(EVENT_BUS_CAPACITY);
+
+        runtime.spawn(async move {
+            while let Some(message) = self.recv().await {
+                if sender.send(message).await.is_err() {
+                    // The receiving side went away
+                    break;
+                }
+            }
+        });
+
+        receiver
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> SignalEvent {
+        SignalEvent::Error(text.to_string())
+    }
+
+    fn text(message: Option<BusMessage>) -> String {
+        match message {
+            Some(BusMessage::Event(event)) => match *event {
+                SignalEvent::Error(text) => text,
+                other => panic!("unexpected event {:?}", other),
+            },
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_every_subscriber_receives_events() {
+        let bus = EventBus::new();
+        let mut first = bus.subscribe("first");
+        let mut second = bus.subscribe("second");
+
+        bus.publish(error("one"));
+        bus.publish(error("two"));
+
+        assert_eq!(text(first.recv().await), "one");
+        assert_eq!(text(first.recv().await), "two");
+        assert_eq!(text(second.recv().await), "one");
+        assert_eq!(text(second.recv().await), "two");
+
+        // Late subscribers only see what is published after they join
+        let mut late = bus.subscribe("late");
+        bus.publish(error("three"));
+        assert_eq!(text(late.recv().await), "three");
+
+        drop(bus);
+        assert_eq!(text(first.recv().await), "three");
+        assert!(first.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_slow_subscriber_is_told_it_lagged() {
+        let bus = EventBus::new();
+        let mut subscriber = bus.subscribe("slow");
+
+        for i in 0..EVENT_BUS_CAPACITY + 3 {
+            bus.publish(error(&i.to_string()));
+        }
+
+        match subscriber.recv().await {
+            Some(BusMessage::Lagged(missed)) => assert_eq!(missed, 3),
+            other => panic!("unexpected message {:?}", other),
+        }
+        assert_eq!(text(subscriber.recv().await), "3");
+    }
+
+    #[tokio::test]
+    async fn test_forward_to_channel() {
+        let bus = EventBus::new();
+        let receiver = bus
+            .subscribe("forwarded")
+            .forward(&tokio::runtime::Handle::current());
+
+        bus.publish(error("hello"));
+        assert_eq!(text(receiver.recv().await.ok()), "hello");
+
+        // The channel closes with the bus
+        drop(bus);
+        assert!(receiver.recv().await.is_err());
+    }
+}
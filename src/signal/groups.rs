@@ -0,0 +1,2592 @@
+//! Groups V2 keys, state decryption and revision tracking
+//!
+//! A group is known by its 32-byte master key, which members share in
+//! every group message. The group server keeps the group state and a log
+//! of its changes, with member ACIs, profile keys and attributes encrypted
+//! under keys derived from the master key, so the server never learns who
+//! is in a group or what it is called.
+//!
+//! The group secret params are derived from the master key by zkgroup:
+//!
+//! - group ID: names the group to the server and in messages
+//! - member ACIs are encrypted as zkgroup UUID ciphertexts, the same for
+//!   the same member every time, so members can be found without
+//!   decrypting everyone and credentials can be checked against them
+//! - members' profile keys are encrypted as profile key ciphertexts,
+//!   bound to the member's ACI
+//! - the title, description, disappearing timer and avatar are encrypted
+//!   blobs, AES-256-GCM-SIV under a key derived from the master key
+//!
+//! The server knows a group by its public params. Each request presents
+//! our auth credential for the day against them, proving we are one of
+//! the group's members without saying which.
+//!
+//! Each change bumps the group revision by one. Changes are applied in
+//! revision order; when the log skips a revision or a change does not
+//! apply, the full current state is fetched instead. Changes embedded in
+//! group messages are not applied, as only the server's log is trusted.
+//!
+//! Our own changes are checked against the group's roles and access
+//! control before they are built, so the server is only asked for changes
+//! it would allow. A change built on a stale revision is turned down by
+//! the server, and is built again once the group is brought up to date.
+//!
+//! An invite link carries the master key and the group's invite link
+//! password. The password lets people outside the group see its title,
+//! size and revision, and add themselves as a member or as someone asking
+//! to join, depending on the link's access control.
+
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD};
+use base64::Engine;
+use prost::Message as _;
+use rand::RngCore;
+use serde::Deserialize;
+use std::collections::HashMap;
+use uuid::Uuid;
+use zeroize::Zeroize;
+use zkgroup::auth::AuthCredentialWithPni;
+use zkgroup::groups::{GroupMasterKey, GroupSecretParams as ZkGroupSecretParams, UuidCiphertext};
+use zkgroup::profiles::{AnyProfileKeyCredentialPresentation, ExpiringProfileKeyCredential};
+use zkgroup::{RandomnessBytes, ServerPublicParams};
+
+use super::proto;
+use super::service_id::Aci;
+use super::types::{
+    Group, GroupAccessControl, GroupMember, GroupPreview, GroupRole, GroupUpdate, InviteLinkAccess,
+    SignalServers,
+};
+
+use proto::access_control::AccessRequired;
+use proto::group_attribute_blob::Content as BlobContent;
+use proto::group_change::actions::{
+    AddMemberAction, AddMemberPendingAdminApprovalAction, AddMemberPendingProfileKeyAction,
+    DeleteMemberAction, DeleteMemberPendingAdminApprovalAction,
+    DeleteMemberPendingProfileKeyAction, ModifyAddFromInviteLinkAccessControlAction,
+    ModifyAttributesAccessControlAction, ModifyDescriptionAction,
+    ModifyDisappearingMessagesTimerAction, ModifyInviteLinkPasswordAction, ModifyMemberRoleAction,
+    ModifyMembersAccessControlAction, ModifyTitleAction, PromoteMemberPendingAdminApprovalAction,
+};
+use proto::group_change::Actions;
+use proto::group_changes::GroupChangeState;
+use proto::group_invite_link::{Contents as InviteLinkContents, GroupInviteLinkContentsV1};
+use proto::member::Role;
+
+/// Size of a group master key
+pub const GROUP_MASTER_KEY_SIZE: usize = 32;
+
+/// Newest group change epoch we understand
+const MAX_CHANGE_EPOCH: u32 = 5;
+
+const DAY_SECS: i64 = 24 * 60 * 60;
+
+/// Size of an invite link password
+const INVITE_LINK_PASSWORD_SIZE: usize = 16;
+
+/// What invite links start with; the rest is a fragment, so it never
+/// reaches the web server
+const INVITE_LINK_PREFIX: &str = "https://signal.group/#";
+
+/// Keys of one group, derived from its master key
+#[derive(Clone)]
+pub struct GroupSecretParams {
+    master_key: [u8; GROUP_MASTER_KEY_SIZE],
+    params: ZkGroupSecretParams,
+}
+
+impl GroupSecretParams {
+    /// Derive the keys of the group with `master_key`
+    pub fn derive_from_master_key(master_key: &[u8]) -> Result<Self> {
+        let master_key: [u8; GROUP_MASTER_KEY_SIZE] = master_key
+            .try_into()
+            .map_err(|_| anyhow!("Invalid group master key length {}", master_key.len()))?;
+        Ok(Self {
+            master_key,
+            params: ZkGroupSecretParams::derive_from_master_key(GroupMasterKey::new(master_key)),
+        })
+    }
+
+    pub fn master_key(&self) -> &[u8] {
+        &self.master_key
+    }
+
+    /// The zkgroup params, which credentials are presented with
+    pub fn zkgroup_params(&self) -> ZkGroupSecretParams {
+        self.params
+    }
+
+    /// Base64 group ID, as groups are named in messages and locally
+    pub fn id(&self) -> String {
+        BASE64.encode(self.params.get_group_identifier())
+    }
+
+    /// Public params the server knows the group by
+    pub fn public_params(&self) -> Vec<u8> {
+        zkgroup::serialize(&self.params.get_public_params())
+    }
+
+    /// Encrypt a member's ACI
+    pub fn encrypt_member(&self, aci: &Aci) -> Result<Vec<u8>> {
+        let aci = libsignal_core::Aci::from(aci.uuid());
+        Ok(zkgroup::serialize(
+            &self.params.encrypt_service_id(aci.into()),
+        ))
+    }
+
+    /// Decrypt a member's ACI
+    pub fn decrypt_member(&self, ciphertext: &[u8]) -> Result<Aci> {
+        let ciphertext: UuidCiphertext =
+            zkgroup::deserialize(ciphertext).map_err(|_| anyhow!("Malformed member ciphertext"))?;
+        match self.params.decrypt_service_id(ciphertext) {
+            Ok(libsignal_core::ServiceId::Aci(aci)) => Ok(Uuid::from(aci).into()),
+            Ok(libsignal_core::ServiceId::Pni(_)) => Err(anyhow!("Member is not an ACI")),
+            Err(_) => Err(anyhow!("Member ciphertext does not decrypt")),
+        }
+    }
+
+    /// Encrypt a group attribute
+    pub fn encrypt_blob(&self, content: BlobContent) -> Result<Vec<u8>> {
+        let blob = proto::GroupAttributeBlob {
+            content: Some(content),
+        };
+        Ok(self
+            .params
+            .encrypt_blob_with_padding(randomness(), &blob.encode_to_vec(), 0))
+    }
+
+    /// Decrypt a group attribute
+    pub fn decrypt_blob(&self, ciphertext: &[u8]) -> Result<Option<BlobContent>> {
+        let plaintext = self
+            .params
+            .decrypt_blob_with_padding(ciphertext)
+            .map_err(|_| anyhow!("Group attribute does not decrypt"))?;
+        Ok(proto::GroupAttributeBlob::decode(plaintext.as_slice())?.content)
+    }
+}
+
+impl Drop for GroupSecretParams {
+    fn drop(&mut self) {
+        self.master_key.zeroize();
+    }
+}
+
+/// Expiring profile key credentials of the people we add as members
+///
+/// The server only takes a member's profile key with a presentation of
+/// their credential, proving the key is theirs.
+#[derive(Clone)]
+pub struct ProfileKeyCredentials {
+    server_params: ServerPublicParams,
+    credentials: HashMap<Aci, ExpiringProfileKeyCredential>,
+}
+
+impl ProfileKeyCredentials {
+    pub fn new(server_params: ServerPublicParams) -> Self {
+        Self {
+            server_params,
+            credentials: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, aci: Aci, credential: ExpiringProfileKeyCredential) {
+        self.credentials.insert(aci, credential);
+    }
+
+    pub fn contains(&self, aci: &Aci) -> bool {
+        self.credentials.contains_key(aci)
+    }
+
+    /// Present the credential of `aci` to the group of `params`
+    fn present(&self, params: &GroupSecretParams, aci: &Aci) -> Result<Vec<u8>> {
+        let credential = self
+            .credentials
+            .get(aci)
+            .ok_or_else(|| anyhow!("Profile key credential of {} missing", aci))?;
+        let presentation = self
+            .server_params
+            .create_expiring_profile_key_credential_presentation(
+                randomness(),
+                params.zkgroup_params(),
+                *credential,
+            );
+        Ok(zkgroup::serialize(&presentation))
+    }
+}
+
+/// Decrypt the state of a group
+///
+/// Members whose ACI does not decrypt are left out.
+pub fn decrypt_group(params: &GroupSecretParams, state: &proto::Group) -> Result<Group> {
+    let mut group = Group {
+        id: params.id(),
+        master_key: params.master_key().to_vec(),
+        revision: state.version.unwrap_or(0),
+        ..Default::default()
+    };
+
+    if let Some(title) = &state.title {
+        group.name = decrypt_title(params, title)?;
+    }
+    group.avatar = state.avatar.clone().filter(|avatar| !avatar.is_empty());
+    if let Some(description) = &state.description {
+        group.description = decrypt_description(params, description)?;
+    }
+    if let Some(timer) = &state.disappearing_messages_timer {
+        group.disappearing_messages_timer = decrypt_timer(params, timer)?;
+    }
+    if let Some(access) = &state.access_control {
+        group.access_control = GroupAccessControl {
+            members_can_add_members: members_allowed(access.members),
+            members_can_edit_group_info: members_allowed(access.attributes),
+        };
+        group.invite_link_access = invite_link_access(access.add_from_invite_link);
+    }
+    group.invite_link_password = state
+        .invite_link_password
+        .clone()
+        .filter(|password| !password.is_empty());
+
+    let decrypt = |user_id: &Option<Vec<u8>>| match decrypt_user_id(params, user_id) {
+        Ok(aci) => Some(aci),
+        Err(e) => {
+            tracing::warn!("Skipping member of group {}: {}", group.id, e);
+            None
+        }
+    };
+    let members = state.members.iter().filter_map(|member| {
+        Some(GroupMember {
+            uuid: decrypt(&member.user_id)?.uuid(),
+            role: role(member.role),
+            joined_at: member.joined_at_version.unwrap_or(0) as i64,
+            invited_by: None,
+        })
+    });
+    let invited = state
+        .members_pending_profile_key
+        .iter()
+        .filter_map(|pending| {
+            let member = pending.member.as_ref()?;
+            Some(GroupMember {
+                uuid: decrypt(&member.user_id)?.uuid(),
+                role: role(member.role),
+                joined_at: pending.timestamp.unwrap_or(0) as i64,
+                invited_by: decrypt(&pending.added_by_user_id).map(|aci| aci.uuid()),
+            })
+        });
+    let requesting = state
+        .members_pending_admin_approval
+        .iter()
+        .filter_map(|pending| {
+            Some(GroupMember {
+                uuid: decrypt(&pending.user_id)?.uuid(),
+                role: GroupRole::Member,
+                joined_at: pending.timestamp.unwrap_or(0) as i64,
+                invited_by: None,
+            })
+        });
+    let members: Vec<GroupMember> = members.collect();
+    let pending_members: Vec<GroupMember> = invited.chain(requesting).collect();
+
+    group.members = members;
+    group.pending_members = pending_members;
+    group.admins = admins(&group.members);
+    Ok(group)
+}
+
+/// Decode the actions of a change from the log
+pub fn change_actions(change: &proto::GroupChange) -> Result<Actions> {
+    Ok(Actions::decode(
+        change.actions.as_deref().unwrap_or_default(),
+    )?)
+}
+
+/// Apply a change to the group state of the revision before it
+///
+/// Fails without touching `group` when the change is for another
+/// revision or names members it cannot decrypt.
+pub fn apply_change(
+    params: &GroupSecretParams,
+    group: &mut Group,
+    actions: &Actions,
+) -> Result<()> {
+    let revision = actions.version.unwrap_or(0);
+    if revision != group.revision + 1 {
+        return Err(anyhow!(
+            "Change to revision {} does not follow revision {}",
+            revision,
+            group.revision
+        ));
+    }
+
+    let mut changed = group.clone();
+    let user = |user_id: &Option<Vec<u8>>| decrypt_user_id(params, user_id).map(|aci| aci.uuid());
+
+    for action in &actions.add_members {
+        let member = action
+            .added
+            .as_ref()
+            .ok_or_else(|| anyhow!("Added member missing"))?;
+        let uuid = user(&member.user_id)?;
+        changed
+            .pending_members
+            .retain(|pending| pending.uuid != uuid);
+        changed.members.retain(|existing| existing.uuid != uuid);
+        changed.members.push(GroupMember {
+            uuid,
+            role: role(member.role),
+            joined_at: revision as i64,
+            invited_by: None,
+        });
+    }
+    for action in &actions.delete_members {
+        let uuid = user(&action.deleted_user_id)?;
+        changed.members.retain(|member| member.uuid != uuid);
+    }
+    for action in &actions.modify_member_roles {
+        let uuid = user(&action.user_id)?;
+        if let Some(member) = changed
+            .members
+            .iter_mut()
+            .find(|member| member.uuid == uuid)
+        {
+            member.role = role(action.role);
+        }
+    }
+    for action in &actions.add_members_pending_profile_key {
+        let pending = action
+            .added
+            .as_ref()
+            .ok_or_else(|| anyhow!("Invited member missing"))?;
+        let member = pending
+            .member
+            .as_ref()
+            .ok_or_else(|| anyhow!("Invited member missing"))?;
+        let uuid = user(&member.user_id)?;
+        changed
+            .pending_members
+            .retain(|existing| existing.uuid != uuid);
+        changed.pending_members.push(GroupMember {
+            uuid,
+            role: role(member.role),
+            joined_at: pending.timestamp.unwrap_or(0) as i64,
+            invited_by: Some(user(&pending.added_by_user_id)?),
+        });
+    }
+    for action in &actions.delete_members_pending_profile_key {
+        let uuid = user(&action.deleted_user_id)?;
+        changed
+            .pending_members
+            .retain(|pending| pending.uuid != uuid);
+    }
+    for action in &actions.promote_members_pending_profile_key {
+        let uuid = user(&action.user_id)?;
+        promote(&mut changed, uuid, None, revision);
+    }
+    for action in &actions.add_members_pending_admin_approval {
+        let pending = action
+            .added
+            .as_ref()
+            .ok_or_else(|| anyhow!("Requesting member missing"))?;
+        let uuid = user(&pending.user_id)?;
+        changed
+            .pending_members
+            .retain(|existing| existing.uuid != uuid);
+        changed.pending_members.push(GroupMember {
+            uuid,
+            role: GroupRole::Member,
+            joined_at: pending.timestamp.unwrap_or(0) as i64,
+            invited_by: None,
+        });
+    }
+    for action in &actions.delete_members_pending_admin_approval {
+        let uuid = user(&action.deleted_user_id)?;
+        changed
+            .pending_members
+            .retain(|pending| pending.uuid != uuid);
+    }
+    for action in &actions.promote_members_pending_admin_approval {
+        let uuid = user(&action.user_id)?;
+        promote(&mut changed, uuid, Some(role(action.role)), revision);
+    }
+
+    if let Some(action) = &actions.modify_title {
+        changed.name = match &action.title {
+            Some(title) => decrypt_title(params, title)?,
+            None => String::new(),
+        };
+    }
+    if let Some(action) = &actions.modify_avatar {
+        changed.avatar = action.avatar.clone().filter(|avatar| !avatar.is_empty());
+    }
+    if let Some(action) = &actions.modify_description {
+        changed.description = match &action.description {
+            Some(description) => decrypt_description(params, description)?,
+            None => None,
+        };
+    }
+    if let Some(action) = &actions.modify_disappearing_messages_timer {
+        changed.disappearing_messages_timer = match &action.timer {
+            Some(timer) => decrypt_timer(params, timer)?,
+            None => None,
+        };
+    }
+    if let Some(action) = &actions.modify_member_access {
+        changed.access_control.members_can_add_members = members_allowed(action.members_access);
+    }
+    if let Some(action) = &actions.modify_attributes_access {
+        changed.access_control.members_can_edit_group_info =
+            members_allowed(action.attributes_access);
+    }
+    if let Some(action) = &actions.modify_add_from_invite_link_access {
+        changed.invite_link_access = invite_link_access(action.add_from_invite_link_access);
+    }
+    if let Some(action) = &actions.modify_invite_link_password {
+        changed.invite_link_password = action
+            .invite_link_password
+            .clone()
+            .filter(|password| !password.is_empty());
+    }
+
+    changed.admins = admins(&changed.members);
+    changed.revision = revision;
+    *group = changed;
+    Ok(())
+}
+
+/// Encrypt the state of a new group for the server, the inverse of
+/// [`decrypt_group`]
+///
+/// Members need their profile key credential in `credentials`. Pending
+/// members someone invited are sent as invitations, the others as
+/// requests to join.
+pub fn encrypt_group(
+    params: &GroupSecretParams,
+    group: &Group,
+    credentials: &ProfileKeyCredentials,
+) -> Result<proto::Group> {
+    let members = group
+        .members
+        .iter()
+        .map(|member| {
+            let presentation = credentials.present(params, &member.uuid.into())?;
+            full_member(member.role, presentation, member.joined_at as u32)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut invited = Vec::new();
+    let mut requesting = Vec::new();
+    for pending in &group.pending_members {
+        let aci = Aci::from(pending.uuid);
+        match pending.invited_by {
+            Some(invited_by) => invited.push(proto::MemberPendingProfileKey {
+                member: Some(proto::Member {
+                    user_id: Some(params.encrypt_member(&aci)?),
+                    role: Some(role_value(pending.role) as i32),
+                    ..Default::default()
+                }),
+                added_by_user_id: Some(params.encrypt_member(&invited_by.into())?),
+                timestamp: Some(pending.joined_at as u64),
+            }),
+            None if credentials.contains(&aci) => requesting.push(requesting_member(
+                credentials.present(params, &aci)?,
+                pending.joined_at,
+            )?),
+            None => requesting.push(proto::MemberPendingAdminApproval {
+                user_id: Some(params.encrypt_member(&aci)?),
+                timestamp: Some(pending.joined_at as u64),
+                ..Default::default()
+            }),
+        }
+    }
+
+    Ok(proto::Group {
+        public_key: Some(params.public_params()),
+        title: Some(params.encrypt_blob(BlobContent::Title(group.name.clone()))?),
+        avatar: group.avatar.clone(),
+        disappearing_messages_timer: Some(encrypt_timer(
+            params,
+            group.disappearing_messages_timer,
+        )?),
+        access_control: Some(proto::AccessControl {
+            attributes: Some(
+                access_required(group.access_control.members_can_edit_group_info) as i32,
+            ),
+            members: Some(access_required(group.access_control.members_can_add_members) as i32),
+            add_from_invite_link: Some(invite_link_required(group.invite_link_access) as i32),
+        }),
+        version: Some(group.revision),
+        members,
+        members_pending_profile_key: invited,
+        members_pending_admin_approval: requesting,
+        invite_link_password: group.invite_link_password.clone(),
+        description: Some(encrypt_description(params, group.description.as_deref())?),
+        ..Default::default()
+    })
+}
+
+/// Check that `actor` may make `update` to `group`, as the server would
+///
+/// Also turns down changes leaving members without an administrator, who
+/// would have nobody left to manage the group.
+pub fn check_permission(group: &Group, actor: &Aci, update: &GroupUpdate) -> Result<()> {
+    let actor = actor.uuid();
+    if !group.is_member(&actor) {
+        return Err(anyhow!("Not a member of group {}", group.id));
+    }
+
+    let allowed = match update {
+        // Adding someone who asked to join approves their request
+        GroupUpdate::AddMembers(acis) => {
+            group.can_add_members(&actor)
+                && (group.is_admin(&actor)
+                    || !acis.iter().any(|aci| group.is_requesting(&aci.uuid())))
+        }
+        // Anyone may leave
+        GroupUpdate::RemoveMembers(acis) => {
+            group.is_admin(&actor) || acis.iter().all(|aci| aci.uuid() == actor)
+        }
+        GroupUpdate::SetAttributes { .. } => group.can_edit_info(&actor),
+        GroupUpdate::SetRole(..)
+        | GroupUpdate::SetAccessControl(_)
+        | GroupUpdate::ApproveRequests(_)
+        | GroupUpdate::DenyRequests(_)
+        | GroupUpdate::SetInviteLink(_)
+        | GroupUpdate::ResetInviteLink => group.is_admin(&actor),
+    };
+    if !allowed {
+        return Err(anyhow!(
+            "Only administrators may make this change to group {}",
+            group.id
+        ));
+    }
+
+    let removed = |uuid: Uuid| match update {
+        GroupUpdate::RemoveMembers(acis) => acis.iter().any(|aci| aci.uuid() == uuid),
+        _ => false,
+    };
+    let demoted = |uuid: Uuid| match update {
+        GroupUpdate::SetRole(aci, role) => aci.uuid() == uuid && *role != GroupRole::Administrator,
+        _ => false,
+    };
+    let remaining = || group.members.iter().filter(|member| !removed(member.uuid));
+    if !group.admins.is_empty()
+        && remaining().next().is_some()
+        && !remaining()
+            .any(|member| member.role == GroupRole::Administrator && !demoted(member.uuid))
+    {
+        return Err(anyhow!(
+            "Group {} needs another administrator first",
+            group.id
+        ));
+    }
+
+    Ok(())
+}
+
+/// Build the change making `update` to `group`, as the change to its next
+/// revision
+///
+/// People in `credentials` are added as members, anyone else we add is
+/// invited. Adding someone who asked to join approves their request.
+/// `now` is the time of invitations in milliseconds.
+pub fn build_change(
+    params: &GroupSecretParams,
+    group: &Group,
+    actor: &Aci,
+    update: &GroupUpdate,
+    credentials: &ProfileKeyCredentials,
+    now: i64,
+) -> Result<Actions> {
+    let revision = group.revision + 1;
+    let source_user_id = params.encrypt_member(actor)?;
+    let mut actions = Actions {
+        source_user_id: Some(source_user_id.clone()),
+        version: Some(revision),
+        ..Default::default()
+    };
+    let pending = |aci: &Aci| {
+        group
+            .pending_members
+            .iter()
+            .find(|pending| pending.uuid == aci.uuid())
+    };
+
+    match update {
+        GroupUpdate::AddMembers(acis) => {
+            for aci in acis {
+                if group.is_member(&aci.uuid()) {
+                    return Err(anyhow!("{} is already in group {}", aci, group.id));
+                }
+                let user_id = Some(params.encrypt_member(aci)?);
+                match (pending(aci), credentials.contains(aci)) {
+                    (Some(pending), _) if pending.invited_by.is_none() => actions
+                        .promote_members_pending_admin_approval
+                        .push(PromoteMemberPendingAdminApprovalAction {
+                            user_id,
+                            role: Some(Role::Default as i32),
+                        }),
+                    (Some(_), _) => {
+                        return Err(anyhow!("{} is already invited to group {}", aci, group.id))
+                    }
+                    (None, true) => actions.add_members.push(AddMemberAction {
+                        added: Some(full_member(
+                            GroupRole::Member,
+                            credentials.present(params, aci)?,
+                            revision,
+                        )?),
+                        join_from_invite_link: None,
+                    }),
+                    (None, false) => actions.add_members_pending_profile_key.push(
+                        AddMemberPendingProfileKeyAction {
+                            added: Some(proto::MemberPendingProfileKey {
+                                member: Some(proto::Member {
+                                    user_id,
+                                    role: Some(Role::Default as i32),
+                                    ..Default::default()
+                                }),
+                                added_by_user_id: Some(source_user_id.clone()),
+                                timestamp: Some(now as u64),
+                            }),
+                        },
+                    ),
+                }
+            }
+        }
+        GroupUpdate::RemoveMembers(acis) => {
+            for aci in acis {
+                let deleted_user_id = Some(params.encrypt_member(aci)?);
+                if group.is_member(&aci.uuid()) {
+                    actions
+                        .delete_members
+                        .push(DeleteMemberAction { deleted_user_id });
+                    continue;
+                }
+                match pending(aci) {
+                    Some(pending) if pending.invited_by.is_some() => actions
+                        .delete_members_pending_profile_key
+                        .push(DeleteMemberPendingProfileKeyAction { deleted_user_id }),
+                    Some(_) => actions
+                        .delete_members_pending_admin_approval
+                        .push(DeleteMemberPendingAdminApprovalAction { deleted_user_id }),
+                    None => return Err(anyhow!("{} is not in group {}", aci, group.id)),
+                }
+            }
+        }
+        GroupUpdate::SetRole(aci, role) => {
+            if !group.is_member(&aci.uuid()) {
+                return Err(anyhow!("{} is not in group {}", aci, group.id));
+            }
+            actions.modify_member_roles.push(ModifyMemberRoleAction {
+                user_id: Some(params.encrypt_member(aci)?),
+                role: Some(role_value(*role) as i32),
+            });
+        }
+        GroupUpdate::SetAccessControl(access) => {
+            actions.modify_member_access = Some(ModifyMembersAccessControlAction {
+                members_access: Some(access_required(access.members_can_add_members) as i32),
+            });
+            actions.modify_attributes_access = Some(ModifyAttributesAccessControlAction {
+                attributes_access: Some(access_required(access.members_can_edit_group_info) as i32),
+            });
+        }
+        GroupUpdate::SetAttributes {
+            name,
+            description,
+            disappearing_messages_timer,
+        } => {
+            let name = name.trim();
+            if name.is_empty() {
+                return Err(anyhow!("A group needs a name"));
+            }
+            let description = description
+                .as_deref()
+                .map(str::trim)
+                .filter(|description| !description.is_empty());
+            let timer = disappearing_messages_timer.filter(|timer| *timer > 0);
+
+            if name != group.name {
+                actions.modify_title = Some(ModifyTitleAction {
+                    title: Some(params.encrypt_blob(BlobContent::Title(name.to_string()))?),
+                });
+            }
+            if description != group.description.as_deref() {
+                actions.modify_description = Some(ModifyDescriptionAction {
+                    description: Some(encrypt_description(params, description)?),
+                });
+            }
+            if timer != group.disappearing_messages_timer {
+                actions.modify_disappearing_messages_timer =
+                    Some(ModifyDisappearingMessagesTimerAction {
+                        timer: Some(encrypt_timer(params, timer)?),
+                    });
+            }
+        }
+        GroupUpdate::ApproveRequests(acis) => {
+            for aci in acis {
+                requesting(group, aci)?;
+                actions.promote_members_pending_admin_approval.push(
+                    PromoteMemberPendingAdminApprovalAction {
+                        user_id: Some(params.encrypt_member(aci)?),
+                        role: Some(Role::Default as i32),
+                    },
+                );
+            }
+        }
+        GroupUpdate::DenyRequests(acis) => {
+            for aci in acis {
+                requesting(group, aci)?;
+                actions.delete_members_pending_admin_approval.push(
+                    DeleteMemberPendingAdminApprovalAction {
+                        deleted_user_id: Some(params.encrypt_member(aci)?),
+                    },
+                );
+            }
+        }
+        GroupUpdate::SetInviteLink(access) => {
+            actions.modify_add_from_invite_link_access =
+                Some(ModifyAddFromInviteLinkAccessControlAction {
+                    add_from_invite_link_access: Some(invite_link_required(*access) as i32),
+                });
+            // A link turned on for the first time needs a password
+            if *access != InviteLinkAccess::Disabled && group.invite_link_password.is_none() {
+                actions.modify_invite_link_password = Some(ModifyInviteLinkPasswordAction {
+                    invite_link_password: Some(new_invite_link_password()),
+                });
+            }
+        }
+        GroupUpdate::ResetInviteLink => {
+            actions.modify_invite_link_password = Some(ModifyInviteLinkPasswordAction {
+                invite_link_password: Some(new_invite_link_password()),
+            });
+        }
+    }
+
+    Ok(actions)
+}
+
+/// Invite link of a group, holding its master key and invite link password
+#[derive(Clone, PartialEq, Eq)]
+pub struct InviteLink {
+    pub master_key: Vec<u8>,
+    pub password: Vec<u8>,
+}
+
+impl InviteLink {
+    /// The link of `group`, unless its link is turned off
+    pub fn of_group(group: &Group) -> Option<Self> {
+        if group.invite_link_access == InviteLinkAccess::Disabled || group.master_key.is_empty() {
+            return None;
+        }
+        Some(Self {
+            master_key: group.master_key.clone(),
+            password: group.invite_link_password.clone()?,
+        })
+    }
+
+    /// Read an invite link, as shared by any Signal client
+    pub fn parse(url: &str) -> Result<Self> {
+        let encoded = url
+            .trim()
+            .strip_prefix(INVITE_LINK_PREFIX)
+            .ok_or_else(|| anyhow!("Not a group invite link"))?;
+        let bytes = URL_SAFE_NO_PAD
+            .decode(encoded.trim_end_matches('='))
+            .map_err(|_| anyhow!("Malformed group invite link"))?;
+        let link = proto::GroupInviteLink::decode(bytes.as_slice())?;
+
+        let Some(InviteLinkContents::ContentsV1(contents)) = link.contents else {
+            return Err(anyhow!("Unsupported group invite link version"));
+        };
+        let master_key = contents.group_master_key.unwrap_or_default();
+        let password = contents.invite_link_password.unwrap_or_default();
+        if master_key.len() != GROUP_MASTER_KEY_SIZE || password.is_empty() {
+            return Err(anyhow!("Malformed group invite link"));
+        }
+        Ok(Self {
+            master_key,
+            password,
+        })
+    }
+
+    /// The link to share
+    pub fn url(&self) -> String {
+        let link = proto::GroupInviteLink {
+            contents: Some(InviteLinkContents::ContentsV1(GroupInviteLinkContentsV1 {
+                group_master_key: Some(self.master_key.clone()),
+                invite_link_password: Some(self.password.clone()),
+            })),
+        };
+        format!(
+            "{}{}",
+            INVITE_LINK_PREFIX,
+            URL_SAFE_NO_PAD.encode(link.encode_to_vec())
+        )
+    }
+}
+
+impl Drop for InviteLink {
+    fn drop(&mut self) {
+        self.master_key.zeroize();
+        self.password.zeroize();
+    }
+}
+
+/// Decrypt what the server shows of a group to holders of its invite link
+///
+/// Fails for links that are turned off, unless we already asked to join.
+pub fn decrypt_join_info(
+    params: &GroupSecretParams,
+    info: &proto::GroupJoinInfo,
+) -> Result<GroupPreview> {
+    let access = invite_link_access(info.add_from_invite_link);
+    let pending_approval = info.pending_admin_approval.unwrap_or(false);
+    if access == InviteLinkAccess::Disabled && !pending_approval {
+        return Err(anyhow!(
+            "The invite link of group {} is turned off",
+            params.id()
+        ));
+    }
+
+    Ok(GroupPreview {
+        id: params.id(),
+        name: match &info.title {
+            Some(title) => decrypt_title(params, title)?,
+            None => String::new(),
+        },
+        description: match &info.description {
+            Some(description) => decrypt_description(params, description)?,
+            None => None,
+        },
+        avatar: info.avatar.clone().filter(|avatar| !avatar.is_empty()),
+        member_count: info.member_count.unwrap_or(0),
+        revision: info.version.unwrap_or(0),
+        requires_approval: access != InviteLinkAccess::Anyone,
+        pending_approval,
+    })
+}
+
+/// Build the change adding `actor` to the group of `preview` through its
+/// invite link: as a member, or as someone asking to join when the link
+/// needs approval
+///
+/// `actor` presents their credential in `credentials`. `now` is the time
+/// of the request in milliseconds.
+pub fn build_join(
+    params: &GroupSecretParams,
+    preview: &GroupPreview,
+    actor: &Aci,
+    credentials: &ProfileKeyCredentials,
+    now: i64,
+) -> Result<Actions> {
+    let revision = preview.revision + 1;
+    let presentation = credentials.present(params, actor)?;
+    let mut actions = Actions {
+        source_user_id: Some(params.encrypt_member(actor)?),
+        version: Some(revision),
+        ..Default::default()
+    };
+
+    if preview.requires_approval {
+        actions
+            .add_members_pending_admin_approval
+            .push(AddMemberPendingAdminApprovalAction {
+                added: Some(requesting_member(presentation, now)?),
+            });
+    } else {
+        actions.add_members.push(AddMemberAction {
+            added: Some(full_member(GroupRole::Member, presentation, revision)?),
+            join_from_invite_link: Some(true),
+        });
+    }
+    Ok(actions)
+}
+
+/// Operations of the group server, so tests can stand in for it
+pub trait GroupServer {
+    /// Fetch the current state of a group
+    async fn fetch_group(&self, params: &GroupSecretParams) -> Result<proto::Group>;
+
+    /// Fetch the changes of a group from `from_revision` on, oldest first
+    async fn fetch_group_changes(
+        &self,
+        params: &GroupSecretParams,
+        from_revision: u32,
+    ) -> Result<Vec<GroupChangeState>>;
+
+    /// Create a group with its first state
+    async fn create_group(&self, params: &GroupSecretParams, state: &proto::Group) -> Result<()>;
+
+    /// Submit a change to a group; `false` when another change took its
+    /// revision first
+    async fn modify_group(&self, params: &GroupSecretParams, actions: &Actions) -> Result<bool>;
+
+    /// Encrypt and upload a group avatar, returning its CDN key
+    async fn upload_avatar(&self, params: &GroupSecretParams, avatar: &[u8]) -> Result<String>;
+
+    /// Fetch what the invite link with `password` shows of a group
+    async fn fetch_join_info(
+        &self,
+        params: &GroupSecretParams,
+        password: &[u8],
+    ) -> Result<proto::GroupJoinInfo>;
+
+    /// Submit a change made through the invite link with `password`, by
+    /// someone outside the group; `false` like [`Self::modify_group`]
+    async fn modify_group_with_link(
+        &self,
+        params: &GroupSecretParams,
+        password: &[u8],
+        actions: &Actions,
+    ) -> Result<bool>;
+}
+
+/// Bring a group up to `revision`, or to the newest revision if unset
+///
+/// Starts from `local`, the state we have, applying the server's changes
+/// one revision at a time. Without local state, or when the changes skip
+/// a revision or do not apply, the full current state is fetched instead.
+pub async fn update_group(
+    server: &impl GroupServer,
+    params: &GroupSecretParams,
+    local: Option<Group>,
+    revision: Option<u32>,
+) -> Result<Group> {
+    let Some(mut group) = local else {
+        return decrypt_group(params, &server.fetch_group(params).await?);
+    };
+    if revision.is_some_and(|revision| revision <= group.revision) {
+        return Ok(group);
+    }
+
+    for state in server
+        .fetch_group_changes(params, group.revision + 1)
+        .await?
+    {
+        let Some(change) = &state.group_change else {
+            continue;
+        };
+        let actions = change_actions(change)?;
+        if actions.version.unwrap_or(0) <= group.revision {
+            continue;
+        }
+
+        if let Err(e) = apply_change(params, &mut group, &actions) {
+            tracing::info!("Fetching state of group {}: {}", group.id, e);
+            return decrypt_group(params, &server.fetch_group(params).await?);
+        }
+    }
+
+    // The log ended before the revision the sender saw
+    if revision.is_some_and(|revision| revision > group.revision) {
+        tracing::info!("Fetching state of group {} past its log", group.id);
+        return decrypt_group(params, &server.fetch_group(params).await?);
+    }
+
+    Ok(group)
+}
+
+/// Create a group on the server from its first state
+///
+/// Uploads `avatar` before the group refers to it. Returns the group as
+/// created.
+pub async fn create_group(
+    server: &impl GroupServer,
+    params: &GroupSecretParams,
+    mut group: Group,
+    credentials: &ProfileKeyCredentials,
+    avatar: Option<&[u8]>,
+) -> Result<Group> {
+    if let Some(avatar) = avatar {
+        group.avatar = Some(server.upload_avatar(params, avatar).await?);
+    }
+    server
+        .create_group(params, &encrypt_group(params, &group, credentials)?)
+        .await?;
+    Ok(group)
+}
+
+/// Make `update` to `group` as `actor`
+///
+/// Returns the group after the change, or `None` when another change took
+/// the revision first and the change has to be made again on the newer
+/// state.
+pub async fn change_group(
+    server: &impl GroupServer,
+    params: &GroupSecretParams,
+    group: &Group,
+    actor: &Aci,
+    update: &GroupUpdate,
+    credentials: &ProfileKeyCredentials,
+    now: i64,
+) -> Result<Option<Group>> {
+    check_permission(group, actor, update)?;
+    let actions = build_change(params, group, actor, update, credentials, now)?;
+    if !server.modify_group(params, &actions).await? {
+        return Ok(None);
+    }
+
+    let mut changed = group.clone();
+    apply_change(params, &mut changed, &actions)?;
+    Ok(Some(changed))
+}
+
+/// Look up the group of an invite link without joining it
+pub async fn preview_group(server: &impl GroupServer, link: &InviteLink) -> Result<GroupPreview> {
+    let params = GroupSecretParams::derive_from_master_key(&link.master_key)?;
+    let info = server.fetch_join_info(&params, &link.password).await?;
+    decrypt_join_info(&params, &info)
+}
+
+/// Join the group of an invite link as `actor`, or ask to join when the
+/// link needs approval
+///
+/// Returns the preview the join was made on, telling which of the two it
+/// was, or `None` when another change took the revision first and joining
+/// has to be tried again. Asking again while we wait changes nothing.
+pub async fn join_group(
+    server: &impl GroupServer,
+    link: &InviteLink,
+    actor: &Aci,
+    credentials: &ProfileKeyCredentials,
+    now: i64,
+) -> Result<Option<GroupPreview>> {
+    let params = GroupSecretParams::derive_from_master_key(&link.master_key)?;
+    let preview = decrypt_join_info(
+        &params,
+        &server.fetch_join_info(&params, &link.password).await?,
+    )?;
+    if preview.pending_approval {
+        return Ok(Some(preview));
+    }
+
+    let actions = build_join(&params, &preview, actor, credentials, now)?;
+    if !server
+        .modify_group_with_link(&params, &link.password, &actions)
+        .await?
+    {
+        return Ok(None);
+    }
+    Ok(Some(preview))
+}
+
+/// The servers' zkgroup public params, which credentials are checked
+/// against
+pub fn server_public_params() -> Result<ServerPublicParams> {
+    let params = SignalServers::default()
+        .zkgroup_params
+        .ok_or_else(|| anyhow!("Built without the servers' zkgroup public params"))?;
+    zkgroup::deserialize(&BASE64.decode(params)?)
+        .map_err(|_| anyhow!("Malformed zkgroup server public params"))
+}
+
+/// Credential for the group server, valid for one day
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupCredential {
+    /// Base64 zkgroup auth credential response
+    pub credential: String,
+    /// Start of the day it is valid for, in seconds
+    pub redemption_time: i64,
+}
+
+/// Group server credentials handed out by the chat service
+#[derive(Debug, Clone, Deserialize)]
+pub struct GroupCredentials {
+    pub credentials: Vec<GroupCredential>,
+    /// Our PNI, which the credentials are for along with our ACI
+    pub pni: Uuid,
+}
+
+impl GroupCredentials {
+    /// Chat service path to ask for a week of credentials from `now`, in
+    /// seconds
+    pub fn path(now: i64) -> String {
+        let today = now - now.rem_euclid(DAY_SECS);
+        format!(
+            "/v1/certificate/auth/group?redemptionStartSeconds={}&redemptionEndSeconds={}&pniAsServiceId=true",
+            today,
+            today + 7 * DAY_SECS
+        )
+    }
+
+    /// The credential valid at `now`, in seconds
+    pub fn valid_at(self, now: i64) -> Result<GroupCredential> {
+        let today = now - now.rem_euclid(DAY_SECS);
+        self.credentials
+            .into_iter()
+            .find(|credential| credential.redemption_time == today)
+            .ok_or_else(|| anyhow!("No group credential for today"))
+    }
+}
+
+/// Client for the group server
+pub struct GroupService {
+    http: reqwest::Client,
+    url: &'static str,
+    server_params: ServerPublicParams,
+    credential: AuthCredentialWithPni,
+}
+
+impl GroupService {
+    /// Client presenting `credential`, issued to `aci` and `pni`
+    ///
+    /// Fails for credentials that are not for us or not from the server.
+    pub fn new(
+        server_params: ServerPublicParams,
+        credential: &GroupCredential,
+        aci: &Aci,
+        pni: Uuid,
+    ) -> Result<Self> {
+        let response = zkgroup::deserialize(&BASE64.decode(&credential.credential)?)
+            .map_err(|_| anyhow!("Malformed group credential"))?;
+        let credential = server_params
+            .receive_auth_credential_with_pni_as_service_id(
+                libsignal_core::Aci::from(aci.uuid()),
+                libsignal_core::Pni::from(pni),
+                credential.redemption_time as u64,
+                response,
+            )
+            .map_err(|_| anyhow!("Group credential does not verify"))?;
+
+        Ok(Self {
+            http: reqwest::Client::new(),
+            url: SignalServers::default().storage,
+            server_params,
+            credential,
+        })
+    }
+
+    /// Basic auth user and password presenting our credential for a group
+    fn authorization(&self, params: &GroupSecretParams) -> (String, String) {
+        let presentation = self
+            .server_params
+            .create_auth_credential_with_pni_presentation(
+                randomness(),
+                params.zkgroup_params(),
+                self.credential,
+            );
+        (
+            hex::encode(params.public_params()),
+            hex::encode(zkgroup::serialize(&presentation)),
+        )
+    }
+
+    /// Request to the group server presenting our credential
+    fn request(
+        &self,
+        method: reqwest::Method,
+        params: &GroupSecretParams,
+        path: &str,
+    ) -> reqwest::RequestBuilder {
+        let (user, password) = self.authorization(params);
+        self.http
+            .request(method, format!("{}{}", self.url, path))
+            .basic_auth(user, Some(password))
+            .header("Accept", "application/x-protobuf")
+    }
+
+    async fn get(&self, params: &GroupSecretParams, path: &str) -> Result<reqwest::Response> {
+        let response = self
+            .request(reqwest::Method::GET, params, path)
+            .send()
+            .await?;
+        check_status(params, path, response)
+    }
+
+    /// Submit a change, with `query` appended to the path; `false` on a
+    /// conflict
+    async fn patch(
+        &self,
+        params: &GroupSecretParams,
+        query: &str,
+        actions: &Actions,
+    ) -> Result<bool> {
+        let response = self
+            .request(
+                reqwest::Method::PATCH,
+                params,
+                &format!("/v2/groups/{}", query),
+            )
+            .header("Content-Type", "application/x-protobuf")
+            .body(actions.encode_to_vec())
+            .send()
+            .await?;
+        if response.status().as_u16() == 409 {
+            return Ok(false);
+        }
+        // Without the query, which may hold an invite link password
+        check_status(params, "/v2/groups/", response)?;
+        Ok(true)
+    }
+}
+
+impl GroupServer for GroupService {
+    async fn fetch_group(&self, params: &GroupSecretParams) -> Result<proto::Group> {
+        let response = self.get(params, "/v2/groups/").await?;
+        Ok(proto::Group::decode(response.bytes().await?)?)
+    }
+
+    /// Pages through the log, which the server hands out a few changes at
+    /// a time with `Content-Range: versions first-last/newest`
+    async fn fetch_group_changes(
+        &self,
+        params: &GroupSecretParams,
+        from_revision: u32,
+    ) -> Result<Vec<GroupChangeState>> {
+        let mut changes = Vec::new();
+        let mut from = from_revision;
+        loop {
+            let path = format!(
+                "/v2/groups/logs/{}?includeFirstState=false&includeLastState=false&maxSupportedChangeEpoch={}",
+                from, MAX_CHANGE_EPOCH
+            );
+            let response = self.get(params, &path).await?;
+            let range = response
+                .headers()
+                .get("Content-Range")
+                .and_then(|range| range.to_str().ok())
+                .and_then(parse_versions_range);
+            let partial = response.status().as_u16() == 206;
+            changes.extend(proto::GroupChanges::decode(response.bytes().await?)?.group_changes);
+
+            match range {
+                Some((last, newest)) if partial && last < newest => from = last + 1,
+                _ => return Ok(changes),
+            }
+        }
+    }
+
+    async fn create_group(&self, params: &GroupSecretParams, state: &proto::Group) -> Result<()> {
+        let response = self
+            .request(reqwest::Method::PUT, params, "/v2/groups/")
+            .header("Content-Type", "application/x-protobuf")
+            .body(state.encode_to_vec())
+            .send()
+            .await?;
+        check_status(params, "/v2/groups/", response)?;
+        Ok(())
+    }
+
+    async fn modify_group(&self, params: &GroupSecretParams, actions: &Actions) -> Result<bool> {
+        self.patch(params, "", actions).await
+    }
+
+    /// Asks the group server for a signed upload form, then posts the
+    /// encrypted avatar to the CDN with it
+    async fn upload_avatar(&self, params: &GroupSecretParams, avatar: &[u8]) -> Result<String> {
+        let response = self.get(params, "/v1/groups/avatar/form").await?;
+        let form = proto::AvatarUploadAttributes::decode(response.bytes().await?)?;
+        let key = form
+            .key
+            .clone()
+            .ok_or_else(|| anyhow!("Avatar upload form has no key"))?;
+
+        let blob = params.encrypt_blob(BlobContent::Avatar(avatar.to_vec()))?;
+        let field = |value: &Option<String>| value.clone().unwrap_or_default();
+        let (content_type, body) = multipart_form(
+            &[
+                ("key", key.clone()),
+                ("x-amz-credential", field(&form.credential)),
+                ("acl", field(&form.acl)),
+                ("x-amz-algorithm", field(&form.algorithm)),
+                ("x-amz-date", field(&form.date)),
+                ("policy", field(&form.policy)),
+                ("x-amz-signature", field(&form.signature)),
+                ("Content-Type", "application/octet-stream".to_string()),
+            ],
+            &blob,
+        );
+
+        let response = self
+            .http
+            .post(format!("{}/", SignalServers::default().cdn))
+            .header("Content-Type", content_type)
+            .body(body)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "Group avatar upload failed with status {}",
+                response.status()
+            ));
+        }
+        Ok(key)
+    }
+
+    async fn fetch_join_info(
+        &self,
+        params: &GroupSecretParams,
+        password: &[u8],
+    ) -> Result<proto::GroupJoinInfo> {
+        let path = format!("/v1/groups/join/{}", URL_SAFE_NO_PAD.encode(password));
+        let response = self
+            .request(reqwest::Method::GET, params, &path)
+            .send()
+            .await?;
+        // A wrong password, or a link turned off
+        if response.status().as_u16() == 403 {
+            return Err(anyhow!(
+                "The invite link of group {} no longer works",
+                params.id()
+            ));
+        }
+        let response = check_status(params, "/v1/groups/join/", response)?;
+        Ok(proto::GroupJoinInfo::decode(response.bytes().await?)?)
+    }
+
+    async fn modify_group_with_link(
+        &self,
+        params: &GroupSecretParams,
+        password: &[u8],
+        actions: &Actions,
+    ) -> Result<bool> {
+        let query = format!("?inviteLinkPassword={}", URL_SAFE_NO_PAD.encode(password));
+        self.patch(params, &query, actions).await
+    }
+}
+
+/// Pass on successful group server responses, naming the usual failures
+fn check_status(
+    params: &GroupSecretParams,
+    path: &str,
+    response: reqwest::Response,
+) -> Result<reqwest::Response> {
+    match response.status().as_u16() {
+        200 | 204 | 206 => Ok(response),
+        403 => Err(anyhow!("Not a member of group {}", params.id())),
+        404 => Err(anyhow!("Group {} not found", params.id())),
+        status => Err(anyhow!(
+            "Group request {} failed with status {}",
+            path,
+            status
+        )),
+    }
+}
+
+/// Form fields and a file as a `multipart/form-data` body, returned with
+/// its content type
+fn multipart_form(fields: &[(&str, String)], file: &[u8]) -> (String, Vec<u8>) {
+    let boundary = format!("----SignalYou{}", Uuid::new_v4().simple());
+    let mut body = Vec::new();
+    for (name, value) in fields {
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                boundary, name, value
+            )
+            .as_bytes(),
+        );
+    }
+    body.extend_from_slice(
+        format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+            boundary
+        )
+        .as_bytes(),
+    );
+    body.extend_from_slice(file);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+    (format!("multipart/form-data; boundary={}", boundary), body)
+}
+
+/// Last and newest revision of a `versions first-last/newest` range
+fn parse_versions_range(range: &str) -> Option<(u32, u32)> {
+    let (span, newest) = range.strip_prefix("versions ")?.split_once('/')?;
+    let (_, last) = span.split_once('-')?;
+    Some((last.parse().ok()?, newest.parse().ok()?))
+}
+
+fn decrypt_user_id(params: &GroupSecretParams, user_id: &Option<Vec<u8>>) -> Result<Aci> {
+    let user_id = user_id
+        .as_deref()
+        .ok_or_else(|| anyhow!("Member ID missing"))?;
+    params.decrypt_member(user_id)
+}
+
+fn decrypt_title(params: &GroupSecretParams, blob: &[u8]) -> Result<String> {
+    match params.decrypt_blob(blob)? {
+        Some(BlobContent::Title(title)) => Ok(title),
+        _ => Ok(String::new()),
+    }
+}
+
+fn decrypt_description(params: &GroupSecretParams, blob: &[u8]) -> Result<Option<String>> {
+    match params.decrypt_blob(blob)? {
+        Some(BlobContent::DescriptionText(text)) => Ok(Some(text).filter(|text| !text.is_empty())),
+        _ => Ok(None),
+    }
+}
+
+fn decrypt_timer(params: &GroupSecretParams, blob: &[u8]) -> Result<Option<u32>> {
+    match params.decrypt_blob(blob)? {
+        Some(BlobContent::DisappearingMessagesDuration(duration)) => {
+            Ok(Some(duration).filter(|duration| *duration > 0))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Check that `aci` asked to join `group` and waits for an administrator
+fn requesting(group: &Group, aci: &Aci) -> Result<()> {
+    if !group.is_requesting(&aci.uuid()) {
+        return Err(anyhow!("{} has not asked to join group {}", aci, group.id));
+    }
+    Ok(())
+}
+
+/// Move a pending member into the group, keeping their invited role
+/// unless `role` is given
+fn promote(group: &mut Group, uuid: Uuid, role: Option<GroupRole>, revision: u32) {
+    let pending = group
+        .pending_members
+        .iter()
+        .position(|pending| pending.uuid == uuid)
+        .map(|index| group.pending_members.remove(index));
+    let role = role
+        .or(pending.map(|pending| pending.role))
+        .unwrap_or(GroupRole::Member);
+
+    group.members.retain(|member| member.uuid != uuid);
+    group.members.push(GroupMember {
+        uuid,
+        role,
+        joined_at: revision as i64,
+        invited_by: None,
+    });
+}
+
+fn encrypt_description(params: &GroupSecretParams, description: Option<&str>) -> Result<Vec<u8>> {
+    params.encrypt_blob(BlobContent::DescriptionText(
+        description.unwrap_or_default().to_string(),
+    ))
+}
+
+/// Encrypt a disappearing timer, with 0 for none
+fn encrypt_timer(params: &GroupSecretParams, timer: Option<u32>) -> Result<Vec<u8>> {
+    params.encrypt_blob(BlobContent::DisappearingMessagesDuration(
+        timer.unwrap_or(0),
+    ))
+}
+
+/// A member with their profile key, presenting their profile key
+/// credential with the ciphertexts the server takes from it
+fn full_member(role: GroupRole, presentation: Vec<u8>, revision: u32) -> Result<proto::Member> {
+    let (user_id, profile_key) = presented_member(&presentation)?;
+    Ok(proto::Member {
+        user_id: Some(user_id),
+        role: Some(role_value(role) as i32),
+        profile_key: Some(profile_key),
+        presentation: Some(presentation),
+        joined_at_version: Some(revision),
+    })
+}
+
+/// Someone asking to join with their profile key, like [`full_member`];
+/// `timestamp` is the time of the request in milliseconds
+fn requesting_member(
+    presentation: Vec<u8>,
+    timestamp: i64,
+) -> Result<proto::MemberPendingAdminApproval> {
+    let (user_id, profile_key) = presented_member(&presentation)?;
+    Ok(proto::MemberPendingAdminApproval {
+        user_id: Some(user_id),
+        profile_key: Some(profile_key),
+        presentation: Some(presentation),
+        timestamp: Some(timestamp as u64),
+    })
+}
+
+/// Encrypted ACI and profile key of a profile key credential presentation
+fn presented_member(presentation: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let presentation = AnyProfileKeyCredentialPresentation::new(presentation)
+        .map_err(|_| anyhow!("Malformed profile key credential presentation"))?;
+    Ok((
+        zkgroup::serialize(&presentation.get_uuid_ciphertext()),
+        zkgroup::serialize(&presentation.get_profile_key_ciphertext()),
+    ))
+}
+
+fn role(role: Option<i32>) -> GroupRole {
+    match role.and_then(|role| Role::try_from(role).ok()) {
+        Some(Role::Administrator) => GroupRole::Administrator,
+        _ => GroupRole::Member,
+    }
+}
+
+fn role_value(role: GroupRole) -> Role {
+    match role {
+        GroupRole::Member => Role::Default,
+        GroupRole::Administrator => Role::Administrator,
+    }
+}
+
+/// Access requirement letting ordinary members in, or administrators only
+fn access_required(members_allowed: bool) -> AccessRequired {
+    if members_allowed {
+        AccessRequired::Member
+    } else {
+        AccessRequired::Administrator
+    }
+}
+
+/// Access requirement for joining with the invite link
+fn invite_link_required(access: InviteLinkAccess) -> AccessRequired {
+    match access {
+        InviteLinkAccess::Disabled => AccessRequired::Unsatisfiable,
+        InviteLinkAccess::AdminApproval => AccessRequired::Administrator,
+        InviteLinkAccess::Anyone => AccessRequired::Any,
+    }
+}
+
+fn invite_link_access(access: Option<i32>) -> InviteLinkAccess {
+    match access.and_then(|access| AccessRequired::try_from(access).ok()) {
+        Some(AccessRequired::Any) => InviteLinkAccess::Anyone,
+        Some(AccessRequired::Administrator) => InviteLinkAccess::AdminApproval,
+        _ => InviteLinkAccess::Disabled,
+    }
+}
+
+fn new_invite_link_password() -> Vec<u8> {
+    let mut password = vec![0u8; INVITE_LINK_PASSWORD_SIZE];
+    rand::rngs::OsRng.fill_bytes(&mut password);
+    password
+}
+
+/// Whether ordinary members satisfy an access requirement
+fn members_allowed(access: Option<i32>) -> bool {
+    matches!(
+        access.and_then(|access| AccessRequired::try_from(access).ok()),
+        Some(AccessRequired::Any | AccessRequired::Member)
+    )
+}
+
+fn admins(members: &[GroupMember]) -> Vec<Uuid> {
+    members
+        .iter()
+        .filter(|member| member.role == GroupRole::Administrator)
+        .map(|member| member.uuid)
+        .collect()
+}
+
+/// Fresh randomness for zkgroup
+fn randomness() -> RandomnessBytes {
+    let mut randomness = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut randomness);
+    randomness
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signal::profiles;
+    use std::cell::{Cell, RefCell};
+    use zkgroup::auth::AnyAuthCredentialPresentation;
+    use zkgroup::groups::ProfileKeyCiphertext;
+    use zkgroup::profiles::ProfileKey;
+    use zkgroup::ServerSecretParams;
+
+    /// Time the tests run at, in seconds
+    const NOW: i64 = 1_700_000_000;
+
+    /// Group server holding one group, standing in for the real one
+    ///
+    /// Accepted changes go into the log and bump the revision of the
+    /// state, which is otherwise left as it was but for the invite link the
+    /// server checks.
+    struct MockGroupServer {
+        server: ServerSecretParams,
+        public_params: Vec<u8>,
+        state: RefCell<proto::Group>,
+        log: RefCell<Vec<proto::GroupChange>>,
+        avatars: RefCell<Vec<Vec<u8>>>,
+        state_fetches: Cell<u32>,
+    }
+
+    impl MockGroupServer {
+        fn new(params: &GroupSecretParams, state: proto::Group, log: Vec<Actions>) -> Self {
+            Self {
+                server: ServerSecretParams::generate([1; 32]),
+                public_params: params.public_params(),
+                state: RefCell::new(state),
+                log: RefCell::new(
+                    log.into_iter()
+                        .map(|actions| proto::GroupChange {
+                            actions: Some(actions.encode_to_vec()),
+                            ..Default::default()
+                        })
+                        .collect(),
+                ),
+                avatars: RefCell::new(Vec::new()),
+                state_fetches: Cell::new(0),
+            }
+        }
+
+        /// Serve only the group whose public params we know, like the
+        /// server checks the presented credential
+        fn authorize(&self, params: &GroupSecretParams) -> Result<()> {
+            if params.public_params() != self.public_params {
+                return Err(anyhow!("Group {} not found", params.id()));
+            }
+            Ok(())
+        }
+
+        /// Profile key credentials of `profile_keys`, as the chat service
+        /// hands them out
+        fn credentials(&self, profile_keys: &[(Aci, [u8; 32])]) -> ProfileKeyCredentials {
+            let public_params = self.server.get_public_params();
+            let expiration = (NOW - NOW % DAY_SECS + 7 * DAY_SECS) as u64;
+            let mut credentials = ProfileKeyCredentials::new(public_params.clone());
+            for (aci, profile_key) in profile_keys {
+                let (_, context) =
+                    profiles::credential_request(&public_params, profile_key, aci).unwrap();
+                let user = libsignal_core::Aci::from(aci.uuid());
+                let response = self
+                    .server
+                    .issue_expiring_profile_key_credential(
+                        [2; 32],
+                        &context.get_request(),
+                        user,
+                        ProfileKey::create(*profile_key).get_commitment(user),
+                        expiration,
+                    )
+                    .unwrap();
+                let response = profiles::ProfileResponse {
+                    credential: Some(BASE64.encode(zkgroup::serialize(&response))),
+                    ..Default::default()
+                };
+                let credential =
+                    profiles::receive_credential(&public_params, &context, &response, NOW).unwrap();
+                credentials.insert(*aci, credential);
+            }
+            credentials
+        }
+
+        /// Turn away members added without a valid presentation of their
+        /// profile key credential
+        fn check_presentations<'a>(
+            &self,
+            params: &GroupSecretParams,
+            presentations: impl IntoIterator<Item = &'a Option<Vec<u8>>>,
+        ) -> Result<()> {
+            for presentation in presentations {
+                let presentation = presentation.as_deref().unwrap_or_default();
+                let presentation = AnyProfileKeyCredentialPresentation::new(presentation)
+                    .map_err(|_| anyhow!("Member added without a credential"))?;
+                self.server
+                    .verify_profile_key_credential_presentation(
+                        params.zkgroup_params().get_public_params(),
+                        &presentation,
+                        NOW as u64,
+                    )
+                    .map_err(|_| anyhow!("Profile key credential does not verify"))?;
+            }
+            Ok(())
+        }
+
+        /// Check the presentations of everyone a change adds
+        fn check_added(&self, params: &GroupSecretParams, actions: &Actions) -> Result<()> {
+            let members = actions.add_members.iter();
+            let requesting = actions.add_members_pending_admin_approval.iter();
+            self.check_presentations(
+                params,
+                members
+                    .filter_map(|action| action.added.as_ref())
+                    .map(|member| &member.presentation)
+                    .chain(
+                        requesting
+                            .filter_map(|action| action.added.as_ref())
+                            .map(|pending| &pending.presentation),
+                    ),
+            )
+        }
+
+        /// Turn away invite link passwords other than the current one
+        fn check_link(&self, params: &GroupSecretParams, password: &[u8]) -> Result<()> {
+            self.authorize(params)?;
+            let state = self.state.borrow();
+            let access = state
+                .access_control
+                .as_ref()
+                .and_then(|access| access.add_from_invite_link);
+            if state.invite_link_password.as_deref() != Some(password)
+                || access == Some(AccessRequired::Unsatisfiable as i32)
+            {
+                return Err(anyhow!("The invite link no longer works"));
+            }
+            Ok(())
+        }
+
+        /// Log a change made on the current revision
+        fn accept(&self, actions: &Actions) -> bool {
+            let mut state = self.state.borrow_mut();
+            if actions.version != Some(state.version.unwrap_or(0) + 1) {
+                return false;
+            }
+            state.version = actions.version;
+            if let Some(action) = &actions.modify_invite_link_password {
+                state.invite_link_password = action.invite_link_password.clone();
+            }
+            if let Some(action) = &actions.modify_add_from_invite_link_access {
+                state
+                    .access_control
+                    .get_or_insert_with(Default::default)
+                    .add_from_invite_link = action.add_from_invite_link_access;
+            }
+            self.log.borrow_mut().push(proto::GroupChange {
+                actions: Some(actions.encode_to_vec()),
+                ..Default::default()
+            });
+            true
+        }
+    }
+
+    impl GroupServer for MockGroupServer {
+        async fn fetch_group(&self, params: &GroupSecretParams) -> Result<proto::Group> {
+            self.authorize(params)?;
+            self.state_fetches.set(self.state_fetches.get() + 1);
+            Ok(self.state.borrow().clone())
+        }
+
+        async fn fetch_group_changes(
+            &self,
+            params: &GroupSecretParams,
+            from_revision: u32,
+        ) -> Result<Vec<GroupChangeState>> {
+            self.authorize(params)?;
+            Ok(self
+                .log
+                .borrow()
+                .iter()
+                .filter(|change| {
+                    change_actions(change).unwrap().version.unwrap_or(0) >= from_revision
+                })
+                .map(|change| GroupChangeState {
+                    group_change: Some(change.clone()),
+                    group_state: None,
+                })
+                .collect())
+        }
+
+        async fn create_group(
+            &self,
+            params: &GroupSecretParams,
+            state: &proto::Group,
+        ) -> Result<()> {
+            self.authorize(params)?;
+            self.check_presentations(
+                params,
+                state.members.iter().map(|member| &member.presentation),
+            )?;
+            *self.state.borrow_mut() = state.clone();
+            Ok(())
+        }
+
+        async fn modify_group(
+            &self,
+            params: &GroupSecretParams,
+            actions: &Actions,
+        ) -> Result<bool> {
+            self.authorize(params)?;
+            self.check_added(params, actions)?;
+            Ok(self.accept(actions))
+        }
+
+        async fn upload_avatar(&self, params: &GroupSecretParams, avatar: &[u8]) -> Result<String> {
+            self.authorize(params)?;
+            let mut avatars = self.avatars.borrow_mut();
+            avatars.push(params.encrypt_blob(BlobContent::Avatar(avatar.to_vec()))?);
+            Ok(format!("groups/avatar-{}", avatars.len()))
+        }
+
+        async fn fetch_join_info(
+            &self,
+            params: &GroupSecretParams,
+            password: &[u8],
+        ) -> Result<proto::GroupJoinInfo> {
+            self.check_link(params, password)?;
+            let state = self.state.borrow();
+            Ok(proto::GroupJoinInfo {
+                public_key: state.public_key.clone(),
+                title: state.title.clone(),
+                avatar: state.avatar.clone(),
+                member_count: Some(state.members.len() as u32),
+                add_from_invite_link: state
+                    .access_control
+                    .as_ref()
+                    .and_then(|access| access.add_from_invite_link),
+                version: state.version,
+                pending_admin_approval: Some(false),
+                description: state.description.clone(),
+            })
+        }
+
+        async fn modify_group_with_link(
+            &self,
+            params: &GroupSecretParams,
+            password: &[u8],
+            actions: &Actions,
+        ) -> Result<bool> {
+            self.check_link(params, password)?;
+            self.check_added(params, actions)?;
+            Ok(self.accept(actions))
+        }
+    }
+
+    fn aci() -> Aci {
+        Uuid::new_v4().into()
+    }
+
+    fn member(params: &GroupSecretParams, aci: &Aci, role: Role) -> proto::Member {
+        proto::Member {
+            user_id: Some(params.encrypt_member(aci).unwrap()),
+            role: Some(role as i32),
+            joined_at_version: Some(0),
+            ..Default::default()
+        }
+    }
+
+    fn title(params: &GroupSecretParams, title: &str) -> Vec<u8> {
+        params
+            .encrypt_blob(BlobContent::Title(title.to_string()))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_secret_params() {
+        let params = GroupSecretParams::derive_from_master_key(&[1; 32]).unwrap();
+        let same = GroupSecretParams::derive_from_master_key(&[1; 32]).unwrap();
+        let other = GroupSecretParams::derive_from_master_key(&[2; 32]).unwrap();
+        assert_eq!(params.id(), same.id());
+        assert_ne!(params.id(), other.id());
+        assert_eq!(BASE64.decode(params.id()).unwrap().len(), 32);
+        assert_eq!(params.public_params().len(), 97);
+        assert_eq!(params.master_key(), &[1; 32]);
+        assert!(GroupSecretParams::derive_from_master_key(&[1; 16]).is_err());
+
+        // Members encrypt the same way every time, and only for the group
+        let alice = aci();
+        let ciphertext = params.encrypt_member(&alice).unwrap();
+        assert_eq!(ciphertext.len(), 65);
+        assert_eq!(same.encrypt_member(&alice).unwrap(), ciphertext);
+        assert_ne!(params.encrypt_member(&aci()).unwrap(), ciphertext);
+        assert_eq!(params.decrypt_member(&ciphertext).unwrap(), alice);
+        assert!(other.decrypt_member(&ciphertext).is_err());
+
+        // Blobs use a fresh nonce
+        let blob = title(&params, "Hikers");
+        assert_ne!(title(&params, "Hikers"), blob);
+        assert_eq!(
+            params.decrypt_blob(&blob).unwrap(),
+            Some(BlobContent::Title("Hikers".to_string()))
+        );
+        assert!(other.decrypt_blob(&blob).is_err());
+    }
+
+    #[test]
+    fn test_decrypt_group() {
+        let params = GroupSecretParams::derive_from_master_key(&[3; 32]).unwrap();
+        let other = GroupSecretParams::derive_from_master_key(&[4; 32]).unwrap();
+        let (alice, bob, carol, dave) = (aci(), aci(), aci(), aci());
+
+        let state = proto::Group {
+            public_key: Some(params.public_params()),
+            title: Some(title(&params, "Hikers")),
+            description: Some(
+                params
+                    .encrypt_blob(BlobContent::DescriptionText("Weekend trips".to_string()))
+                    .unwrap(),
+            ),
+            disappearing_messages_timer: Some(
+                params
+                    .encrypt_blob(BlobContent::DisappearingMessagesDuration(3600))
+                    .unwrap(),
+            ),
+            access_control: Some(proto::AccessControl {
+                attributes: Some(AccessRequired::Member as i32),
+                members: Some(AccessRequired::Administrator as i32),
+                add_from_invite_link: Some(AccessRequired::Unsatisfiable as i32),
+            }),
+            version: Some(5),
+            members: vec![
+                member(&params, &alice, Role::Administrator),
+                member(&params, &bob, Role::Default),
+                // Encrypted for another group, so left out
+                member(&other, &aci(), Role::Default),
+            ],
+            members_pending_profile_key: vec![proto::MemberPendingProfileKey {
+                member: Some(member(&params, &carol, Role::Default)),
+                added_by_user_id: Some(params.encrypt_member(&alice).unwrap()),
+                timestamp: Some(1000),
+            }],
+            members_pending_admin_approval: vec![proto::MemberPendingAdminApproval {
+                user_id: Some(params.encrypt_member(&dave).unwrap()),
+                timestamp: Some(2000),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let group = decrypt_group(&params, &state).unwrap();
+        assert_eq!(group.id, params.id());
+        assert_eq!(group.master_key, vec![3; 32]);
+        assert_eq!(group.revision, 5);
+        assert_eq!(group.name, "Hikers");
+        assert_eq!(group.description.as_deref(), Some("Weekend trips"));
+        assert_eq!(group.disappearing_messages_timer, Some(3600));
+        assert!(!group.access_control.members_can_add_members);
+        assert!(group.access_control.members_can_edit_group_info);
+
+        let members: Vec<_> = group.members.iter().map(|member| member.uuid).collect();
+        assert_eq!(members, vec![alice.uuid(), bob.uuid()]);
+        assert_eq!(group.admins, vec![alice.uuid()]);
+
+        assert_eq!(group.pending_members.len(), 2);
+        assert_eq!(group.pending_members[0].uuid, carol.uuid());
+        assert_eq!(group.pending_members[0].invited_by, Some(alice.uuid()));
+        assert_eq!(group.pending_members[0].joined_at, 1000);
+        assert_eq!(group.pending_members[1].uuid, dave.uuid());
+        assert_eq!(group.pending_members[1].invited_by, None);
+
+        // A title under another key fails the whole state
+        let state = proto::Group {
+            title: Some(title(&other, "Hikers")),
+            ..state
+        };
+        assert!(decrypt_group(&params, &state).is_err());
+    }
+
+    #[test]
+    fn test_apply_change() {
+        let params = GroupSecretParams::derive_from_master_key(&[5; 32]).unwrap();
+        let (alice, bob, carol, dave) = (aci(), aci(), aci(), aci());
+        let state = proto::Group {
+            title: Some(title(&params, "Hikers")),
+            version: Some(1),
+            members: vec![
+                member(&params, &alice, Role::Administrator),
+                member(&params, &bob, Role::Default),
+            ],
+            members_pending_admin_approval: vec![proto::MemberPendingAdminApproval {
+                user_id: Some(params.encrypt_member(&dave).unwrap()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut group = decrypt_group(&params, &state).unwrap();
+
+        let actions = Actions {
+            source_user_id: Some(params.encrypt_member(&alice).unwrap()),
+            version: Some(2),
+            add_members: vec![proto::group_change::actions::AddMemberAction {
+                added: Some(member(&params, &carol, Role::Default)),
+                join_from_invite_link: None,
+            }],
+            delete_members: vec![proto::group_change::actions::DeleteMemberAction {
+                deleted_user_id: Some(params.encrypt_member(&bob).unwrap()),
+            }],
+            modify_member_roles: vec![proto::group_change::actions::ModifyMemberRoleAction {
+                user_id: Some(params.encrypt_member(&alice).unwrap()),
+                role: Some(Role::Default as i32),
+            }],
+            promote_members_pending_admin_approval: vec![
+                proto::group_change::actions::PromoteMemberPendingAdminApprovalAction {
+                    user_id: Some(params.encrypt_member(&dave).unwrap()),
+                    role: Some(Role::Administrator as i32),
+                },
+            ],
+            modify_title: Some(proto::group_change::actions::ModifyTitleAction {
+                title: Some(title(&params, "Climbers")),
+            }),
+            modify_member_access: Some(
+                proto::group_change::actions::ModifyMembersAccessControlAction {
+                    members_access: Some(AccessRequired::Member as i32),
+                },
+            ),
+            ..Default::default()
+        };
+
+        // A change for a later revision leaves the group as it was
+        let skipped = Actions {
+            version: Some(3),
+            ..actions.clone()
+        };
+        assert!(apply_change(&params, &mut group, &skipped).is_err());
+        assert_eq!(group.revision, 1);
+
+        apply_change(&params, &mut group, &actions).unwrap();
+        assert_eq!(group.revision, 2);
+        assert_eq!(group.name, "Climbers");
+        assert!(group.access_control.members_can_add_members);
+        let members: Vec<_> = group.members.iter().map(|m| (m.uuid, m.role)).collect();
+        assert_eq!(
+            members,
+            vec![
+                (alice.uuid(), GroupRole::Member),
+                (carol.uuid(), GroupRole::Member),
+                (dave.uuid(), GroupRole::Administrator),
+            ]
+        );
+        assert_eq!(group.admins, vec![dave.uuid()]);
+        assert!(group.pending_members.is_empty());
+
+        // Members under another key fail the change as a whole
+        let other = GroupSecretParams::derive_from_master_key(&[6; 32]).unwrap();
+        let foreign = Actions {
+            version: Some(3),
+            modify_title: Some(proto::group_change::actions::ModifyTitleAction {
+                title: Some(title(&params, "Walkers")),
+            }),
+            delete_members: vec![proto::group_change::actions::DeleteMemberAction {
+                deleted_user_id: Some(other.encrypt_member(&carol).unwrap()),
+            }],
+            ..Default::default()
+        };
+        assert!(apply_change(&params, &mut group, &foreign).is_err());
+        assert_eq!(group.name, "Climbers");
+        assert_eq!(group.revision, 2);
+    }
+
+    #[tokio::test]
+    async fn test_update_group() {
+        let params = GroupSecretParams::derive_from_master_key(&[8; 32]).unwrap();
+        let (alice, bob, carol) = (aci(), aci(), aci());
+        let base = proto::Group {
+            public_key: Some(params.public_params()),
+            title: Some(title(&params, "Hikers")),
+            version: Some(1),
+            members: vec![member(&params, &alice, Role::Administrator)],
+            ..Default::default()
+        };
+        let add = |aci: &Aci, version: u32| Actions {
+            version: Some(version),
+            add_members: vec![proto::group_change::actions::AddMemberAction {
+                added: Some(member(&params, aci, Role::Default)),
+                join_from_invite_link: None,
+            }],
+            ..Default::default()
+        };
+        let current = proto::Group {
+            version: Some(3),
+            members: vec![
+                member(&params, &alice, Role::Administrator),
+                member(&params, &bob, Role::Default),
+                member(&params, &carol, Role::Default),
+            ],
+            ..base.clone()
+        };
+        let local = decrypt_group(&params, &base).unwrap();
+
+        // Changes are applied in order from the revision we have
+        let server =
+            MockGroupServer::new(&params, current.clone(), vec![add(&bob, 2), add(&carol, 3)]);
+        let group = update_group(&server, &params, Some(local.clone()), Some(3))
+            .await
+            .unwrap();
+        assert_eq!(group.revision, 3);
+        assert_eq!(group.members.len(), 3);
+        assert_eq!(server.state_fetches.get(), 0);
+
+        // A revision we already have needs nothing from the server
+        let group = update_group(&server, &params, Some(group), Some(2))
+            .await
+            .unwrap();
+        assert_eq!(group.revision, 3);
+        assert_eq!(server.state_fetches.get(), 0);
+
+        // A gap in the log is filled with the full state
+        let server = MockGroupServer::new(&params, current.clone(), vec![add(&carol, 3)]);
+        let group = update_group(&server, &params, Some(local.clone()), None)
+            .await
+            .unwrap();
+        assert_eq!(group.revision, 3);
+        assert_eq!(group.members.len(), 3);
+        assert_eq!(server.state_fetches.get(), 1);
+
+        // So is a log ending before the revision the sender saw
+        let server = MockGroupServer::new(&params, current.clone(), vec![add(&bob, 2)]);
+        let group = update_group(&server, &params, Some(local), Some(3))
+            .await
+            .unwrap();
+        assert_eq!(group.revision, 3);
+        assert_eq!(server.state_fetches.get(), 1);
+
+        // Without local state the full state is fetched
+        let group = update_group(&server, &params, None, None).await.unwrap();
+        assert_eq!(group.revision, 3);
+        assert_eq!(group.name, "Hikers");
+
+        // The server serves no other group
+        let other = GroupSecretParams::derive_from_master_key(&[9; 32]).unwrap();
+        assert!(update_group(&server, &other, None, None).await.is_err());
+    }
+
+    #[test]
+    fn test_check_permission() {
+        let (alice, bob, carol) = (aci(), aci(), aci());
+        let member = |aci: &Aci, role| GroupMember {
+            uuid: aci.uuid(),
+            role,
+            joined_at: 0,
+            invited_by: None,
+        };
+        let mut group = Group {
+            id: "group".to_string(),
+            members: vec![
+                member(&alice, GroupRole::Administrator),
+                member(&bob, GroupRole::Member),
+            ],
+            admins: vec![alice.uuid()],
+            ..Default::default()
+        };
+        let allowed = |group: &Group, actor: &Aci, update: GroupUpdate| {
+            check_permission(group, actor, &update).is_ok()
+        };
+
+        // Administrators manage the group
+        assert!(allowed(
+            &group,
+            &alice,
+            GroupUpdate::AddMembers(vec![carol])
+        ));
+        assert!(allowed(
+            &group,
+            &alice,
+            GroupUpdate::RemoveMembers(vec![bob])
+        ));
+        assert!(allowed(
+            &group,
+            &alice,
+            GroupUpdate::SetRole(bob, GroupRole::Administrator)
+        ));
+        assert!(allowed(
+            &group,
+            &alice,
+            GroupUpdate::SetAccessControl(GroupAccessControl::default())
+        ));
+
+        // Members only as far as access control lets them, and may leave
+        assert!(!allowed(&group, &bob, GroupUpdate::AddMembers(vec![carol])));
+        assert!(!allowed(
+            &group,
+            &bob,
+            GroupUpdate::RemoveMembers(vec![alice])
+        ));
+        assert!(!allowed(
+            &group,
+            &bob,
+            GroupUpdate::SetRole(bob, GroupRole::Administrator)
+        ));
+        assert!(allowed(&group, &bob, GroupUpdate::RemoveMembers(vec![bob])));
+        let rename = || GroupUpdate::SetAttributes {
+            name: "Climbers".to_string(),
+            description: None,
+            disappearing_messages_timer: None,
+        };
+        assert!(!allowed(&group, &bob, rename()));
+        group.access_control = GroupAccessControl {
+            members_can_add_members: true,
+            members_can_edit_group_info: true,
+        };
+        assert!(allowed(&group, &bob, GroupUpdate::AddMembers(vec![carol])));
+        assert!(allowed(&group, &bob, rename()));
+
+        // Adding someone who asked to join approves them, which only
+        // administrators may
+        let dave = aci();
+        group.pending_members.push(member(&dave, GroupRole::Member));
+        assert!(!allowed(&group, &bob, GroupUpdate::AddMembers(vec![dave])));
+        assert!(allowed(&group, &alice, GroupUpdate::AddMembers(vec![dave])));
+
+        // People outside the group can do nothing
+        assert!(!allowed(
+            &group,
+            &carol,
+            GroupUpdate::AddMembers(vec![carol])
+        ));
+
+        // The last administrator cannot leave members behind without one
+        assert!(!allowed(
+            &group,
+            &alice,
+            GroupUpdate::RemoveMembers(vec![alice])
+        ));
+        assert!(!allowed(
+            &group,
+            &alice,
+            GroupUpdate::SetRole(alice, GroupRole::Member)
+        ));
+        assert!(allowed(
+            &group,
+            &alice,
+            GroupUpdate::RemoveMembers(vec![alice, bob])
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_create_and_change_group() {
+        let params = GroupSecretParams::derive_from_master_key(&[10; 32]).unwrap();
+        let (alice, bob, carol, dave) = (aci(), aci(), aci(), aci());
+        let server = MockGroupServer::new(&params, proto::Group::default(), Vec::new());
+        let credentials = server.credentials(&[(alice, [1; 32]), (bob, [2; 32]), (carol, [3; 32])]);
+
+        // The created state decrypts to the group we made
+        let group = Group {
+            id: params.id(),
+            master_key: params.master_key().to_vec(),
+            name: "Hikers".to_string(),
+            description: Some("Weekend trips".to_string()),
+            members: vec![GroupMember {
+                uuid: alice.uuid(),
+                role: GroupRole::Administrator,
+                joined_at: 0,
+                invited_by: None,
+            }],
+            admins: vec![alice.uuid()],
+            disappearing_messages_timer: Some(3600),
+            access_control: GroupAccessControl {
+                members_can_add_members: false,
+                members_can_edit_group_info: true,
+            },
+            ..Default::default()
+        };
+        // Members need a credential
+        let without = server.credentials(&[(bob, [2; 32])]);
+        assert!(
+            create_group(&server, &params, group.clone(), &without, None)
+                .await
+                .is_err()
+        );
+        let group = create_group(&server, &params, group, &credentials, Some(b"image"))
+            .await
+            .unwrap();
+        assert_eq!(group.avatar.as_deref(), Some("groups/avatar-1"));
+        assert_eq!(
+            params.decrypt_blob(&server.avatars.borrow()[0]).unwrap(),
+            Some(BlobContent::Avatar(b"image".to_vec()))
+        );
+        let created = decrypt_group(&params, &server.state.borrow()).unwrap();
+        assert_eq!(created.name, "Hikers");
+        assert_eq!(created.description.as_deref(), Some("Weekend trips"));
+        assert_eq!(created.avatar, group.avatar);
+        assert_eq!(created.disappearing_messages_timer, Some(3600));
+        assert_eq!(created.access_control, group.access_control);
+        assert_eq!(created.admins, vec![alice.uuid()]);
+        let stored = server.state.borrow().members[0]
+            .profile_key
+            .clone()
+            .unwrap();
+        let stored: ProfileKeyCiphertext = zkgroup::deserialize(&stored).unwrap();
+        let profile_key = params
+            .zkgroup_params()
+            .decrypt_profile_key(stored, libsignal_core::Aci::from(alice.uuid()))
+            .unwrap();
+        assert_eq!(profile_key.get_bytes(), [1; 32]);
+
+        // People with a known profile key join, the others are invited
+        let add = GroupUpdate::AddMembers(vec![bob, dave]);
+        let group = change_group(&server, &params, &group, &alice, &add, &credentials, 7000)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(group.revision, 1);
+        let members: Vec<_> = group.members.iter().map(|m| m.uuid).collect();
+        assert_eq!(members, vec![alice.uuid(), bob.uuid()]);
+        assert_eq!(group.pending_members.len(), 1);
+        assert_eq!(group.pending_members[0].uuid, dave.uuid());
+        assert_eq!(group.pending_members[0].invited_by, Some(alice.uuid()));
+        assert_eq!(group.pending_members[0].joined_at, 7000);
+
+        // Others see the change the same way from the log
+        let seen = update_group(&server, &params, Some(created), None)
+            .await
+            .unwrap();
+        assert_eq!(seen.revision, 1);
+        assert_eq!(seen.members.len(), 2);
+        assert_eq!(seen.pending_members[0].invited_by, Some(alice.uuid()));
+
+        // A change on a stale revision has to be made again
+        let stale = Group {
+            revision: 0,
+            ..group.clone()
+        };
+        let promote = GroupUpdate::SetRole(bob, GroupRole::Administrator);
+        assert!(
+            change_group(&server, &params, &stale, &alice, &promote, &credentials, 0)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        let group = change_group(&server, &params, &group, &alice, &promote, &credentials, 0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(group.admins, vec![alice.uuid(), bob.uuid()]);
+
+        // Removing an invited member withdraws the invitation
+        let remove = GroupUpdate::RemoveMembers(vec![dave]);
+        let group = change_group(&server, &params, &group, &bob, &remove, &credentials, 0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(group.pending_members.is_empty());
+
+        // Only what differs is changed
+        let attributes = GroupUpdate::SetAttributes {
+            name: " Climbers ".to_string(),
+            description: Some("Weekend trips".to_string()),
+            disappearing_messages_timer: None,
+        };
+        let actions = build_change(&params, &group, &bob, &attributes, &credentials, 0).unwrap();
+        assert!(actions.modify_title.is_some());
+        assert!(actions.modify_description.is_none());
+        assert!(actions.modify_disappearing_messages_timer.is_some());
+        let group = change_group(&server, &params, &group, &bob, &attributes, &credentials, 0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(group.name, "Climbers");
+        assert_eq!(group.disappearing_messages_timer, None);
+        assert_eq!(group.revision, 4);
+
+        // Members already in the group, or not in it, are turned down
+        let again = GroupUpdate::AddMembers(vec![bob]);
+        assert!(build_change(&params, &group, &alice, &again, &credentials, 0).is_err());
+        let stranger = GroupUpdate::RemoveMembers(vec![carol]);
+        assert!(build_change(&params, &group, &alice, &stranger, &credentials, 0).is_err());
+    }
+
+    #[test]
+    fn test_invite_link() {
+        let link = InviteLink {
+            master_key: vec![1; 32],
+            password: vec![2; 16],
+        };
+        let url = link.url();
+        assert!(url.starts_with("https://signal.group/#"));
+        assert!(!url.contains('='));
+        let parsed = InviteLink::parse(&format!(" {} ", url)).unwrap();
+        assert!(parsed == link);
+
+        // Padded links from other clients read the same
+        assert!(InviteLink::parse(&format!("{}==", url)).unwrap() == link);
+
+        assert!(InviteLink::parse("https://signal.me/#p/+15551234567").is_err());
+        assert!(InviteLink::parse("https://signal.group/#not-base64!").is_err());
+        let short = InviteLink {
+            master_key: vec![1; 16],
+            password: vec![2; 16],
+        };
+        assert!(InviteLink::parse(&short.url()).is_err());
+
+        // Only groups with the link turned on have one
+        let mut group = Group {
+            master_key: vec![1; 32],
+            invite_link_password: Some(vec![2; 16]),
+            ..Default::default()
+        };
+        assert!(InviteLink::of_group(&group).is_none());
+        group.invite_link_access = InviteLinkAccess::AdminApproval;
+        assert!(InviteLink::of_group(&group).unwrap() == link);
+    }
+
+    #[tokio::test]
+    async fn test_join_group() {
+        let params = GroupSecretParams::derive_from_master_key(&[11; 32]).unwrap();
+        let (alice, bob, carol, dave) = (aci(), aci(), aci(), aci());
+        let server = MockGroupServer::new(&params, proto::Group::default(), Vec::new());
+        let credentials = server.credentials(&[(alice, [1; 32])]);
+        let joining = server.credentials(&[(bob, [2; 32]), (carol, [3; 32]), (dave, [4; 32])]);
+        let group = Group {
+            id: params.id(),
+            master_key: params.master_key().to_vec(),
+            name: "Hikers".to_string(),
+            members: vec![GroupMember {
+                uuid: alice.uuid(),
+                role: GroupRole::Administrator,
+                joined_at: 0,
+                invited_by: None,
+            }],
+            admins: vec![alice.uuid()],
+            ..Default::default()
+        };
+        let group = create_group(&server, &params, group, &credentials, None)
+            .await
+            .unwrap();
+        let change = |group: Group, update: GroupUpdate| {
+            let server = &server;
+            let params = &params;
+            let credentials = &credentials;
+            async move {
+                change_group(server, params, &group, &alice, &update, credentials, 0)
+                    .await
+                    .unwrap()
+                    .unwrap()
+            }
+        };
+
+        // Turning the link on gives it a password, and only admins may
+        let approval = GroupUpdate::SetInviteLink(InviteLinkAccess::AdminApproval);
+        assert!(check_permission(&group, &bob, &approval).is_err());
+        let group = change(group, approval).await;
+        assert_eq!(group.invite_link_access, InviteLinkAccess::AdminApproval);
+        let link = InviteLink::of_group(&group).unwrap();
+
+        let preview = preview_group(&server, &link).await.unwrap();
+        assert_eq!(preview.id, params.id());
+        assert_eq!(preview.name, "Hikers");
+        assert_eq!(preview.member_count, 1);
+        assert_eq!(preview.revision, 1);
+        assert!(preview.requires_approval);
+
+        // Joining takes a profile key credential
+        assert!(join_group(&server, &link, &aci(), &joining, 0)
+            .await
+            .is_err());
+
+        // Bob and Carol ask to join, and administrators see their requests
+        let joined = join_group(&server, &link, &bob, &joining, 5000)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(joined.requires_approval);
+        join_group(&server, &link, &carol, &joining, 6000)
+            .await
+            .unwrap()
+            .unwrap();
+        let group = update_group(&server, &params, Some(group), None)
+            .await
+            .unwrap();
+        assert_eq!(group.revision, 3);
+        assert!(group.is_requesting(&bob.uuid()));
+        assert!(group.is_requesting(&carol.uuid()));
+        assert!(!group.is_member(&bob.uuid()));
+        assert_eq!(group.pending_members[0].joined_at, 5000);
+
+        // Bob is let in and Carol turned down
+        let group = change(group, GroupUpdate::ApproveRequests(vec![bob])).await;
+        assert!(group.is_member(&bob.uuid()));
+        let deny = GroupUpdate::DenyRequests(vec![carol]);
+        assert!(check_permission(&group, &bob, &deny).is_err());
+        let group = change(group, deny).await;
+        assert!(group.pending_members.is_empty());
+        let again = GroupUpdate::ApproveRequests(vec![carol]);
+        assert!(build_change(&params, &group, &alice, &again, &credentials, 0).is_err());
+
+        // With the link open to anyone, Dave joins right away
+        let group = change(group, GroupUpdate::SetInviteLink(InviteLinkAccess::Anyone)).await;
+        assert_eq!(
+            InviteLink::of_group(&group).unwrap().password,
+            link.password
+        );
+        let joined = join_group(&server, &link, &dave, &joining, 0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!joined.requires_approval);
+        let group = update_group(&server, &params, Some(group), None)
+            .await
+            .unwrap();
+        assert!(group.is_member(&dave.uuid()));
+
+        // A join on a stale revision has to be tried again
+        let stale = GroupPreview {
+            revision: 1,
+            ..joined
+        };
+        let actions = build_join(&params, &stale, &carol, &joining, 0).unwrap();
+        assert!(!server
+            .modify_group_with_link(&params, &link.password, &actions)
+            .await
+            .unwrap());
+
+        // Resetting the link turns the old one away
+        let group = change(group, GroupUpdate::ResetInviteLink).await;
+        let reset = InviteLink::of_group(&group).unwrap();
+        assert!(reset.password != link.password);
+        assert!(preview_group(&server, &link).await.is_err());
+        assert!(preview_group(&server, &reset).await.is_ok());
+
+        // And turning it off turns everyone away
+        let group = change(
+            group,
+            GroupUpdate::SetInviteLink(InviteLinkAccess::Disabled),
+        )
+        .await;
+        assert!(InviteLink::of_group(&group).is_none());
+        assert!(group.invite_link_password.is_some());
+        assert!(preview_group(&server, &reset).await.is_err());
+    }
+
+    #[test]
+    fn test_credentials() {
+        let now = 1_700_000_000;
+        let today = now - now % DAY_SECS;
+        assert_eq!(
+            GroupCredentials::path(now),
+            format!(
+                "/v1/certificate/auth/group?redemptionStartSeconds={}&redemptionEndSeconds={}&pniAsServiceId=true",
+                today,
+                today + 7 * DAY_SECS
+            )
+        );
+
+        // Credentials are issued to our ACI and PNI for a day each
+        let server = ServerSecretParams::generate([1; 32]);
+        let (alice, pni) = (aci(), Uuid::new_v4());
+        let issue = |redemption_time: i64| {
+            let response = server.issue_auth_credential_with_pni_as_service_id(
+                [2; 32],
+                libsignal_core::Aci::from(alice.uuid()),
+                libsignal_core::Pni::from(pni),
+                redemption_time as u64,
+            );
+            BASE64.encode(zkgroup::serialize(&response))
+        };
+        let json = format!(
+            r#"{{"credentials": [
+                {{"credential": "{}", "redemptionTime": {}}},
+                {{"credential": "{}", "redemptionTime": {}}}
+            ], "pni": "{}"}}"#,
+            issue(today - DAY_SECS),
+            today - DAY_SECS,
+            issue(today),
+            today,
+            pni
+        );
+        let credentials: GroupCredentials = serde_json::from_str(&json).unwrap();
+        assert_eq!(credentials.pni, pni);
+        let credential = credentials.clone().valid_at(now).unwrap();
+        assert_eq!(credential.redemption_time, today);
+        assert!(credentials.valid_at(now + 2 * DAY_SECS).is_err());
+
+        // Presentations prove membership of the group they are made for
+        let service =
+            GroupService::new(server.get_public_params(), &credential, &alice, pni).unwrap();
+        let params = GroupSecretParams::derive_from_master_key(&[1; 32]).unwrap();
+        let other = GroupSecretParams::derive_from_master_key(&[2; 32]).unwrap();
+        let (user, password) = service.authorization(&params);
+        assert_eq!(user, hex::encode(params.public_params()));
+        let presentation =
+            AnyAuthCredentialPresentation::new(&hex::decode(password).unwrap()).unwrap();
+        let verify = |params: &GroupSecretParams| {
+            server.verify_auth_credential_presentation(
+                params.zkgroup_params().get_public_params(),
+                &presentation,
+                now as u64,
+            )
+        };
+        assert!(verify(&params).is_ok());
+        assert!(verify(&other).is_err());
+
+        // Credentials for someone else, or from other servers, are turned
+        // down
+        let public_params = server.get_public_params();
+        assert!(GroupService::new(public_params, &credential, &aci(), pni).is_err());
+        let elsewhere = ServerSecretParams::generate([3; 32]).get_public_params();
+        assert!(GroupService::new(elsewhere, &credential, &alice, pni).is_err());
+
+        assert_eq!(parse_versions_range("versions 4-9/12"), Some((9, 12)));
+        assert_eq!(parse_versions_range("bytes 0-9/12"), None);
+    }
+}
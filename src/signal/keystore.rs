@@ -0,0 +1,461 @@
+//! Database encryption key storage
+//!
+//! The store is encrypted with a random 256-bit SQLCipher raw key, kept in
+//! the Secret Service (GNOME Keyring, KWallet).
+//!
+//! Without a Secret Service the key can instead be kept in a file next to
+//! the database, but only when `UNPROTECTED_KEY_FILE_ENV` is set at
+//! startup. That file is wrapped with a key derived from the world-readable
+//! machine ID, so it does not protect anything: whoever can read the data
+//! directory can decrypt the database. It is meant for tests and headless
+//! setups that accept this.
+//!
+//! Which of the two holds the key is recorded next to the database, so a
+//! store keeps using its backend when the Secret Service comes or goes.
+
+use anyhow::{anyhow, Result};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::collections::HashMap;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+use super::crypto::{SignalCipher, SignalHkdf, KEY_SIZE, NONCE_SIZE};
+
+/// Name of the key file used when the Secret Service is unavailable
+pub const KEY_FILE: &str = "signal.db.key";
+
+/// Environment variable that allows keeping the key in an unprotected file
+pub const UNPROTECTED_KEY_FILE_ENV: &str = "SIGNAL_YOU_UNPROTECTED_KEY_FILE";
+
+/// Name of the file recording which backend holds the key
+pub const BACKEND_FILE: &str = "signal.db.keystore";
+
+/// Contents of `BACKEND_FILE` for each backend
+const SECRET_SERVICE_BACKEND: &str = "secret-service";
+const FILE_BACKEND: &str = "file";
+
+/// Application the key is filed under in the Secret Service
+const SECRET_APPLICATION: &str = "com.signalyou.Messenger";
+
+/// Label shown for the key in keyring managers such as Seahorse
+const SECRET_LABEL: &str = "Signal You database key";
+
+/// HKDF info for the machine-bound wrapping key of the key file
+const KEY_FILE_INFO: &[u8] = b"Signal You database key file";
+
+/// Whether `UNPROTECTED_KEY_FILE_ENV` allows keeping the key in a file
+///
+/// Read once at startup and passed on to [`KeyStore::detect`].
+pub fn unprotected_key_file_allowed() -> bool {
+    std::env::var_os(UNPROTECTED_KEY_FILE_ENV).is_some()
+}
+
+/// Random raw key for SQLCipher
+#[derive(Clone, PartialEq, Eq, Zeroize, ZeroizeOnDrop)]
+pub struct DatabaseKey([u8; KEY_SIZE]);
+
+impl DatabaseKey {
+    /// Generate a new random key
+    pub fn generate() -> Self {
+        let mut key = [0u8; KEY_SIZE];
+        OsRng.fill_bytes(&mut key);
+        Self(key)
+    }
+
+    /// Create from stored key bytes
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let key = bytes
+            .try_into()
+            .map_err(|_| anyhow!("Invalid database key length: {}", bytes.len()))?;
+        Ok(Self(key))
+    }
+
+    /// Get the key bytes
+    pub fn as_bytes(&self) -> &[u8; KEY_SIZE] {
+        &self.0
+    }
+
+    /// The key in SQLCipher's raw key syntax, `x'<64 hex digits>'`
+    ///
+    /// A raw key is used as-is, skipping SQLCipher's passphrase derivation.
+    pub fn to_sql(&self) -> Zeroizing<String> {
+        Zeroizing::new(format!("x'{}'", hex::encode(self.0)))
+    }
+}
+
+/// Which of the keys of a store to access
+///
+/// While the database is rekeyed its old key is kept as the previous key,
+/// so it can still be opened if rekeying is interrupted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeySlot {
+    Current,
+    Previous,
+}
+
+/// Where the database key is kept
+#[derive(Clone)]
+pub enum KeyStore {
+    /// Default collection of the Secret Service, one item per data directory
+    SecretService { data_dir: PathBuf },
+    /// File encrypted with AES-256-GCM under `wrapping_key`
+    ///
+    /// Only as safe as the wrapping key; see the module documentation.
+    File {
+        path: PathBuf,
+        wrapping_key: [u8; KEY_SIZE],
+    },
+}
+
+impl KeyStore {
+    /// The backend holding the key of the store in `data_dir`
+    ///
+    /// A store keeps the backend its key was first saved to. A new store
+    /// uses the Secret Service if it is reachable, else a key file if
+    /// `allow_key_file`. A key file is never used without it.
+    pub async fn detect(data_dir: &Path, allow_key_file: bool) -> Result<Self> {
+        let backend = match std::fs::read_to_string(data_dir.join(BACKEND_FILE)) {
+            Ok(backend) => Some(backend.trim().to_string()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        match backend.as_deref() {
+            Some(SECRET_SERVICE_BACKEND) => Ok(Self::SecretService {
+                data_dir: data_dir.to_path_buf(),
+            }),
+            Some(FILE_BACKEND) => Self::key_file(data_dir, allow_key_file),
+            Some(other) => Err(anyhow!("Unknown database key store {:?}", other)),
+            // Stores from before the backend was recorded
+            None if data_dir.join(KEY_FILE).exists() => Self::key_file(data_dir, allow_key_file),
+            None => match oo7::dbus::Service::new().await {
+                Ok(_) => Ok(Self::SecretService {
+                    data_dir: data_dir.to_path_buf(),
+                }),
+                Err(e) if allow_key_file => {
+                    tracing::warn!("Secret Service unavailable ({})", e);
+                    Self::key_file(data_dir, allow_key_file)
+                }
+                Err(e) => Err(anyhow!(
+                    "Secret Service unavailable ({}); set {}=1 to keep the database key \
+                     in an unprotected file instead",
+                    e,
+                    UNPROTECTED_KEY_FILE_ENV
+                )),
+            },
+        }
+    }
+
+    /// Key file in `data_dir`, if `allow_key_file`
+    fn key_file(data_dir: &Path, allow_key_file: bool) -> Result<Self> {
+        if !allow_key_file {
+            return Err(anyhow!(
+                "The database key is kept in an unprotected file; set {}=1 to use it",
+                UNPROTECTED_KEY_FILE_ENV
+            ));
+        }
+        tracing::warn!(
+            "Keeping the database key in an unprotected file: anyone who can read {:?} \
+             can decrypt the database",
+            data_dir
+        );
+
+        Ok(Self::File {
+            path: data_dir.join(KEY_FILE),
+            wrapping_key: machine_wrapping_key()?,
+        })
+    }
+
+    /// Load the database key, if one was saved
+    pub async fn load(&self) -> Result<Option<DatabaseKey>> {
+        self.load_slot(KeySlot::Current).await
+    }
+
+    /// Save the database key, replacing any previous one
+    pub async fn save(&self, key: &DatabaseKey) -> Result<()> {
+        self.save_slot(KeySlot::Current, key).await
+    }
+
+    /// Load one of the keys, if it was saved
+    pub async fn load_slot(&self, slot: KeySlot) -> Result<Option<DatabaseKey>> {
+        match self {
+            Self::SecretService { data_dir } => {
+                let service = oo7::dbus::Service::new().await.map_err(|e| {
+                    anyhow!(
+                        "Database key is in the Secret Service, which is unavailable: {}",
+                        e
+                    )
+                })?;
+                let collection = service.default_collection().await?;
+
+                let items = collection
+                    .search_items(&secret_attributes(data_dir, slot))
+                    .await?;
+                let Some(item) = items.first() else {
+                    return Ok(None);
+                };
+                if item.is_locked().await? {
+                    item.unlock().await?;
+                }
+
+                DatabaseKey::from_bytes(&item.secret().await?).map(Some)
+            }
+            Self::File { path, wrapping_key } => {
+                let contents = match std::fs::read(slot_path(path, slot)) {
+                    Ok(contents) => contents,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                    Err(e) => return Err(e.into()),
+                };
+                if contents.len() < NONCE_SIZE {
+                    return Err(anyhow!("Database key file is truncated"));
+                }
+
+                let (nonce, ciphertext) = contents.split_at(NONCE_SIZE);
+                let key = Zeroizing::new(
+                    SignalCipher::decrypt(wrapping_key, nonce.try_into()?, ciphertext)
+                        .map_err(|_| anyhow!("Failed to decrypt database key file"))?,
+                );
+
+                DatabaseKey::from_bytes(&key).map(Some)
+            }
+        }
+    }
+
+    /// Save one of the keys, replacing the one saved there before
+    pub async fn save_slot(&self, slot: KeySlot, key: &DatabaseKey) -> Result<()> {
+        match self {
+            Self::SecretService { data_dir } => {
+                let service = oo7::dbus::Service::new().await?;
+                let collection = service.default_collection().await?;
+                if collection.is_locked().await? {
+                    collection.unlock().await?;
+                }
+
+                collection
+                    .create_item(
+                        SECRET_LABEL,
+                        &secret_attributes(data_dir, slot),
+                        key.as_bytes(),
+                        true,
+                        "application/octet-stream",
+                    )
+                    .await?;
+            }
+            Self::File { path, wrapping_key } => {
+                let path = slot_path(path, slot);
+                let nonce = SignalCipher::generate_nonce();
+                let ciphertext = SignalCipher::encrypt(wrapping_key, &nonce, key.as_bytes())?;
+
+                // Write the new key beside the old one, then swap them
+                let mut temp_path = path.clone().into_os_string();
+                temp_path.push(".tmp");
+                let mut file = std::fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .mode(0o600)
+                    .open(&temp_path)?;
+                file.write_all(&nonce)?;
+                file.write_all(&ciphertext)?;
+                file.sync_all()?;
+                std::fs::rename(&temp_path, path)?;
+            }
+        }
+        self.record_backend()?;
+
+        tracing::info!("Saved {:?} database key", slot);
+        Ok(())
+    }
+
+    /// Forget one of the keys
+    pub async fn delete_slot(&self, slot: KeySlot) -> Result<()> {
+        match self {
+            Self::SecretService { data_dir } => {
+                let service = oo7::dbus::Service::new().await?;
+                let collection = service.default_collection().await?;
+                for item in collection
+                    .search_items(&secret_attributes(data_dir, slot))
+                    .await?
+                {
+                    item.delete().await?;
+                }
+            }
+            Self::File { path, .. } => match std::fs::remove_file(slot_path(path, slot)) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            },
+        }
+        Ok(())
+    }
+
+    /// Note next to the database which backend holds its key
+    fn record_backend(&self) -> Result<()> {
+        let (data_dir, backend) = match self {
+            Self::SecretService { data_dir } => (data_dir.as_path(), SECRET_SERVICE_BACKEND),
+            Self::File { path, .. } => (
+                path.parent()
+                    .ok_or_else(|| anyhow!("Key file {:?} has no directory", path))?,
+                FILE_BACKEND,
+            ),
+        };
+
+        std::fs::write(data_dir.join(BACKEND_FILE), backend)?;
+        Ok(())
+    }
+}
+
+/// Attributes identifying a key of the store in `data_dir`
+fn secret_attributes(data_dir: &Path, slot: KeySlot) -> HashMap<&'static str, String> {
+    let key_type = match slot {
+        KeySlot::Current => "database-key",
+        KeySlot::Previous => "previous-database-key",
+    };
+
+    HashMap::from([
+        ("application", SECRET_APPLICATION.to_string()),
+        ("type", key_type.to_string()),
+        ("data-dir", data_dir.to_string_lossy().into_owned()),
+    ])
+}
+
+/// Key file holding a key, the previous one beside the current one
+fn slot_path(path: &Path, slot: KeySlot) -> PathBuf {
+    match slot {
+        KeySlot::Current => path.to_path_buf(),
+        KeySlot::Previous => {
+            let mut path = path.as_os_str().to_owned();
+            path.push(".previous");
+            PathBuf::from(path)
+        }
+    }
+}
+
+/// Derive the key file's wrapping key from the machine ID
+///
+/// The machine ID is readable by every local user, so this only keeps the
+/// key out of plain sight; it is not a secret.
+fn machine_wrapping_key() -> Result<[u8; KEY_SIZE]> {
+    let machine_id = std::fs::read_to_string("/etc/machine-id")
+        .or_else(|_| std::fs::read_to_string("/var/lib/dbus/machine-id"))
+        .map_err(|e| anyhow!("Failed to read machine ID: {}", e))?;
+
+    let derived = Zeroizing::new(SignalHkdf::derive_secrets(
+        machine_id.trim().as_bytes(),
+        &[],
+        KEY_FILE_INFO,
+        KEY_SIZE,
+    )?);
+
+    let mut key = [0u8; KEY_SIZE];
+    key.copy_from_slice(&derived);
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_raw_key_syntax() {
+        let key = DatabaseKey::from_bytes(&[0xab; KEY_SIZE]).unwrap();
+        assert_eq!(*key.to_sql(), format!("x'{}'", "ab".repeat(KEY_SIZE)));
+
+        assert!(DatabaseKey::from_bytes(&[0xab; 16]).is_err());
+        assert!(DatabaseKey::generate() != DatabaseKey::generate());
+    }
+
+    #[tokio::test]
+    async fn test_key_file_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join(KEY_FILE);
+        let key_store = KeyStore::File {
+            path: path.clone(),
+            wrapping_key: [1; KEY_SIZE],
+        };
+
+        assert!(key_store.load().await.unwrap().is_none());
+
+        let key = DatabaseKey::generate();
+        key_store.save(&key).await.unwrap();
+        assert!(key_store.load().await.unwrap() == Some(key.clone()));
+
+        // The key is not stored in the clear
+        let contents = std::fs::read(&path).unwrap();
+        assert!(!contents
+            .windows(KEY_SIZE)
+            .any(|window| window == key.as_bytes()));
+
+        // Saving again replaces the key
+        let rotated = DatabaseKey::generate();
+        key_store.save(&rotated).await.unwrap();
+        assert!(key_store.load().await.unwrap() == Some(rotated.clone()));
+
+        // The previous key is kept apart from the current one
+        key_store.save_slot(KeySlot::Previous, &key).await.unwrap();
+        assert!(key_store.load_slot(KeySlot::Previous).await.unwrap() == Some(key));
+        assert!(key_store.load().await.unwrap() == Some(rotated));
+        key_store.delete_slot(KeySlot::Previous).await.unwrap();
+        key_store.delete_slot(KeySlot::Previous).await.unwrap();
+        assert!(key_store
+            .load_slot(KeySlot::Previous)
+            .await
+            .unwrap()
+            .is_none());
+
+        let wrong_wrapping_key = KeyStore::File {
+            path,
+            wrapping_key: [2; KEY_SIZE],
+        };
+        assert!(wrong_wrapping_key.load().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_detect_keeps_recorded_backend() {
+        let temp_dir = TempDir::new().unwrap();
+        let key_store = KeyStore::File {
+            path: temp_dir.path().join(KEY_FILE),
+            wrapping_key: [1; KEY_SIZE],
+        };
+        key_store.save(&DatabaseKey::generate()).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(temp_dir.path().join(BACKEND_FILE)).unwrap(),
+            FILE_BACKEND
+        );
+
+        // A Secret Service that appears later is not switched to
+        let detected = KeyStore::detect(temp_dir.path(), true).await.unwrap();
+        assert!(matches!(detected, KeyStore::File { .. }));
+
+        // Nor is a recorded Secret Service given up for a key file
+        std::fs::write(temp_dir.path().join(BACKEND_FILE), SECRET_SERVICE_BACKEND).unwrap();
+        let detected = KeyStore::detect(temp_dir.path(), true).await.unwrap();
+        assert!(matches!(detected, KeyStore::SecretService { .. }));
+
+        std::fs::write(temp_dir.path().join(BACKEND_FILE), "floppy").unwrap();
+        assert!(KeyStore::detect(temp_dir.path(), true).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_detect_refuses_key_file_without_opt_in() {
+        let temp_dir = TempDir::new().unwrap();
+        let key_store = KeyStore::File {
+            path: temp_dir.path().join(KEY_FILE),
+            wrapping_key: [1; KEY_SIZE],
+        };
+        key_store.save(&DatabaseKey::generate()).await.unwrap();
+
+        // Recorded as the backend
+        assert!(KeyStore::detect(temp_dir.path(), false).await.is_err());
+
+        // Found from before the backend was recorded
+        std::fs::remove_file(temp_dir.path().join(BACKEND_FILE)).unwrap();
+        assert!(KeyStore::detect(temp_dir.path(), false).await.is_err());
+        let detected = KeyStore::detect(temp_dir.path(), true).await.unwrap();
+        assert!(matches!(detected, KeyStore::File { .. }));
+    }
+}
@@ -14,16 +14,41 @@
 //! - `ratchet`: Double Ratchet algorithm implementation
 //! - `protocol`: High-level protocol interface
 //! - `store`: Encrypted database storage using SQLCipher
+//! - `database`: SQLite writer thread and read connection pool
+//! - `keystore`: Database encryption key storage
 //! - `client`: Signal service client for messaging
+//! - `attachments`: Attachment encryption and CDN download
+//! - `profiles`: Profile decryption and avatar download
+//! - `groups`: Groups V2 keys, state decryption and revision tracking
+//! - `storage_service`: Encrypted account state shared between devices
+//! - `conversations`: Conversation upkeep for stored messages
+//! - `events`: Broadcast bus delivering client events to subscribers
+//! - `codec`: Content protobuf encoding and decoding
+//! - `proto`: Generated Signal service protobuf messages
+//! - `service_id`: Typed ACI/PNI service identifiers
 //! - `types`: Data type definitions
 
+mod attachments;
 mod client;
+mod codec;
+mod conversations;
 mod crypto;
+mod database;
+mod events;
+mod groups;
+mod keystore;
+mod profiles;
+mod proto;
 mod protocol;
 mod ratchet;
+mod service_id;
+mod storage_service;
 mod store;
-mod types;
+pub mod types;
 mod x3dh;
 
 // Re-export main types
-pub use client::{SignalClient, SignalEvent};
+pub use client::{ClientHandle, ConnectionStatus, SignalClient, SignalEvent};
+pub use events::{BusMessage, EventBus, EventSubscriber};
+pub use keystore::unprotected_key_file_allowed;
+pub use store::SignalStore;
@@ -0,0 +1,467 @@
+//! Profile encryption, decryption and avatar download
+//!
+//! Profiles are stored on the server encrypted under the owner's 32-byte
+//! profile key, which they share with the people they message. Each field
+//! is AES-256-GCM encrypted as `nonce || ciphertext`, its plaintext padded
+//! with zeros to one of a few fixed lengths so the length gives little
+//! away; the name holds the given and family names separated by a NUL.
+//! Avatars on the CDN are encrypted the same way, without padding.
+//!
+//! The server keeps one profile per profile key version, which is derived
+//! from the key and the owner's ACI, so only holders of the key can ask
+//! for the profile that goes with it. Uploading a profile also takes a
+//! commitment to the key, which the server checks credential requests
+//! against.
+//!
+//! Holders of the key may also ask for an expiring profile key credential
+//! along with the profile. It proves the key is the owner's, so it can be
+//! presented to the group server to add the owner to a group.
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use zkgroup::profiles::{
+    ExpiringProfileKeyCredential, ProfileKey, ProfileKeyCredentialRequestContext,
+};
+use zkgroup::ServerPublicParams;
+
+use super::crypto::{SignalCipher, NONCE_SIZE};
+use super::service_id::Aci;
+use super::types::{Profile, SignalServers};
+
+/// Size of a profile key
+pub const PROFILE_KEY_SIZE: usize = 32;
+
+const MAC_SIZE: usize = 16;
+
+/// Lengths profile fields are padded to before encryption, each field
+/// taking the shortest its text fits in
+const NAME_PADDED_LENGTHS: &[usize] = &[53, 257];
+const ABOUT_PADDED_LENGTHS: &[usize] = &[128, 254, 512];
+const ABOUT_EMOJI_PADDED_LENGTHS: &[usize] = &[32];
+
+/// Largest profile avatar we download, in bytes
+const MAX_AVATAR_SIZE: usize = 10 * 1024 * 1024;
+
+/// Profile fields as returned by the versioned profile endpoint, each
+/// base64 encoded and encrypted
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileResponse {
+    pub name: Option<String>,
+    pub about: Option<String>,
+    pub about_emoji: Option<String>,
+    /// CDN path of the encrypted avatar
+    pub avatar: Option<String>,
+    /// Base64 expiring profile key credential response, when one was
+    /// asked for
+    pub credential: Option<String>,
+}
+
+/// Our profile as uploaded to `PUT /v1/profile`, its fields base64
+/// encoded and encrypted
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileWrite {
+    version: String,
+    name: String,
+    about: Option<String>,
+    about_emoji: Option<String>,
+    /// Whether the profile has an avatar
+    avatar: bool,
+    /// Keep the avatar already uploaded rather than uploading another
+    same_avatar: bool,
+    commitment: String,
+    badge_ids: Vec<String>,
+}
+
+/// Version of a profile key, as the server indexes profiles by it
+pub fn profile_key_version(profile_key: &[u8], aci: &Aci) -> Result<String> {
+    let version = ProfileKey::create(profile_key_array(profile_key)?)
+        .get_profile_key_version(libsignal_core::Aci::from(aci.uuid()));
+    // Serialized, the version is its lowercase hex encoding
+    String::from_utf8(zkgroup::serialize(&version))
+        .map_err(|_| anyhow!("Profile key version is not hex"))
+}
+
+/// Ask for the expiring profile key credential of `aci` with their
+/// profile
+///
+/// Returns the path to fetch the profile from, and the context to receive
+/// the credential with.
+pub fn credential_request(
+    server_params: &ServerPublicParams,
+    profile_key: &[u8],
+    aci: &Aci,
+) -> Result<(String, ProfileKeyCredentialRequestContext)> {
+    let version = profile_key_version(profile_key, aci)?;
+    let mut randomness = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut randomness);
+    let context = server_params.create_profile_key_credential_request_context(
+        randomness,
+        libsignal_core::Aci::from(aci.uuid()),
+        ProfileKey::create(profile_key_array(profile_key)?),
+    );
+
+    let path = format!(
+        "/v1/profile/{}/{}/{}?credentialType=expiringProfileKey",
+        aci,
+        version,
+        hex::encode(zkgroup::serialize(&context.get_request()))
+    );
+    Ok((path, context))
+}
+
+/// Receive the credential of a profile fetched from the path of
+/// [`credential_request`], checking it is for the key we asked about and
+/// has not expired at `now`, in seconds
+pub fn receive_credential(
+    server_params: &ServerPublicParams,
+    context: &ProfileKeyCredentialRequestContext,
+    response: &ProfileResponse,
+    now: i64,
+) -> Result<ExpiringProfileKeyCredential> {
+    let credential = response
+        .credential
+        .as_deref()
+        .ok_or_else(|| anyhow!("Profile came without a credential"))?;
+    let credential = zkgroup::deserialize(&BASE64.decode(credential)?)
+        .map_err(|_| anyhow!("Malformed profile key credential"))?;
+    server_params
+        .receive_expiring_profile_key_credential(context, &credential, now as u64)
+        .map_err(|_| anyhow!("Profile key credential does not verify"))
+}
+
+/// Decrypt the fields of a fetched profile
+///
+/// Fields that fail to decrypt are left out rather than failing the whole
+/// profile, since an older key may have encrypted some of them.
+pub fn decrypt_profile(profile_key: &[u8], response: &ProfileResponse) -> Result<Profile> {
+    let key = profile_key_array(profile_key)?;
+    let field = |value: &Option<String>, name: &str| -> Option<String> {
+        let value = value.as_deref().filter(|value| !value.is_empty())?;
+        match decrypt_field(&key, value) {
+            Ok(text) => Some(text).filter(|text| !text.is_empty()),
+            Err(e) => {
+                tracing::warn!("Failed to decrypt profile {}: {}", name, e);
+                None
+            }
+        }
+    };
+
+    let (given_name, family_name) = match field(&response.name, "name") {
+        Some(name) => match name.split_once('\0') {
+            Some((given, family)) => (
+                Some(given.to_string()).filter(|given| !given.is_empty()),
+                Some(family.to_string()).filter(|family| !family.is_empty()),
+            ),
+            None => (Some(name), None),
+        },
+        None => (None, None),
+    };
+
+    Ok(Profile {
+        given_name,
+        family_name,
+        about: field(&response.about, "about"),
+        about_emoji: field(&response.about_emoji, "about emoji"),
+        avatar_url: response.avatar.clone().filter(|avatar| !avatar.is_empty()),
+    })
+}
+
+/// Encrypt our profile for upload under our profile key
+///
+/// The profile keeps the avatar we uploaded last, if it had one.
+pub fn encrypt_profile(profile_key: &[u8], aci: &Aci, profile: &Profile) -> Result<ProfileWrite> {
+    let key = profile_key_array(profile_key)?;
+    let name = match &profile.family_name {
+        Some(family_name) => format!(
+            "{}\0{}",
+            profile.given_name.as_deref().unwrap_or_default(),
+            family_name
+        ),
+        None => profile.given_name.clone().unwrap_or_default(),
+    };
+    if name.is_empty() {
+        return Err(anyhow!("A profile needs a given name"));
+    }
+    let field = |value: &Option<String>, padded_lengths| {
+        value
+            .as_deref()
+            .filter(|value| !value.is_empty())
+            .map(|value| encrypt_field(&key, value, padded_lengths))
+            .transpose()
+    };
+
+    let commitment = ProfileKey::create(key).get_commitment(libsignal_core::Aci::from(aci.uuid()));
+    Ok(ProfileWrite {
+        version: profile_key_version(profile_key, aci)?,
+        name: encrypt_field(&key, &name, NAME_PADDED_LENGTHS)?,
+        about: field(&profile.about, ABOUT_PADDED_LENGTHS)?,
+        about_emoji: field(&profile.about_emoji, ABOUT_EMOJI_PADDED_LENGTHS)?,
+        avatar: profile.avatar_url.is_some(),
+        same_avatar: profile.avatar_url.is_some(),
+        commitment: BASE64.encode(zkgroup::serialize(&commitment)),
+        badge_ids: vec![],
+    })
+}
+
+/// Decrypt a downloaded profile avatar
+pub fn decrypt_avatar(profile_key: &[u8], blob: &[u8]) -> Result<Vec<u8>> {
+    decrypt(&profile_key_array(profile_key)?, blob)
+}
+
+/// Download a profile avatar from the CDN and decrypt it
+pub async fn download_avatar(profile_key: &[u8], avatar_url: &str) -> Result<Vec<u8>> {
+    let url = format!("{}/{}", SignalServers::default().cdn, avatar_url);
+    tracing::debug!("Downloading profile avatar {}", avatar_url);
+
+    let response = reqwest::get(&url).await?.error_for_status()?;
+    if response
+        .content_length()
+        .is_some_and(|length| length as usize > MAX_AVATAR_SIZE)
+    {
+        return Err(anyhow!("Profile avatar {} is too large", avatar_url));
+    }
+
+    let blob = response.bytes().await?;
+    if blob.len() > MAX_AVATAR_SIZE {
+        return Err(anyhow!("Profile avatar {} is too large", avatar_url));
+    }
+
+    decrypt_avatar(profile_key, &blob)
+}
+
+fn profile_key_array(profile_key: &[u8]) -> Result<[u8; PROFILE_KEY_SIZE]> {
+    profile_key
+        .try_into()
+        .map_err(|_| anyhow!("Invalid profile key length {}", profile_key.len()))
+}
+
+/// Pad a profile field to the shortest length it fits in, encrypt it and
+/// base64 encode it
+fn encrypt_field(
+    key: &[u8; PROFILE_KEY_SIZE],
+    plaintext: &str,
+    padded_lengths: &[usize],
+) -> Result<String> {
+    let padded_length = padded_lengths
+        .iter()
+        .copied()
+        .find(|&length| length >= plaintext.len())
+        .ok_or_else(|| anyhow!("Profile field of {} bytes is too long", plaintext.len()))?;
+    let mut padded = plaintext.as_bytes().to_vec();
+    padded.resize(padded_length, 0);
+
+    let nonce = SignalCipher::generate_nonce();
+    let mut blob = nonce.to_vec();
+    blob.extend(SignalCipher::encrypt(key, &nonce, &padded)?);
+    Ok(BASE64.encode(blob))
+}
+
+/// Decrypt a base64 profile field and strip its padding
+fn decrypt_field(key: &[u8; PROFILE_KEY_SIZE], value: &str) -> Result<String> {
+    let mut plaintext = decrypt(key, &BASE64.decode(value)?)?;
+    let length = plaintext
+        .iter()
+        .rposition(|&byte| byte != 0)
+        .map_or(0, |last| last + 1);
+    plaintext.truncate(length);
+    Ok(String::from_utf8(plaintext)?)
+}
+
+fn decrypt(key: &[u8; PROFILE_KEY_SIZE], blob: &[u8]) -> Result<Vec<u8>> {
+    if blob.len() < NONCE_SIZE + MAC_SIZE {
+        return Err(anyhow!("Encrypted profile data too short"));
+    }
+    let (nonce, ciphertext) = blob.split_at(NONCE_SIZE);
+    let nonce: &[u8; NONCE_SIZE] = nonce.try_into()?;
+    SignalCipher::decrypt(key, nonce, ciphertext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zkgroup::ServerSecretParams;
+
+    #[test]
+    fn test_profile_key_version() {
+        // zkgroup's result matches a separate Python implementation of
+        // its HMAC-SHA256 Sho construction
+        let aci: Aci = uuid::Uuid::parse_str("9d0652a3-dcc3-4d11-975f-74d61598733f")
+            .unwrap()
+            .into();
+        let profile_key: Vec<u8> = (0..32).collect();
+        assert_eq!(
+            profile_key_version(&profile_key, &aci).unwrap(),
+            "9f00db604b71826c17e1a2fa3c007da9b6665168d07fad13408c2191dcc0b17b"
+        );
+
+        let aci: Aci = uuid::Uuid::new_v4().into();
+        let version = profile_key_version(&[1; 32], &aci).unwrap();
+        assert_eq!(version.len(), 64);
+        assert_eq!(profile_key_version(&[1; 32], &aci).unwrap(), version);
+
+        // Versions differ between keys and between people sharing a key
+        assert_ne!(profile_key_version(&[2; 32], &aci).unwrap(), version);
+        let other: Aci = uuid::Uuid::new_v4().into();
+        assert_ne!(profile_key_version(&[1; 32], &other).unwrap(), version);
+
+        assert!(profile_key_version(&[1; 16], &aci).is_err());
+    }
+
+    #[test]
+    fn test_profile_key_credential() {
+        let server = ServerSecretParams::generate([1; 32]);
+        let public_params = server.get_public_params();
+        let aci: Aci = uuid::Uuid::new_v4().into();
+        let now = 1_700_000_000;
+        let expiration = (now - now % 86400 + 7 * 86400) as u64;
+
+        let (path, context) = credential_request(&public_params, &[1; 32], &aci).unwrap();
+        let version = profile_key_version(&[1; 32], &aci).unwrap();
+        assert!(path.starts_with(&format!("/v1/profile/{}/{}/", aci, version)));
+        assert!(path.ends_with("?credentialType=expiringProfileKey"));
+
+        // The server answers with a credential for the key in the request
+        let issue = |context: &ProfileKeyCredentialRequestContext, profile_key: [u8; 32]| {
+            let aci = libsignal_core::Aci::from(aci.uuid());
+            let response = server
+                .issue_expiring_profile_key_credential(
+                    [2; 32],
+                    &context.get_request(),
+                    aci,
+                    ProfileKey::create(profile_key).get_commitment(aci),
+                    expiration,
+                )
+                .unwrap();
+            ProfileResponse {
+                credential: Some(BASE64.encode(zkgroup::serialize(&response))),
+                ..Default::default()
+            }
+        };
+        let response = issue(&context, [1; 32]);
+        let credential = receive_credential(&public_params, &context, &response, now).unwrap();
+        assert_eq!(credential.get_expiration_time(), expiration);
+
+        // Expired credentials, or those for another request, are turned down
+        assert!(
+            receive_credential(&public_params, &context, &response, expiration as i64).is_err()
+        );
+        let (_, other) = credential_request(&public_params, &[2; 32], &aci).unwrap();
+        let response = issue(&other, [2; 32]);
+        assert!(receive_credential(&public_params, &context, &response, now).is_err());
+        let response = ProfileResponse::default();
+        assert!(receive_credential(&public_params, &context, &response, now).is_err());
+    }
+
+    #[test]
+    fn test_encrypt_profile() {
+        let key = [7; PROFILE_KEY_SIZE];
+        let aci: Aci = uuid::Uuid::new_v4().into();
+        let profile = Profile {
+            given_name: Some("Alice".to_string()),
+            family_name: Some("Smith".to_string()),
+            about: Some("Out hiking".to_string()),
+            about_emoji: None,
+            avatar_url: Some("profiles/abc".to_string()),
+        };
+
+        let write = encrypt_profile(&key, &aci, &profile).unwrap();
+        assert_eq!(write.version, profile_key_version(&key, &aci).unwrap());
+        let commitment = ProfileKey::create(key).get_commitment(aci.uuid().into());
+        assert_eq!(
+            BASE64.decode(&write.commitment).unwrap(),
+            zkgroup::serialize(&commitment)
+        );
+        assert!(write.avatar && write.same_avatar);
+
+        // Fields are padded to a fixed length, and decrypt to what we wrote
+        let name = BASE64.decode(&write.name).unwrap();
+        assert_eq!(name.len(), NONCE_SIZE + 53 + MAC_SIZE);
+        let response = ProfileResponse {
+            name: Some(write.name),
+            about: write.about,
+            about_emoji: write.about_emoji,
+            avatar: Some("profiles/abc".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(decrypt_profile(&key, &response).unwrap(), profile);
+
+        // Without an avatar, none is kept
+        let profile = Profile {
+            given_name: Some("Bob".to_string()),
+            ..Default::default()
+        };
+        let write = encrypt_profile(&key, &aci, &profile).unwrap();
+        assert!(!write.avatar && !write.same_avatar);
+        let json = serde_json::to_value(&write).unwrap();
+        assert!(json.get("aboutEmoji").is_some());
+        assert!(json.get("sameAvatar").is_some());
+
+        // A name is required, and fields must fit the longest padding
+        assert!(encrypt_profile(&key, &aci, &Profile::default()).is_err());
+        let profile = Profile {
+            given_name: Some("Bob".to_string()),
+            about: Some("a".repeat(513)),
+            ..Default::default()
+        };
+        assert!(encrypt_profile(&key, &aci, &profile).is_err());
+    }
+
+    #[test]
+    fn test_decrypt_profile() {
+        let key = [7; PROFILE_KEY_SIZE];
+        let response = ProfileResponse {
+            name: Some(encrypt_field(&key, "Alice\0Smith", NAME_PADDED_LENGTHS).unwrap()),
+            about: Some(encrypt_field(&key, "Out hiking", ABOUT_PADDED_LENGTHS).unwrap()),
+            about_emoji: Some(
+                encrypt_field(&key, "\u{1F3D4}", ABOUT_EMOJI_PADDED_LENGTHS).unwrap(),
+            ),
+            avatar: Some("profiles/abc".to_string()),
+            ..Default::default()
+        };
+
+        let profile = decrypt_profile(&key, &response).unwrap();
+        assert_eq!(
+            profile,
+            Profile {
+                given_name: Some("Alice".to_string()),
+                family_name: Some("Smith".to_string()),
+                about: Some("Out hiking".to_string()),
+                about_emoji: Some("\u{1F3D4}".to_string()),
+                avatar_url: Some("profiles/abc".to_string()),
+            }
+        );
+        assert_eq!(profile.name().as_deref(), Some("Alice Smith"));
+
+        // A name without a family name has no separator
+        let response = ProfileResponse {
+            name: Some(encrypt_field(&key, "Bob", NAME_PADDED_LENGTHS).unwrap()),
+            ..Default::default()
+        };
+        let profile = decrypt_profile(&key, &response).unwrap();
+        assert_eq!(profile.given_name.as_deref(), Some("Bob"));
+        assert_eq!(profile.family_name, None);
+
+        // Fields under another key are left out
+        let profile = decrypt_profile(&[8; PROFILE_KEY_SIZE], &response).unwrap();
+        assert_eq!(profile, Profile::default());
+        assert!(decrypt_profile(&[8; 16], &response).is_err());
+    }
+
+    #[test]
+    fn test_decrypt_avatar() {
+        let key = [7; PROFILE_KEY_SIZE];
+        let nonce = SignalCipher::generate_nonce();
+        let mut blob = nonce.to_vec();
+        blob.extend(SignalCipher::encrypt(&key, &nonce, b"image").unwrap());
+
+        assert_eq!(decrypt_avatar(&key, &blob).unwrap(), b"image");
+
+        blob[NONCE_SIZE] ^= 1;
+        assert!(decrypt_avatar(&key, &blob).is_err());
+        assert!(decrypt_avatar(&key, &blob[..NONCE_SIZE]).is_err());
+    }
+}
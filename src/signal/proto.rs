@@ -0,0 +1,7 @@
+//! Generated Signal service protobuf messages
+//!
+//! Compiled from `src/proto/*.proto` by `build.rs` using prost.
+
+#![allow(clippy::all)]
+
+include!(concat!(env!("OUT_DIR"), "/signalservice.rs"));
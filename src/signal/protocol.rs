@@ -4,7 +4,7 @@
 //! establishing sessions, encrypting/decrypting messages, and managing keys.
 
 use anyhow::{anyhow, Result};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
@@ -13,6 +13,7 @@ use super::crypto::{
     SignedPreKey,
 };
 use super::ratchet::{RatchetMessage, SessionState};
+use super::service_id::{Aci, ServiceId, ServiceIdKind};
 use super::x3dh::{x3dh_initiate, x3dh_respond, InitialMessage};
 
 /// Number of pre-keys to generate at a time
@@ -21,24 +22,33 @@ const PRE_KEY_BATCH_SIZE: u32 = 100;
 /// Maximum pre-key ID before wrapping
 const MAX_PRE_KEY_ID: u32 = 0x00FFFFFF;
 
-/// Signal Protocol address (user + device)
+/// Plaintexts are padded to a multiple of this size to hide their length
+const PADDING_BLOCK_SIZE: usize = 160;
+
+/// Marks the end of the plaintext within a padded block
+const PADDING_TERMINATOR: u8 = 0x80;
+
+/// Archived sessions kept per address, as in libsignal
+const MAX_ARCHIVED_SESSIONS: usize = 40;
+
+/// Signal Protocol address (service ID + device)
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct ProtocolAddress {
-    pub name: String,
+    pub service_id: ServiceId,
     pub device_id: u32,
 }
 
 impl ProtocolAddress {
-    pub fn new(name: impl Into<String>, device_id: u32) -> Self {
+    pub fn new(service_id: impl Into<ServiceId>, device_id: u32) -> Self {
         Self {
-            name: name.into(),
+            service_id: service_id.into(),
             device_id,
         }
     }
 
     /// Convert to string representation
     pub fn to_string(&self) -> String {
-        format!("{}.{}", self.name, self.device_id)
+        format!("{}.{}", self.service_id, self.device_id)
     }
 
     /// Parse from string representation
@@ -48,16 +58,19 @@ impl ProtocolAddress {
             return Err(anyhow!("Invalid address format"));
         }
         let device_id: u32 = parts[0].parse()?;
-        let name = parts[1].to_string();
-        Ok(Self { name, device_id })
+        let service_id: ServiceId = parts[1].parse()?;
+        Ok(Self {
+            service_id,
+            device_id,
+        })
     }
 }
 
-/// Signal Protocol wrapper with session management
-pub struct SignalProtocol {
-    /// Our identity key pair
+/// Identity key and pre-keys of one of our service IDs
+struct IdentityKeys {
+    /// Identity key pair
     identity_key: IdentityKeyPair,
-    /// Our registration ID
+    /// Registration ID
     registration_id: u32,
     /// Pre-keys (one-time keys)
     pre_keys: HashMap<u32, PreKey>,
@@ -65,74 +78,25 @@ pub struct SignalProtocol {
     next_pre_key_id: u32,
     /// Signed pre-key
     signed_pre_key: Option<SignedPreKey>,
-    /// Active sessions with other users
-    sessions: Arc<RwLock<HashMap<ProtocolAddress, SessionState>>>,
-    /// Trusted identity keys
-    trusted_identities: HashMap<String, IdentityPublicKey>,
 }
 
-impl SignalProtocol {
-    /// Create a new protocol instance with fresh identity
-    pub fn new() -> Result<Self> {
-        let identity_key = IdentityKeyPair::generate();
-        let registration_id = rand::random::<u32>() & 0x3FFF; // 14-bit ID
-
-        tracing::info!("Generated new identity key pair, registration_id={}", registration_id);
-
-        Ok(Self {
-            identity_key,
-            registration_id,
-            pre_keys: HashMap::new(),
-            next_pre_key_id: 1,
-            signed_pre_key: None,
-            sessions: Arc::new(RwLock::new(HashMap::new())),
-            trusted_identities: HashMap::new(),
-        })
-    }
-
-    /// Create from existing identity key
-    pub fn from_identity(private_key: &[u8; 32], registration_id: u32) -> Result<Self> {
-        let identity_key = IdentityKeyPair::from_private_key(private_key)?;
-
-        Ok(Self {
+impl IdentityKeys {
+    fn new(identity_key: IdentityKeyPair, registration_id: u32) -> Self {
+        Self {
             identity_key,
             registration_id,
             pre_keys: HashMap::new(),
             next_pre_key_id: 1,
             signed_pre_key: None,
-            sessions: Arc::new(RwLock::new(HashMap::new())),
-            trusted_identities: HashMap::new(),
-        })
-    }
-
-    /// Get our identity public key
-    pub fn identity_public_key(&self) -> IdentityPublicKey {
-        self.identity_key.public_key()
-    }
-
-    /// Get our identity private key bytes (for secure storage)
-    pub fn identity_private_key(&self) -> [u8; 32] {
-        self.identity_key.private_key_bytes()
-    }
-
-    /// Get our registration ID
-    pub fn registration_id(&self) -> u32 {
-        self.registration_id
+        }
     }
 
-    /// Generate identity key pair
-    pub fn generate_identity_key_pair(&self) -> Result<(Vec<u8>, Vec<u8>)> {
-        let key_pair = IdentityKeyPair::generate();
-        let public_key = key_pair.public_key().as_bytes().to_vec();
-        let private_key = key_pair.private_key_bytes().to_vec();
-
-        tracing::info!("Generated identity key pair");
-
-        Ok((public_key, private_key))
+    fn generate() -> Self {
+        let registration_id = rand::random::<u32>() & 0x3FFF; // 14-bit ID
+        Self::new(IdentityKeyPair::generate(), registration_id)
     }
 
-    /// Generate a batch of pre-keys
-    pub fn generate_pre_keys(&mut self, count: u32) -> Result<Vec<(u32, Vec<u8>)>> {
+    fn generate_pre_keys(&mut self, count: u32) -> Vec<(u32, Vec<u8>)> {
         let start_id = self.next_pre_key_id;
         let mut result = Vec::with_capacity(count as usize);
 
@@ -151,11 +115,10 @@ impl SignalProtocol {
 
         tracing::info!("Generated {} pre-keys starting at {}", count, start_id);
 
-        Ok(result)
+        result
     }
 
-    /// Generate a signed pre-key
-    pub fn generate_signed_pre_key(&mut self, id: u32) -> Result<(Vec<u8>, Vec<u8>)> {
+    fn generate_signed_pre_key(&mut self, id: u32) -> (Vec<u8>, Vec<u8>) {
         let signed_pre_key = SignedPreKey::generate(id, &self.identity_key);
 
         let public_key = signed_pre_key.key_pair.public_key().as_bytes().to_vec();
@@ -165,16 +128,10 @@ impl SignalProtocol {
 
         tracing::info!("Generated signed pre-key {}", id);
 
-        Ok((public_key, signature))
-    }
-
-    /// Get the current signed pre-key for publishing
-    pub fn get_signed_pre_key(&self) -> Option<&SignedPreKey> {
-        self.signed_pre_key.as_ref()
+        (public_key, signature)
     }
 
-    /// Create our pre-key bundle for publishing to the server
-    pub fn create_pre_key_bundle(&self, device_id: u32) -> Result<PreKeyBundle> {
+    fn create_pre_key_bundle(&self, device_id: u32) -> Result<PreKeyBundle> {
         let signed_pre_key = self
             .signed_pre_key
             .as_ref()
@@ -199,6 +156,225 @@ impl SignalProtocol {
             identity_key: self.identity_key.public_key(),
         })
     }
+}
+
+/// Current session with an address and the sessions it replaced
+///
+/// Archiving keeps the current session around, the way libsignal's
+/// `archive_current_state` does, so messages still in flight on it can be
+/// decrypted after a new session took over.
+#[derive(Default)]
+struct SessionRecord {
+    current: Option<SessionState>,
+    /// Archived sessions, most recent first
+    previous: VecDeque<SessionState>,
+}
+
+impl SessionRecord {
+    /// Move the current session to the archived ones, returning whether
+    /// there was one
+    fn archive_current_state(&mut self) -> bool {
+        match self.current.take() {
+            Some(state) => {
+                self.previous.push_front(state);
+                self.previous.truncate(MAX_ARCHIVED_SESSIONS);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Make a session current, archiving the one it replaces
+    fn promote_state(&mut self, state: SessionState) {
+        self.archive_current_state();
+        self.current = Some(state);
+    }
+
+    /// Decrypt with the current session or, failing that, an archived one
+    ///
+    /// Sessions are tried on a copy so a failed attempt leaves them as they
+    /// were. An archived session that decrypts the message becomes current
+    /// again, since the sender is still using it.
+    fn decrypt(&mut self, message: &RatchetMessage) -> Result<Vec<u8>> {
+        let mut error = None;
+
+        if let Some(current) = &self.current {
+            let mut state = current.clone();
+            match state.decrypt(message) {
+                Ok(padded) => {
+                    self.current = Some(state);
+                    return Ok(padded);
+                }
+                Err(e) => error = Some(e),
+            }
+        }
+
+        for index in 0..self.previous.len() {
+            let mut state = self.previous[index].clone();
+            if let Ok(padded) = state.decrypt(message) {
+                self.previous.remove(index);
+                self.promote_state(state);
+                return Ok(padded);
+            }
+        }
+
+        Err(error.unwrap_or_else(|| anyhow!("No current session")))
+    }
+}
+
+/// Signal Protocol wrapper with session management
+pub struct SignalProtocol {
+    /// Keys of our account identity (ACI)
+    aci: IdentityKeys,
+    /// Keys of our phone number identity (PNI)
+    pni: IdentityKeys,
+    /// Sessions with other users
+    sessions: Arc<RwLock<HashMap<ProtocolAddress, SessionRecord>>>,
+    /// Sessions others started with our PNI, kept apart from the ACI ones
+    /// since they were agreed with the PNI identity key
+    pni_sessions: Arc<RwLock<HashMap<ProtocolAddress, SessionRecord>>>,
+    /// Trusted identity keys
+    trusted_identities: HashMap<ServiceId, IdentityPublicKey>,
+}
+
+impl SignalProtocol {
+    /// Create a new protocol instance with fresh ACI and PNI identities
+    pub fn new() -> Result<Self> {
+        let aci = IdentityKeys::generate();
+
+        tracing::info!(
+            "Generated new identity key pairs, registration_id={}",
+            aci.registration_id
+        );
+
+        Ok(Self {
+            aci,
+            pni: IdentityKeys::generate(),
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+            pni_sessions: Arc::new(RwLock::new(HashMap::new())),
+            trusted_identities: HashMap::new(),
+        })
+    }
+
+    /// Create from existing identity key
+    ///
+    /// The PNI identity is freshly generated; restore it with
+    /// `with_pni_identity`.
+    pub fn from_identity(private_key: &[u8; 32], registration_id: u32) -> Result<Self> {
+        let identity_key = IdentityKeyPair::from_private_key(private_key)?;
+
+        Ok(Self {
+            aci: IdentityKeys::new(identity_key, registration_id),
+            pni: IdentityKeys::generate(),
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+            pni_sessions: Arc::new(RwLock::new(HashMap::new())),
+            trusted_identities: HashMap::new(),
+        })
+    }
+
+    /// Replace the PNI identity with an existing key
+    pub fn with_pni_identity(mut self, private_key: &[u8; 32], registration_id: u32) -> Result<Self> {
+        let identity_key = IdentityKeyPair::from_private_key(private_key)?;
+        self.pni = IdentityKeys::new(identity_key, registration_id);
+        Ok(self)
+    }
+
+    fn keys(&self, kind: ServiceIdKind) -> &IdentityKeys {
+        match kind {
+            ServiceIdKind::Aci => &self.aci,
+            ServiceIdKind::Pni => &self.pni,
+        }
+    }
+
+    fn keys_mut(&mut self, kind: ServiceIdKind) -> &mut IdentityKeys {
+        match kind {
+            ServiceIdKind::Aci => &mut self.aci,
+            ServiceIdKind::Pni => &mut self.pni,
+        }
+    }
+
+    fn sessions(&self, kind: ServiceIdKind) -> &RwLock<HashMap<ProtocolAddress, SessionRecord>> {
+        match kind {
+            ServiceIdKind::Aci => &self.sessions,
+            ServiceIdKind::Pni => &self.pni_sessions,
+        }
+    }
+
+    /// Get our identity public key
+    pub fn identity_public_key(&self) -> IdentityPublicKey {
+        self.aci.identity_key.public_key()
+    }
+
+    /// Get our identity private key bytes (for secure storage)
+    pub fn identity_private_key(&self) -> [u8; 32] {
+        self.aci.identity_key.private_key_bytes()
+    }
+
+    /// Get our registration ID
+    pub fn registration_id(&self) -> u32 {
+        self.aci.registration_id
+    }
+
+    /// Get our PNI identity public key
+    pub fn pni_identity_public_key(&self) -> IdentityPublicKey {
+        self.pni.identity_key.public_key()
+    }
+
+    /// Get our PNI identity private key bytes (for secure storage)
+    pub fn pni_identity_private_key(&self) -> [u8; 32] {
+        self.pni.identity_key.private_key_bytes()
+    }
+
+    /// Get our PNI registration ID
+    pub fn pni_registration_id(&self) -> u32 {
+        self.pni.registration_id
+    }
+
+    /// Generate identity key pair
+    pub fn generate_identity_key_pair(&self) -> Result<(Vec<u8>, Vec<u8>)> {
+        let key_pair = IdentityKeyPair::generate();
+        let public_key = key_pair.public_key().as_bytes().to_vec();
+        let private_key = key_pair.private_key_bytes().to_vec();
+
+        tracing::info!("Generated identity key pair");
+
+        Ok((public_key, private_key))
+    }
+
+    /// Generate a batch of pre-keys
+    pub fn generate_pre_keys(&mut self, count: u32) -> Result<Vec<(u32, Vec<u8>)>> {
+        Ok(self.aci.generate_pre_keys(count))
+    }
+
+    /// Generate a batch of pre-keys for our PNI
+    pub fn generate_pni_pre_keys(&mut self, count: u32) -> Result<Vec<(u32, Vec<u8>)>> {
+        Ok(self.pni.generate_pre_keys(count))
+    }
+
+    /// Generate a signed pre-key
+    pub fn generate_signed_pre_key(&mut self, id: u32) -> Result<(Vec<u8>, Vec<u8>)> {
+        Ok(self.aci.generate_signed_pre_key(id))
+    }
+
+    /// Generate a signed pre-key for our PNI
+    pub fn generate_pni_signed_pre_key(&mut self, id: u32) -> Result<(Vec<u8>, Vec<u8>)> {
+        Ok(self.pni.generate_signed_pre_key(id))
+    }
+
+    /// Get the current signed pre-key for publishing
+    pub fn get_signed_pre_key(&self) -> Option<&SignedPreKey> {
+        self.aci.signed_pre_key.as_ref()
+    }
+
+    /// Create our pre-key bundle for publishing to the server
+    pub fn create_pre_key_bundle(&self, device_id: u32) -> Result<PreKeyBundle> {
+        self.aci.create_pre_key_bundle(device_id)
+    }
+
+    /// Create our PNI pre-key bundle for publishing to the server
+    pub fn create_pni_pre_key_bundle(&self, device_id: u32) -> Result<PreKeyBundle> {
+        self.pni.create_pre_key_bundle(device_id)
+    }
 
     /// Process a pre-key bundle to establish a session
     pub async fn process_pre_key_bundle(
@@ -210,14 +386,14 @@ impl SignalProtocol {
         bundle.verify()?;
 
         // Check if we trust this identity
-        if let Some(trusted) = self.trusted_identities.get(&address.name) {
+        if let Some(trusted) = self.trusted_identities.get(&address.service_id) {
             if trusted.as_bytes() != bundle.identity_key.as_bytes() {
-                return Err(anyhow!("Identity key mismatch for {}", address.name));
+                return Err(anyhow!("Identity key mismatch for {}", address.service_id));
             }
         }
 
         // Perform X3DH key agreement
-        let x3dh_result = x3dh_initiate(&self.identity_key, bundle)?;
+        let x3dh_result = x3dh_initiate(&self.aci.identity_key, bundle)?;
 
         // Initialize the Double Ratchet session
         let our_ratchet_key = DhKeyPair::generate();
@@ -229,7 +405,10 @@ impl SignalProtocol {
 
         // Store the session
         let mut sessions = self.sessions.write().await;
-        sessions.insert(address.clone(), session);
+        sessions
+            .entry(address.clone())
+            .or_default()
+            .promote_state(session);
 
         tracing::info!("Established session with {}", address.to_string());
 
@@ -242,9 +421,10 @@ impl SignalProtocol {
 
         let session = sessions
             .get_mut(address)
+            .and_then(|record| record.current.as_mut())
             .ok_or_else(|| anyhow!("No session for {}", address.to_string()))?;
 
-        let message = session.encrypt(plaintext)?;
+        let message = session.encrypt(&pad_plaintext(plaintext))?;
 
         tracing::debug!("Encrypted message for {}", address.to_string());
 
@@ -259,7 +439,7 @@ impl SignalProtocol {
         plaintext: &[u8],
     ) -> Result<Vec<u8>> {
         // Perform X3DH key agreement
-        let x3dh_result = x3dh_initiate(&self.identity_key, bundle)?;
+        let x3dh_result = x3dh_initiate(&self.aci.identity_key, bundle)?;
 
         // Initialize session
         let our_ratchet_key = DhKeyPair::generate();
@@ -269,18 +449,21 @@ impl SignalProtocol {
             &bundle.signed_pre_key_public,
         )?;
 
-        // Encrypt the plaintext
-        let encrypted_message = session.encrypt(plaintext)?;
+        // Encrypt the padded plaintext
+        let encrypted_message = session.encrypt(&pad_plaintext(plaintext))?;
 
         // Store the session
         {
             let mut sessions = self.sessions.write().await;
-            sessions.insert(address.clone(), session);
+            sessions
+                .entry(address.clone())
+                .or_default()
+                .promote_state(session);
         }
 
         // Create initial message with X3DH data
         let initial = InitialMessage::new(
-            self.identity_key.public_key(),
+            self.aci.identity_key.public_key(),
             x3dh_result.ephemeral_public_key,
             x3dh_result.used_pre_key_id,
             bundle.signed_pre_key_id,
@@ -295,32 +478,42 @@ impl SignalProtocol {
         Ok(initial.serialize())
     }
 
-    /// Decrypt a message from a sender
-    pub async fn decrypt(&self, address: &ProtocolAddress, ciphertext: &[u8]) -> Result<Vec<u8>> {
-        let mut sessions = self.sessions.write().await;
+    /// Decrypt a message from a sender, sent to one of our identities
+    pub async fn decrypt(
+        &self,
+        destination: ServiceIdKind,
+        address: &ProtocolAddress,
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>> {
+        let mut sessions = self.sessions(destination).write().await;
 
-        let session = sessions
+        let record = sessions
             .get_mut(address)
             .ok_or_else(|| anyhow!("No session for {}", address.to_string()))?;
 
         let message = RatchetMessage::deserialize(ciphertext)?;
-        let plaintext = session.decrypt(&message)?;
+        let padded = record.decrypt(&message)?;
+        let plaintext = unpad_plaintext(&padded)?;
 
         tracing::debug!("Decrypted message from {}", address.to_string());
 
-        Ok(plaintext)
+        Ok(plaintext.to_vec())
     }
 
     /// Decrypt an initial message (first message in a conversation)
     pub async fn decrypt_initial(
         &mut self,
+        destination: ServiceIdKind,
         address: &ProtocolAddress,
         ciphertext: &[u8],
     ) -> Result<Vec<u8>> {
         let initial = InitialMessage::deserialize(ciphertext)?;
 
+        // The sender used the bundle of whichever identity they addressed
+        let keys = self.keys(destination);
+
         // Get our signed pre-key
-        let signed_pre_key = self
+        let signed_pre_key = keys
             .signed_pre_key
             .as_ref()
             .ok_or_else(|| anyhow!("No signed pre-key"))?;
@@ -331,14 +524,14 @@ impl SignalProtocol {
 
         // Get our one-time pre-key if used
         let one_time_pre_key = if let Some(id) = initial.pre_key_id {
-            self.pre_keys.get(&id).map(|pk| &pk.key_pair)
+            keys.pre_keys.get(&id).map(|pk| &pk.key_pair)
         } else {
             None
         };
 
         // Perform X3DH key agreement (Bob's side)
         let shared_secret = x3dh_respond(
-            &self.identity_key,
+            &keys.identity_key,
             &signed_pre_key.key_pair,
             one_time_pre_key,
             &initial.identity_key,
@@ -351,19 +544,23 @@ impl SignalProtocol {
 
         // Decrypt the initial message
         let ratchet_message = RatchetMessage::deserialize(&initial.encrypted_message)?;
-        let plaintext = session.decrypt(&ratchet_message)?;
+        let padded = session.decrypt(&ratchet_message)?;
+        let plaintext = unpad_plaintext(&padded)?.to_vec();
 
         // Store session and trust identity
         {
-            let mut sessions = self.sessions.write().await;
-            sessions.insert(address.clone(), session);
+            let mut sessions = self.sessions(destination).write().await;
+            sessions
+                .entry(address.clone())
+                .or_default()
+                .promote_state(session);
         }
         self.trusted_identities
-            .insert(address.name.clone(), initial.identity_key.clone());
+            .insert(address.service_id, initial.identity_key.clone());
 
         // Remove used one-time pre-key
         if let Some(id) = initial.pre_key_id {
-            self.pre_keys.remove(&id);
+            self.keys_mut(destination).pre_keys.remove(&id);
         }
 
         tracing::info!(
@@ -374,10 +571,46 @@ impl SignalProtocol {
         Ok(plaintext)
     }
 
-    /// Check if we have a session with an address
+    /// Archive the session with an address
+    ///
+    /// The next message to the address starts a new session from a fresh
+    /// pre-key bundle, while late messages on the archived session can
+    /// still be decrypted. Returns whether a session was current.
+    pub async fn archive_session(&self, address: &ProtocolAddress) -> bool {
+        let mut sessions = self.sessions.write().await;
+        let archived = sessions
+            .get_mut(address)
+            .is_some_and(SessionRecord::archive_current_state);
+
+        if archived {
+            tracing::info!("Archived session with {}", address.to_string());
+        }
+
+        archived
+    }
+
+    /// Get our current ratchet public key in the session with an address
+    pub async fn session_ratchet_key(&self, address: &ProtocolAddress) -> Option<Vec<u8>> {
+        let sessions = self.sessions.read().await;
+        sessions
+            .get(address)
+            .and_then(|record| record.current.as_ref())
+            .map(|session| session.our_ratchet_key().as_bytes().to_vec())
+    }
+
+    /// Get the sender's ratchet public key from a regular ciphertext
+    pub fn message_ratchet_key(ciphertext: &[u8]) -> Option<Vec<u8>> {
+        RatchetMessage::deserialize(ciphertext)
+            .ok()
+            .map(|message| message.header.dh_ratchet_key.as_bytes().to_vec())
+    }
+
+    /// Check if we have a current session with an address
     pub async fn has_session(&self, address: &ProtocolAddress) -> bool {
         let sessions = self.sessions.read().await;
-        sessions.contains_key(address)
+        sessions
+            .get(address)
+            .is_some_and(|record| record.current.is_some())
     }
 
     /// Get session state for serialization
@@ -385,6 +618,7 @@ impl SignalProtocol {
         let sessions = self.sessions.read().await;
         sessions
             .get(address)
+            .and_then(|record| record.current.as_ref())
             .and_then(|s| s.serialize().ok())
     }
 
@@ -392,35 +626,39 @@ impl SignalProtocol {
     pub async fn restore_session(&self, address: &ProtocolAddress, data: &[u8]) -> Result<()> {
         let session = SessionState::deserialize(data)?;
         let mut sessions = self.sessions.write().await;
-        sessions.insert(address.clone(), session);
+        sessions.entry(address.clone()).or_default().current = Some(session);
         Ok(())
     }
 
     /// Get safety number for verification
-    pub fn get_safety_number(&self, local_id: &str, remote_id: &str) -> Result<String> {
+    pub fn get_safety_number(&self, local_id: &Aci, remote_id: &ServiceId) -> Result<String> {
         let remote_identity = self
             .trusted_identities
             .get(remote_id)
             .ok_or_else(|| anyhow!("No trusted identity for {}", remote_id))?;
 
         let fingerprint = calculate_fingerprint(
-            &self.identity_key.public_key(),
-            local_id,
+            &self.aci.identity_key.public_key(),
+            &local_id.to_string(),
             remote_identity,
-            remote_id,
+            &remote_id.to_string(),
         );
 
         Ok(fingerprint)
     }
 
     /// Trust an identity key
-    pub fn trust_identity(&mut self, name: &str, identity_key: IdentityPublicKey) {
-        self.trusted_identities.insert(name.to_string(), identity_key);
+    pub fn trust_identity(&mut self, service_id: ServiceId, identity_key: IdentityPublicKey) {
+        self.trusted_identities.insert(service_id, identity_key);
     }
 
     /// Check if an identity is trusted
-    pub fn is_identity_trusted(&self, name: &str, identity_key: &IdentityPublicKey) -> bool {
-        if let Some(trusted) = self.trusted_identities.get(name) {
+    pub fn is_identity_trusted(
+        &self,
+        service_id: &ServiceId,
+        identity_key: &IdentityPublicKey,
+    ) -> bool {
+        if let Some(trusted) = self.trusted_identities.get(service_id) {
             trusted.as_bytes() == identity_key.as_bytes()
         } else {
             false
@@ -428,14 +666,18 @@ impl SignalProtocol {
     }
 
     /// Get number of available pre-keys
-    pub fn pre_key_count(&self) -> usize {
-        self.pre_keys.len()
+    pub fn pre_key_count(&self, kind: ServiceIdKind) -> usize {
+        self.keys(kind).pre_keys.len()
     }
 
-    /// Ensure we have enough pre-keys
-    pub fn refill_pre_keys_if_needed(&mut self) -> Result<Option<Vec<(u32, Vec<u8>)>>> {
-        if self.pre_keys.len() < 10 {
-            let new_keys = self.generate_pre_keys(PRE_KEY_BATCH_SIZE)?;
+    /// Ensure we have enough pre-keys for one of our identities
+    pub fn refill_pre_keys_if_needed(
+        &mut self,
+        kind: ServiceIdKind,
+    ) -> Result<Option<Vec<(u32, Vec<u8>)>>> {
+        let keys = self.keys_mut(kind);
+        if keys.pre_keys.len() < 10 {
+            let new_keys = keys.generate_pre_keys(PRE_KEY_BATCH_SIZE);
             Ok(Some(new_keys))
         } else {
             Ok(None)
@@ -449,9 +691,102 @@ impl Default for SignalProtocol {
     }
 }
 
+/// Pad a plaintext the way other Signal clients do
+///
+/// The plaintext is followed by a 0x80 terminator and zero bytes up to one
+/// byte short of a multiple of `PADDING_BLOCK_SIZE`, leaving the cipher room
+/// for a single byte of its own padding. Room is reserved for the terminator
+/// and that byte, so a 158-byte plaintext fills the first block and a
+/// 159-byte one spills into a second.
+fn pad_plaintext(plaintext: &[u8]) -> Vec<u8> {
+    let padded_len = ((plaintext.len() + 1) / PADDING_BLOCK_SIZE + 1) * PADDING_BLOCK_SIZE - 1;
+
+    let mut padded = Vec::with_capacity(padded_len);
+    padded.extend_from_slice(plaintext);
+    padded.push(PADDING_TERMINATOR);
+    padded.resize(padded_len, 0);
+    padded
+}
+
+/// Remove padding added by `pad_plaintext`
+///
+/// Rejects input that is not one of the padded lengths, lacks the
+/// terminator, or carries more than one block of padding.
+fn unpad_plaintext(padded: &[u8]) -> Result<&[u8]> {
+    if padded.len() % PADDING_BLOCK_SIZE != PADDING_BLOCK_SIZE - 1 {
+        return Err(anyhow!("Invalid padded length: {}", padded.len()));
+    }
+
+    let terminator = padded
+        .iter()
+        .rposition(|&b| b != 0)
+        .ok_or_else(|| anyhow!("Missing padding terminator"))?;
+
+    if padded[terminator] != PADDING_TERMINATOR {
+        return Err(anyhow!("Invalid padding terminator"));
+    }
+    if padded.len() - terminator > PADDING_BLOCK_SIZE {
+        return Err(anyhow!("Padding exceeds one block"));
+    }
+
+    Ok(&padded[..terminator])
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_address_string_round_trip() {
+        let pni = ServiceId::Pni(Uuid::new_v4().into());
+        let address = ProtocolAddress::new(pni, 3);
+
+        let encoded = address.to_string();
+        assert!(encoded.starts_with("PNI:"));
+        assert!(encoded.ends_with(".3"));
+        assert_eq!(ProtocolAddress::from_string(&encoded).unwrap(), address);
+        assert!(ProtocolAddress::from_string("alice.1").is_err());
+    }
+
+    #[test]
+    fn test_pni_identity_is_separate() {
+        let mut protocol = SignalProtocol::new().unwrap();
+        protocol.generate_signed_pre_key(1).unwrap();
+        protocol.generate_pni_pre_keys(5).unwrap();
+        protocol.generate_pni_signed_pre_key(1).unwrap();
+
+        assert_ne!(
+            protocol.identity_public_key().as_bytes(),
+            protocol.pni_identity_public_key().as_bytes()
+        );
+        assert_eq!(protocol.pre_key_count(ServiceIdKind::Aci), 0);
+        assert_eq!(protocol.pre_key_count(ServiceIdKind::Pni), 5);
+
+        let bundle = protocol.create_pni_pre_key_bundle(1).unwrap();
+        bundle.verify().unwrap();
+        assert_eq!(
+            bundle.identity_key.as_bytes(),
+            protocol.pni_identity_public_key().as_bytes()
+        );
+        assert!(bundle.pre_key_id.is_some());
+
+        // Restoring keeps the PNI key
+        let restored = SignalProtocol::from_identity(
+            &protocol.identity_private_key(),
+            protocol.registration_id(),
+        )
+        .unwrap()
+        .with_pni_identity(
+            &protocol.pni_identity_private_key(),
+            protocol.pni_registration_id(),
+        )
+        .unwrap();
+        assert_eq!(
+            restored.pni_identity_public_key().as_bytes(),
+            protocol.pni_identity_public_key().as_bytes()
+        );
+    }
 
     #[tokio::test]
     async fn test_session_establishment() {
@@ -467,7 +802,7 @@ mod tests {
         let bob_bundle = bob.create_pre_key_bundle(1).unwrap();
 
         // Alice establishes session with Bob
-        let bob_address = ProtocolAddress::new("bob", 1);
+        let bob_address = ProtocolAddress::new(Aci::from(Uuid::new_v4()), 1);
         alice
             .process_pre_key_bundle(&bob_address, &bob_bundle)
             .await
@@ -481,8 +816,8 @@ mod tests {
             .unwrap();
 
         // Bob decrypts the message
-        let alice_address = ProtocolAddress::new("alice", 1);
-        let decrypted = bob.decrypt_initial(&alice_address, &ciphertext).await.unwrap();
+        let alice_address = ProtocolAddress::new(Aci::from(Uuid::new_v4()), 1);
+        let decrypted = bob.decrypt_initial(ServiceIdKind::Aci, &alice_address, &ciphertext).await.unwrap();
 
         assert_eq!(plaintext.as_slice(), decrypted.as_slice());
     }
@@ -503,15 +838,15 @@ mod tests {
         let alice_bundle = alice.create_pre_key_bundle(1).unwrap();
         let bob_bundle = bob.create_pre_key_bundle(1).unwrap();
 
-        let alice_address = ProtocolAddress::new("alice", 1);
-        let bob_address = ProtocolAddress::new("bob", 1);
+        let alice_address = ProtocolAddress::new(Aci::from(Uuid::new_v4()), 1);
+        let bob_address = ProtocolAddress::new(Aci::from(Uuid::new_v4()), 1);
 
         // Alice initiates with Bob
         let msg1 = alice
             .encrypt_initial(&bob_address, &bob_bundle, b"Hello Bob!")
             .await
             .unwrap();
-        let decrypted1 = bob.decrypt_initial(&alice_address, &msg1).await.unwrap();
+        let decrypted1 = bob.decrypt_initial(ServiceIdKind::Aci, &alice_address, &msg1).await.unwrap();
         assert_eq!(b"Hello Bob!", decrypted1.as_slice());
 
         // Bob responds to Alice
@@ -519,12 +854,171 @@ mod tests {
             .encrypt_initial(&alice_address, &alice_bundle, b"Hi Alice!")
             .await
             .unwrap();
-        let decrypted2 = alice.decrypt_initial(&bob_address, &msg2).await.unwrap();
+        let decrypted2 = alice.decrypt_initial(ServiceIdKind::Aci, &bob_address, &msg2).await.unwrap();
         assert_eq!(b"Hi Alice!", decrypted2.as_slice());
 
         // Continue conversation with established sessions
         let msg3 = alice.encrypt(&bob_address, b"How are you?").await.unwrap();
-        let decrypted3 = bob.decrypt(&alice_address, &msg3).await.unwrap();
+        let decrypted3 = bob.decrypt(ServiceIdKind::Aci, &alice_address, &msg3).await.unwrap();
         assert_eq!(b"How are you?", decrypted3.as_slice());
     }
+
+    #[tokio::test]
+    async fn test_archive_session() {
+        let alice = SignalProtocol::new().unwrap();
+        let mut bob = SignalProtocol::new().unwrap();
+        bob.generate_pre_keys(1).unwrap();
+        bob.generate_signed_pre_key(1).unwrap();
+
+        let bob_address = ProtocolAddress::new(Aci::from(Uuid::new_v4()), 1);
+        let bundle = bob.create_pre_key_bundle(1).unwrap();
+        alice.process_pre_key_bundle(&bob_address, &bundle).await.unwrap();
+
+        // Our ratchet key is what a failed message from us would carry
+        let ratchet_key = alice.session_ratchet_key(&bob_address).await.unwrap();
+        let ciphertext = alice.encrypt(&bob_address, b"Hi").await.unwrap();
+        assert_eq!(
+            SignalProtocol::message_ratchet_key(&ciphertext),
+            Some(ratchet_key)
+        );
+
+        assert!(alice.archive_session(&bob_address).await);
+        assert!(!alice.has_session(&bob_address).await);
+        assert!(!alice.archive_session(&bob_address).await);
+    }
+
+    #[tokio::test]
+    async fn test_archived_session_decrypts_late_messages() {
+        let bob = SignalProtocol::new().unwrap();
+        let alice_address = ProtocolAddress::new(Aci::from(Uuid::new_v4()), 1);
+
+        // Session pairs as agreed by X3DH
+        let session_pair = |secret: [u8; 32]| {
+            let bob_ratchet = DhKeyPair::generate();
+            let alice = SessionState::initialize_alice(
+                &secret,
+                DhKeyPair::generate(),
+                bob_ratchet.public_key(),
+            )
+            .unwrap();
+            (alice, SessionState::initialize_bob(&secret, bob_ratchet))
+        };
+        let (mut alice_old, bob_old) = session_pair([1; 32]);
+        let (mut alice_new, bob_new) = session_pair([2; 32]);
+
+        bob.restore_session(&alice_address, &bob_old.serialize().unwrap())
+            .await
+            .unwrap();
+        let late = alice_old
+            .encrypt(&pad_plaintext(b"late"))
+            .unwrap()
+            .serialize();
+
+        // A new session replaces the old one before the late message arrives
+        assert!(bob.archive_session(&alice_address).await);
+        bob.restore_session(&alice_address, &bob_new.serialize().unwrap())
+            .await
+            .unwrap();
+        let current = alice_new
+            .encrypt(&pad_plaintext(b"current"))
+            .unwrap()
+            .serialize();
+
+        let decrypted = bob
+            .decrypt(ServiceIdKind::Aci, &alice_address, &late)
+            .await
+            .unwrap();
+        assert_eq!(decrypted, b"late");
+
+        // The archived session is current again, the new one archived
+        let decrypted = bob
+            .decrypt(ServiceIdKind::Aci, &alice_address, &current)
+            .await
+            .unwrap();
+        assert_eq!(decrypted, b"current");
+
+        // Garbage decrypts with neither and leaves both sessions intact
+        let mut garbage = alice_new.encrypt(&pad_plaintext(b"x")).unwrap();
+        garbage.ciphertext[0] ^= 1;
+        assert!(bob
+            .decrypt(ServiceIdKind::Aci, &alice_address, &garbage.serialize())
+            .await
+            .is_err());
+        assert!(bob.has_session(&alice_address).await);
+    }
+
+    #[test]
+    fn test_padding_known_vectors() {
+        // Lengths and layout as produced by the Android and Desktop clients
+        // (PushTransportDetails / padMessage)
+        let mut expected = vec![0u8; 159];
+        expected[0] = 0x80;
+        assert_eq!(pad_plaintext(&[]), expected);
+
+        let mut expected = b"Hi".to_vec();
+        expected.push(0x80);
+        expected.resize(159, 0);
+        assert_eq!(pad_plaintext(b"Hi"), expected);
+        assert_eq!(unpad_plaintext(&expected).unwrap(), b"Hi");
+
+        // 158 bytes and the terminator fill the first block
+        let padded = pad_plaintext(&[0x41; 158]);
+        assert_eq!(padded.len(), 159);
+        assert_eq!(padded[157], 0x41);
+        assert_eq!(padded[158], 0x80);
+
+        // One more byte spills into a second block
+        let padded = pad_plaintext(&[0x41; 159]);
+        assert_eq!(padded.len(), 319);
+        assert_eq!(padded[159], 0x80);
+        assert!(padded[160..].iter().all(|&b| b == 0));
+
+        let padded = pad_plaintext(&[0x41; 318]);
+        assert_eq!(padded.len(), 319);
+        assert_eq!(pad_plaintext(&[0x41; 319]).len(), 479);
+    }
+
+    #[test]
+    fn test_padding_round_trip() {
+        for len in [0, 1, 157, 158, 159, 160, 318, 319, 1000] {
+            // Trailing zeros and 0x80 bytes in the plaintext must survive
+            let plaintext: Vec<u8> = (0..len).map(|i| [0x00, 0x80, 0x7f][i % 3]).collect();
+            let padded = pad_plaintext(&plaintext);
+
+            assert_eq!(padded.len() % 160, 159);
+            assert_eq!(unpad_plaintext(&padded).unwrap(), plaintext.as_slice());
+        }
+    }
+
+    #[test]
+    fn test_unpad_rejects_malformed_padding() {
+        assert!(unpad_plaintext(&[]).is_err());
+
+        // All zeros, no terminator
+        assert!(unpad_plaintext(&[0u8; 159]).is_err());
+
+        // Wrong terminator byte
+        let mut padded = pad_plaintext(b"Hi");
+        padded[2] = 0x81;
+        assert!(unpad_plaintext(&padded).is_err());
+
+        // Garbage after the terminator
+        let mut padded = pad_plaintext(b"Hi");
+        padded[100] = 0x01;
+        assert!(unpad_plaintext(&padded).is_err());
+
+        // Unpadded plaintext
+        assert!(unpad_plaintext(&[0x41; 159]).is_err());
+
+        // Lengths pad_plaintext does not produce
+        assert!(unpad_plaintext(b"Hi\x80").is_err());
+        let mut padded = pad_plaintext(b"Hi");
+        padded.push(0);
+        assert!(unpad_plaintext(&padded).is_err());
+
+        // More than a block of padding
+        let mut padded = vec![0u8; 319];
+        padded[0] = 0x80;
+        assert!(unpad_plaintext(&padded).is_err());
+    }
 }
@@ -0,0 +1,287 @@
+//! Service identifiers
+//!
+//! Signal accounts have two service IDs: the ACI (account identity), which
+//! never changes, and the PNI (phone number identity), which belongs to the
+//! phone number and moves with it. Each has its own identity key.
+//!
+//! String form: an ACI is a bare UUID, a PNI carries a `PNI:` prefix.
+//! Binary form: an ACI is the 16 UUID bytes, a PNI is prefixed with a kind
+//! byte of 0x01. The fixed-width binary form always has the kind byte.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// String prefix of a PNI
+const PNI_PREFIX: &str = "PNI:";
+
+/// Kind byte of an ACI in the fixed-width binary form
+const ACI_KIND_BYTE: u8 = 0x00;
+
+/// Kind byte of a PNI in the binary forms
+const PNI_KIND_BYTE: u8 = 0x01;
+
+/// Which of the two identities a service ID refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ServiceIdKind {
+    Aci,
+    Pni,
+}
+
+/// Account identity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Aci(Uuid);
+
+/// Phone number identity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Pni(Uuid);
+
+/// Either identity of an account
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum ServiceId {
+    Aci(Aci),
+    Pni(Pni),
+}
+
+impl Aci {
+    pub fn uuid(&self) -> Uuid {
+        self.0
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.0.is_nil()
+    }
+}
+
+impl Pni {
+    pub fn uuid(&self) -> Uuid {
+        self.0
+    }
+}
+
+impl From<Uuid> for Aci {
+    fn from(uuid: Uuid) -> Self {
+        Self(uuid)
+    }
+}
+
+impl From<Uuid> for Pni {
+    fn from(uuid: Uuid) -> Self {
+        Self(uuid)
+    }
+}
+
+impl ServiceId {
+    pub fn kind(&self) -> ServiceIdKind {
+        match self {
+            ServiceId::Aci(_) => ServiceIdKind::Aci,
+            ServiceId::Pni(_) => ServiceIdKind::Pni,
+        }
+    }
+
+    pub fn uuid(&self) -> Uuid {
+        match self {
+            ServiceId::Aci(aci) => aci.uuid(),
+            ServiceId::Pni(pni) => pni.uuid(),
+        }
+    }
+
+    pub fn aci(&self) -> Option<Aci> {
+        match self {
+            ServiceId::Aci(aci) => Some(*aci),
+            ServiceId::Pni(_) => None,
+        }
+    }
+
+    pub fn pni(&self) -> Option<Pni> {
+        match self {
+            ServiceId::Pni(pni) => Some(*pni),
+            ServiceId::Aci(_) => None,
+        }
+    }
+
+    /// Variable-width binary form: 16 bytes for an ACI, 17 for a PNI
+    pub fn to_binary(&self) -> Vec<u8> {
+        match self {
+            ServiceId::Aci(aci) => aci.0.as_bytes().to_vec(),
+            ServiceId::Pni(_) => self.to_fixed_width_binary().to_vec(),
+        }
+    }
+
+    /// Fixed-width binary form: kind byte followed by the 16 UUID bytes
+    pub fn to_fixed_width_binary(&self) -> [u8; 17] {
+        let mut bytes = [0u8; 17];
+        bytes[0] = match self.kind() {
+            ServiceIdKind::Aci => ACI_KIND_BYTE,
+            ServiceIdKind::Pni => PNI_KIND_BYTE,
+        };
+        bytes[1..].copy_from_slice(self.uuid().as_bytes());
+        bytes
+    }
+
+    /// Parse either binary form
+    pub fn from_binary(bytes: &[u8]) -> Result<Self> {
+        match bytes.len() {
+            16 => Ok(ServiceId::Aci(Aci(Uuid::from_slice(bytes)?))),
+            17 => {
+                let uuid = Uuid::from_slice(&bytes[1..])?;
+                match bytes[0] {
+                    ACI_KIND_BYTE => Ok(ServiceId::Aci(Aci(uuid))),
+                    PNI_KIND_BYTE => Ok(ServiceId::Pni(Pni(uuid))),
+                    kind => Err(anyhow!("Unknown service ID kind: {}", kind)),
+                }
+            }
+            len => Err(anyhow!("Invalid service ID length: {}", len)),
+        }
+    }
+}
+
+impl From<Aci> for ServiceId {
+    fn from(aci: Aci) -> Self {
+        ServiceId::Aci(aci)
+    }
+}
+
+impl From<Pni> for ServiceId {
+    fn from(pni: Pni) -> Self {
+        ServiceId::Pni(pni)
+    }
+}
+
+impl fmt::Display for Aci {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Display for Pni {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", PNI_PREFIX, self.0)
+    }
+}
+
+impl fmt::Display for ServiceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServiceId::Aci(aci) => aci.fmt(f),
+            ServiceId::Pni(pni) => pni.fmt(f),
+        }
+    }
+}
+
+impl FromStr for ServiceId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.strip_prefix(PNI_PREFIX) {
+            Some(uuid) => Ok(ServiceId::Pni(Pni(uuid.parse()?))),
+            None => Ok(ServiceId::Aci(Aci(s.parse()?))),
+        }
+    }
+}
+
+impl FromStr for Aci {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        s.parse::<ServiceId>()?
+            .aci()
+            .ok_or_else(|| anyhow!("Expected an ACI, got {}", s))
+    }
+}
+
+impl FromStr for Pni {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        s.parse::<ServiceId>()?
+            .pni()
+            .ok_or_else(|| anyhow!("Expected a PNI, got {}", s))
+    }
+}
+
+macro_rules! string_conversions {
+    ($($ty:ty),*) => {$(
+        impl TryFrom<String> for $ty {
+            type Error = anyhow::Error;
+
+            fn try_from(s: String) -> Result<Self> {
+                s.parse()
+            }
+        }
+
+        impl From<$ty> for String {
+            fn from(id: $ty) -> String {
+                id.to_string()
+            }
+        }
+    )*};
+}
+
+string_conversions!(Aci, Pni, ServiceId);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UUID: &str = "9d0652a3-dcc3-4d11-975f-74d61598733f";
+
+    #[test]
+    fn test_string_encoding() {
+        let aci: ServiceId = UUID.parse().unwrap();
+        assert_eq!(aci.kind(), ServiceIdKind::Aci);
+        assert_eq!(aci.to_string(), UUID);
+
+        let pni: ServiceId = format!("PNI:{}", UUID).parse().unwrap();
+        assert_eq!(pni.kind(), ServiceIdKind::Pni);
+        assert_eq!(pni.to_string(), format!("PNI:{}", UUID));
+        assert_eq!(aci.uuid(), pni.uuid());
+        assert_ne!(aci, pni);
+
+        assert!(UUID.parse::<Pni>().is_err());
+        assert!(format!("PNI:{}", UUID).parse::<Aci>().is_err());
+        assert!("ACI:nonsense".parse::<ServiceId>().is_err());
+    }
+
+    #[test]
+    fn test_binary_encoding() {
+        let uuid: Uuid = UUID.parse().unwrap();
+        let aci = ServiceId::from(Aci::from(uuid));
+        let pni = ServiceId::from(Pni::from(uuid));
+
+        assert_eq!(aci.to_binary(), uuid.as_bytes().to_vec());
+        let mut expected = vec![0x01];
+        expected.extend_from_slice(uuid.as_bytes());
+        assert_eq!(pni.to_binary(), expected);
+
+        assert_eq!(aci.to_fixed_width_binary()[0], 0x00);
+        assert_eq!(pni.to_fixed_width_binary()[0], 0x01);
+
+        for id in [aci, pni] {
+            assert_eq!(ServiceId::from_binary(&id.to_binary()).unwrap(), id);
+            assert_eq!(
+                ServiceId::from_binary(&id.to_fixed_width_binary()).unwrap(),
+                id
+            );
+        }
+
+        let mut bad_kind = pni.to_fixed_width_binary();
+        bad_kind[0] = 0x02;
+        assert!(ServiceId::from_binary(&bad_kind).is_err());
+        assert!(ServiceId::from_binary(&[0u8; 15]).is_err());
+    }
+
+    #[test]
+    fn test_serde_uses_string_form() {
+        let pni: ServiceId = format!("PNI:{}", UUID).parse().unwrap();
+        let json = serde_json::to_string(&pni).unwrap();
+
+        assert_eq!(json, format!("\"PNI:{}\"", UUID));
+        assert_eq!(serde_json::from_str::<ServiceId>(&json).unwrap(), pni);
+    }
+}
@@ -0,0 +1,720 @@
+//! Storage service client
+//!
+//! The storage service keeps account state shared by all of an account's
+//! devices, such as contact names, blocks and settings, as encrypted
+//! records listed by an encrypted manifest. Every write replaces the
+//! manifest with one of the next version; a write based on an outdated
+//! version is refused, and the writer must merge the newer manifest and
+//! try again.
+//!
+//! Keys are derived from the storage key, itself derived from the
+//! account's master key:
+//!
+//! - manifest key: `HMAC-SHA256(storage_key, "Manifest_" || version)`
+//! - record key: `HMAC-SHA256(storage_key, "Item_" || base64(id))`, or
+//!   HKDF over the manifest's record key material when it has some
+//!
+//! Manifest and records are AES-256-GCM encrypted as `nonce || ciphertext`.
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::Buf;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use prost::encoding::{decode_key, decode_varint, skip_field, DecodeContext, WireType};
+use prost::Message as _;
+use rand::RngCore;
+use serde::Deserialize;
+use sha2::Sha256;
+
+use super::crypto::{SignalCipher, NONCE_SIZE};
+use super::proto;
+use super::types::*;
+
+use proto::manifest_record::identifier::Type as IdentifierType;
+
+/// `ManifestRecord.Identifier.Type` of contact records
+pub const CONTACT_RECORD: i32 = IdentifierType::Contact as i32;
+/// `ManifestRecord.Identifier.Type` of group records
+pub const GROUP_V2_RECORD: i32 = IdentifierType::Groupv2 as i32;
+/// `ManifestRecord.Identifier.Type` of the account record
+pub const ACCOUNT_RECORD: i32 = IdentifierType::Account as i32;
+
+/// Size of the random raw ID of a new record
+const STORAGE_ID_SIZE: usize = 16;
+
+/// HKDF info prefix of record keys derived from the record key material
+const RECORD_KEY_INFO: &[u8] = b"20240801_SIGNAL_STORAGE_SERVICE_ITEM_";
+
+/// Fields of `ContactRecord` we model; others are kept on write
+const CONTACT_FIELDS: &[u32] = &[1, 2, 3, 6, 7, 8, 9, 10, 11, 12, 13, 15, 17, 18, 22];
+/// Fields of `GroupV2Record` we model
+const GROUP_V2_FIELDS: &[u32] = &[1, 2, 3, 4, 5, 6];
+/// Fields of `AccountRecord` we model
+const ACCOUNT_FIELDS: &[u32] = &[1, 2, 3, 4, 5, 6, 7, 8, 11, 14];
+
+/// Storage service credentials handed out by the chat service
+#[derive(Debug, Clone, Deserialize)]
+pub struct StorageCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// Derive the storage key from the account's master key
+pub fn derive_storage_key(master_key: &[u8]) -> [u8; 32] {
+    hmac_sha256(master_key, b"Storage Service Encryption")
+}
+
+/// The storage key of the account, if the primary device shared one
+///
+/// Primaries predating the master key send the storage key itself.
+pub fn storage_key(keys: &AccountKeys) -> Option<[u8; 32]> {
+    if let Some(master) = &keys.master {
+        return Some(derive_storage_key(master));
+    }
+
+    keys.storage_service
+        .as_deref()
+        .and_then(|key| key.try_into().ok())
+}
+
+/// Identifier for a new record of `record_type`
+pub fn new_storage_id(record_type: i32) -> StorageId {
+    let mut raw = vec![0u8; STORAGE_ID_SIZE];
+    rand::rngs::OsRng.fill_bytes(&mut raw);
+    StorageId { raw, record_type }
+}
+
+/// Manifest type of a record
+pub fn record_type(record: &StorageRecord) -> i32 {
+    match record {
+        StorageRecord::Contact(_) => CONTACT_RECORD,
+        StorageRecord::GroupV2(_) => GROUP_V2_RECORD,
+        StorageRecord::Account(_) => ACCOUNT_RECORD,
+    }
+}
+
+/// Encrypt a manifest for upload
+pub fn encrypt_manifest(
+    storage_key: &[u8; 32],
+    manifest: &StorageManifest,
+) -> Result<proto::StorageManifest> {
+    let record = proto::ManifestRecord {
+        version: Some(manifest.version),
+        identifiers: manifest
+            .identifiers
+            .iter()
+            .map(|id| proto::manifest_record::Identifier {
+                raw: Some(id.raw.clone()),
+                r#type: Some(id.record_type),
+            })
+            .collect(),
+        source_device: None,
+        record_ikm: manifest.record_ikm.clone(),
+    };
+
+    let key = manifest_key(storage_key, manifest.version);
+    Ok(proto::StorageManifest {
+        version: Some(manifest.version),
+        value: Some(encrypt(&key, &record.encode_to_vec())?),
+    })
+}
+
+/// Decrypt a manifest fetched from the server
+pub fn decrypt_manifest(
+    storage_key: &[u8; 32],
+    manifest: &proto::StorageManifest,
+) -> Result<StorageManifest> {
+    let version = manifest.version.unwrap_or(0);
+    let key = manifest_key(storage_key, version);
+    let plaintext = decrypt(&key, manifest.value.as_deref().unwrap_or_default())
+        .map_err(|_| anyhow!("Could not decrypt storage manifest {}", version))?;
+    let record = proto::ManifestRecord::decode(plaintext.as_slice())?;
+
+    if record.version != Some(version) {
+        return Err(anyhow!(
+            "Storage manifest {} holds version {:?}",
+            version,
+            record.version
+        ));
+    }
+
+    Ok(StorageManifest {
+        version,
+        identifiers: record
+            .identifiers
+            .into_iter()
+            .filter_map(|id| {
+                Some(StorageId {
+                    raw: id.raw.filter(|raw| !raw.is_empty())?,
+                    record_type: id.r#type.unwrap_or(0),
+                })
+            })
+            .collect(),
+        record_ikm: record.record_ikm.filter(|ikm| !ikm.is_empty()),
+    })
+}
+
+/// Encrypt a serialized `StorageRecord` for upload under `id`
+pub fn encrypt_record(
+    storage_key: &[u8; 32],
+    manifest: &StorageManifest,
+    id: &StorageId,
+    plaintext: &[u8],
+) -> Result<Vec<u8>> {
+    encrypt(&record_key(storage_key, manifest, &id.raw), plaintext)
+}
+
+/// Decrypt a record fetched from the server into a serialized `StorageRecord`
+pub fn decrypt_record(
+    storage_key: &[u8; 32],
+    manifest: &StorageManifest,
+    id: &StorageId,
+    ciphertext: &[u8],
+) -> Result<Vec<u8>> {
+    decrypt(&record_key(storage_key, manifest, &id.raw), ciphertext)
+        .map_err(|_| anyhow!("Could not decrypt storage record {}", hex::encode(&id.raw)))
+}
+
+/// Decode a serialized `StorageRecord`
+///
+/// Returns `None` for kinds of records we do not model.
+pub fn decode_record(plaintext: &[u8]) -> Result<Option<StorageRecord>> {
+    use proto::storage_record::Record;
+
+    let record = proto::StorageRecord::decode(plaintext)?;
+    let non_empty = |s: Option<String>| s.filter(|s| !s.is_empty());
+    let muted_until = |timestamp: Option<u64>| timestamp.filter(|t| *t > 0).map(|t| t as i64);
+
+    Ok(match record.record {
+        Some(Record::Contact(contact)) => {
+            let nickname = contact.nickname.unwrap_or_default();
+            Some(StorageRecord::Contact(ContactRecord {
+                aci: contact.aci.as_deref().and_then(|aci| aci.parse().ok()),
+                pni: contact.pni.as_deref().and_then(parse_record_pni),
+                e164: non_empty(contact.e164),
+                profile_key: contact.profile_key.filter(|key| !key.is_empty()),
+                given_name: non_empty(contact.given_name),
+                family_name: non_empty(contact.family_name),
+                system_given_name: non_empty(contact.system_given_name),
+                system_family_name: non_empty(contact.system_family_name),
+                nickname_given_name: non_empty(nickname.given),
+                nickname_family_name: non_empty(nickname.family),
+                username: non_empty(contact.username),
+                blocked: contact.blocked.unwrap_or(false),
+                whitelisted: contact.whitelisted.unwrap_or(false),
+                archived: contact.archived.unwrap_or(false),
+                marked_unread: contact.marked_unread.unwrap_or(false),
+                muted_until: muted_until(contact.muted_until_timestamp),
+            }))
+        }
+        Some(Record::GroupV2(group)) => Some(StorageRecord::GroupV2(GroupV2Record {
+            master_key: group
+                .master_key
+                .ok_or_else(|| anyhow!("Group record without a master key"))?,
+            blocked: group.blocked.unwrap_or(false),
+            whitelisted: group.whitelisted.unwrap_or(false),
+            archived: group.archived.unwrap_or(false),
+            marked_unread: group.marked_unread.unwrap_or(false),
+            muted_until: muted_until(group.muted_until_timestamp),
+        })),
+        Some(Record::Account(account)) => Some(StorageRecord::Account(AccountRecord {
+            profile_key: account.profile_key.filter(|key| !key.is_empty()),
+            given_name: non_empty(account.given_name),
+            family_name: non_empty(account.family_name),
+            avatar_url: non_empty(account.avatar_url),
+            note_to_self_archived: account.note_to_self_archived.unwrap_or(false),
+            read_receipts: account.read_receipts.unwrap_or(false),
+            sealed_sender_indicators: account.sealed_sender_indicators.unwrap_or(false),
+            typing_indicators: account.typing_indicators.unwrap_or(false),
+            link_previews: account.link_previews.unwrap_or(false),
+            pinned_conversations: account
+                .pinned_conversations
+                .into_iter()
+                .filter_map(decode_pinned_conversation)
+                .collect(),
+        })),
+        None => None,
+    })
+}
+
+/// Serialize a `StorageRecord`
+///
+/// Fields of `previous`, the record being replaced, that we do not model
+/// are carried over so writing a record never drops what newer clients
+/// keep in it.
+pub fn encode_record(record: &StorageRecord, previous: Option<&[u8]>) -> Result<Vec<u8>> {
+    let (field, known, mut encoded) = match record {
+        StorageRecord::Contact(contact) => {
+            let nickname = (contact.nickname_given_name.is_some()
+                || contact.nickname_family_name.is_some())
+            .then(|| proto::contact_record::Name {
+                given: contact.nickname_given_name.clone(),
+                family: contact.nickname_family_name.clone(),
+            });
+            let contact = proto::ContactRecord {
+                aci: contact.aci.map(|aci| aci.to_string()),
+                e164: contact.e164.clone(),
+                profile_key: contact.profile_key.clone(),
+                given_name: contact.given_name.clone(),
+                family_name: contact.family_name.clone(),
+                username: contact.username.clone(),
+                blocked: Some(contact.blocked),
+                whitelisted: Some(contact.whitelisted),
+                archived: Some(contact.archived),
+                marked_unread: Some(contact.marked_unread),
+                muted_until_timestamp: contact.muted_until.map(|t| t as u64),
+                pni: contact.pni.map(|pni| pni.uuid().to_string()),
+                system_given_name: contact.system_given_name.clone(),
+                system_family_name: contact.system_family_name.clone(),
+                nickname,
+            };
+            (1, CONTACT_FIELDS, contact.encode_to_vec())
+        }
+        StorageRecord::GroupV2(group) => {
+            let group = proto::GroupV2Record {
+                master_key: Some(group.master_key.clone()),
+                blocked: Some(group.blocked),
+                whitelisted: Some(group.whitelisted),
+                archived: Some(group.archived),
+                marked_unread: Some(group.marked_unread),
+                muted_until_timestamp: group.muted_until.map(|t| t as u64),
+            };
+            (3, GROUP_V2_FIELDS, group.encode_to_vec())
+        }
+        StorageRecord::Account(account) => {
+            let account = proto::AccountRecord {
+                profile_key: account.profile_key.clone(),
+                given_name: account.given_name.clone(),
+                family_name: account.family_name.clone(),
+                avatar_url: account.avatar_url.clone(),
+                note_to_self_archived: Some(account.note_to_self_archived),
+                read_receipts: Some(account.read_receipts),
+                sealed_sender_indicators: Some(account.sealed_sender_indicators),
+                typing_indicators: Some(account.typing_indicators),
+                link_previews: Some(account.link_previews),
+                pinned_conversations: account
+                    .pinned_conversations
+                    .iter()
+                    .map(encode_pinned_conversation)
+                    .collect(),
+            };
+            (4, ACCOUNT_FIELDS, account.encode_to_vec())
+        }
+    };
+
+    if let Some(previous) = previous {
+        encoded.extend(unknown_record_fields(previous, field, known)?);
+    }
+
+    let mut record = Vec::with_capacity(encoded.len() + 8);
+    prost::encoding::encode_key(field, WireType::LengthDelimited, &mut record);
+    prost::encoding::encode_varint(encoded.len() as u64, &mut record);
+    record.extend(encoded);
+    Ok(record)
+}
+
+/// Read a pinned conversation of an account record, `None` if it names
+/// no conversation
+fn decode_pinned_conversation(
+    pinned: proto::account_record::PinnedConversation,
+) -> Option<PinnedConversation> {
+    use proto::account_record::pinned_conversation::Identifier;
+
+    Some(match pinned.identifier? {
+        Identifier::Contact(contact) => PinnedConversation::Contact {
+            service_id: contact
+                .service_id
+                .as_deref()
+                .and_then(|service_id| service_id.parse().ok()),
+            e164: contact.e164.filter(|e164| !e164.is_empty()),
+        },
+        Identifier::LegacyGroupId(id) => PinnedConversation::LegacyGroup(id),
+        Identifier::GroupMasterKey(master_key) => PinnedConversation::Group { master_key },
+    })
+}
+
+fn encode_pinned_conversation(
+    pinned: &PinnedConversation,
+) -> proto::account_record::PinnedConversation {
+    use proto::account_record::pinned_conversation::{Contact, Identifier};
+
+    let identifier = match pinned {
+        PinnedConversation::Contact { service_id, e164 } => Identifier::Contact(Contact {
+            service_id: service_id.map(|service_id| service_id.to_string()),
+            e164: e164.clone(),
+        }),
+        PinnedConversation::LegacyGroup(id) => Identifier::LegacyGroupId(id.clone()),
+        PinnedConversation::Group { master_key } => Identifier::GroupMasterKey(master_key.clone()),
+    };
+    proto::account_record::PinnedConversation {
+        identifier: Some(identifier),
+    }
+}
+
+/// Client for the storage service of one account
+pub struct StorageService {
+    http: reqwest::Client,
+    url: &'static str,
+    credentials: StorageCredentials,
+    storage_key: [u8; 32],
+}
+
+impl StorageService {
+    pub fn new(storage_key: [u8; 32], credentials: StorageCredentials) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            url: SignalServers::default().storage,
+            credentials,
+            storage_key,
+        }
+    }
+
+    /// Fetch the manifest unless it is still at `version`
+    ///
+    /// Returns `None` when the manifest is unchanged or the account has
+    /// none yet.
+    pub async fn fetch_manifest(&self, version: u64) -> Result<Option<StorageManifest>> {
+        let path = match version {
+            0 => "/v1/storage/manifest".to_string(),
+            version => format!("/v1/storage/manifest/version/{}", version),
+        };
+        let response = self
+            .http
+            .get(format!("{}{}", self.url, path))
+            .basic_auth(&self.credentials.username, Some(&self.credentials.password))
+            .send()
+            .await?;
+
+        match response.status().as_u16() {
+            204 | 404 => Ok(None),
+            200 => {
+                let manifest = proto::StorageManifest::decode(response.bytes().await?)?;
+                Ok(Some(decrypt_manifest(&self.storage_key, &manifest)?))
+            }
+            status => Err(anyhow!(
+                "Storage manifest fetch failed with status {}",
+                status
+            )),
+        }
+    }
+
+    /// Fetch and decrypt the records `ids` of `manifest`
+    ///
+    /// Records the server does not return, or that fail to decrypt, are
+    /// left out.
+    pub async fn read_records(
+        &self,
+        manifest: &StorageManifest,
+        ids: &[StorageId],
+    ) -> Result<Vec<(StorageId, Vec<u8>)>> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let operation = proto::ReadOperation {
+            read_key: ids.iter().map(|id| id.raw.clone()).collect(),
+        };
+        let response = self
+            .http
+            .put(format!("{}/v1/storage/read", self.url))
+            .basic_auth(&self.credentials.username, Some(&self.credentials.password))
+            .header("Content-Type", "application/x-protobuf")
+            .body(operation.encode_to_vec())
+            .send()
+            .await?
+            .error_for_status()?;
+        let items = proto::StorageItems::decode(response.bytes().await?)?;
+
+        let records = items
+            .items
+            .into_iter()
+            .filter_map(|item| {
+                let raw = item.key?;
+                let id = ids.iter().find(|id| id.raw == raw)?.clone();
+                let value = item.value.unwrap_or_default();
+                match decrypt_record(&self.storage_key, manifest, &id, &value) {
+                    Ok(plaintext) => Some((id, plaintext)),
+                    Err(e) => {
+                        tracing::warn!("Skipping storage record: {}", e);
+                        None
+                    }
+                }
+            })
+            .collect();
+        Ok(records)
+    }
+
+    /// Replace the manifest with `manifest`, adding and removing records
+    ///
+    /// `manifest` must be one version past the server's. If another device
+    /// wrote first, nothing is written and the newer manifest is returned.
+    pub async fn write(
+        &self,
+        manifest: &StorageManifest,
+        inserts: &[(StorageId, Vec<u8>)],
+        deletes: &[StorageId],
+    ) -> Result<Option<StorageManifest>> {
+        let operation = proto::WriteOperation {
+            manifest: Some(encrypt_manifest(&self.storage_key, manifest)?),
+            insert_item: inserts
+                .iter()
+                .map(|(id, plaintext)| {
+                    Ok(proto::StorageItem {
+                        key: Some(id.raw.clone()),
+                        value: Some(encrypt_record(&self.storage_key, manifest, id, plaintext)?),
+                    })
+                })
+                .collect::<Result<_>>()?,
+            delete_key: deletes.iter().map(|id| id.raw.clone()).collect(),
+            clear_all: None,
+        };
+        let response = self
+            .http
+            .put(format!("{}/v1/storage", self.url))
+            .basic_auth(&self.credentials.username, Some(&self.credentials.password))
+            .header("Content-Type", "application/x-protobuf")
+            .body(operation.encode_to_vec())
+            .send()
+            .await?;
+
+        match response.status().as_u16() {
+            200 => Ok(None),
+            409 => {
+                let manifest = proto::StorageManifest::decode(response.bytes().await?)?;
+                Ok(Some(decrypt_manifest(&self.storage_key, &manifest)?))
+            }
+            status => Err(anyhow!("Storage write failed with status {}", status)),
+        }
+    }
+}
+
+/// Key of the manifest of `version`
+fn manifest_key(storage_key: &[u8; 32], version: u64) -> [u8; 32] {
+    hmac_sha256(storage_key, format!("Manifest_{}", version).as_bytes())
+}
+
+/// Key of the record named `raw` in `manifest`
+fn record_key(storage_key: &[u8; 32], manifest: &StorageManifest, raw: &[u8]) -> [u8; 32] {
+    match &manifest.record_ikm {
+        Some(ikm) => {
+            let mut info = RECORD_KEY_INFO.to_vec();
+            info.extend_from_slice(raw);
+
+            let mut key = [0u8; 32];
+            Hkdf::<Sha256>::new(None, ikm)
+                .expand(&info, &mut key)
+                .expect("32 bytes is a valid HKDF output length");
+            key
+        }
+        None => hmac_sha256(
+            storage_key,
+            format!("Item_{}", BASE64.encode(raw)).as_bytes(),
+        ),
+    }
+}
+
+/// PNI of a contact record, which leaves out the `PNI:` prefix
+fn parse_record_pni(pni: &str) -> Option<Pni> {
+    pni.parse()
+        .ok()
+        .or_else(|| pni.parse::<uuid::Uuid>().ok().map(Pni::from))
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+fn encrypt(key: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>> {
+    let nonce = SignalCipher::generate_nonce();
+    let mut blob = nonce.to_vec();
+    blob.extend(SignalCipher::encrypt(key, &nonce, plaintext)?);
+    Ok(blob)
+}
+
+fn decrypt(key: &[u8; 32], blob: &[u8]) -> Result<Vec<u8>> {
+    if blob.len() < NONCE_SIZE {
+        return Err(anyhow!("Storage ciphertext too short"));
+    }
+
+    let (nonce, ciphertext) = blob.split_at(NONCE_SIZE);
+    SignalCipher::decrypt(key, nonce.try_into()?, ciphertext)
+}
+
+/// Fields outside `known` of the record held in field `field` of a
+/// serialized `StorageRecord`
+fn unknown_record_fields(mut record: &[u8], field: u32, known: &[u32]) -> Result<Vec<u8>> {
+    let mut unknown = Vec::new();
+
+    while record.has_remaining() {
+        let (number, wire_type) = decode_key(&mut record)?;
+        if number != field || wire_type != WireType::LengthDelimited {
+            skip_field(wire_type, number, &mut record, DecodeContext::default())?;
+            continue;
+        }
+
+        let length = decode_varint(&mut record)? as usize;
+        if length > record.len() {
+            return Err(anyhow!("Storage record runs past its end"));
+        }
+        let (mut fields, rest) = record.split_at(length);
+        record = rest;
+
+        while fields.has_remaining() {
+            let start = fields;
+            let (number, wire_type) = decode_key(&mut fields)?;
+            skip_field(wire_type, number, &mut fields, DecodeContext::default())?;
+            if !known.contains(&number) {
+                unknown.extend_from_slice(&start[..start.len() - fields.len()]);
+            }
+        }
+    }
+
+    Ok(unknown)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(record_ikm: Option<Vec<u8>>) -> StorageManifest {
+        StorageManifest {
+            version: 7,
+            identifiers: vec![
+                new_storage_id(CONTACT_RECORD),
+                new_storage_id(ACCOUNT_RECORD),
+            ],
+            record_ikm,
+        }
+    }
+
+    #[test]
+    fn test_storage_key() {
+        let master = vec![1u8; 32];
+        let legacy = vec![2u8; 32];
+
+        let keys = AccountKeys {
+            storage_service: Some(legacy.clone()),
+            master: Some(master.clone()),
+        };
+        assert_eq!(storage_key(&keys), Some(derive_storage_key(&master)));
+        assert_ne!(derive_storage_key(&master), [1u8; 32]);
+
+        // Primaries predating the master key send the storage key itself
+        let keys = AccountKeys {
+            storage_service: Some(legacy),
+            master: None,
+        };
+        assert_eq!(storage_key(&keys), Some([2u8; 32]));
+        assert_eq!(storage_key(&AccountKeys::default()), None);
+    }
+
+    #[test]
+    fn test_manifest_round_trip() {
+        let storage_key = derive_storage_key(&[3u8; 32]);
+        let manifest = manifest(Some(vec![4u8; 32]));
+
+        let encrypted = encrypt_manifest(&storage_key, &manifest).unwrap();
+        assert_eq!(
+            decrypt_manifest(&storage_key, &encrypted).unwrap(),
+            manifest
+        );
+
+        let other_key = derive_storage_key(&[5u8; 32]);
+        assert!(decrypt_manifest(&other_key, &encrypted).is_err());
+
+        // The manifest key is bound to the version
+        let relabeled = proto::StorageManifest {
+            version: Some(8),
+            ..encrypted
+        };
+        assert!(decrypt_manifest(&storage_key, &relabeled).is_err());
+    }
+
+    #[test]
+    fn test_record_round_trip() {
+        let storage_key = derive_storage_key(&[6u8; 32]);
+        let records = [
+            StorageRecord::Contact(ContactRecord {
+                aci: Some(uuid::Uuid::new_v4().into()),
+                pni: Some(uuid::Uuid::new_v4().into()),
+                e164: Some("+15550005555".to_string()),
+                system_given_name: Some("Alice".to_string()),
+                nickname_given_name: Some("Al".to_string()),
+                blocked: true,
+                muted_until: Some(1_700_000_000_000),
+                ..Default::default()
+            }),
+            StorageRecord::GroupV2(GroupV2Record {
+                master_key: vec![7u8; 32],
+                archived: true,
+                ..Default::default()
+            }),
+            StorageRecord::Account(AccountRecord {
+                given_name: Some("Me".to_string()),
+                read_receipts: true,
+                link_previews: true,
+                pinned_conversations: vec![
+                    PinnedConversation::Group {
+                        master_key: vec![9u8; 32],
+                    },
+                    PinnedConversation::Contact {
+                        service_id: Some(Pni::from(uuid::Uuid::new_v4()).into()),
+                        e164: Some("+15550006666".to_string()),
+                    },
+                ],
+                ..Default::default()
+            }),
+        ];
+
+        for record_ikm in [None, Some(vec![8u8; 32])] {
+            let manifest = manifest(record_ikm);
+            for record in &records {
+                let id = new_storage_id(record_type(record));
+                let plaintext = encode_record(record, None).unwrap();
+                let ciphertext = encrypt_record(&storage_key, &manifest, &id, &plaintext).unwrap();
+
+                let decrypted = decrypt_record(&storage_key, &manifest, &id, &ciphertext).unwrap();
+                assert_eq!(decode_record(&decrypted).unwrap().as_ref(), Some(record));
+
+                // Each record has its own key
+                let other = new_storage_id(record_type(record));
+                assert!(decrypt_record(&storage_key, &manifest, &other, &ciphertext).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_record_keeps_unknown_fields() {
+        // An account record with a universal expire timer, which we do
+        // not model
+        let timer = 86_400u32;
+        let mut account = proto::AccountRecord {
+            given_name: Some("Me".to_string()),
+            read_receipts: Some(true),
+            ..Default::default()
+        }
+        .encode_to_vec();
+        prost::encoding::uint32::encode(17, &timer, &mut account);
+        let mut previous = Vec::new();
+        prost::encoding::bytes::encode(4, &account, &mut previous);
+
+        let Some(StorageRecord::Account(mut record)) = decode_record(&previous).unwrap() else {
+            panic!("Expected an account record");
+        };
+        record.read_receipts = false;
+        let updated =
+            encode_record(&StorageRecord::Account(record.clone()), Some(&previous)).unwrap();
+
+        assert_eq!(
+            decode_record(&updated).unwrap(),
+            Some(StorageRecord::Account(record))
+        );
+        let unknown = unknown_record_fields(&updated, 4, ACCOUNT_FIELDS).unwrap();
+        let mut expected = Vec::new();
+        prost::encoding::uint32::encode(17, &timer, &mut expected);
+        assert_eq!(unknown, expected);
+    }
+}
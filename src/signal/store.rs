@@ -5,97 +5,37 @@
 
 use anyhow::{anyhow, Result};
 use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::HashSet;
 use std::path::Path;
-use std::sync::Arc;
-use tokio::sync::Mutex;
 
+use super::database::{self, Database};
+use super::groups::GroupSecretParams;
+use super::keystore::{DatabaseKey, KeySlot, KeyStore};
 use super::protocol::ProtocolAddress;
 use super::types::*;
 
 /// Database schema version for migrations
-const SCHEMA_VERSION: u32 = 1;
+const SCHEMA_VERSION: u32 = 17;
 
-/// Encrypted Signal data store
-pub struct SignalStore {
-    /// SQLCipher connection (wrapped for async safety)
-    db: Arc<Mutex<Connection>>,
-    /// Data directory path
-    data_dir: std::path::PathBuf,
-}
-
-impl SignalStore {
-    /// Create or open the encrypted store
-    pub async fn new(data_dir: &Path) -> Result<Self> {
-        std::fs::create_dir_all(data_dir)?;
-
-        let db_path = data_dir.join("signal.db");
-        let db = Connection::open(&db_path)?;
-
-        tracing::info!("Opening Signal store at {:?}", data_dir);
-
-        let store = Self {
-            db: Arc::new(Mutex::new(db)),
-            data_dir: data_dir.to_path_buf(),
-        };
-
-        // Run migrations
-        store.migrate().await?;
-
-        Ok(store)
-    }
-
-    /// Create with encryption key
-    pub async fn new_encrypted(data_dir: &Path, key: &str) -> Result<Self> {
-        std::fs::create_dir_all(data_dir)?;
-
-        let db_path = data_dir.join("signal.db");
-        let db = Connection::open(&db_path)?;
-
-        // Set SQLCipher encryption key
-        db.execute_batch(&format!("PRAGMA key = '{}';", key))?;
-
-        // Verify encryption is working
-        db.query_row("SELECT count(*) FROM sqlite_master;", [], |_| Ok(()))
-            .map_err(|_| anyhow!("Failed to verify database encryption"))?;
-
-        tracing::info!("Opening encrypted Signal store at {:?}", data_dir);
-
-        let store = Self {
-            db: Arc::new(Mutex::new(db)),
-            data_dir: data_dir.to_path_buf(),
-        };
-
-        store.migrate().await?;
-
-        Ok(store)
-    }
-
-    /// Run database migrations
-    pub async fn migrate(&self) -> Result<()> {
-        let db = self.db.lock().await;
-
-        // Check current schema version
-        let current_version: u32 = db
-            .query_row(
-                "SELECT value FROM metadata WHERE key = 'schema_version'",
-                [],
-                |row| row.get(0),
-            )
-            .unwrap_or(0);
+/// Database file name inside the data directory
+const DATABASE_FILE: &str = "signal.db";
 
-        if current_version >= SCHEMA_VERSION {
-            return Ok(());
-        }
+/// How long sent content is kept for answering resend requests (ms)
+const RECENT_SEND_RETENTION_MS: i64 = 24 * 60 * 60 * 1000;
 
-        tracing::info!(
-            "Running database migrations from v{} to v{}",
-            current_version,
-            SCHEMA_VERSION
-        );
+/// A schema change, applied once and in order inside its own transaction
+struct Migration {
+    version: u32,
+    description: &'static str,
+    sql: &'static str,
+}
 
-        // Create tables
-        db.execute_batch(
-            r#"
+/// Every schema change ever shipped; append new versions, never edit old ones
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial schema",
+        sql: r#"
             -- Metadata table for schema versioning
             CREATE TABLE IF NOT EXISTS metadata (
                 key TEXT PRIMARY KEY,
@@ -255,669 +195,2770 @@ impl SignalStore {
                 created_at INTEGER NOT NULL,
                 PRIMARY KEY (address, distribution_id)
             );
-            "#,
-        )?;
+        "#,
+    },
+    Migration {
+        version: 2,
+        description: "envelope metadata on messages",
+        sql: r#"
+            ALTER TABLE messages ADD COLUMN server_guid TEXT;
+            ALTER TABLE messages ADD COLUMN server_timestamp INTEGER;
+            ALTER TABLE messages ADD COLUMN destination_service_id TEXT;
+            ALTER TABLE messages ADD COLUMN urgent INTEGER DEFAULT 1;
+            ALTER TABLE messages ADD COLUMN story INTEGER DEFAULT 0;
+
+            CREATE UNIQUE INDEX IF NOT EXISTS idx_messages_server_guid
+                ON messages(server_guid) WHERE server_guid IS NOT NULL;
+        "#,
+    },
+    Migration {
+        version: 3,
+        description: "recent-send log for answering resend requests",
+        sql: r#"
+            CREATE TABLE IF NOT EXISTS recent_sends (
+                recipient_uuid TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                content BLOB NOT NULL,
+                created_at INTEGER NOT NULL,
+                PRIMARY KEY (recipient_uuid, timestamp)
+            );
+        "#,
+    },
+    Migration {
+        version: 4,
+        description: "phone number identities (PNI) alongside account identities",
+        sql: r#"
+            ALTER TABLE contacts ADD COLUMN pni TEXT;
+            ALTER TABLE conversations ADD COLUMN recipient_pni TEXT;
+
+            CREATE UNIQUE INDEX IF NOT EXISTS idx_contacts_pni
+                ON contacts(pni) WHERE pni IS NOT NULL;
+
+            CREATE TABLE IF NOT EXISTS pni_pre_keys (
+                id INTEGER PRIMARY KEY,
+                public_key BLOB NOT NULL,
+                private_key BLOB NOT NULL,
+                created_at INTEGER NOT NULL
+            );
 
-        // Update schema version
-        db.execute(
-            "INSERT OR REPLACE INTO metadata (key, value) VALUES ('schema_version', ?)",
-            params![SCHEMA_VERSION.to_string()],
-        )?;
+            CREATE TABLE IF NOT EXISTS pni_signed_pre_keys (
+                id INTEGER PRIMARY KEY,
+                public_key BLOB NOT NULL,
+                private_key BLOB NOT NULL,
+                signature BLOB NOT NULL,
+                timestamp INTEGER NOT NULL,
+                created_at INTEGER NOT NULL
+            );
+        "#,
+    },
+    Migration {
+        version: 5,
+        description: "recipients unifying ACI, PNI and phone number",
+        sql: r#"
+            CREATE TABLE IF NOT EXISTS recipients (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                aci TEXT UNIQUE,
+                pni TEXT UNIQUE,
+                e164 TEXT UNIQUE,
+                created_at INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL
+            );
+
+            ALTER TABLE contacts ADD COLUMN recipient_id INTEGER REFERENCES recipients(id);
+            ALTER TABLE conversations ADD COLUMN recipient_id INTEGER REFERENCES recipients(id);
+
+            CREATE INDEX IF NOT EXISTS idx_conversations_recipient
+                ON conversations(recipient_id);
+
+            -- Existing contacts and 1:1 conversations were keyed by ACI
+            INSERT OR IGNORE INTO recipients (aci, pni, e164, created_at, updated_at)
+                SELECT uuid, pni, phone_number, created_at, updated_at FROM contacts
+                WHERE uuid != '00000000-0000-0000-0000-000000000000';
+            INSERT OR IGNORE INTO recipients (aci, pni, created_at, updated_at)
+                SELECT recipient_uuid, recipient_pni, created_at, updated_at FROM conversations
+                WHERE is_group = 0
+                  AND recipient_uuid != '00000000-0000-0000-0000-000000000000';
+
+            UPDATE contacts
+                SET recipient_id = (SELECT id FROM recipients WHERE aci = contacts.uuid);
+            UPDATE conversations
+                SET recipient_id = (SELECT id FROM recipients WHERE aci = conversations.recipient_uuid)
+                WHERE is_group = 0;
+        "#,
+    },
+    Migration {
+        version: 6,
+        description: "full-text search over message text",
+        sql: r#"
+            -- Keyed by the rowid of the message it indexes
+            CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(
+                body,
+                caption,
+                file_name,
+                tokenize = 'unicode61 remove_diacritics 2'
+            );
+
+            INSERT INTO messages_fts (rowid, body, caption, file_name)
+                SELECT rowid,
+                       json_extract(content_json, '$.body'),
+                       json_extract(content_json, '$.caption'),
+                       json_extract(content_json, '$.attachment.file_name')
+                FROM messages;
+
+            CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages BEGIN
+                INSERT INTO messages_fts (rowid, body, caption, file_name)
+                    VALUES (new.rowid,
+                            json_extract(new.content_json, '$.body'),
+                            json_extract(new.content_json, '$.caption'),
+                            json_extract(new.content_json, '$.attachment.file_name'));
+            END;
+
+            CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages BEGIN
+                DELETE FROM messages_fts WHERE rowid = old.rowid;
+            END;
+
+            CREATE TRIGGER IF NOT EXISTS messages_fts_update
+                AFTER UPDATE OF content_json ON messages BEGIN
+                UPDATE messages_fts
+                    SET body = json_extract(new.content_json, '$.body'),
+                        caption = json_extract(new.content_json, '$.caption'),
+                        file_name = json_extract(new.content_json, '$.attachment.file_name')
+                    WHERE rowid = new.rowid;
+            END;
+        "#,
+    },
+    Migration {
+        version: 7,
+        description: "quote references and attachment upload times",
+        sql: r#"
+            -- Quotes name their message by author and sent timestamp, so a
+            -- quote can be matched to a message that arrives after it
+            ALTER TABLE messages ADD COLUMN quote_author TEXT;
+            ALTER TABLE messages ADD COLUMN quote_timestamp INTEGER;
+
+            ALTER TABLE attachments ADD COLUMN upload_timestamp INTEGER;
+
+            -- Quotes and reactions look up their target by author and timestamp
+            CREATE INDEX IF NOT EXISTS idx_messages_sender_timestamp
+                ON messages(sender_uuid, timestamp);
+        "#,
+    },
+    Migration {
+        version: 8,
+        description: "contact details from contact sync",
+        sql: r#"
+            ALTER TABLE contacts ADD COLUMN color TEXT;
+            ALTER TABLE contacts ADD COLUMN expire_timer INTEGER DEFAULT 0;
+            ALTER TABLE contacts ADD COLUMN inbox_position INTEGER;
+        "#,
+    },
+    Migration {
+        version: 9,
+        description: "per-recipient delivery state of outgoing messages",
+        sql: r#"
+            CREATE TABLE IF NOT EXISTS message_recipients (
+                message_id TEXT NOT NULL,
+                recipient_id INTEGER NOT NULL,
+                status TEXT NOT NULL,
+                unidentified INTEGER DEFAULT 0,
+                updated_at INTEGER NOT NULL,
+                PRIMARY KEY (message_id, recipient_id),
+                FOREIGN KEY (message_id) REFERENCES messages(id),
+                FOREIGN KEY (recipient_id) REFERENCES recipients(id)
+            );
+        "#,
+    },
+    Migration {
+        version: 10,
+        description: "read state of incoming messages",
+        sql: r#"
+            -- Set on incoming messages until they are read here or on another device
+            ALTER TABLE messages ADD COLUMN unread INTEGER DEFAULT 0;
+
+            CREATE INDEX IF NOT EXISTS idx_messages_unread
+                ON messages(conversation_id) WHERE unread = 1;
+        "#,
+    },
+    Migration {
+        version: 11,
+        description: "blocked groups and account keys",
+        sql: r#"
+            -- Groups blocked on the primary device, known or not
+            CREATE TABLE IF NOT EXISTS blocked_groups (
+                group_id TEXT PRIMARY KEY,
+                created_at INTEGER NOT NULL
+            );
+
+            CREATE INDEX IF NOT EXISTS idx_contacts_blocked
+                ON contacts(recipient_id) WHERE blocked = 1;
+        "#,
+    },
+    Migration {
+        version: 12,
+        description: "storage service records",
+        sql: r#"
+            -- Records listed by the storage service manifest we last synced;
+            -- `record` is the decrypted StorageRecord, unset for kinds of
+            -- records we do not model
+            CREATE TABLE IF NOT EXISTS storage_records (
+                storage_id BLOB PRIMARY KEY,
+                record_type INTEGER NOT NULL,
+                record BLOB,
+                updated_at INTEGER NOT NULL
+            );
+
+            ALTER TABLE contacts ADD COLUMN nickname TEXT;
+            ALTER TABLE contacts ADD COLUMN username TEXT;
+        "#,
+    },
+    Migration {
+        version: 13,
+        description: "pinned conversations",
+        sql: r#"
+            -- Conversations pinned in our account record, by their place
+            -- in its list; a conversation may be pinned before it exists
+            CREATE TABLE IF NOT EXISTS pinned_conversations (
+                conversation_id TEXT PRIMARY KEY,
+                position INTEGER NOT NULL
+            );
+        "#,
+    },
+    Migration {
+        version: 14,
+        description: "decrypted profiles",
+        sql: r#"
+            -- Profile fetched with the contact's profile key; `profile_name`
+            -- already holds the name. The avatar is cached at
+            -- `profile_avatar_path`, downloaded from `profile_avatar_url`.
+            ALTER TABLE contacts ADD COLUMN profile_about TEXT;
+            ALTER TABLE contacts ADD COLUMN profile_about_emoji TEXT;
+            ALTER TABLE contacts ADD COLUMN profile_avatar_url TEXT;
+            ALTER TABLE contacts ADD COLUMN profile_avatar_path TEXT;
+            ALTER TABLE contacts ADD COLUMN profile_fetched_at INTEGER;
+        "#,
+    },
+    Migration {
+        version: 15,
+        description: "groups v2 state",
+        sql: r#"
+            -- Groups found through the storage service have a master key
+            -- and no members until their state is fetched
+            ALTER TABLE groups ADD COLUMN master_key BLOB;
+
+            -- Pending members were invited or asked to join; `invited_by`
+            -- is unset for requests to join
+            ALTER TABLE group_members ADD COLUMN pending INTEGER DEFAULT 0;
+            ALTER TABLE group_members ADD COLUMN invited_by TEXT;
+        "#,
+    },
+    Migration {
+        version: 16,
+        description: "group avatars",
+        sql: r#"
+            -- CDN key of the encrypted group avatar
+            ALTER TABLE groups ADD COLUMN avatar_url TEXT;
+        "#,
+    },
+    Migration {
+        version: 17,
+        description: "group invite links",
+        sql: r#"
+            -- Who may join with the invite link, and its password
+            ALTER TABLE groups ADD COLUMN access_invite_link TEXT NOT NULL DEFAULT 'Disabled';
+            ALTER TABLE groups ADD COLUMN invite_link_password BLOB;
+        "#,
+    },
+];
+
+/// Whether the other party or the group of a conversation is blocked, as a
+/// column of `conversations`
+const CONVERSATION_BLOCKED: &str = r#"
+    EXISTS (SELECT 1 FROM contacts
+            WHERE contacts.recipient_id = conversations.recipient_id AND contacts.blocked = 1)
+    OR EXISTS (SELECT 1 FROM blocked_groups
+               WHERE blocked_groups.group_id = conversations.group_id)"#;
+
+/// Query for [`recipient_details_from_row`], to be followed by a filter
+const RECIPIENT_DETAILS: &str = r#"
+    SELECT r.aci, r.e164, c.nickname, c.name, c.profile_name, c.username,
+           c.avatar_path, c.profile_avatar_path
+    FROM recipients r LEFT JOIN contacts c ON c.recipient_id = r.id"#;
+
+/// Marks the start of a match in FTS5 snippets
+const SNIPPET_OPEN: &str = "\u{2}";
+
+/// Marks the end of a match in FTS5 snippets
+const SNIPPET_CLOSE: &str = "\u{3}";
+
+/// Tokens of context in a search snippet
+const SNIPPET_TOKENS: i32 = 12;
+
+/// Encrypted Signal data store
+///
+/// Cheap to clone; all clones share the same database handle.
+#[derive(Clone)]
+pub struct SignalStore {
+    /// SQLCipher database, accessed off the async runtime
+    db: Database,
+    /// Data directory path
+    data_dir: std::path::PathBuf,
+    /// Where the database key is kept
+    key_store: KeyStore,
+}
+
+impl SignalStore {
+    /// Create or open the encrypted store, keeping its key in the system
+    /// keyring, or in a file if `allow_key_file` and there is no keyring
+    pub async fn new(data_dir: &Path, allow_key_file: bool) -> Result<Self> {
+        let key_store = KeyStore::detect(data_dir, allow_key_file).await?;
+        Self::with_key_store(data_dir, key_store).await
+    }
+
+    /// Create or open the encrypted store with its key kept in `key_store`
+    ///
+    /// A new key is generated for a new store. An existing unencrypted
+    /// database is encrypted in place.
+    pub async fn with_key_store(data_dir: &Path, key_store: KeyStore) -> Result<Self> {
+        std::fs::create_dir_all(data_dir)?;
+
+        let db_path = data_dir.join(DATABASE_FILE);
+        let plaintext = database::is_plaintext(&db_path)?;
+
+        let key = match key_store.load().await? {
+            Some(key) => key,
+            None if db_path.exists() && !plaintext => {
+                return Err(anyhow!(
+                    "Database is encrypted but its key is missing from the keyring"
+                ));
+            }
+            None => {
+                let key = DatabaseKey::generate();
+                key_store.save(&key).await?;
+                tracing::info!("Generated database key");
+                key
+            }
+        };
+
+        if plaintext {
+            tracing::info!("Encrypting existing plaintext database");
+            database::encrypt_plaintext(&db_path, &key)?;
+        }
+
+        let db = match Database::open(&db_path, Some(&key)) {
+            Ok(db) => db,
+            // Rekeying was interrupted before the database switched keys
+            Err(e) => match key_store.load_slot(KeySlot::Previous).await? {
+                Some(previous) => {
+                    let db = Database::open(&db_path, Some(&previous)).map_err(|_| e)?;
+                    tracing::warn!("Database is still under its previous key, restoring it");
+                    key_store.save(&previous).await?;
+                    db
+                }
+                None => return Err(e),
+            },
+        };
+        key_store.delete_slot(KeySlot::Previous).await?;
+        tracing::info!("Opening encrypted Signal store at {:?}", data_dir);
+
+        let store = Self {
+            db,
+            data_dir: data_dir.to_path_buf(),
+            key_store,
+        };
+
+        // Run migrations
+        store.migrate().await?;
+
+        Ok(store)
+    }
+
+    /// Bring the schema up to date
+    ///
+    /// Each pending migration runs in its own transaction and records its
+    /// version in `PRAGMA user_version`. An existing database is copied to
+    /// `signal.db.v<version>.bak` first.
+    pub async fn migrate(&self) -> Result<()> {
+        let data_dir = self.data_dir.clone();
+
+        self.db
+            .write(move |db| {
+                let user_version: u32 =
+                    db.pragma_query_value(None, "user_version", |row| row.get(0))?;
+                let current_version = if user_version > 0 {
+                    user_version
+                } else {
+                    legacy_schema_version(db)?
+                };
+
+                if current_version > SCHEMA_VERSION {
+                    return Err(anyhow!(
+                        "Database schema v{} is newer than the supported v{}",
+                        current_version,
+                        SCHEMA_VERSION
+                    ));
+                }
+                if current_version != user_version {
+                    db.pragma_update(None, "user_version", current_version)?;
+                }
+                if current_version == SCHEMA_VERSION {
+                    return Ok(());
+                }
+
+                tracing::info!(
+                    "Running database migrations from v{} to v{}",
+                    current_version,
+                    SCHEMA_VERSION
+                );
+
+                // Keep the database as it was in case a migration goes wrong
+                if current_version > 0 {
+                    // Fold the WAL into the main file so the copy is complete
+                    db.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+
+                    let backup =
+                        data_dir.join(format!("{}.v{}.bak", DATABASE_FILE, current_version));
+                    std::fs::copy(data_dir.join(DATABASE_FILE), &backup).map_err(|e| {
+                        anyhow!("Failed to back up database before migrating: {}", e)
+                    })?;
+                    tracing::info!("Backed up database to {:?}", backup);
+                }
+
+                for migration in MIGRATIONS.iter().filter(|m| m.version > current_version) {
+                    tracing::info!(
+                        "Applying migration v{}: {}",
+                        migration.version,
+                        migration.description
+                    );
+
+                    let tx = db.transaction()?;
+                    tx.execute_batch(migration.sql)
+                        .map_err(|e| anyhow!("Migration v{} failed: {}", migration.version, e))?;
+                    tx.pragma_update(None, "user_version", migration.version)?;
+                    tx.commit()?;
+                }
+
+                tracing::info!("Database migrations complete");
+                Ok(())
+            })
+            .await
+    }
 
-        tracing::info!("Database migrations complete");
+    /// Re-encrypt the database with a freshly generated key
+    ///
+    /// Both keys are saved before the database switches: the new one as
+    /// the current key and the old one as the previous key. Whichever step
+    /// is interrupted, the key the database is under is still in the key
+    /// store, and the next open settles on it.
+    pub async fn rekey(&self) -> Result<()> {
+        let old_key = self
+            .key_store
+            .load()
+            .await?
+            .ok_or_else(|| anyhow!("Database key is missing from the keyring"))?;
+        let new_key = DatabaseKey::generate();
+
+        self.key_store
+            .save_slot(KeySlot::Previous, &old_key)
+            .await?;
+        self.key_store.save(&new_key).await?;
+
+        if let Err(e) = self.db.rekey(&new_key).await {
+            tracing::error!("Failed to rekey database: {}", e);
+            self.key_store.save(&old_key).await?;
+            self.key_store.delete_slot(KeySlot::Previous).await?;
+            return Err(e);
+        }
+        self.key_store.delete_slot(KeySlot::Previous).await?;
 
+        tracing::info!("Rotated database key");
         Ok(())
     }
 
     // ==================== Identity Operations ====================
 
-    /// Store our identity keys
+    /// Store the identity keys of our ACI or PNI
     pub async fn store_local_identity(
         &self,
+        kind: ServiceIdKind,
         public_key: &[u8],
         private_key: &[u8],
         registration_id: u32,
     ) -> Result<()> {
-        let db = self.db.lock().await;
-        let now = chrono::Utc::now().timestamp();
+        let public_key = public_key.to_vec();
+        let private_key = private_key.to_vec();
+
+        self.db
+            .write(move |db| {
+                let now = chrono::Utc::now().timestamp();
+
+                db.execute(
+                    r#"INSERT OR REPLACE INTO identities
+                       (address, public_key, private_key, registration_id, trusted, created_at, updated_at)
+                       VALUES (?, ?, ?, ?, 1, ?, ?)"#,
+                    params![
+                        local_identity_address(kind),
+                        public_key,
+                        private_key,
+                        registration_id,
+                        now,
+                        now
+                    ],
+                )?;
+
+                tracing::info!("Stored local {:?} identity", kind);
+                Ok(())
+            })
+            .await
+    }
 
-        db.execute(
-            r#"INSERT OR REPLACE INTO identities
-               (address, public_key, private_key, registration_id, trusted, created_at, updated_at)
-               VALUES ('local', ?, ?, ?, 1, ?, ?)"#,
-            params![public_key, private_key, registration_id, now, now],
-        )?;
+    /// Get the identity keys of our ACI or PNI
+    pub async fn get_local_identity(
+        &self,
+        kind: ServiceIdKind,
+    ) -> Result<Option<(Vec<u8>, Vec<u8>, u32)>> {
+        self.db
+            .read(move |db| {
+                let result = db
+                    .query_row(
+                        "SELECT public_key, private_key, registration_id FROM identities WHERE address = ?",
+                        params![local_identity_address(kind)],
+                        |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Vec<u8>>(1)?, row.get::<_, u32>(2)?)),
+                    )
+                    .optional()?;
+
+                Ok(result)
+            })
+            .await
+    }
 
-        tracing::info!("Stored local identity");
-        Ok(())
+    /// Store the service IDs, number and device of our linked account
+    pub async fn store_account(&self, identity: &SignalIdentity) -> Result<()> {
+        let identity = identity.clone();
+
+        self.db
+            .write(move |db| {
+                let entries = [
+                    ("local_aci", Some(identity.aci.to_string())),
+                    ("local_pni", identity.pni.map(|pni| pni.to_string())),
+                    ("local_phone_number", identity.phone_number.clone()),
+                    ("local_device_id", Some(identity.device_id.to_string())),
+                ];
+
+                for (key, value) in entries {
+                    match value {
+                        Some(value) => db.execute(
+                            "INSERT OR REPLACE INTO metadata (key, value) VALUES (?, ?)",
+                            params![key, value],
+                        )?,
+                        None => db.execute("DELETE FROM metadata WHERE key = ?", params![key])?,
+                    };
+                }
+
+                tracing::info!("Stored account {}", identity.aci);
+                Ok(())
+            })
+            .await
     }
 
-    /// Get our local identity
-    pub async fn get_local_identity(&self) -> Result<Option<(Vec<u8>, Vec<u8>, u32)>> {
-        let db = self.db.lock().await;
+    /// Store the account keys shared by the primary device
+    ///
+    /// Keys left out are kept as they were.
+    pub async fn store_account_keys(&self, keys: &AccountKeys) -> Result<()> {
+        let keys = keys.clone();
+
+        self.db
+            .write(move |db| {
+                let entries = [
+                    ("storage_service_key", keys.storage_service),
+                    ("master_key", keys.master),
+                ];
+
+                for (key, value) in entries {
+                    if let Some(value) = value {
+                        db.execute(
+                            "INSERT OR REPLACE INTO metadata (key, value) VALUES (?, ?)",
+                            params![key, hex::encode(value)],
+                        )?;
+                    }
+                }
+
+                tracing::info!("Stored account keys");
+                Ok(())
+            })
+            .await
+    }
 
-        let result = db
-            .query_row(
-                "SELECT public_key, private_key, registration_id FROM identities WHERE address = 'local'",
-                [],
-                |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Vec<u8>>(1)?, row.get::<_, u32>(2)?)),
-            )
-            .optional()?;
+    /// Get the account keys shared by the primary device
+    pub async fn get_account_keys(&self) -> Result<AccountKeys> {
+        self.db
+            .read(|db| {
+                let key = |name: &str| -> Result<Option<Vec<u8>>> {
+                    let value: Option<String> = db
+                        .query_row(
+                            "SELECT value FROM metadata WHERE key = ?",
+                            params![name],
+                            |row| row.get(0),
+                        )
+                        .optional()?;
+                    Ok(value.map(hex::decode).transpose()?)
+                };
+
+                Ok(AccountKeys {
+                    storage_service: key("storage_service_key")?,
+                    master: key("master_key")?,
+                })
+            })
+            .await
+    }
 
-        Ok(result)
+    /// Get our own profile key, as our account record has it
+    pub async fn get_own_profile_key(&self) -> Result<Option<Vec<u8>>> {
+        self.db
+            .read(|db| {
+                let value: Option<String> = db
+                    .query_row(
+                        "SELECT value FROM metadata WHERE key = 'profile_key'",
+                        [],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                Ok(value.map(hex::decode).transpose()?)
+            })
+            .await
     }
 
     /// Store identity (our own or trusted contact)
     pub async fn store_identity(&self, identity: &SignalIdentity, keys: &[u8]) -> Result<()> {
-        let db = self.db.lock().await;
-        let now = chrono::Utc::now().timestamp();
-        let address = format!("{}.{}", identity.uuid, identity.device_id);
-
-        db.execute(
-            r#"INSERT OR REPLACE INTO identities
-               (address, public_key, registration_id, trusted, created_at, updated_at)
-               VALUES (?, ?, ?, 1, ?, ?)"#,
-            params![address, keys, identity.registration_id, now, now],
-        )?;
-
-        tracing::info!("Stored identity for {:?}", identity.uuid);
-        Ok(())
+        let identity = identity.clone();
+        let keys = keys.to_vec();
+
+        self.db
+            .write(move |db| {
+                let now = chrono::Utc::now().timestamp();
+                let address = ProtocolAddress::new(identity.aci, identity.device_id).to_string();
+
+                db.execute(
+                    r#"INSERT OR REPLACE INTO identities
+                       (address, public_key, registration_id, trusted, created_at, updated_at)
+                       VALUES (?, ?, ?, 1, ?, ?)"#,
+                    params![address, keys, identity.registration_id, now, now],
+                )?;
+
+                tracing::info!("Stored identity for {}", identity.aci);
+                Ok(())
+            })
+            .await
     }
 
-    /// Get stored identity
+    /// Get the identity of our linked account
     pub async fn get_identity(&self) -> Result<Option<SignalIdentity>> {
-        let db = self.db.lock().await;
+        self.db
+            .read(|db| {
+                let metadata = |key: &str| -> Result<Option<String>> {
+                    Ok(db
+                        .query_row(
+                            "SELECT value FROM metadata WHERE key = ?",
+                            params![key],
+                            |row| row.get(0),
+                        )
+                        .optional()?)
+                };
 
-        let result = db
-            .query_row(
-                "SELECT public_key, registration_id FROM identities WHERE address = 'local'",
-                [],
-                |row| {
-                    Ok(SignalIdentity {
-                        uuid: uuid::Uuid::nil(),
-                        phone_number: None,
-                        device_id: 1,
-                        registration_id: row.get(1)?,
-                    })
-                },
-            )
-            .optional()?;
+                let Some(aci) = metadata("local_aci")? else {
+                    return Ok(None);
+                };
+                let pni = metadata("local_pni")?.map(|pni| pni.parse()).transpose()?;
+                let phone_number = metadata("local_phone_number")?;
+                let device_id = metadata("local_device_id")?
+                    .map(|id| id.parse())
+                    .transpose()?
+                    .unwrap_or(1);
+
+                let registration_id: Option<u32> = db
+                    .query_row(
+                        "SELECT registration_id FROM identities WHERE address = ?",
+                        params![local_identity_address(ServiceIdKind::Aci)],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+
+                Ok(Some(SignalIdentity {
+                    aci: aci.parse()?,
+                    pni,
+                    phone_number,
+                    device_id,
+                    registration_id: registration_id.unwrap_or(0),
+                }))
+            })
+            .await
+    }
+
+    /// Whether the primary device was asked for its state since we linked
+    pub async fn initial_sync_requested(&self) -> Result<bool> {
+        self.db
+            .read(|db| {
+                let requested = db
+                    .query_row(
+                        "SELECT value FROM metadata WHERE key = 'initial_sync_requested'",
+                        [],
+                        |row| row.get::<_, String>(0),
+                    )
+                    .optional()?;
+                Ok(requested.is_some())
+            })
+            .await
+    }
 
-        Ok(result)
+    /// Record that the primary device was asked for its state
+    pub async fn set_initial_sync_requested(&self) -> Result<()> {
+        self.db
+            .write(|db| {
+                db.execute(
+                    "INSERT OR REPLACE INTO metadata (key, value) VALUES ('initial_sync_requested', '1')",
+                    [],
+                )?;
+                Ok(())
+            })
+            .await
     }
 
     /// Check if an identity is trusted
     pub async fn is_identity_trusted(&self, address: &ProtocolAddress) -> Result<bool> {
-        let db = self.db.lock().await;
         let addr_str = address.to_string();
 
-        let trusted: bool = db
-            .query_row(
-                "SELECT trusted FROM identities WHERE address = ?",
-                params![addr_str],
-                |row| row.get(0),
-            )
-            .unwrap_or(false);
-
-        Ok(trusted)
+        self.db
+            .read(move |db| {
+                let trusted: bool = db
+                    .query_row(
+                        "SELECT trusted FROM identities WHERE address = ?",
+                        params![addr_str],
+                        |row| row.get(0),
+                    )
+                    .unwrap_or(false);
+
+                Ok(trusted)
+            })
+            .await
     }
 
     // ==================== Pre-Key Operations ====================
 
-    /// Store pre-keys
-    pub async fn store_pre_keys(&self, keys: &[(u32, Vec<u8>, Vec<u8>)]) -> Result<()> {
-        let db = self.db.lock().await;
-        let now = chrono::Utc::now().timestamp();
-
-        for (id, public_key, private_key) in keys {
-            db.execute(
-                "INSERT OR REPLACE INTO pre_keys (id, public_key, private_key, created_at) VALUES (?, ?, ?, ?)",
-                params![id, public_key, private_key, now],
-            )?;
-        }
-
-        tracing::info!("Stored {} pre-keys", keys.len());
-        Ok(())
+    /// Store pre-keys of our ACI or PNI
+    pub async fn store_pre_keys(
+        &self,
+        kind: ServiceIdKind,
+        keys: &[(u32, Vec<u8>, Vec<u8>)],
+    ) -> Result<()> {
+        let keys = keys.to_vec();
+
+        self.db
+            .write(move |db| {
+                let now = chrono::Utc::now().timestamp();
+                let (table, _) = pre_key_tables(kind);
+                let tx = db.transaction()?;
+
+                for (id, public_key, private_key) in &keys {
+                    tx.execute(
+                        &format!(
+                            "INSERT OR REPLACE INTO {} (id, public_key, private_key, created_at) VALUES (?, ?, ?, ?)",
+                            table
+                        ),
+                        params![id, public_key, private_key, now],
+                    )?;
+                }
+                tx.commit()?;
+
+                tracing::info!("Stored {} {:?} pre-keys", keys.len(), kind);
+                Ok(())
+            })
+            .await
     }
 
     /// Get pre-key by ID
-    pub async fn get_pre_key(&self, id: u32) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
-        let db = self.db.lock().await;
-
-        let result = db
-            .query_row(
-                "SELECT public_key, private_key FROM pre_keys WHERE id = ?",
-                params![id],
-                |row| Ok((row.get(0)?, row.get(1)?)),
-            )
-            .optional()?;
-
-        Ok(result)
+    pub async fn get_pre_key(
+        &self,
+        kind: ServiceIdKind,
+        id: u32,
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        self.db
+            .read(move |db| {
+                let (table, _) = pre_key_tables(kind);
+
+                let result = db
+                    .query_row(
+                        &format!("SELECT public_key, private_key FROM {} WHERE id = ?", table),
+                        params![id],
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    )
+                    .optional()?;
+
+                Ok(result)
+            })
+            .await
     }
 
     /// Remove used pre-key
-    pub async fn remove_pre_key(&self, id: u32) -> Result<()> {
-        let db = self.db.lock().await;
-        db.execute("DELETE FROM pre_keys WHERE id = ?", params![id])?;
-        tracing::info!("Removed pre-key {}", id);
-        Ok(())
+    pub async fn remove_pre_key(&self, kind: ServiceIdKind, id: u32) -> Result<()> {
+        self.db
+            .write(move |db| {
+                let (table, _) = pre_key_tables(kind);
+                db.execute(&format!("DELETE FROM {} WHERE id = ?", table), params![id])?;
+                tracing::info!("Removed {:?} pre-key {}", kind, id);
+                Ok(())
+            })
+            .await
     }
 
     /// Get pre-key count
-    pub async fn pre_key_count(&self) -> Result<usize> {
-        let db = self.db.lock().await;
-        let count: usize = db.query_row("SELECT COUNT(*) FROM pre_keys", [], |row| row.get(0))?;
-        Ok(count)
+    pub async fn pre_key_count(&self, kind: ServiceIdKind) -> Result<usize> {
+        self.db
+            .read(move |db| {
+                let (table, _) = pre_key_tables(kind);
+                let count: usize = db
+                    .query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0))?;
+                Ok(count)
+            })
+            .await
     }
 
     /// Store signed pre-key
     pub async fn store_signed_pre_key(
         &self,
+        kind: ServiceIdKind,
         id: u32,
         public_key: &[u8],
         private_key: &[u8],
         signature: &[u8],
         timestamp: i64,
     ) -> Result<()> {
-        let db = self.db.lock().await;
-        let now = chrono::Utc::now().timestamp();
-
-        db.execute(
-            r#"INSERT OR REPLACE INTO signed_pre_keys
-               (id, public_key, private_key, signature, timestamp, created_at)
-               VALUES (?, ?, ?, ?, ?, ?)"#,
-            params![id, public_key, private_key, signature, timestamp, now],
-        )?;
-
-        tracing::info!("Stored signed pre-key {}", id);
-        Ok(())
+        let public_key = public_key.to_vec();
+        let private_key = private_key.to_vec();
+        let signature = signature.to_vec();
+
+        self.db
+            .write(move |db| {
+                let now = chrono::Utc::now().timestamp();
+                let (_, table) = pre_key_tables(kind);
+
+                db.execute(
+                    &format!(
+                        r#"INSERT OR REPLACE INTO {}
+                           (id, public_key, private_key, signature, timestamp, created_at)
+                           VALUES (?, ?, ?, ?, ?, ?)"#,
+                        table
+                    ),
+                    params![id, public_key, private_key, signature, timestamp, now],
+                )?;
+
+                tracing::info!("Stored {:?} signed pre-key {}", kind, id);
+                Ok(())
+            })
+            .await
     }
 
     /// Get signed pre-key
     pub async fn get_signed_pre_key(
         &self,
+        kind: ServiceIdKind,
         id: u32,
     ) -> Result<Option<(Vec<u8>, Vec<u8>, Vec<u8>, i64)>> {
-        let db = self.db.lock().await;
-
-        let result = db
-            .query_row(
-                "SELECT public_key, private_key, signature, timestamp FROM signed_pre_keys WHERE id = ?",
-                params![id],
-                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
-            )
-            .optional()?;
-
-        Ok(result)
+        self.db
+            .read(move |db| {
+                let (_, table) = pre_key_tables(kind);
+
+                let result = db
+                    .query_row(
+                        &format!(
+                            "SELECT public_key, private_key, signature, timestamp FROM {} WHERE id = ?",
+                            table
+                        ),
+                        params![id],
+                        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+                    )
+                    .optional()?;
+
+                Ok(result)
+            })
+            .await
     }
 
     // ==================== Session Operations ====================
 
     /// Store session
     pub async fn store_session(&self, address: &ProtocolAddress, session_data: &[u8]) -> Result<()> {
-        let db = self.db.lock().await;
-        let now = chrono::Utc::now().timestamp();
         let addr_str = address.to_string();
-
-        db.execute(
-            r#"INSERT OR REPLACE INTO sessions (address, session_data, created_at, updated_at)
-               VALUES (?, ?, ?, ?)"#,
-            params![addr_str, session_data, now, now],
-        )?;
-
-        tracing::debug!("Stored session for {}", addr_str);
-        Ok(())
+        let session_data = session_data.to_vec();
+
+        self.db
+            .write(move |db| {
+                let now = chrono::Utc::now().timestamp();
+
+                db.prepare_cached(
+                    r#"INSERT OR REPLACE INTO sessions (address, session_data, created_at, updated_at)
+                       VALUES (?, ?, ?, ?)"#,
+                )?
+                .execute(params![addr_str, session_data, now, now])?;
+
+                tracing::debug!("Stored session for {}", addr_str);
+                Ok(())
+            })
+            .await
     }
 
     /// Get session
     pub async fn get_session(&self, address: &ProtocolAddress) -> Result<Option<Vec<u8>>> {
-        let db = self.db.lock().await;
         let addr_str = address.to_string();
 
-        let result = db
-            .query_row(
-                "SELECT session_data FROM sessions WHERE address = ?",
-                params![addr_str],
-                |row| row.get(0),
-            )
-            .optional()?;
+        self.db
+            .read(move |db| {
+                let result = db
+                    .prepare_cached("SELECT session_data FROM sessions WHERE address = ?")?
+                    .query_row(params![addr_str], |row| row.get(0))
+                    .optional()?;
 
-        Ok(result)
+                Ok(result)
+            })
+            .await
     }
 
     /// Check if session exists
     pub async fn has_session(&self, address: &ProtocolAddress) -> Result<bool> {
-        let db = self.db.lock().await;
         let addr_str = address.to_string();
 
-        let count: i32 = db.query_row(
-            "SELECT COUNT(*) FROM sessions WHERE address = ?",
-            params![addr_str],
-            |row| row.get(0),
-        )?;
+        self.db
+            .read(move |db| {
+                let count: i32 = db
+                    .prepare_cached("SELECT COUNT(*) FROM sessions WHERE address = ?")?
+                    .query_row(params![addr_str], |row| row.get(0))?;
 
-        Ok(count > 0)
+                Ok(count > 0)
+            })
+            .await
     }
 
     /// Delete session
     pub async fn delete_session(&self, address: &ProtocolAddress) -> Result<()> {
-        let db = self.db.lock().await;
         let addr_str = address.to_string();
 
-        db.execute("DELETE FROM sessions WHERE address = ?", params![addr_str])?;
+        self.db
+            .write(move |db| {
+                db.execute("DELETE FROM sessions WHERE address = ?", params![addr_str])?;
 
-        tracing::info!("Deleted session for {}", addr_str);
-        Ok(())
+                tracing::info!("Deleted session for {}", addr_str);
+                Ok(())
+            })
+            .await
     }
 
     // ==================== Conversation Operations ====================
 
     /// Store a conversation
     pub async fn store_conversation(&self, conversation: &Conversation) -> Result<()> {
-        let db = self.db.lock().await;
-        let now = chrono::Utc::now().timestamp();
-
-        db.execute(
-            r#"INSERT OR REPLACE INTO conversations
-               (id, recipient_uuid, recipient_device_id, is_group, group_id, name,
-                archived, muted_until, unread_count, last_message_id, created_at, updated_at)
-               VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
-            params![
-                conversation.id,
-                conversation.recipient.uuid.to_string(),
-                conversation.recipient.device_id,
-                conversation.is_group,
-                conversation.group_id,
-                conversation.name,
-                conversation.archived,
-                conversation.muted_until,
-                conversation.unread_count,
-                conversation.last_message.as_ref().map(|m| &m.id),
-                now,
-                now,
-            ],
-        )?;
+        let conversation = conversation.clone();
+
+        self.db
+            .write(move |db| {
+                let now = chrono::Utc::now().timestamp();
+                let tx = db.transaction()?;
+
+                let recipient = &conversation.recipient;
+                let aci = Some(recipient.aci).filter(|aci| !aci.is_nil());
+                let e164 = recipient.phone_number.as_deref();
+
+                let recipient_id = if conversation.is_group
+                    || (aci.is_none() && recipient.pni.is_none() && e164.is_none())
+                {
+                    None
+                } else {
+                    Some(merge_recipient_in(&tx, aci, recipient.pni, e164)?.id)
+                };
 
-        tracing::info!("Stored conversation {}", conversation.id);
-        Ok(())
+                tx.execute(
+                    r#"INSERT OR REPLACE INTO conversations
+                       (id, recipient_uuid, recipient_device_id, is_group, group_id, name,
+                        archived, muted_until, unread_count, last_message_id, created_at, updated_at,
+                        recipient_pni, recipient_id)
+                       VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
+                    params![
+                        conversation.id,
+                        conversation.recipient.aci.to_string(),
+                        conversation.recipient.device_id,
+                        conversation.is_group,
+                        conversation.group_id,
+                        conversation.name,
+                        conversation.archived,
+                        conversation.muted_until,
+                        conversation.unread_count,
+                        conversation.last_message.as_ref().map(|m| &m.id),
+                        now,
+                        now,
+                        conversation.recipient.pni.map(|pni| pni.to_string()),
+                        recipient_id,
+                    ],
+                )?;
+                tx.commit()?;
+
+                tracing::info!("Stored conversation {}", conversation.id);
+                Ok(())
+            })
+            .await
     }
 
-    /// Get all conversations
+    /// Get all conversations, most recently active first
     pub async fn get_conversations(&self) -> Result<Vec<Conversation>> {
-        let db = self.db.lock().await;
+        self.db
+            .read(|db| {
+                let mut stmt = db.prepare_cached(&format!(
+                    r#"SELECT id, recipient_uuid, recipient_device_id, is_group, group_id, name,
+                              archived, muted_until, unread_count, recipient_pni, last_message_id,
+                              {}, pinned.position
+                       FROM conversations
+                       LEFT JOIN pinned_conversations pinned ON pinned.conversation_id = id
+                       ORDER BY pinned.position IS NULL, pinned.position, updated_at DESC"#,
+                    CONVERSATION_BLOCKED
+                ))?;
+
+                let mut conversations: Vec<(Conversation, Option<String>)> = stmt
+                    .query_map([], conversation_from_row)?
+                    .filter_map(|r| r.ok())
+                    .collect();
+                for (conversation, last_message_id) in &mut conversations {
+                    if let Some(id) = last_message_id {
+                        conversation.last_message =
+                            load_message(db, id)?.map(|(message, _)| message);
+                    }
+                }
+
+                Ok(conversations
+                    .into_iter()
+                    .map(|(conversation, _)| conversation)
+                    .collect())
+            })
+            .await
+    }
 
-        let mut stmt = db.prepare(
-            r#"SELECT id, recipient_uuid, recipient_device_id, is_group, group_id, name,
-                      archived, muted_until, unread_count
-               FROM conversations ORDER BY updated_at DESC"#,
-        )?;
+    /// Get a specific conversation
+    pub async fn get_conversation(&self, id: &str) -> Result<Option<Conversation>> {
+        let id = id.to_string();
 
-        let conversations = stmt
-            .query_map([], |row| {
-                Ok(Conversation {
-                    id: row.get(0)?,
-                    recipient: SignalIdentity {
-                        uuid: row
-                            .get::<_, String>(1)?
-                            .parse()
-                            .unwrap_or(uuid::Uuid::nil()),
-                        phone_number: None,
-                        device_id: row.get(2)?,
-                        registration_id: 0,
-                    },
-                    is_group: row.get(3)?,
-                    group_id: row.get(4)?,
-                    name: row.get(5)?,
-                    last_message: None,
-                    unread_count: row.get(8)?,
-                    archived: row.get(6)?,
-                    muted_until: row.get(7)?,
-                })
-            })?
-            .filter_map(|r| r.ok())
-            .collect();
+        self.db
+            .read(move |db| Ok(load_conversation(db, &id)?))
+            .await
+    }
+
+    /// Store a message and create or update the conversation it belongs to
+    ///
+    /// `recipient` is the other party of a 1:1 conversation; `group_id` is
+    /// set for group conversations. The conversation takes its name from
+    /// the group or contact, its last message follows the newest message,
+    /// and an `unread` message raises its unread count the first time it is
+    /// stored. Returns the updated conversation.
+    pub async fn store_conversation_message(
+        &self,
+        message: &Message,
+        recipient: &SignalIdentity,
+        group_id: Option<&str>,
+        unread: bool,
+    ) -> Result<Conversation> {
+        let message = message.clone();
+        let recipient = recipient.clone();
+        let group_id = group_id.map(str::to_string);
+
+        self.db
+            .write(move |db| {
+                let now = chrono::Utc::now().timestamp();
+                let tx = db.transaction()?;
+
+                let aci = Some(recipient.aci).filter(|aci| !aci.is_nil());
+                let e164 = recipient.phone_number.as_deref();
+                let recipient_id = if group_id.is_some()
+                    || (aci.is_none() && recipient.pni.is_none() && e164.is_none())
+                {
+                    None
+                } else {
+                    Some(merge_recipient_in(&tx, aci, recipient.pni, e164)?.id)
+                };
 
-        Ok(conversations)
+                let name = match (&group_id, recipient_id) {
+                    (Some(group_id), _) => group_name(&tx, group_id)?,
+                    (None, Some(recipient_id)) => contact_name(&tx, recipient_id)?,
+                    (None, None) => None,
+                };
+                let fallback_name = match &group_id {
+                    Some(_) => "Unknown group".to_string(),
+                    None => e164.unwrap_or("Unknown").to_string(),
+                };
+
+                // A conversation keeps its name until its group or contact has one
+                tx.prepare_cached(
+                    r#"INSERT INTO conversations
+                       (id, recipient_uuid, recipient_device_id, is_group, group_id, name,
+                        created_at, updated_at, recipient_pni, recipient_id)
+                       VALUES (?1, ?2, ?3, ?4, ?5, COALESCE(?6, ?7), ?8, ?8, ?9, ?10)
+                       ON CONFLICT(id) DO UPDATE SET
+                           name = COALESCE(?6, name),
+                           recipient_id = COALESCE(?10, recipient_id)"#,
+                )?
+                .execute(params![
+                    message.conversation_id,
+                    recipient.aci.to_string(),
+                    recipient.device_id,
+                    group_id.is_some(),
+                    group_id,
+                    name,
+                    fallback_name,
+                    now,
+                    recipient.pni.map(|pni| pni.to_string()),
+                    recipient_id,
+                ])?;
+
+                // Neither stored yet, nor from an envelope already stored
+                let server_guid = message
+                    .envelope
+                    .as_ref()
+                    .and_then(|envelope| envelope.server_guid.clone());
+                let is_new = tx
+                    .prepare_cached("SELECT 1 FROM messages WHERE id = ? OR server_guid = ?")?
+                    .query_row(params![message.id, server_guid], |_| Ok(()))
+                    .optional()?
+                    .is_none();
+
+                insert_message(&tx, &message, unread)?;
+                if unread && is_new {
+                    tx.prepare_cached(
+                        "UPDATE conversations SET unread_count = unread_count + 1 WHERE id = ?",
+                    )?
+                    .execute(params![message.conversation_id])?;
+                }
+
+                let conversation = load_conversation(&tx, &message.conversation_id)?
+                    .ok_or_else(|| anyhow!("Conversation {} vanished", message.conversation_id))?;
+                tx.commit()?;
+
+                tracing::debug!(
+                    "Stored message {} in conversation {}",
+                    message.id,
+                    conversation.id
+                );
+                Ok(conversation)
+            })
+            .await
     }
 
-    /// Get a specific conversation
-    pub async fn get_conversation(&self, id: &str) -> Result<Option<Conversation>> {
-        let db = self.db.lock().await;
+    /// Reset the unread count of a conversation and mark its messages read
+    ///
+    /// Returns the conversation with the author and sent timestamp of each
+    /// message that was unread, or `None` for an unknown conversation.
+    pub async fn mark_conversation_read(
+        &self,
+        id: &str,
+    ) -> Result<Option<(Conversation, Vec<(String, i64)>)>> {
+        let id = id.to_string();
+
+        self.db
+            .write(move |db| {
+                let tx = db.transaction()?;
+                let read = tx
+                    .prepare_cached(
+                        r#"UPDATE messages SET unread = 0
+                           WHERE conversation_id = ? AND unread = 1
+                           RETURNING sender_uuid, timestamp"#,
+                    )?
+                    .query_map(params![id], |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect::<rusqlite::Result<Vec<(String, i64)>>>()?;
+                tx.prepare_cached("UPDATE conversations SET unread_count = 0 WHERE id = ?")?
+                    .execute(params![id])?;
+
+                let conversation = load_conversation(&tx, &id)?;
+                tx.commit()?;
+
+                Ok(conversation.map(|conversation| (conversation, read)))
+            })
+            .await
+    }
 
-        let result = db
-            .query_row(
-                r#"SELECT id, recipient_uuid, recipient_device_id, is_group, group_id, name,
-                          archived, muted_until, unread_count
-                   FROM conversations WHERE id = ?"#,
-                params![id],
-                |row| {
-                    Ok(Conversation {
-                        id: row.get(0)?,
-                        recipient: SignalIdentity {
-                            uuid: row
-                                .get::<_, String>(1)?
-                                .parse()
-                                .unwrap_or(uuid::Uuid::nil()),
-                            phone_number: None,
-                            device_id: row.get(2)?,
-                            registration_id: 0,
-                        },
-                        is_group: row.get(3)?,
-                        group_id: row.get(4)?,
-                        name: row.get(5)?,
-                        last_message: None,
-                        unread_count: row.get(8)?,
-                        archived: row.get(6)?,
-                        muted_until: row.get(7)?,
-                    })
-                },
-            )
-            .optional()?;
+    /// Apply messages read on another device, given by author and sent
+    /// timestamp
+    ///
+    /// Reading a message also reads everything before it in its
+    /// conversation. Returns the conversations whose unread count changed.
+    /// Reads of messages not stored yet are dropped.
+    pub async fn apply_read_sync(&self, reads: &[(String, i64)]) -> Result<Vec<Conversation>> {
+        let reads = reads.to_vec();
+
+        self.db
+            .write(move |db| {
+                let tx = db.transaction()?;
+                let mut changed: Vec<String> = Vec::new();
+
+                for (sender_uuid, timestamp) in &reads {
+                    let conversation_id: Option<String> = tx
+                        .prepare_cached(
+                            "SELECT conversation_id FROM messages WHERE sender_uuid = ? AND timestamp = ?",
+                        )?
+                        .query_row(params![sender_uuid, timestamp], |row| row.get(0))
+                        .optional()?;
+                    let Some(conversation_id) = conversation_id else {
+                        tracing::debug!("Read message {} from {} is not stored", timestamp, sender_uuid);
+                        continue;
+                    };
 
-        Ok(result)
+                    let read = tx
+                        .prepare_cached(
+                            r#"UPDATE messages SET unread = 0
+                               WHERE conversation_id = ? AND unread = 1 AND timestamp <= ?"#,
+                        )?
+                        .execute(params![conversation_id, timestamp])?;
+                    if read == 0 {
+                        continue;
+                    }
+
+                    tx.prepare_cached(
+                        r#"UPDATE conversations SET unread_count =
+                               (SELECT COUNT(*) FROM messages WHERE conversation_id = ?1 AND unread = 1)
+                           WHERE id = ?1"#,
+                    )?
+                    .execute(params![conversation_id])?;
+                    if !changed.contains(&conversation_id) {
+                        changed.push(conversation_id);
+                    }
+                }
+
+                let mut conversations = Vec::new();
+                for id in &changed {
+                    conversations.extend(load_conversation(&tx, id)?);
+                }
+                tx.commit()?;
+
+                Ok(conversations)
+            })
+            .await
     }
 
     // ==================== Message Operations ====================
 
-    /// Store a message
+    /// Store a message with its attachment and reactions
+    ///
+    /// The reactions of `message` are added to those already stored; use
+    /// `apply_reaction` to remove one.
     pub async fn store_message(&self, message: &Message) -> Result<()> {
-        let db = self.db.lock().await;
-        let now = chrono::Utc::now().timestamp();
-
-        let content_type = match &message.content {
-            MessageContent::Text { .. } => "text",
-            MessageContent::Image { .. } => "image",
-            MessageContent::Video { .. } => "video",
-            MessageContent::Audio { .. } => "audio",
-            MessageContent::File { .. } => "file",
-            MessageContent::Voice { .. } => "voice",
-            MessageContent::Sticker { .. } => "sticker",
-            MessageContent::Contact { .. } => "contact",
-            MessageContent::Location { .. } => "location",
-        };
+        let message = message.clone();
+
+        self.db
+            .write(move |db| {
+                let tx = db.transaction()?;
+                insert_message(&tx, &message, false)?;
+                tx.commit()?;
+
+                tracing::debug!("Stored message {}", message.id);
+                Ok(())
+            })
+            .await
+    }
 
-        let content_json = serde_json::to_string(&message.content)?;
-        let status = format!("{:?}", message.status);
+    /// Get the newest `limit` messages of a conversation, newest first
+    pub async fn get_messages(&self, conversation_id: &str, limit: usize) -> Result<Vec<Message>> {
+        let conversation_id = conversation_id.to_string();
+
+        self.db
+            .read(move |db| {
+                let mut stmt = db.prepare_cached(
+                    r#"SELECT id, conversation_id, sender_uuid, sender_device_id, timestamp,
+                              received_timestamp, content_type, content_json, status, expires_at,
+                              server_guid, server_timestamp, destination_service_id, urgent, story,
+                              quote_id, quote_author, quote_timestamp
+                       FROM messages WHERE conversation_id = ?
+                       ORDER BY timestamp DESC, id DESC LIMIT ?"#,
+                )?;
+
+                let rows = stmt
+                    .query_map(params![conversation_id, limit], message_with_quote)?
+                    .filter_map(|r| r.ok())
+                    .collect();
+
+                Ok(hydrate_messages(db, rows)?)
+            })
+            .await
+    }
 
-        db.execute(
-            r#"INSERT OR REPLACE INTO messages
-               (id, conversation_id, sender_uuid, sender_device_id, timestamp,
-                received_timestamp, content_type, content_json, status, quote_id,
-                expires_at, created_at)
-               VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
-            params![
-                message.id,
-                message.conversation_id,
-                message.sender.uuid.to_string(),
-                message.sender.device_id,
-                message.timestamp,
-                message.received_timestamp,
-                content_type,
-                content_json,
-                status,
-                message.quote.as_ref().map(|q| &q.id),
-                message.expires_at,
-                now,
-            ],
-        )?;
+    /// Get up to `limit` messages sent before `cursor`, oldest first
+    pub async fn get_messages_before(
+        &self,
+        conversation_id: &str,
+        cursor: &MessageCursor,
+        limit: usize,
+    ) -> Result<Vec<Message>> {
+        let conversation_id = conversation_id.to_string();
+        let cursor = cursor.clone();
+
+        self.db
+            .read(move |db| Ok(messages_before(db, &conversation_id, &cursor, limit)?))
+            .await
+    }
 
-        // Update conversation's last message
-        db.execute(
-            "UPDATE conversations SET last_message_id = ?, updated_at = ? WHERE id = ?",
-            params![message.id, now, message.conversation_id],
-        )?;
+    /// Get up to `limit` messages sent after `cursor`, oldest first
+    pub async fn get_messages_after(
+        &self,
+        conversation_id: &str,
+        cursor: &MessageCursor,
+        limit: usize,
+    ) -> Result<Vec<Message>> {
+        let conversation_id = conversation_id.to_string();
+        let cursor = cursor.clone();
+
+        self.db
+            .read(move |db| Ok(messages_after(db, &conversation_id, &cursor, limit)?))
+            .await
+    }
 
-        tracing::debug!("Stored message {}", message.id);
-        Ok(())
+    /// Get up to `limit` messages centred on `message_id`
+    ///
+    /// Returns `None` if the message is not part of the conversation.
+    pub async fn get_messages_around(
+        &self,
+        conversation_id: &str,
+        message_id: &str,
+        limit: usize,
+    ) -> Result<Option<MessageWindow>> {
+        let conversation_id = conversation_id.to_string();
+        let message_id = message_id.to_string();
+
+        self.db
+            .read(move |db| {
+                let focus = db
+                    .prepare_cached(
+                        r#"SELECT id, conversation_id, sender_uuid, sender_device_id, timestamp,
+                                  received_timestamp, content_type, content_json, status,
+                                  expires_at, server_guid, server_timestamp,
+                                  destination_service_id, urgent, story,
+                                  quote_id, quote_author, quote_timestamp
+                           FROM messages WHERE id = ? AND conversation_id = ?"#,
+                    )?
+                    .query_row(params![message_id, conversation_id], message_with_quote)
+                    .optional()?;
+                let Some((mut focus, quote)) = focus else {
+                    return Ok(None);
+                };
+                hydrate_message(db, &mut focus, quote)?;
+
+                let cursor = MessageCursor::of(&focus);
+                let older_count = limit.saturating_sub(1) / 2;
+                let newer_count = limit.saturating_sub(1) - older_count;
+
+                // One extra row on each side tells whether there is more
+                let mut older = messages_before(db, &conversation_id, &cursor, older_count + 1)?;
+                let has_older = older.len() > older_count;
+                if has_older {
+                    older.remove(0);
+                }
+
+                let mut newer = messages_after(db, &conversation_id, &cursor, newer_count + 1)?;
+                let has_newer = newer.len() > newer_count;
+                newer.truncate(newer_count);
+
+                let focus_index = older.len();
+                let mut messages = older;
+                messages.push(focus);
+                messages.append(&mut newer);
+
+                Ok(Some(MessageWindow {
+                    messages,
+                    focus_index,
+                    has_older,
+                    has_newer,
+                }))
+            })
+            .await
     }
 
-    /// Get messages for a conversation
-    pub async fn get_messages(&self, conversation_id: &str, limit: usize) -> Result<Vec<Message>> {
-        let db = self.db.lock().await;
+    /// Get a message by ID
+    pub async fn get_message(&self, message_id: &str) -> Result<Option<Message>> {
+        let message_id = message_id.to_string();
 
-        let mut stmt = db.prepare(
-            r#"SELECT id, conversation_id, sender_uuid, sender_device_id, timestamp,
-                      received_timestamp, content_type, content_json, status, expires_at
-               FROM messages WHERE conversation_id = ?
-               ORDER BY timestamp DESC LIMIT ?"#,
-        )?;
+        self.db
+            .read(move |db| {
+                let Some((mut message, quote)) = load_message(db, &message_id)? else {
+                    return Ok(None);
+                };
+                hydrate_message(db, &mut message, quote)?;
+
+                Ok(Some(message))
+            })
+            .await
+    }
 
-        let messages = stmt
-            .query_map(params![conversation_id, limit], |row| {
-                let content_json: String = row.get(7)?;
-                let content: MessageContent =
-                    serde_json::from_str(&content_json).unwrap_or(MessageContent::Text {
-                        body: "[Error loading message]".to_string(),
-                    });
-
-                let status_str: String = row.get(8)?;
-                let status = match status_str.as_str() {
-                    "Sending" => MessageStatus::Sending,
-                    "Sent" => MessageStatus::Sent,
-                    "Delivered" => MessageStatus::Delivered,
-                    "Read" => MessageStatus::Read,
-                    _ => MessageStatus::Failed,
+    /// Add or remove a reaction by `sender_uuid` to a message of a conversation
+    ///
+    /// The target is found by its author and sent timestamp. Returns the ID
+    /// of the target, or `None` if it is not stored.
+    pub async fn apply_reaction(
+        &self,
+        conversation_id: &str,
+        sender_uuid: &str,
+        update: &ReactionUpdate,
+        timestamp: i64,
+    ) -> Result<Option<String>> {
+        let conversation_id = conversation_id.to_string();
+        let sender_uuid = sender_uuid.to_string();
+        let update = update.clone();
+
+        self.db
+            .write(move |db| {
+                let target: Option<String> = db
+                    .prepare_cached(
+                        r#"SELECT id FROM messages
+                           WHERE conversation_id = ? AND sender_uuid = ? AND timestamp = ?"#,
+                    )?
+                    .query_row(
+                        params![
+                            conversation_id,
+                            update.target_author.to_string(),
+                            update.target_timestamp
+                        ],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                let Some(target) = target else {
+                    tracing::debug!(
+                        "Reaction target {} from {} is not stored",
+                        update.target_timestamp,
+                        update.target_author
+                    );
+                    return Ok(None);
                 };
 
-                Ok(Message {
-                    id: row.get(0)?,
-                    conversation_id: row.get(1)?,
-                    sender: SignalIdentity {
-                        uuid: row
-                            .get::<_, String>(2)?
-                            .parse()
-                            .unwrap_or(uuid::Uuid::nil()),
-                        phone_number: None,
-                        device_id: row.get(3)?,
-                        registration_id: 0,
-                    },
-                    timestamp: row.get(4)?,
-                    received_timestamp: row.get(5)?,
-                    content,
-                    status,
-                    quote: None,
-                    reactions: Vec::new(),
-                    expires_at: row.get(9)?,
-                })
-            })?
-            .filter_map(|r| r.ok())
-            .collect();
+                if update.remove {
+                    db.prepare_cached(
+                        "DELETE FROM reactions WHERE message_id = ? AND sender_uuid = ? AND emoji = ?",
+                    )?
+                    .execute(params![target, sender_uuid, update.emoji])?;
+                } else {
+                    // One reaction per sender; a new one replaces the old
+                    db.prepare_cached(
+                        r#"INSERT OR REPLACE INTO reactions (message_id, emoji, sender_uuid, timestamp)
+                           VALUES (?, ?, ?, ?)"#,
+                    )?
+                    .execute(params![target, update.emoji, sender_uuid, timestamp])?;
+                }
+
+                Ok(Some(target))
+            })
+            .await
+    }
+
+    /// Search message text, best matches first
+    ///
+    /// Every word of `query` has to match, the last one as a prefix so that
+    /// results can update while typing. Bodies, captions and attachment file
+    /// names are searched, optionally within one conversation only.
+    pub async fn search_messages(
+        &self,
+        query: &str,
+        conversation_id: Option<&str>,
+        paging: SearchPaging,
+    ) -> Result<Vec<SearchHit>> {
+        let Some(fts_query) = fts_query(query) else {
+            return Ok(Vec::new());
+        };
+        let conversation_id = conversation_id.map(str::to_string);
+
+        self.db
+            .read(move |db| {
+                let mut stmt = db.prepare_cached(
+                    r#"SELECT m.id, m.conversation_id, m.sender_uuid, m.sender_device_id, m.timestamp,
+                              m.received_timestamp, m.content_type, m.content_json, m.status,
+                              m.expires_at, m.server_guid, m.server_timestamp,
+                              m.destination_service_id, m.urgent, m.story,
+                              m.quote_id, m.quote_author, m.quote_timestamp,
+                              snippet(messages_fts, -1, ?, ?, '…', ?), messages_fts.rank,
+                              COALESCE(c.name, '')
+                       FROM messages_fts
+                       JOIN messages m ON m.rowid = messages_fts.rowid
+                       LEFT JOIN conversations c ON c.id = m.conversation_id
+                       WHERE messages_fts MATCH ? AND (? IS NULL OR m.conversation_id = ?)
+                       ORDER BY messages_fts.rank, m.timestamp DESC
+                       LIMIT ? OFFSET ?"#,
+                )?;
+
+                let rows = stmt
+                    .query_map(
+                        params![
+                            SNIPPET_OPEN,
+                            SNIPPET_CLOSE,
+                            SNIPPET_TOKENS,
+                            fts_query,
+                            conversation_id,
+                            conversation_id,
+                            paging.limit,
+                            paging.offset,
+                        ],
+                        |row| {
+                            let (snippet, highlights) = parse_snippet(&row.get::<_, String>(18)?);
+
+                            Ok((
+                                SearchHit {
+                                    message: message_from_row(row)?,
+                                    conversation_name: row.get(20)?,
+                                    snippet,
+                                    highlights,
+                                    rank: row.get(19)?,
+                                },
+                                quote_reference(row)?,
+                            ))
+                        },
+                    )?
+                    .filter_map(|r| r.ok())
+                    .collect::<Vec<_>>();
+
+                let mut hits = Vec::with_capacity(rows.len());
+                for (mut hit, quote) in rows {
+                    hydrate_message(db, &mut hit.message, quote)?;
+                    hits.push(hit);
+                }
+
+                Ok(hits)
+            })
+            .await
+    }
 
-        Ok(messages)
+    /// Check whether a message from the envelope with this server GUID is stored
+    pub async fn has_server_guid(&self, server_guid: &str) -> Result<bool> {
+        let server_guid = server_guid.to_string();
+
+        self.db
+            .read(move |db| {
+                let count: i64 = db
+                    .prepare_cached("SELECT COUNT(*) FROM messages WHERE server_guid = ?")?
+                    .query_row(params![server_guid], |row| row.get(0))?;
+
+                Ok(count > 0)
+            })
+            .await
+    }
+
+    /// Check whether `sender_uuid` has a message sent at `timestamp` stored
+    /// in a conversation
+    pub async fn has_message(
+        &self,
+        conversation_id: &str,
+        sender_uuid: &str,
+        timestamp: i64,
+    ) -> Result<bool> {
+        let conversation_id = conversation_id.to_string();
+        let sender_uuid = sender_uuid.to_string();
+
+        self.db
+            .read(move |db| {
+                let count: i64 = db
+                    .prepare_cached(
+                        r#"SELECT COUNT(*) FROM messages
+                           WHERE conversation_id = ? AND sender_uuid = ? AND timestamp = ?"#,
+                    )?
+                    .query_row(params![conversation_id, sender_uuid, timestamp], |row| {
+                        row.get(0)
+                    })?;
+
+                Ok(count > 0)
+            })
+            .await
+    }
+
+    /// Remove the placeholder left for a message we could not decrypt
+    ///
+    /// Called once the sender has resent the message with the same timestamp.
+    pub async fn delete_decryption_placeholder(
+        &self,
+        sender_uuid: &str,
+        timestamp: i64,
+    ) -> Result<bool> {
+        let sender_uuid = sender_uuid.to_string();
+
+        self.db
+            .write(move |db| {
+                let deleted = db
+                    .prepare_cached(
+                        r#"DELETE FROM messages
+                           WHERE sender_uuid = ? AND timestamp = ? AND content_type = 'decryption_failed'"#,
+                    )?
+                    .execute(params![sender_uuid, timestamp])?;
+
+                Ok(deleted > 0)
+            })
+            .await
     }
 
     /// Update message status
     pub async fn update_message_status(&self, message_id: &str, status: MessageStatus) -> Result<()> {
-        let db = self.db.lock().await;
-        let status_str = format!("{:?}", status);
+        let message_id = message_id.to_string();
 
-        db.execute(
-            "UPDATE messages SET status = ? WHERE id = ?",
-            params![status_str, message_id],
-        )?;
+        self.db
+            .write(move |db| {
+                let status_str = format!("{:?}", status);
 
-        tracing::debug!("Updated message {} status to {:?}", message_id, status);
-        Ok(())
+                db.prepare_cached("UPDATE messages SET status = ? WHERE id = ?")?
+                    .execute(params![status_str, message_id])?;
+
+                tracing::debug!("Updated message {} status to {:?}", message_id, status);
+                Ok(())
+            })
+            .await
+    }
+
+    /// Mark the messages `sender` sent in a conversation up to `read_at` as
+    /// read, returning the IDs of those that were not read before
+    pub async fn mark_sent_messages_read(
+        &self,
+        conversation_id: &str,
+        sender: Aci,
+        read_at: i64,
+    ) -> Result<Vec<String>> {
+        let conversation_id = conversation_id.to_string();
+
+        self.db
+            .write(move |db| {
+                let tx = db.transaction()?;
+                let ids = tx
+                    .prepare_cached(
+                        r#"UPDATE messages SET status = 'Read'
+                           WHERE conversation_id = ? AND sender_uuid = ? AND timestamp <= ?
+                             AND status IN ('Sent', 'Delivered')
+                           RETURNING id"#,
+                    )?
+                    .query_map(params![conversation_id, sender.to_string(), read_at], |row| {
+                        row.get(0)
+                    })?
+                    .collect::<rusqlite::Result<Vec<String>>>()?;
+
+                // The reader is the other party of the conversation
+                let now = chrono::Utc::now().timestamp();
+                for id in &ids {
+                    tx.prepare_cached(
+                        r#"UPDATE message_recipients SET status = 'Read', updated_at = ?
+                           WHERE message_id = ? AND recipient_id =
+                               (SELECT recipient_id FROM conversations WHERE id = ?)"#,
+                    )?
+                    .execute(params![now, id, conversation_id])?;
+                }
+                tx.commit()?;
+
+                Ok(ids)
+            })
+            .await
+    }
+
+    /// Record who an outgoing message was sent to, each with `status`
+    ///
+    /// Recipients already recorded for the message keep their state.
+    pub async fn store_message_recipients(
+        &self,
+        message_id: &str,
+        recipients: &[SentRecipient],
+        status: MessageStatus,
+    ) -> Result<()> {
+        let message_id = message_id.to_string();
+        let recipients = recipients.to_vec();
+
+        self.db
+            .write(move |db| {
+                let now = chrono::Utc::now().timestamp();
+                let tx = db.transaction()?;
+
+                for sent in &recipients {
+                    let identity = &sent.identity;
+                    let recipient = merge_recipient_in(
+                        &tx,
+                        Some(identity.aci).filter(|aci| !aci.is_nil()),
+                        identity.pni,
+                        identity.phone_number.as_deref(),
+                    )?;
+                    tx.prepare_cached(
+                        r#"INSERT OR IGNORE INTO message_recipients
+                           (message_id, recipient_id, status, unidentified, updated_at)
+                           VALUES (?, ?, ?, ?, ?)"#,
+                    )?
+                    .execute(params![
+                        message_id,
+                        recipient.id,
+                        format!("{:?}", status),
+                        sent.unidentified,
+                        now,
+                    ])?;
+                }
+                tx.commit()?;
+
+                Ok(())
+            })
+            .await
+    }
+
+    /// Get the delivery state of an outgoing message for each recipient
+    pub async fn get_message_recipients(&self, message_id: &str) -> Result<Vec<RecipientStatus>> {
+        let message_id = message_id.to_string();
+
+        self.db
+            .read(move |db| {
+                let mut stmt = db.prepare_cached(
+                    r#"SELECT r.aci, r.pni, r.e164, m.status, m.updated_at
+                       FROM message_recipients m JOIN recipients r ON r.id = m.recipient_id
+                       WHERE m.message_id = ?
+                       ORDER BY r.id"#,
+                )?;
+                let statuses = stmt
+                    .query_map(params![message_id], |row| {
+                        Ok(RecipientStatus {
+                            recipient: SignalIdentity {
+                                aci: parse_aci(
+                                    &row.get::<_, Option<String>>(0)?.unwrap_or_default(),
+                                ),
+                                pni: parse_pni(row.get(1)?),
+                                phone_number: row.get(2)?,
+                                device_id: 1,
+                                registration_id: 0,
+                            },
+                            status: parse_status(&row.get::<_, String>(3)?),
+                            updated_at: row.get(4)?,
+                        })
+                    })?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+
+                Ok(statuses)
+            })
+            .await
+    }
+
+    // ==================== Recent Send Operations ====================
+
+    /// Remember encoded content sent to a recipient so it can be resent
+    pub async fn store_recent_send(
+        &self,
+        recipient_uuid: &str,
+        timestamp: i64,
+        content: &[u8],
+    ) -> Result<()> {
+        let recipient_uuid = recipient_uuid.to_string();
+        let content = content.to_vec();
+
+        self.db
+            .write(move |db| {
+                let now = chrono::Utc::now().timestamp_millis();
+
+                db.prepare_cached("DELETE FROM recent_sends WHERE created_at < ?")?
+                    .execute(params![now - RECENT_SEND_RETENTION_MS])?;
+                db.prepare_cached(
+                    r#"INSERT OR REPLACE INTO recent_sends (recipient_uuid, timestamp, content, created_at)
+                       VALUES (?, ?, ?, ?)"#,
+                )?
+                .execute(params![recipient_uuid, timestamp, content, now])?;
+
+                Ok(())
+            })
+            .await
+    }
+
+    /// Get content previously sent to a recipient at the given timestamp
+    pub async fn get_recent_send(
+        &self,
+        recipient_uuid: &str,
+        timestamp: i64,
+    ) -> Result<Option<Vec<u8>>> {
+        let recipient_uuid = recipient_uuid.to_string();
+
+        self.db
+            .read(move |db| {
+                let cutoff = chrono::Utc::now().timestamp_millis() - RECENT_SEND_RETENTION_MS;
+
+                let result = db
+                    .prepare_cached(
+                        r#"SELECT content FROM recent_sends
+                           WHERE recipient_uuid = ? AND timestamp = ? AND created_at >= ?"#,
+                    )?
+                    .query_row(params![recipient_uuid, timestamp, cutoff], |row| row.get(0))
+                    .optional()?;
+
+                Ok(result)
+            })
+            .await
     }
 
     // ==================== Contact Operations ====================
 
     /// Store a contact
     pub async fn store_contact(&self, contact: &SignalIdentity) -> Result<()> {
-        let db = self.db.lock().await;
-        let now = chrono::Utc::now().timestamp();
+        let contact = contact.clone();
+
+        self.db
+            .write(move |db| {
+                let now = chrono::Utc::now().timestamp();
+                let tx = db.transaction()?;
+
+                let recipient = merge_recipient_in(
+                    &tx,
+                    Some(contact.aci).filter(|aci| !aci.is_nil()),
+                    contact.pni,
+                    contact.phone_number.as_deref(),
+                )?;
+                let key = match (recipient.service_id(), &recipient.e164) {
+                    (Some(service_id), _) => service_id.to_string(),
+                    (None, Some(e164)) => e164.clone(),
+                    (None, None) => return Err(anyhow!("Contact without any identifier")),
+                };
 
-        db.execute(
-            r#"INSERT OR REPLACE INTO contacts
-               (uuid, phone_number, created_at, updated_at)
-               VALUES (?, ?, ?, ?)"#,
-            params![
-                contact.uuid.to_string(),
-                contact.phone_number,
-                now,
-                now,
-            ],
-        )?;
+                // One contact row per recipient, keyed by its preferred identifier
+                tx.execute(
+                    "DELETE FROM contacts WHERE recipient_id = ? AND uuid != ?",
+                    params![recipient.id, key],
+                )?;
+                tx.execute(
+                    r#"INSERT INTO contacts
+                       (uuid, pni, phone_number, recipient_id, created_at, updated_at)
+                       VALUES (?, ?, ?, ?, ?, ?)
+                       ON CONFLICT(uuid) DO UPDATE SET
+                           pni = excluded.pni,
+                           phone_number = excluded.phone_number,
+                           recipient_id = excluded.recipient_id,
+                           updated_at = excluded.updated_at"#,
+                    params![
+                        key,
+                        recipient.pni.map(|pni| pni.to_string()),
+                        recipient.e164,
+                        recipient.id,
+                        now,
+                        now,
+                    ],
+                )?;
+                tx.commit()?;
+
+                tracing::info!("Stored contact {}", key);
+                Ok(())
+            })
+            .await
+    }
 
-        tracing::info!("Stored contact {:?}", contact.uuid);
-        Ok(())
+    /// Store a contact received in a contact sync from the primary device
+    ///
+    /// Replaces the contact's name, color, expiration timer and avatar with
+    /// the synced ones, renames its conversation to match, and returns the
+    /// contact's identity.
+    pub async fn store_synced_contact(&self, details: &ContactDetails) -> Result<SignalIdentity> {
+        let synced = details.clone();
+        let avatar_dir = self.data_dir.join("avatars");
+
+        let (identity, avatar_path) = self
+            .db
+            .write(move |db| {
+                let now = chrono::Utc::now().timestamp();
+                let tx = db.transaction()?;
+
+                let recipient = merge_recipient_in(
+                    &tx,
+                    synced.aci.filter(|aci| !aci.is_nil()),
+                    None,
+                    synced.phone_number.as_deref(),
+                )?;
+                let key = match (recipient.service_id(), &recipient.e164) {
+                    (Some(service_id), _) => service_id.to_string(),
+                    (None, Some(e164)) => e164.clone(),
+                    (None, None) => return Err(anyhow!("Contact without any identifier")),
+                };
+                let avatar_path = avatar_dir.join(format!("contact-{}", key));
+
+                tx.execute(
+                    "DELETE FROM contacts WHERE recipient_id = ? AND uuid != ?",
+                    params![recipient.id, key],
+                )?;
+                tx.execute(
+                    r#"INSERT INTO contacts
+                       (uuid, pni, phone_number, name, profile_key, avatar_path, color,
+                        expire_timer, inbox_position, recipient_id, created_at, updated_at)
+                       VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?11)
+                       ON CONFLICT(uuid) DO UPDATE SET
+                           pni = excluded.pni,
+                           phone_number = excluded.phone_number,
+                           name = excluded.name,
+                           profile_key = COALESCE(excluded.profile_key, profile_key),
+                           profile_fetched_at = CASE
+                               WHEN excluded.profile_key IS NOT NULL
+                                    AND excluded.profile_key IS NOT profile_key
+                               THEN NULL ELSE profile_fetched_at END,
+                           avatar_path = excluded.avatar_path,
+                           color = excluded.color,
+                           expire_timer = excluded.expire_timer,
+                           inbox_position = excluded.inbox_position,
+                           recipient_id = excluded.recipient_id,
+                           updated_at = excluded.updated_at"#,
+                    params![
+                        key,
+                        recipient.pni.map(|pni| pni.to_string()),
+                        recipient.e164,
+                        synced.name,
+                        synced.profile_key,
+                        synced
+                            .avatar
+                            .as_ref()
+                            .map(|_| avatar_path.to_string_lossy().into_owned()),
+                        synced.color,
+                        synced.expire_timer,
+                        synced.inbox_position,
+                        recipient.id,
+                        now,
+                    ],
+                )?;
+
+                if let Some(name) = contact_name(&tx, recipient.id)? {
+                    tx.execute(
+                        "UPDATE conversations SET name = ? WHERE recipient_id = ? AND is_group = 0",
+                        params![name, recipient.id],
+                    )?;
+                }
+                tx.commit()?;
+
+                let identity = SignalIdentity {
+                    aci: recipient.aci.unwrap_or(Aci::from(uuid::Uuid::nil())),
+                    pni: recipient.pni,
+                    phone_number: recipient.e164,
+                    device_id: 1,
+                    registration_id: 0,
+                };
+                tracing::debug!("Stored synced contact {}", key);
+                Ok((identity, avatar_path))
+            })
+            .await?;
+
+        match &details.avatar {
+            Some(avatar) => {
+                tokio::fs::create_dir_all(self.data_dir.join("avatars")).await?;
+                tokio::fs::write(&avatar_path, &avatar.data).await?;
+            }
+            // A contact synced without an avatar has none
+            None => {
+                let _ = tokio::fs::remove_file(&avatar_path).await;
+            }
+        }
+
+        Ok(identity)
+    }
+
+    /// Replace the blocked people and groups with the lists from the
+    /// primary device
+    ///
+    /// Returns the conversations that were blocked or unblocked.
+    pub async fn store_blocked(
+        &self,
+        identities: &[SignalIdentity],
+        group_ids: &[String],
+    ) -> Result<Vec<Conversation>> {
+        let identities = identities.to_vec();
+        let group_ids = group_ids.to_vec();
+
+        self.db
+            .write(move |db| {
+                let now = chrono::Utc::now().timestamp();
+                let tx = db.transaction()?;
+                let blocked_conversations = |db: &Connection| -> Result<Vec<String>> {
+                    let mut stmt = db.prepare(&format!(
+                        "SELECT id FROM conversations WHERE {}",
+                        CONVERSATION_BLOCKED
+                    ))?;
+                    let ids = stmt
+                        .query_map([], |row| row.get(0))?
+                        .collect::<rusqlite::Result<Vec<String>>>()?;
+                    Ok(ids)
+                };
+                let before = blocked_conversations(&tx)?;
+
+                tx.execute("UPDATE contacts SET blocked = 0 WHERE blocked = 1", [])?;
+                for identity in &identities {
+                    let recipient = merge_recipient_in(
+                        &tx,
+                        Some(identity.aci).filter(|aci| !aci.is_nil()),
+                        identity.pni,
+                        identity.phone_number.as_deref(),
+                    )?;
+                    let updated = tx.execute(
+                        "UPDATE contacts SET blocked = 1, updated_at = ? WHERE recipient_id = ?",
+                        params![now, recipient.id],
+                    )?;
+                    if updated > 0 {
+                        continue;
+                    }
+
+                    // Blocked people need not be contacts
+                    let key = match (recipient.service_id(), &recipient.e164) {
+                        (Some(service_id), _) => service_id.to_string(),
+                        (None, Some(e164)) => e164.clone(),
+                        (None, None) => continue,
+                    };
+                    tx.execute(
+                        r#"INSERT INTO contacts
+                           (uuid, pni, phone_number, blocked, recipient_id, created_at, updated_at)
+                           VALUES (?1, ?2, ?3, 1, ?4, ?5, ?5)"#,
+                        params![
+                            key,
+                            recipient.pni.map(|pni| pni.to_string()),
+                            recipient.e164,
+                            recipient.id,
+                            now,
+                        ],
+                    )?;
+                }
+
+                tx.execute("DELETE FROM blocked_groups", [])?;
+                for group_id in &group_ids {
+                    tx.execute(
+                        "INSERT OR IGNORE INTO blocked_groups (group_id, created_at) VALUES (?, ?)",
+                        params![group_id, now],
+                    )?;
+                }
+
+                let after = blocked_conversations(&tx)?;
+                let mut changed = Vec::new();
+                for id in before.iter().filter(|id| !after.contains(id)).chain(
+                    after.iter().filter(|id| !before.contains(id)),
+                ) {
+                    changed.extend(load_conversation(&tx, id)?);
+                }
+                tx.commit()?;
+
+                tracing::info!(
+                    "Stored {} blocked people and {} blocked groups",
+                    identities.len(),
+                    group_ids.len()
+                );
+                Ok(changed)
+            })
+            .await
+    }
+
+    /// Whether messages from `sender` are to be dropped
+    pub async fn is_blocked(&self, sender: &SignalIdentity) -> Result<bool> {
+        let aci = sender.aci.to_string();
+
+        self.db
+            .read(move |db| {
+                let blocked = db
+                    .prepare_cached(
+                        r#"SELECT EXISTS (SELECT 1 FROM contacts c
+                                          JOIN recipients r ON r.id = c.recipient_id
+                                          WHERE r.aci = ? AND c.blocked = 1)"#,
+                    )?
+                    .query_row(params![aci], |row| row.get(0))?;
+                Ok(blocked)
+            })
+            .await
+    }
+
+    /// Whether messages to a group are to be dropped
+    pub async fn is_group_blocked(&self, group_id: &str) -> Result<bool> {
+        let group_id = group_id.to_string();
+
+        self.db
+            .read(move |db| {
+                let blocked = db
+                    .prepare_cached("SELECT EXISTS (SELECT 1 FROM blocked_groups WHERE group_id = ?)")?
+                    .query_row(params![group_id], |row| row.get(0))?;
+                Ok(blocked)
+            })
+            .await
     }
 
     /// Get all contacts
     pub async fn get_contacts(&self) -> Result<Vec<SignalIdentity>> {
-        let db = self.db.lock().await;
+        self.db
+            .read(|db| {
+                let mut stmt = db.prepare_cached(
+                    r#"SELECT COALESCE(r.aci, c.uuid), COALESCE(r.e164, c.phone_number),
+                              COALESCE(r.pni, c.pni)
+                       FROM contacts c LEFT JOIN recipients r ON r.id = c.recipient_id
+                       WHERE c.blocked = 0 ORDER BY c.name"#,
+                )?;
+
+                let contacts = stmt
+                    .query_map([], |row| {
+                        Ok(SignalIdentity {
+                            aci: parse_aci(&row.get::<_, String>(0)?),
+                            pni: parse_pni(row.get(2)?),
+                            phone_number: row.get(1)?,
+                            device_id: 1,
+                            registration_id: 0,
+                        })
+                    })?
+                    .filter_map(|r| r.ok())
+                    .collect();
+
+                Ok(contacts)
+            })
+            .await
+    }
 
-        let mut stmt = db.prepare(
-            "SELECT uuid, phone_number FROM contacts WHERE blocked = 0 ORDER BY name",
-        )?;
+    // ==================== Profile Operations ====================
+
+    /// Store the profile key a contact shared with us
+    ///
+    /// Returns whether the key is new to us, in which case the contact's
+    /// profile is due to be fetched again.
+    pub async fn store_profile_key(&self, aci: Aci, profile_key: &[u8]) -> Result<bool> {
+        let profile_key = profile_key.to_vec();
+
+        self.db
+            .write(move |db| {
+                let now = chrono::Utc::now().timestamp();
+                let tx = db.transaction()?;
+
+                let recipient = merge_recipient_in(&tx, Some(aci), None, None)?;
+                let current: Option<Vec<u8>> = tx
+                    .query_row(
+                        "SELECT profile_key FROM contacts WHERE recipient_id = ?",
+                        params![recipient.id],
+                        |row| row.get(0),
+                    )
+                    .optional()?
+                    .flatten();
+                if current.as_deref() == Some(profile_key.as_slice()) {
+                    return Ok(false);
+                }
+
+                let updated = tx.execute(
+                    r#"UPDATE contacts SET profile_key = ?, profile_fetched_at = NULL, updated_at = ?
+                       WHERE recipient_id = ?"#,
+                    params![profile_key, now, recipient.id],
+                )?;
+                // Whoever messages us need not be a contact yet
+                if updated == 0 {
+                    tx.execute(
+                        r#"INSERT INTO contacts
+                           (uuid, pni, phone_number, profile_key, recipient_id, created_at, updated_at)
+                           VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)"#,
+                        params![
+                            aci.to_string(),
+                            recipient.pni.map(|pni| pni.to_string()),
+                            recipient.e164,
+                            profile_key,
+                            recipient.id,
+                            now,
+                        ],
+                    )?;
+                }
+                tx.commit()?;
+
+                tracing::debug!("Stored new profile key for {}", aci);
+                Ok(true)
+            })
+            .await
+    }
 
-        let contacts = stmt
-            .query_map([], |row| {
-                Ok(SignalIdentity {
-                    uuid: row
-                        .get::<_, String>(0)?
-                        .parse()
-                        .unwrap_or(uuid::Uuid::nil()),
-                    phone_number: row.get(1)?,
-                    device_id: 1,
-                    registration_id: 0,
-                })
-            })?
-            .filter_map(|r| r.ok())
-            .collect();
+    /// Get the profile key of a contact, if they shared one
+    pub async fn get_profile_key(&self, aci: Aci) -> Result<Option<Vec<u8>>> {
+        let aci = aci.to_string();
+
+        self.db
+            .read(move |db| {
+                let profile_key = db
+                    .prepare_cached(
+                        r#"SELECT c.profile_key FROM contacts c
+                           JOIN recipients r ON r.id = c.recipient_id
+                           WHERE r.aci = ?"#,
+                    )?
+                    .query_row(params![aci], |row| row.get(0))
+                    .optional()?
+                    .flatten();
+                Ok(profile_key)
+            })
+            .await
+    }
 
-        Ok(contacts)
+    /// Get the CDN path of the profile avatar we last cached for a contact
+    pub async fn get_profile_avatar_url(&self, aci: Aci) -> Result<Option<String>> {
+        let aci = aci.to_string();
+
+        self.db
+            .read(move |db| {
+                let url = db
+                    .prepare_cached(
+                        r#"SELECT c.profile_avatar_url FROM contacts c
+                           JOIN recipients r ON r.id = c.recipient_id
+                           WHERE r.aci = ? AND c.profile_avatar_path IS NOT NULL"#,
+                    )?
+                    .query_row(params![aci], |row| row.get(0))
+                    .optional()?
+                    .flatten();
+                Ok(url)
+            })
+            .await
     }
 
-    // ==================== Group Operations ====================
+    /// Contacts with a profile key whose profile was never fetched, or
+    /// last fetched before `fetched_before` (Unix seconds)
+    pub async fn get_stale_profiles(&self, fetched_before: i64) -> Result<Vec<Aci>> {
+        self.db
+            .read(move |db| {
+                let mut stmt = db.prepare_cached(
+                    r#"SELECT r.aci FROM contacts c
+                       JOIN recipients r ON r.id = c.recipient_id
+                       WHERE c.profile_key IS NOT NULL AND r.aci IS NOT NULL AND c.blocked = 0
+                         AND (c.profile_fetched_at IS NULL OR c.profile_fetched_at < ?)
+                       ORDER BY c.profile_fetched_at IS NOT NULL, c.profile_fetched_at"#,
+                )?;
+                let acis = stmt
+                    .query_map(params![fetched_before], |row| row.get::<_, String>(0))?
+                    .filter_map(|r| r.ok())
+                    .filter_map(|aci| aci.parse().ok())
+                    .collect();
+                Ok(acis)
+            })
+            .await
+    }
 
-    /// Store a group
-    pub async fn store_group(&self, group: &Group) -> Result<()> {
-        let db = self.db.lock().await;
-        let now = chrono::Utc::now().timestamp();
+    /// Store a contact's decrypted profile
+    ///
+    /// `avatar` is the newly downloaded avatar image; when it is `None`
+    /// and the profile still has an avatar, the cached one is kept. The
+    /// contact's conversation is renamed to match, and returned.
+    pub async fn store_profile(
+        &self,
+        aci: Aci,
+        profile: &Profile,
+        avatar: Option<Vec<u8>>,
+    ) -> Result<Vec<Conversation>> {
+        let profile = profile.clone();
+        let avatar_path = self
+            .data_dir
+            .join("avatars")
+            .join(format!("profile-{}", aci));
+
+        match (&profile.avatar_url, &avatar) {
+            (Some(_), Some(image)) => {
+                tokio::fs::create_dir_all(self.data_dir.join("avatars")).await?;
+                tokio::fs::write(&avatar_path, image).await?;
+            }
+            (Some(_), None) => {}
+            (None, _) => {
+                let _ = tokio::fs::remove_file(&avatar_path).await;
+            }
+        }
+        let avatar_path = avatar_path.to_string_lossy().into_owned();
+
+        self.db
+            .write(move |db| {
+                let now = chrono::Utc::now().timestamp();
+                let tx = db.transaction()?;
+
+                let recipient = merge_recipient_in(&tx, Some(aci), None, None)?;
+                tx.execute(
+                    r#"UPDATE contacts SET
+                           profile_name = ?1,
+                           profile_about = ?2,
+                           profile_about_emoji = ?3,
+                           profile_avatar_url = ?4,
+                           profile_avatar_path = CASE
+                               WHEN ?4 IS NULL THEN NULL
+                               WHEN ?5 THEN ?6
+                               ELSE profile_avatar_path END,
+                           profile_fetched_at = ?7,
+                           updated_at = ?7
+                       WHERE recipient_id = ?8"#,
+                    params![
+                        profile.name(),
+                        profile.about,
+                        profile.about_emoji,
+                        profile.avatar_url,
+                        avatar.is_some(),
+                        avatar_path,
+                        now,
+                        recipient.id,
+                    ],
+                )?;
+
+                let mut changed = Vec::new();
+                if let Some(name) = contact_name(&tx, recipient.id)? {
+                    let mut stmt = tx.prepare(
+                        "SELECT id FROM conversations WHERE recipient_id = ? AND is_group = 0",
+                    )?;
+                    let ids = stmt
+                        .query_map(params![recipient.id], |row| row.get::<_, String>(0))?
+                        .collect::<rusqlite::Result<Vec<_>>>()?;
+                    drop(stmt);
+                    tx.execute(
+                        "UPDATE conversations SET name = ? WHERE recipient_id = ? AND is_group = 0",
+                        params![name, recipient.id],
+                    )?;
+                    for id in &ids {
+                        changed.extend(load_conversation(&tx, id)?);
+                    }
+                }
+                tx.commit()?;
+
+                tracing::debug!("Stored profile of {}", aci);
+                Ok(changed)
+            })
+            .await
+    }
 
-        db.execute(
-            r#"INSERT OR REPLACE INTO groups
-               (id, name, description, disappearing_timer, access_members, access_info,
-                created_at, updated_at)
-               VALUES (?, ?, ?, ?, ?, ?, ?, ?)"#,
-            params![
-                group.id,
-                group.name,
-                group.description,
-                group.disappearing_messages_timer,
-                group.access_control.members_can_add_members,
-                group.access_control.members_can_edit_group_info,
-                now,
-                now,
-            ],
-        )?;
+    // ==================== Recipient Operations ====================
+
+    /// Get what we know to show a person by
+    pub async fn get_recipient_details(&self, aci: Aci) -> Result<Option<RecipientDetails>> {
+        let aci = aci.to_string();
+
+        self.db
+            .read(move |db| {
+                let details = db
+                    .prepare_cached(&format!("{} WHERE r.aci = ?", RECIPIENT_DETAILS))?
+                    .query_row(params![aci], recipient_details_from_row)
+                    .optional()?;
+                Ok(details)
+            })
+            .await
+    }
 
-        // Store members
-        for member in &group.members {
-            db.execute(
-                r#"INSERT OR REPLACE INTO group_members (group_id, member_uuid, role, joined_at)
-                   VALUES (?, ?, ?, ?)"#,
-                params![
-                    group.id,
-                    member.uuid.to_string(),
-                    format!("{:?}", member.role),
-                    member.joined_at,
-                ],
-            )?;
-        }
+    /// Get what we know to show everyone known by their ACI by
+    pub async fn get_all_recipient_details(&self) -> Result<Vec<RecipientDetails>> {
+        self.db
+            .read(|db| {
+                let mut stmt =
+                    db.prepare_cached(&format!("{} WHERE r.aci IS NOT NULL", RECIPIENT_DETAILS))?;
+                let details = stmt
+                    .query_map([], recipient_details_from_row)?
+                    .filter_map(|r| r.ok())
+                    .collect();
+                Ok(details)
+            })
+            .await
+    }
 
-        tracing::info!("Stored group {}", group.id);
-        Ok(())
+    /// Record that an ACI, PNI and phone number belong to the same person
+    ///
+    /// Recipients previously known by only some of these identifiers are
+    /// merged, together with their conversations. An identifier that another
+    /// account held before moves to this recipient.
+    pub async fn merge_recipient(
+        &self,
+        aci: Option<Aci>,
+        pni: Option<Pni>,
+        e164: Option<&str>,
+    ) -> Result<Recipient> {
+        let e164 = e164.map(str::to_string);
+
+        self.db
+            .write(move |db| {
+                let tx = db.transaction()?;
+                let recipient = merge_recipient_in(&tx, aci, pni, e164.as_deref())?;
+                tx.commit()?;
+
+                Ok(recipient)
+            })
+            .await
+    }
+
+    /// Look up the recipient holding a service ID
+    pub async fn get_recipient(&self, service_id: &ServiceId) -> Result<Option<Recipient>> {
+        let service_id = *service_id;
+
+        self.db
+            .read(move |db| {
+                let column = match service_id.kind() {
+                    ServiceIdKind::Aci => "aci",
+                    ServiceIdKind::Pni => "pni",
+                };
+
+                find_recipient(db, column, &service_id.to_string())
+            })
+            .await
+    }
+
+    // ==================== Group Operations ====================
+
+    /// Store the state of a group, replacing its members
+    ///
+    /// Returns the group's conversation, renamed after the group, if there
+    /// is one.
+    pub async fn store_group(&self, group: &Group) -> Result<Option<Conversation>> {
+        let group = group.clone();
+
+        self.db
+            .write(move |db| {
+                let now = chrono::Utc::now().timestamp();
+                let tx = db.transaction()?;
+
+                tx.execute(
+                    r#"INSERT INTO groups
+                       (id, master_key, name, description, disappearing_timer, access_members,
+                        access_info, revision, avatar_url, access_invite_link,
+                        invite_link_password, created_at, updated_at)
+                       VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?12)
+                       ON CONFLICT(id) DO UPDATE SET
+                           master_key = COALESCE(excluded.master_key, master_key),
+                           name = excluded.name,
+                           description = excluded.description,
+                           avatar_url = excluded.avatar_url,
+                           disappearing_timer = excluded.disappearing_timer,
+                           access_members = excluded.access_members,
+                           access_info = excluded.access_info,
+                           access_invite_link = excluded.access_invite_link,
+                           invite_link_password = excluded.invite_link_password,
+                           revision = excluded.revision,
+                           updated_at = excluded.updated_at"#,
+                    params![
+                        group.id,
+                        Some(&group.master_key).filter(|key| !key.is_empty()),
+                        group.name,
+                        group.description,
+                        group.disappearing_messages_timer,
+                        group.access_control.members_can_add_members,
+                        group.access_control.members_can_edit_group_info,
+                        group.revision,
+                        group.avatar,
+                        format!("{:?}", group.invite_link_access),
+                        group.invite_link_password,
+                        now,
+                    ],
+                )?;
+
+                tx.execute(
+                    "DELETE FROM group_members WHERE group_id = ?",
+                    params![group.id],
+                )?;
+                let members = group.members.iter().map(|member| (member, false));
+                let pending = group.pending_members.iter().map(|member| (member, true));
+                for (member, pending) in members.chain(pending) {
+                    tx.execute(
+                        r#"INSERT OR REPLACE INTO group_members
+                           (group_id, member_uuid, role, joined_at, pending, invited_by)
+                           VALUES (?, ?, ?, ?, ?, ?)"#,
+                        params![
+                            group.id,
+                            member.uuid.to_string(),
+                            format!("{:?}", member.role),
+                            member.joined_at,
+                            pending,
+                            member.invited_by.map(|uuid| uuid.to_string()),
+                        ],
+                    )?;
+                }
+
+                let conversation_id: Option<String> = tx
+                    .query_row(
+                        "SELECT id FROM conversations WHERE group_id = ?",
+                        params![group.id],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                let conversation = match conversation_id {
+                    Some(id) => {
+                        if let Some(name) = group_name(&tx, &group.id)? {
+                            tx.execute(
+                                "UPDATE conversations SET name = ? WHERE id = ?",
+                                params![name, id],
+                            )?;
+                        }
+                        load_conversation(&tx, &id)?
+                    }
+                    None => None,
+                };
+                tx.commit()?;
+
+                tracing::info!("Stored group {} at revision {}", group.id, group.revision);
+                Ok(conversation)
+            })
+            .await
     }
 
     /// Get a group
+    ///
+    /// A group found through the storage service has no members until its
+    /// state is stored.
     pub async fn get_group(&self, id: &str) -> Result<Option<Group>> {
-        let db = self.db.lock().await;
-
-        let group = db
-            .query_row(
-                r#"SELECT id, name, description, disappearing_timer, access_members, access_info
-                   FROM groups WHERE id = ?"#,
-                params![id],
-                |row| {
-                    Ok(Group {
-                        id: row.get(0)?,
-                        name: row.get(1)?,
-                        description: row.get(2)?,
-                        avatar: None,
-                        members: Vec::new(),
-                        admins: Vec::new(),
-                        pending_members: Vec::new(),
-                        disappearing_messages_timer: row.get(3)?,
-                        access_control: GroupAccessControl {
-                            members_can_add_members: row.get(4)?,
-                            members_can_edit_group_info: row.get(5)?,
+        let id = id.to_string();
+
+        self.db
+            .read(move |db| {
+                let group = db
+                    .query_row(
+                        r#"SELECT id, master_key, revision, name, description, disappearing_timer,
+                                  access_members, access_info, avatar_url, access_invite_link,
+                                  invite_link_password
+                           FROM groups WHERE id = ?"#,
+                        params![id],
+                        |row| {
+                            Ok(Group {
+                                id: row.get(0)?,
+                                master_key: row.get::<_, Option<Vec<u8>>>(1)?.unwrap_or_default(),
+                                revision: row.get(2)?,
+                                name: row.get(3)?,
+                                description: row.get(4)?,
+                                avatar: row.get(8)?,
+                                members: Vec::new(),
+                                admins: Vec::new(),
+                                pending_members: Vec::new(),
+                                disappearing_messages_timer: row.get(5)?,
+                                access_control: GroupAccessControl {
+                                    members_can_add_members: row.get(6)?,
+                                    members_can_edit_group_info: row.get(7)?,
+                                },
+                                invite_link_access: match row.get::<_, String>(9)?.as_str() {
+                                    "AdminApproval" => InviteLinkAccess::AdminApproval,
+                                    "Anyone" => InviteLinkAccess::Anyone,
+                                    _ => InviteLinkAccess::Disabled,
+                                },
+                                invite_link_password: row.get(10)?,
+                            })
                         },
-                    })
-                },
-            )
-            .optional()?;
+                    )
+                    .optional()?;
 
-        if let Some(mut group) = group {
-            // Load members
-            let mut stmt = db.prepare(
-                "SELECT member_uuid, role, joined_at FROM group_members WHERE group_id = ?",
-            )?;
+                let Some(mut group) = group else {
+                    return Ok(None);
+                };
 
-            group.members = stmt
-                .query_map(params![id], |row| {
-                    let role_str: String = row.get(1)?;
-                    let role = if role_str == "Administrator" {
-                        GroupRole::Administrator
-                    } else {
-                        GroupRole::Member
+                let mut stmt = db.prepare(
+                    r#"SELECT member_uuid, role, joined_at, pending, invited_by
+                       FROM group_members WHERE group_id = ? ORDER BY rowid"#,
+                )?;
+                let members = stmt.query_map(params![id], |row| {
+                    let role = match row.get::<_, String>(1)?.as_str() {
+                        "Administrator" => GroupRole::Administrator,
+                        _ => GroupRole::Member,
                     };
-
-                    Ok(GroupMember {
+                    let member = GroupMember {
                         uuid: row
                             .get::<_, String>(0)?
                             .parse()
                             .unwrap_or(uuid::Uuid::nil()),
                         role,
                         joined_at: row.get(2)?,
-                    })
-                })?
-                .filter_map(|r| r.ok())
-                .collect();
+                        invited_by: row
+                            .get::<_, Option<String>>(4)?
+                            .and_then(|uuid| uuid.parse().ok()),
+                    };
+                    Ok((member, row.get::<_, bool>(3)?))
+                })?;
+                for (member, pending) in members.filter_map(|r| r.ok()) {
+                    if pending {
+                        group.pending_members.push(member);
+                    } else {
+                        group.members.push(member);
+                    }
+                }
+                group.admins = group
+                    .members
+                    .iter()
+                    .filter(|member| member.role == GroupRole::Administrator)
+                    .map(|member| member.uuid)
+                    .collect();
+
+                Ok(Some(group))
+            })
+            .await
+    }
 
-            return Ok(Some(group));
-        }
+    // ==================== Storage Service Operations ====================
+
+    /// Get the storage service manifest we last synced
+    ///
+    /// Has version 0 and no records before the first sync.
+    pub async fn get_storage_manifest(&self) -> Result<StorageManifest> {
+        self.db
+            .read(|db| {
+                let metadata = |key: &str| -> Result<Option<String>> {
+                    Ok(db
+                        .query_row(
+                            "SELECT value FROM metadata WHERE key = ?",
+                            params![key],
+                            |row| row.get(0),
+                        )
+                        .optional()?)
+                };
+
+                let version = metadata("storage_version")?
+                    .map(|version| version.parse())
+                    .transpose()?
+                    .unwrap_or(0);
+                let record_ikm = metadata("storage_record_ikm")?
+                    .map(hex::decode)
+                    .transpose()?;
+
+                let identifiers = db
+                    .prepare_cached(
+                        "SELECT storage_id, record_type FROM storage_records ORDER BY rowid",
+                    )?
+                    .query_map([], |row| {
+                        Ok(StorageId {
+                            raw: row.get(0)?,
+                            record_type: row.get(1)?,
+                        })
+                    })?
+                    .collect::<rusqlite::Result<_>>()?;
+
+                Ok(StorageManifest {
+                    version,
+                    identifiers,
+                    record_ikm,
+                })
+            })
+            .await
+    }
 
-        Ok(None)
+    /// Get the synced storage service records of `record_type`, serialized
+    pub async fn get_storage_records(&self, record_type: i32) -> Result<Vec<(StorageId, Vec<u8>)>> {
+        self.db
+            .read(move |db| {
+                let records = db
+                    .prepare_cached(
+                        r#"SELECT storage_id, record FROM storage_records
+                           WHERE record_type = ? AND record IS NOT NULL ORDER BY rowid"#,
+                    )?
+                    .query_map(params![record_type], |row| {
+                        let id = StorageId {
+                            raw: row.get(0)?,
+                            record_type,
+                        };
+                        Ok((id, row.get(1)?))
+                    })?
+                    .collect::<rusqlite::Result<_>>()?;
+                Ok(records)
+            })
+            .await
+    }
+
+    /// Adopt a newer storage service manifest and apply its new records
+    ///
+    /// `records` holds the serialized and decoded records of `manifest`
+    /// that are not stored yet. Records of kinds we do not model are only
+    /// listed, to be kept when writing, and records left out of the
+    /// manifest are forgotten. A record replaces the local state it
+    /// covers, as the manifest is newer than anything stored.
+    pub async fn apply_storage_manifest(
+        &self,
+        manifest: &StorageManifest,
+        records: &[(StorageId, Vec<u8>, StorageRecord)],
+    ) -> Result<StorageChanges> {
+        let manifest = manifest.clone();
+        let records = records.to_vec();
+
+        self.db
+            .write(move |db| {
+                let now = chrono::Utc::now().timestamp();
+                let tx = db.transaction()?;
+
+                let listed: HashSet<&[u8]> = manifest
+                    .identifiers
+                    .iter()
+                    .map(|id| id.raw.as_slice())
+                    .collect();
+                let stored: Vec<Vec<u8>> = tx
+                    .prepare("SELECT storage_id FROM storage_records")?
+                    .query_map([], |row| row.get(0))?
+                    .collect::<rusqlite::Result<_>>()?;
+                for raw in stored.iter().filter(|raw| !listed.contains(raw.as_slice())) {
+                    tx.execute("DELETE FROM storage_records WHERE storage_id = ?", params![raw])?;
+                }
+                for id in &manifest.identifiers {
+                    tx.execute(
+                        r#"INSERT OR IGNORE INTO storage_records (storage_id, record_type, updated_at)
+                           VALUES (?, ?, ?)"#,
+                        params![id.raw, id.record_type, now],
+                    )?;
+                }
+
+                let mut changes = StorageChanges::default();
+                let mut conversation_ids = Vec::new();
+                for (id, plaintext, record) in &records {
+                    tx.execute(
+                        "UPDATE storage_records SET record = ?, updated_at = ? WHERE storage_id = ?",
+                        params![plaintext, now, id.raw],
+                    )?;
+
+                    match record {
+                        StorageRecord::Contact(contact) => {
+                            if let Some((identity, ids)) = apply_contact_record(&tx, contact, now)? {
+                                changes.contacts.push(identity);
+                                conversation_ids.extend(ids);
+                            }
+                        }
+                        StorageRecord::Account(account) => {
+                            conversation_ids.extend(apply_account_record(&tx, account)?);
+                            changes.account = Some(account.clone());
+                        }
+                        StorageRecord::GroupV2(record) => {
+                            if let Some((ids, unfetched)) = apply_group_record(&tx, record, now)? {
+                                conversation_ids.extend(ids);
+                                if unfetched {
+                                    changes.groups.push(record.master_key.clone());
+                                }
+                            }
+                        }
+                    }
+                }
+
+                tx.execute(
+                    "INSERT OR REPLACE INTO metadata (key, value) VALUES ('storage_version', ?)",
+                    params![manifest.version.to_string()],
+                )?;
+                match &manifest.record_ikm {
+                    Some(ikm) => tx.execute(
+                        "INSERT OR REPLACE INTO metadata (key, value) VALUES ('storage_record_ikm', ?)",
+                        params![hex::encode(ikm)],
+                    )?,
+                    None => tx.execute("DELETE FROM metadata WHERE key = 'storage_record_ikm'", [])?,
+                };
+
+                conversation_ids.sort();
+                conversation_ids.dedup();
+                for id in &conversation_ids {
+                    changes.conversations.extend(load_conversation(&tx, id)?);
+                }
+                tx.commit()?;
+
+                tracing::info!(
+                    "Applied {} records of storage manifest {}",
+                    records.len(),
+                    manifest.version
+                );
+                Ok(changes)
+            })
+            .await
     }
 
     // ==================== Utility Operations ====================
 
     /// Clear all data (for account unlinking)
     pub async fn clear(&self) -> Result<()> {
-        let db = self.db.lock().await;
-
-        tracing::warn!("Clearing all Signal data");
-
-        db.execute_batch(
-            r#"
-            DELETE FROM sender_keys;
-            DELETE FROM group_members;
-            DELETE FROM groups;
-            DELETE FROM contacts;
-            DELETE FROM reactions;
-            DELETE FROM attachments;
-            DELETE FROM messages;
-            DELETE FROM conversations;
-            DELETE FROM sessions;
-            DELETE FROM signed_pre_keys;
-            DELETE FROM pre_keys;
-            DELETE FROM identities;
-            "#,
-        )?;
-
-        Ok(())
+        self.db
+            .write(|db| {
+                tracing::warn!("Clearing all Signal data");
+
+                db.execute_batch(
+                    r#"
+                    DELETE FROM sender_keys;
+                    DELETE FROM group_members;
+                    DELETE FROM groups;
+                    DELETE FROM contacts;
+                    DELETE FROM blocked_groups;
+                    DELETE FROM storage_records;
+                    DELETE FROM reactions;
+                    DELETE FROM message_recipients;
+                    DELETE FROM attachments;
+                    DELETE FROM messages;
+                    DELETE FROM conversations;
+                    DELETE FROM pinned_conversations;
+                    DELETE FROM recipients;
+                    DELETE FROM sessions;
+                    DELETE FROM signed_pre_keys;
+                    DELETE FROM pre_keys;
+                    DELETE FROM pni_signed_pre_keys;
+                    DELETE FROM pni_pre_keys;
+                    DELETE FROM identities;
+                    DELETE FROM metadata WHERE key IN (
+                        'storage_service_key', 'master_key', 'storage_version',
+                        'storage_record_ikm', 'profile_key', 'profile_given_name',
+                        'profile_family_name', 'profile_avatar_url',
+                        'initial_sync_requested'
+                    );
+                    "#,
+                )?;
+
+                Ok(())
+            })
+            .await
     }
 
     /// Get database path
@@ -926,20 +2967,1346 @@ impl SignalStore {
     }
 }
 
+/// Schema version recorded in `metadata` by databases that predate
+/// `PRAGMA user_version`, or 0 for a new database
+fn legacy_schema_version(db: &Connection) -> Result<u32> {
+    let has_metadata: bool = db.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'metadata'",
+        [],
+        |row| row.get(0),
+    )?;
+    if !has_metadata {
+        return Ok(0);
+    }
+
+    let version: Option<String> = db
+        .query_row(
+            "SELECT value FROM metadata WHERE key = 'schema_version'",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    Ok(version.map(|v| v.parse()).transpose()?.unwrap_or(0))
+}
+
+/// Insert a message and make it its conversation's last message if it is
+/// the newest
+///
+/// A message already stored is updated in place, keeping its read state
+/// and creation time; `unread` only applies to a new one. Another message
+/// from an envelope already stored is dropped.
+fn insert_message(db: &Connection, message: &Message, unread: bool) -> Result<()> {
+    let now = chrono::Utc::now().timestamp();
+
+    let content_type = match &message.content {
+        MessageContent::Text { .. } => "text",
+        MessageContent::Image { .. } => "image",
+        MessageContent::Video { .. } => "video",
+        MessageContent::Audio { .. } => "audio",
+        MessageContent::File { .. } => "file",
+        MessageContent::Voice { .. } => "voice",
+        MessageContent::Sticker { .. } => "sticker",
+        MessageContent::Contact { .. } => "contact",
+        MessageContent::Location { .. } => "location",
+        MessageContent::DecryptionFailed => "decryption_failed",
+        MessageContent::NumbersMerged { .. } => "numbers_merged",
+        MessageContent::NotFound => "not_found",
+    };
+
+    let content_json = serde_json::to_string(&message.content)?;
+    let status = format!("{:?}", message.status);
+    let envelope = message.envelope.clone().unwrap_or_default();
+
+    let stored = db
+        .prepare_cached(
+            r#"INSERT INTO messages
+               (id, conversation_id, sender_uuid, sender_device_id, timestamp,
+                received_timestamp, content_type, content_json, status, quote_id,
+                quote_author, quote_timestamp, expires_at, created_at, server_guid,
+                server_timestamp, destination_service_id, urgent, story, unread)
+               VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+               ON CONFLICT(id) DO UPDATE SET
+                   conversation_id = excluded.conversation_id,
+                   sender_uuid = excluded.sender_uuid,
+                   sender_device_id = excluded.sender_device_id,
+                   timestamp = excluded.timestamp,
+                   received_timestamp = excluded.received_timestamp,
+                   content_type = excluded.content_type,
+                   content_json = excluded.content_json,
+                   status = excluded.status,
+                   quote_id = excluded.quote_id,
+                   quote_author = excluded.quote_author,
+                   quote_timestamp = excluded.quote_timestamp,
+                   expires_at = excluded.expires_at,
+                   server_guid = excluded.server_guid,
+                   server_timestamp = excluded.server_timestamp,
+                   destination_service_id = excluded.destination_service_id,
+                   urgent = excluded.urgent,
+                   story = excluded.story
+               ON CONFLICT(server_guid) WHERE server_guid IS NOT NULL DO NOTHING"#,
+        )?
+        .execute(params![
+            message.id,
+            message.conversation_id,
+            message.sender.aci.to_string(),
+            message.sender.device_id,
+            message.timestamp,
+            message.received_timestamp,
+            content_type,
+            content_json,
+            status,
+            message.quote.as_ref().map(|q| &q.id).filter(|id| !id.is_empty()),
+            message.quote.as_ref().map(|q| q.sender.aci.to_string()),
+            message.quote.as_ref().map(|q| q.timestamp),
+            message.expires_at,
+            now,
+            envelope.server_guid,
+            envelope.server_timestamp,
+            envelope.destination_service_id.map(|id| id.to_string()),
+            envelope.urgent,
+            envelope.story,
+            unread,
+        ])?;
+    if stored == 0 {
+        return Ok(());
+    }
+
+    // A replaced message may have had a different attachment
+    let attachment = message.content.attachment();
+    db.prepare_cached("DELETE FROM attachments WHERE message_id = ? AND id IS NOT ?")?
+        .execute(params![message.id, attachment.map(|a| &a.id)])?;
+    if let Some(attachment) = attachment {
+        // Update in place to keep the path of an already downloaded file
+        db.prepare_cached(
+            r#"INSERT INTO attachments
+               (id, message_id, content_type, file_name, size, digest, key, cdn_number,
+                upload_timestamp, thumbnail, width, height, created_at)
+               VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+               ON CONFLICT(id) DO UPDATE SET
+                   message_id = excluded.message_id,
+                   content_type = excluded.content_type,
+                   file_name = excluded.file_name,
+                   size = excluded.size,
+                   digest = excluded.digest,
+                   key = excluded.key,
+                   cdn_number = excluded.cdn_number,
+                   upload_timestamp = excluded.upload_timestamp,
+                   thumbnail = excluded.thumbnail,
+                   width = excluded.width,
+                   height = excluded.height"#,
+        )?
+        .execute(params![
+            attachment.id,
+            message.id,
+            attachment.content_type,
+            attachment.file_name,
+            attachment.size,
+            attachment.digest,
+            attachment.key,
+            attachment.cdn_number,
+            attachment.upload_timestamp,
+            attachment.thumbnail,
+            attachment.width,
+            attachment.height,
+            now,
+        ])?;
+    }
+
+    for reaction in &message.reactions {
+        db.prepare_cached(
+            r#"INSERT OR REPLACE INTO reactions (message_id, emoji, sender_uuid, timestamp)
+               VALUES (?, ?, ?, ?)"#,
+        )?
+        .execute(params![
+            message.id,
+            reaction.emoji,
+            reaction.sender.aci.to_string(),
+            reaction.timestamp,
+        ])?;
+    }
+
+    // Make it the conversation's last message, unless a newer one is stored
+    db.prepare_cached(
+        r#"UPDATE conversations SET last_message_id = ?1, updated_at = ?2
+           WHERE id = ?3 AND NOT EXISTS (
+               SELECT 1 FROM messages last
+               WHERE last.id = conversations.last_message_id AND last.id != ?1
+                 AND (last.timestamp, last.id) > (?4, ?1))"#,
+    )?
+    .execute(params![message.id, now, message.conversation_id, message.timestamp])?;
+
+    Ok(())
+}
+
+/// Turn user input into an FTS5 query requiring all of its words
+///
+/// Words are quoted so that FTS5 operators and punctuation in the input
+/// are taken literally; the last word matches as a prefix.
+fn fts_query(input: &str) -> Option<String> {
+    let terms: Vec<String> = input
+        .split_whitespace()
+        .filter(|word| word.chars().any(char::is_alphanumeric))
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect();
+
+    if terms.is_empty() {
+        return None;
+    }
+
+    Some(format!("{}*", terms.join(" ")))
+}
+
+/// Strip the match markers from a snippet, returning the byte ranges they enclosed
+fn parse_snippet(marked: &str) -> (String, Vec<std::ops::Range<usize>>) {
+    let mut snippet = String::with_capacity(marked.len());
+    let mut highlights = Vec::new();
+    let mut start = None;
+
+    for c in marked.chars() {
+        match c {
+            '\u{2}' => start = Some(snippet.len()),
+            '\u{3}' => {
+                if let Some(start) = start.take() {
+                    highlights.push(start..snippet.len());
+                }
+            }
+            c => snippet.push(c),
+        }
+    }
+
+    (snippet, highlights)
+}
+
+/// Up to `limit` messages of a conversation before `cursor`, oldest first
+///
+/// The `timestamp <=` bound lets `idx_messages_conversation` seek to the
+/// cursor; the row value comparison then breaks ties on the ID.
+fn messages_before(
+    db: &Connection,
+    conversation_id: &str,
+    cursor: &MessageCursor,
+    limit: usize,
+) -> rusqlite::Result<Vec<Message>> {
+    let mut stmt = db.prepare_cached(
+        r#"SELECT id, conversation_id, sender_uuid, sender_device_id, timestamp,
+                  received_timestamp, content_type, content_json, status, expires_at,
+                  server_guid, server_timestamp, destination_service_id, urgent, story,
+                  quote_id, quote_author, quote_timestamp
+           FROM messages
+           WHERE conversation_id = ?1 AND timestamp <= ?2 AND (timestamp, id) < (?2, ?3)
+           ORDER BY timestamp DESC, id DESC LIMIT ?4"#,
+    )?;
+
+    let mut rows: Vec<_> = stmt
+        .query_map(
+            params![conversation_id, cursor.timestamp, cursor.id, limit],
+            message_with_quote,
+        )?
+        .filter_map(|r| r.ok())
+        .collect();
+    rows.reverse();
+
+    hydrate_messages(db, rows)
+}
+
+/// Up to `limit` messages of a conversation after `cursor`, oldest first
+fn messages_after(
+    db: &Connection,
+    conversation_id: &str,
+    cursor: &MessageCursor,
+    limit: usize,
+) -> rusqlite::Result<Vec<Message>> {
+    let mut stmt = db.prepare_cached(
+        r#"SELECT id, conversation_id, sender_uuid, sender_device_id, timestamp,
+                  received_timestamp, content_type, content_json, status, expires_at,
+                  server_guid, server_timestamp, destination_service_id, urgent, story,
+                  quote_id, quote_author, quote_timestamp
+           FROM messages
+           WHERE conversation_id = ?1 AND timestamp >= ?2 AND (timestamp, id) > (?2, ?3)
+           ORDER BY timestamp ASC, id ASC LIMIT ?4"#,
+    )?;
+
+    let rows = stmt
+        .query_map(
+            params![conversation_id, cursor.timestamp, cursor.id, limit],
+            message_with_quote,
+        )?
+        .filter_map(|r| r.ok())
+        .collect();
+
+    hydrate_messages(db, rows)
+}
+
+/// Load a message by ID, without its attachment, reactions and quote,
+/// together with where its quote points
+fn load_message(
+    db: &Connection,
+    id: &str,
+) -> rusqlite::Result<Option<(Message, Option<QuoteReference>)>> {
+    db.prepare_cached(
+        r#"SELECT id, conversation_id, sender_uuid, sender_device_id, timestamp,
+                  received_timestamp, content_type, content_json, status, expires_at,
+                  server_guid, server_timestamp, destination_service_id, urgent, story,
+                  quote_id, quote_author, quote_timestamp
+           FROM messages WHERE id = ?"#,
+    )?
+    .query_row(params![id], message_with_quote)
+    .optional()
+}
+
+/// Store a contact record from the storage service
+///
+/// Returns the contact's identity and the IDs of their conversations, or
+/// `None` for a record naming no one.
+fn apply_contact_record(
+    db: &Connection,
+    contact: &ContactRecord,
+    now: i64,
+) -> Result<Option<(SignalIdentity, Vec<String>)>> {
+    let aci = contact.aci.filter(|aci| !aci.is_nil());
+    if aci.is_none() && contact.pni.is_none() && contact.e164.is_none() {
+        return Ok(None);
+    }
+
+    let recipient = merge_recipient_in(db, aci, contact.pni, contact.e164.as_deref())?;
+    let key = match (recipient.service_id(), &recipient.e164) {
+        (Some(service_id), _) => service_id.to_string(),
+        (None, Some(e164)) => e164.clone(),
+        (None, None) => return Ok(None),
+    };
+    let full_name = |given: &Option<String>, family: &Option<String>| {
+        let parts: Vec<&str> = [given, family].into_iter().flatten().map(String::as_str).collect();
+        (!parts.is_empty()).then(|| parts.join(" "))
+    };
+
+    db.execute(
+        "DELETE FROM contacts WHERE recipient_id = ? AND uuid != ?",
+        params![recipient.id, key],
+    )?;
+    // Names the record leaves out may still be known from a contact sync
+    db.execute(
+        r#"INSERT INTO contacts
+           (uuid, pni, phone_number, name, profile_name, profile_key, nickname, username,
+            blocked, recipient_id, created_at, updated_at)
+           VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?11)
+           ON CONFLICT(uuid) DO UPDATE SET
+               pni = excluded.pni,
+               phone_number = excluded.phone_number,
+               name = COALESCE(excluded.name, name),
+               profile_name = COALESCE(excluded.profile_name, profile_name),
+               profile_key = COALESCE(excluded.profile_key, profile_key),
+               profile_fetched_at = CASE
+                   WHEN excluded.profile_key IS NOT NULL AND excluded.profile_key IS NOT profile_key
+                   THEN NULL ELSE profile_fetched_at END,
+               nickname = excluded.nickname,
+               username = excluded.username,
+               blocked = excluded.blocked,
+               recipient_id = excluded.recipient_id,
+               updated_at = excluded.updated_at"#,
+        params![
+            key,
+            recipient.pni.map(|pni| pni.to_string()),
+            recipient.e164,
+            full_name(&contact.system_given_name, &contact.system_family_name),
+            full_name(&contact.given_name, &contact.family_name),
+            contact.profile_key,
+            full_name(&contact.nickname_given_name, &contact.nickname_family_name),
+            contact.username,
+            contact.blocked,
+            recipient.id,
+            now,
+        ],
+    )?;
+
+    db.execute(
+        "UPDATE conversations SET archived = ?, muted_until = ? WHERE recipient_id = ? AND is_group = 0",
+        params![contact.archived, contact.muted_until, recipient.id],
+    )?;
+    if let Some(name) = contact_name(db, recipient.id)? {
+        db.execute(
+            "UPDATE conversations SET name = ? WHERE recipient_id = ? AND is_group = 0",
+            params![name, recipient.id],
+        )?;
+    }
+    let conversation_ids = db
+        .prepare_cached("SELECT id FROM conversations WHERE recipient_id = ? AND is_group = 0")?
+        .query_map(params![recipient.id], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let identity = SignalIdentity {
+        aci: recipient.aci.unwrap_or(Aci::from(uuid::Uuid::nil())),
+        pni: recipient.pni,
+        phone_number: recipient.e164,
+        device_id: 1,
+        registration_id: 0,
+    };
+    Ok(Some((identity, conversation_ids)))
+}
+
+/// Store the settings of a group from the storage service, adding the
+/// group by its master key if it is new
+///
+/// Returns the IDs of the group's conversations and whether the group's
+/// state is still to be fetched, or `None` for a malformed master key.
+fn apply_group_record(
+    db: &Connection,
+    record: &GroupV2Record,
+    now: i64,
+) -> Result<Option<(Vec<String>, bool)>> {
+    let params = match GroupSecretParams::derive_from_master_key(&record.master_key) {
+        Ok(params) => params,
+        Err(e) => {
+            tracing::warn!("Skipping group storage record: {}", e);
+            return Ok(None);
+        }
+    };
+    let id = params.id();
+
+    db.execute(
+        r#"INSERT INTO groups (id, master_key, name, created_at, updated_at)
+           VALUES (?1, ?2, '', ?3, ?3)
+           ON CONFLICT(id) DO UPDATE SET master_key = excluded.master_key"#,
+        params![id, record.master_key, now],
+    )?;
+    let unfetched: bool = db.query_row(
+        "SELECT NOT EXISTS (SELECT 1 FROM group_members WHERE group_id = ?)",
+        params![id],
+        |row| row.get(0),
+    )?;
+
+    if record.blocked {
+        db.execute(
+            "INSERT OR IGNORE INTO blocked_groups (group_id, created_at) VALUES (?, ?)",
+            params![id, now],
+        )?;
+    } else {
+        db.execute("DELETE FROM blocked_groups WHERE group_id = ?", params![id])?;
+    }
+
+    db.execute(
+        "UPDATE conversations SET archived = ?, muted_until = ? WHERE group_id = ?",
+        params![record.archived, record.muted_until, id],
+    )?;
+    let conversation_ids = db
+        .prepare_cached("SELECT id FROM conversations WHERE group_id = ?")?
+        .query_map(params![id], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(Some((conversation_ids, unfetched)))
+}
+
+/// Store our account record from the storage service
+///
+/// Returns the IDs of the conversations it changed: Note to Self, and
+/// those pinned or unpinned.
+fn apply_account_record(db: &Connection, account: &AccountRecord) -> Result<Vec<String>> {
+    let entries = [
+        ("profile_key", account.profile_key.as_ref().map(hex::encode)),
+        ("profile_given_name", account.given_name.clone()),
+        ("profile_family_name", account.family_name.clone()),
+        ("profile_avatar_url", account.avatar_url.clone()),
+    ];
+    for (key, value) in entries {
+        match value {
+            Some(value) => db.execute(
+                "INSERT OR REPLACE INTO metadata (key, value) VALUES (?, ?)",
+                params![key, value],
+            )?,
+            None => db.execute("DELETE FROM metadata WHERE key = ?", params![key])?,
+        };
+    }
+
+    // Note to Self is the conversation with our own ACI
+    let local_aci: Option<String> = db
+        .query_row(
+            "SELECT value FROM metadata WHERE key = 'local_aci'",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?;
+    let mut changed = apply_pinned_conversations(db, &account.pinned_conversations)?;
+    let Some(local_aci) = local_aci else {
+        return Ok(changed);
+    };
+    let updated = db.execute(
+        "UPDATE conversations SET archived = ? WHERE id = ?",
+        params![account.note_to_self_archived, local_aci],
+    )?;
+
+    if updated > 0 && !changed.contains(&local_aci) {
+        changed.push(local_aci);
+    }
+    Ok(changed)
+}
+
+/// Replace the pinned conversations with those of our account record
+///
+/// Returns the IDs of the stored conversations whose place changed.
+fn apply_pinned_conversations(
+    db: &Connection,
+    pinned: &[PinnedConversation],
+) -> Result<Vec<String>> {
+    let mut ids = Vec::new();
+    for conversation in pinned {
+        let id = match conversation {
+            PinnedConversation::Contact { service_id, e164 } => {
+                let (aci, pni) = match service_id {
+                    Some(ServiceId::Aci(aci)) => (Some(*aci), None),
+                    Some(ServiceId::Pni(pni)) => (None, Some(*pni)),
+                    None => (None, None),
+                };
+                if aci.is_none() && pni.is_none() && e164.is_none() {
+                    continue;
+                }
+                // Chats with one person are named by their ACI
+                let recipient = merge_recipient_in(db, aci, pni, e164.as_deref())?;
+                let stored: Option<String> = db
+                    .prepare_cached(
+                        "SELECT id FROM conversations WHERE recipient_id = ? AND is_group = 0",
+                    )?
+                    .query_row(params![recipient.id], |row| row.get(0))
+                    .optional()?;
+                match stored.or_else(|| recipient.aci.map(|aci| aci.to_string())) {
+                    Some(id) => id,
+                    None => continue,
+                }
+            }
+            PinnedConversation::Group { master_key } => {
+                match GroupSecretParams::derive_from_master_key(master_key) {
+                    Ok(params) => params.id(),
+                    Err(e) => {
+                        tracing::warn!("Skipping pinned group: {}", e);
+                        continue;
+                    }
+                }
+            }
+            PinnedConversation::LegacyGroup(_) => continue,
+        };
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+
+    let previous: Vec<(String, u32)> = db
+        .prepare("SELECT conversation_id, position FROM pinned_conversations")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    let current: Vec<(String, u32)> = ids
+        .into_iter()
+        .enumerate()
+        .map(|(position, id)| (id, position as u32))
+        .collect();
+
+    db.execute("DELETE FROM pinned_conversations", [])?;
+    for (id, position) in &current {
+        db.execute(
+            "INSERT INTO pinned_conversations (conversation_id, position) VALUES (?, ?)",
+            params![id, position],
+        )?;
+    }
+
+    let moved: HashSet<&String> = previous
+        .iter()
+        .filter(|entry| !current.contains(entry))
+        .chain(current.iter().filter(|entry| !previous.contains(entry)))
+        .map(|(id, _)| id)
+        .collect();
+    let mut changed = Vec::new();
+    for id in moved {
+        let exists: bool = db.query_row(
+            "SELECT EXISTS (SELECT 1 FROM conversations WHERE id = ?)",
+            params![id],
+            |row| row.get(0),
+        )?;
+        if exists {
+            changed.push(id.clone());
+        }
+    }
+    Ok(changed)
+}
+
+/// Load a conversation by ID with its last message
+fn load_conversation(db: &Connection, id: &str) -> rusqlite::Result<Option<Conversation>> {
+    let conversation = db
+        .prepare_cached(&format!(
+            r#"SELECT id, recipient_uuid, recipient_device_id, is_group, group_id, name,
+                      archived, muted_until, unread_count, recipient_pni, last_message_id, {},
+                      pinned.position
+               FROM conversations
+               LEFT JOIN pinned_conversations pinned ON pinned.conversation_id = id
+               WHERE id = ?"#,
+            CONVERSATION_BLOCKED
+        ))?
+        .query_row(params![id], conversation_from_row)
+        .optional()?;
+
+    let Some((mut conversation, last_message_id)) = conversation else {
+        return Ok(None);
+    };
+    if let Some(id) = last_message_id {
+        conversation.last_message = load_message(db, &id)?.map(|(message, _)| message);
+    }
+
+    Ok(Some(conversation))
+}
+
+/// Build a conversation and its last message ID from a row of the
+/// `load_conversation` column list
+fn conversation_from_row(row: &rusqlite::Row) -> rusqlite::Result<(Conversation, Option<String>)> {
+    let conversation = Conversation {
+        id: row.get(0)?,
+        recipient: SignalIdentity {
+            aci: parse_aci(&row.get::<_, String>(1)?),
+            pni: parse_pni(row.get(9)?),
+            phone_number: None,
+            device_id: row.get(2)?,
+            registration_id: 0,
+        },
+        is_group: row.get(3)?,
+        group_id: row.get(4)?,
+        name: row.get(5)?,
+        last_message: None,
+        unread_count: row.get(8)?,
+        archived: row.get(6)?,
+        muted_until: row.get(7)?,
+        blocked: row.get(11)?,
+        pinned: row.get(12)?,
+    };
+
+    Ok((conversation, row.get(10)?))
+}
+
+/// Name of a group, if it is known
+fn group_name(db: &Connection, group_id: &str) -> rusqlite::Result<Option<String>> {
+    Ok(db
+        .prepare_cached("SELECT name FROM groups WHERE id = ? AND name != ''")?
+        .query_row(params![group_id], |row| row.get::<_, String>(0))
+        .optional()?
+        .filter(|name| !name.is_empty()))
+}
+
+/// Read a row of `RECIPIENT_DETAILS`
+fn recipient_details_from_row(row: &rusqlite::Row) -> rusqlite::Result<RecipientDetails> {
+    let path = |index| -> rusqlite::Result<Option<std::path::PathBuf>> {
+        Ok(row
+            .get::<_, Option<String>>(index)?
+            .filter(|path| !path.is_empty())
+            .map(std::path::PathBuf::from))
+    };
+
+    Ok(RecipientDetails {
+        aci: parse_aci(&row.get::<_, String>(0)?),
+        phone_number: row.get(1)?,
+        nickname: row.get(2)?,
+        system_name: row.get(3)?,
+        profile_name: row.get(4)?,
+        username: row.get(5)?,
+        avatar_path: path(6)?.or(path(7)?),
+    })
+}
+
+/// Best known name of a recipient, ranked as in
+/// [`RecipientDetails::display_name`]
+fn contact_name(db: &Connection, recipient_id: i64) -> rusqlite::Result<Option<String>> {
+    Ok(db
+        .prepare_cached(
+            r#"SELECT COALESCE(NULLIF(c.nickname, ''), NULLIF(c.name, ''),
+                              NULLIF(c.profile_name, ''), r.e164, NULLIF(c.username, ''))
+               FROM recipients r LEFT JOIN contacts c ON c.recipient_id = r.id
+               WHERE r.id = ?"#,
+        )?
+        .query_row(params![recipient_id], |row| row.get(0))
+        .optional()?
+        .flatten())
+}
+
+/// Fill in the attachment, reactions and quote of a message read by
+/// `message_from_row`
+fn hydrate_message(
+    db: &Connection,
+    message: &mut Message,
+    quote: Option<QuoteReference>,
+) -> rusqlite::Result<()> {
+    if let Some(attachment) = message.content.attachment_mut() {
+        let stored = db
+            .prepare_cached(
+                r#"SELECT id, content_type, file_name, size, digest, key, cdn_number,
+                          upload_timestamp, width, height, thumbnail
+                   FROM attachments WHERE message_id = ?"#,
+            )?
+            .query_row(params![message.id], |row| {
+                Ok(Attachment {
+                    id: row.get(0)?,
+                    content_type: row.get(1)?,
+                    file_name: row.get(2)?,
+                    size: row.get(3)?,
+                    digest: row.get::<_, Option<Vec<u8>>>(4)?.unwrap_or_default(),
+                    key: row.get::<_, Option<Vec<u8>>>(5)?.unwrap_or_default(),
+                    cdn_number: row.get::<_, Option<u32>>(6)?.unwrap_or_default(),
+                    upload_timestamp: row.get::<_, Option<i64>>(7)?.unwrap_or_default(),
+                    width: row.get(8)?,
+                    height: row.get(9)?,
+                    thumbnail: row.get(10)?,
+                })
+            })
+            .optional()?;
+
+        // Messages stored before attachment rows were kept only have the
+        // attachment in their content
+        if let Some(stored) = stored {
+            *attachment = stored;
+        }
+    }
+
+    message.reactions = db
+        .prepare_cached(
+            r#"SELECT emoji, sender_uuid, timestamp FROM reactions
+               WHERE message_id = ? ORDER BY timestamp, id"#,
+        )?
+        .query_map(params![message.id], |row| {
+            Ok(Reaction {
+                emoji: row.get(0)?,
+                sender: SignalIdentity {
+                    aci: parse_aci(&row.get::<_, String>(1)?),
+                    pni: None,
+                    phone_number: None,
+                    device_id: 1,
+                    registration_id: 0,
+                },
+                timestamp: row.get(2)?,
+            })
+        })?
+        .filter_map(|r| r.ok())
+        .collect();
+
+    message.quote = quote
+        .map(|quote| {
+            resolve_quote(
+                db,
+                &message.conversation_id,
+                quote.id.as_deref(),
+                &quote.author,
+                quote.timestamp,
+            )
+            .map(Box::new)
+        })
+        .transpose()?;
+
+    Ok(())
+}
+
+/// Hydrate messages loaded with `message_with_quote`
+fn hydrate_messages(
+    db: &Connection,
+    rows: Vec<(Message, Option<QuoteReference>)>,
+) -> rusqlite::Result<Vec<Message>> {
+    let mut messages = Vec::with_capacity(rows.len());
+    for (mut message, quote) in rows {
+        hydrate_message(db, &mut message, quote)?;
+        messages.push(message);
+    }
+    Ok(messages)
+}
+
+/// The message a quote refers to, or a "not found" stub
+///
+/// The quote's message ID is only known for our own replies; quotes
+/// received from others are matched by author and sent timestamp.
+fn resolve_quote(
+    db: &Connection,
+    conversation_id: &str,
+    quote_id: Option<&str>,
+    author: &str,
+    timestamp: i64,
+) -> rusqlite::Result<Message> {
+    if let Some((quoted, _)) = quote_id
+        .map(|id| load_message(db, id))
+        .transpose()?
+        .flatten()
+    {
+        return Ok(quoted);
+    }
+
+    let quoted = db
+        .prepare_cached(
+            r#"SELECT id, conversation_id, sender_uuid, sender_device_id, timestamp,
+                      received_timestamp, content_type, content_json, status, expires_at,
+                      server_guid, server_timestamp, destination_service_id, urgent, story
+               FROM messages
+               WHERE conversation_id = ? AND sender_uuid = ? AND timestamp = ?
+               LIMIT 1"#,
+        )?
+        .query_row(
+            params![conversation_id, author, timestamp],
+            message_from_row,
+        )
+        .optional()?;
+
+    Ok(
+        quoted
+            .unwrap_or_else(|| Message::quote_stub(conversation_id, parse_aci(author), timestamp)),
+    )
+}
+
+/// Parse a message status as stored by its `Debug` name
+fn parse_status(status: &str) -> MessageStatus {
+    match status {
+        "Sending" => MessageStatus::Sending,
+        "Sent" => MessageStatus::Sent,
+        "Delivered" => MessageStatus::Delivered,
+        "Read" => MessageStatus::Read,
+        _ => MessageStatus::Failed,
+    }
+}
+
+/// Message a stored message quotes, as kept in its row
+struct QuoteReference {
+    /// ID of the quoted message, known only for our own replies
+    id: Option<String>,
+    author: String,
+    timestamp: i64,
+}
+
+/// Read the quote columns following the `get_messages` column list
+fn quote_reference(row: &rusqlite::Row) -> rusqlite::Result<Option<QuoteReference>> {
+    let author: Option<String> = row.get(16)?;
+    let timestamp: Option<i64> = row.get(17)?;
+
+    Ok(match (author, timestamp) {
+        (Some(author), Some(timestamp)) => Some(QuoteReference {
+            id: row.get(15)?,
+            author,
+            timestamp,
+        }),
+        _ => None,
+    })
+}
+
+/// Build a message and its quote reference from a row of the
+/// `get_messages` column list
+fn message_with_quote(row: &rusqlite::Row) -> rusqlite::Result<(Message, Option<QuoteReference>)> {
+    Ok((message_from_row(row)?, quote_reference(row)?))
+}
+
+/// Build a message from a row of the `get_messages` column list
+fn message_from_row(row: &rusqlite::Row) -> rusqlite::Result<Message> {
+    let content_json: String = row.get(7)?;
+    let content: MessageContent =
+        serde_json::from_str(&content_json).unwrap_or(MessageContent::Text {
+            body: "[Error loading message]".to_string(),
+        });
+
+    let status = parse_status(&row.get::<_, String>(8)?);
+
+    let server_guid: Option<String> = row.get(10)?;
+    let server_timestamp: Option<i64> = row.get(11)?;
+    let envelope = if server_guid.is_some() || server_timestamp.is_some() {
+        Some(EnvelopeMetadata {
+            server_guid,
+            server_timestamp,
+            destination_service_id: row
+                .get::<_, Option<String>>(12)?
+                .and_then(|id| id.parse().ok()),
+            urgent: row.get::<_, Option<bool>>(13)?.unwrap_or(true),
+            story: row.get::<_, Option<bool>>(14)?.unwrap_or(false),
+        })
+    } else {
+        None
+    };
+
+    Ok(Message {
+        id: row.get(0)?,
+        conversation_id: row.get(1)?,
+        sender: SignalIdentity {
+            aci: parse_aci(&row.get::<_, String>(2)?),
+            pni: None,
+            phone_number: None,
+            device_id: row.get(3)?,
+            registration_id: 0,
+        },
+        timestamp: row.get(4)?,
+        received_timestamp: row.get(5)?,
+        content,
+        status,
+        quote: None,
+        reactions: Vec::new(),
+        expires_at: row.get(9)?,
+        envelope,
+    })
+}
+
+/// Find the recipient whose `column` (aci, pni or e164) holds `value`
+fn find_recipient(db: &Connection, column: &str, value: &str) -> Result<Option<Recipient>> {
+    let recipient = db
+        .prepare_cached(&format!(
+            "SELECT id, aci, pni, e164 FROM recipients WHERE {} = ?",
+            column
+        ))?
+        .query_row(
+            params![value],
+            |row| {
+                Ok(Recipient {
+                    id: row.get(0)?,
+                    aci: row.get::<_, Option<String>>(1)?.and_then(|aci| aci.parse().ok()),
+                    pni: parse_pni(row.get(2)?),
+                    e164: row.get(3)?,
+                })
+            },
+        )
+        .optional()?;
+
+    Ok(recipient)
+}
+
+/// Unify the given identifiers into a single recipient row
+///
+/// The row holding the ACI is kept. Rows found by PNI or phone number are
+/// merged into it unless they belong to a different ACI, in which case the
+/// identifier is taken from them instead.
+fn merge_recipient_in(
+    db: &Connection,
+    aci: Option<Aci>,
+    pni: Option<Pni>,
+    e164: Option<&str>,
+) -> Result<Recipient> {
+    if aci.is_none() && pni.is_none() && e164.is_none() {
+        return Err(anyhow!("Recipient without any identifier"));
+    }
+    let now = chrono::Utc::now().timestamp();
+
+    let by_aci = match aci {
+        Some(aci) => find_recipient(db, "aci", &aci.to_string())?,
+        None => None,
+    };
+    let by_pni = match pni {
+        Some(pni) => find_recipient(db, "pni", &pni.to_string())?,
+        None => None,
+    };
+    let by_e164 = match e164 {
+        Some(e164) => find_recipient(db, "e164", e164)?,
+        None => None,
+    };
+
+    let mut primary = match by_aci {
+        Some(recipient) => recipient,
+        None => {
+            let adoptable = [&by_pni, &by_e164]
+                .into_iter()
+                .flatten()
+                .find(|recipient| aci.is_none() || recipient.aci.is_none());
+
+            match adoptable {
+                Some(recipient) => recipient.clone(),
+                None => {
+                    db.execute(
+                        "INSERT INTO recipients (created_at, updated_at) VALUES (?, ?)",
+                        params![now, now],
+                    )?;
+                    Recipient {
+                        id: db.last_insert_rowid(),
+                        aci: None,
+                        pni: None,
+                        e164: None,
+                    }
+                }
+            }
+        }
+    };
+    let primary_aci = aci.or(primary.aci);
+
+    let mut merged = Vec::new();
+    for (other, column) in [(by_pni, "pni"), (by_e164, "e164")] {
+        let Some(other) = other else { continue };
+        if other.id == primary.id || merged.contains(&other.id) {
+            continue;
+        }
+
+        if other.aci.is_some() && other.aci != primary_aci {
+            // The PNI or number now belongs to someone else
+            db.execute(
+                &format!(
+                    "UPDATE recipients SET {} = NULL, updated_at = ? WHERE id = ?",
+                    column
+                ),
+                params![now, other.id],
+            )?;
+        } else {
+            absorb_recipient(db, &mut primary, &other)?;
+            merged.push(other.id);
+        }
+    }
+
+    primary.aci = primary_aci;
+    primary.pni = pni.or(primary.pni);
+    primary.e164 = e164.map(str::to_string).or(primary.e164);
+
+    db.execute(
+        "UPDATE recipients SET aci = ?, pni = ?, e164 = ?, updated_at = ? WHERE id = ?",
+        params![
+            primary.aci.map(|aci| aci.to_string()),
+            primary.pni.map(|pni| pni.to_string()),
+            primary.e164,
+            now,
+            primary.id,
+        ],
+    )?;
+    db.execute(
+        r#"UPDATE conversations SET recipient_uuid = COALESCE(?, recipient_uuid), recipient_pni = ?
+           WHERE recipient_id = ?"#,
+        params![
+            primary.aci.map(|aci| aci.to_string()),
+            primary.pni.map(|pni| pni.to_string()),
+            primary.id,
+        ],
+    )?;
+
+    Ok(primary)
+}
+
+/// Fold `other` into `primary`, moving its conversation and contact over
+fn absorb_recipient(db: &Connection, primary: &mut Recipient, other: &Recipient) -> Result<()> {
+    tracing::info!("Merging recipient {} into {}", other.id, primary.id);
+
+    let conversation_of = |id: i64| -> Result<Option<(String, i64)>> {
+        Ok(db
+            .query_row(
+                "SELECT id, unread_count FROM conversations WHERE recipient_id = ? AND is_group = 0",
+                params![id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?)
+    };
+
+    match (conversation_of(primary.id)?, conversation_of(other.id)?) {
+        (Some((target, _)), Some((source, unread_count))) => {
+            db.execute(
+                "UPDATE messages SET conversation_id = ? WHERE conversation_id = ?",
+                params![target, source],
+            )?;
+            db.execute(
+                "UPDATE conversations SET unread_count = unread_count + ? WHERE id = ?",
+                params![unread_count, target],
+            )?;
+            db.execute("DELETE FROM conversations WHERE id = ?", params![source])?;
+
+            let now = chrono::Utc::now().timestamp_millis();
+            insert_message(
+                db,
+                &Message {
+                    id: uuid::Uuid::new_v4().to_string(),
+                    conversation_id: target,
+                    sender: SignalIdentity {
+                        aci: primary
+                            .aci
+                            .or(other.aci)
+                            .unwrap_or_else(|| Aci::from(uuid::Uuid::nil())),
+                        pni: None,
+                        phone_number: None,
+                        device_id: 1,
+                        registration_id: 0,
+                    },
+                    timestamp: now,
+                    received_timestamp: Some(now),
+                    content: MessageContent::NumbersMerged {
+                        phone_number: other.e164.clone().or_else(|| primary.e164.clone()),
+                    },
+                    status: MessageStatus::Delivered,
+                    quote: None,
+                    reactions: Vec::new(),
+                    expires_at: None,
+                    envelope: None,
+                },
+                false,
+            )?;
+        }
+        (None, Some((source, _))) => {
+            db.execute(
+                "UPDATE conversations SET recipient_id = ? WHERE id = ?",
+                params![primary.id, source],
+            )?;
+        }
+        _ => {}
+    }
+
+    let has_contact: bool = db.query_row(
+        "SELECT COUNT(*) > 0 FROM contacts WHERE recipient_id = ?",
+        params![primary.id],
+        |row| row.get(0),
+    )?;
+    if has_contact {
+        db.execute("DELETE FROM contacts WHERE recipient_id = ?", params![other.id])?;
+    } else {
+        db.execute(
+            "UPDATE contacts SET recipient_id = ? WHERE recipient_id = ?",
+            params![primary.id, other.id],
+        )?;
+    }
+
+    db.execute(
+        "UPDATE OR IGNORE message_recipients SET recipient_id = ? WHERE recipient_id = ?",
+        params![primary.id, other.id],
+    )?;
+    db.execute(
+        "DELETE FROM message_recipients WHERE recipient_id = ?",
+        params![other.id],
+    )?;
+    db.execute("DELETE FROM recipients WHERE id = ?", params![other.id])?;
+
+    primary.aci = primary.aci.or(other.aci);
+    primary.pni = primary.pni.or(other.pni);
+    primary.e164 = primary.e164.clone().or_else(|| other.e164.clone());
+
+    Ok(())
+}
+
+/// Identities table address of our own ACI or PNI keys
+fn local_identity_address(kind: ServiceIdKind) -> &'static str {
+    match kind {
+        ServiceIdKind::Aci => "local",
+        ServiceIdKind::Pni => "local_pni",
+    }
+}
+
+/// One-time and signed pre-key tables of our ACI or PNI
+fn pre_key_tables(kind: ServiceIdKind) -> (&'static str, &'static str) {
+    match kind {
+        ServiceIdKind::Aci => ("pre_keys", "signed_pre_keys"),
+        ServiceIdKind::Pni => ("pni_pre_keys", "pni_signed_pre_keys"),
+    }
+}
+
+/// Parse a stored ACI, falling back to the nil ACI for unreadable rows
+fn parse_aci(s: &str) -> Aci {
+    s.parse().unwrap_or_else(|_| Aci::from(uuid::Uuid::nil()))
+}
+
+/// Parse an optional stored PNI
+fn parse_pni(s: Option<String>) -> Option<Pni> {
+    s.and_then(|s| s.parse().ok())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::signal::keystore::KEY_FILE;
     use tempfile::TempDir;
 
+    /// Build a database as the given schema version left it, with one message
+    fn fixture_database(dir: &Path, version: u32) {
+        let db = Connection::open(dir.join(DATABASE_FILE)).unwrap();
+
+        for migration in &MIGRATIONS[..version as usize] {
+            db.execute_batch(migration.sql).unwrap();
+        }
+        db.pragma_update(None, "user_version", version).unwrap();
+
+        if version >= 1 {
+            db.execute_batch(
+                r#"
+                INSERT INTO conversations (id, recipient_uuid, name, created_at, updated_at)
+                    VALUES ('conv-1', '9d0652a3-dcc3-4d11-975f-74d61598733f', 'Alice', 0, 0);
+                INSERT INTO messages (id, conversation_id, sender_uuid, timestamp, content_type,
+                                      content_json, status, created_at)
+                    VALUES ('msg-1', 'conv-1', '9d0652a3-dcc3-4d11-975f-74d61598733f', 1000,
+                            'text', '{"type":"Text","body":"Hello"}', 'Delivered', 0);
+                "#,
+            )
+            .unwrap();
+        }
+    }
+
+    /// Open a store keeping its key in a file rather than the Secret Service
+    async fn open_store(dir: &Path) -> Result<SignalStore> {
+        SignalStore::with_key_store(dir, test_key_store(dir)).await
+    }
+
+    fn test_key_store(dir: &Path) -> KeyStore {
+        KeyStore::File {
+            path: dir.join(KEY_FILE),
+            wrapping_key: [7; 32],
+        }
+    }
+
+    async fn user_version(store: &SignalStore) -> u32 {
+        store
+            .db
+            .read(|db| Ok(db.pragma_query_value(None, "user_version", |row| row.get(0))?))
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn test_migrations_are_sequential() {
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, index as u32 + 1);
+        }
+        assert_eq!(MIGRATIONS.len() as u32, SCHEMA_VERSION);
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_from_every_version() {
+        for version in 0..SCHEMA_VERSION {
+            let temp_dir = TempDir::new().unwrap();
+            fixture_database(temp_dir.path(), version);
+
+            let store = open_store(temp_dir.path()).await.unwrap();
+            assert_eq!(user_version(&store).await, SCHEMA_VERSION);
+
+            let backup = temp_dir
+                .path()
+                .join(format!("{}.v{}.bak", DATABASE_FILE, version));
+            assert_eq!(backup.exists(), version > 0, "backup from v{}", version);
+
+            if version >= 1 {
+                let messages = store.get_messages("conv-1", 10).await.unwrap();
+                assert_eq!(messages.len(), 1, "messages from v{}", version);
+
+                let conversation = store.get_conversation("conv-1").await.unwrap().unwrap();
+                assert_eq!(conversation.name, "Alice");
+
+                let hits = store
+                    .search_messages("hello", None, SearchPaging::default())
+                    .await
+                    .unwrap();
+                assert_eq!(hits.len(), 1, "search index from v{}", version);
+            }
+
+            // Reopening an up-to-date database changes nothing
+            drop(store);
+            let store = open_store(temp_dir.path()).await.unwrap();
+            assert_eq!(user_version(&store).await, SCHEMA_VERSION);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_from_legacy_version_metadata() {
+        let temp_dir = TempDir::new().unwrap();
+        fixture_database(temp_dir.path(), 1);
+
+        // Before user_version, the version lived in the metadata table
+        {
+            let db = Connection::open(temp_dir.path().join(DATABASE_FILE)).unwrap();
+            db.pragma_update(None, "user_version", 0).unwrap();
+            db.execute(
+                "INSERT INTO metadata (key, value) VALUES ('schema_version', '1')",
+                [],
+            )
+            .unwrap();
+        }
+
+        let store = open_store(temp_dir.path()).await.unwrap();
+        assert_eq!(user_version(&store).await, SCHEMA_VERSION);
+        assert!(!store.has_server_guid("guid-1").await.unwrap());
+        assert_eq!(store.get_messages("conv-1", 10).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_newer_schema_is_rejected() {
+        let temp_dir = TempDir::new().unwrap();
+        fixture_database(temp_dir.path(), SCHEMA_VERSION);
+        {
+            let db = Connection::open(temp_dir.path().join(DATABASE_FILE)).unwrap();
+            db.pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+                .unwrap();
+        }
+
+        assert!(open_store(temp_dir.path()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_database_is_encrypted() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join(DATABASE_FILE);
+        fixture_database(temp_dir.path(), SCHEMA_VERSION);
+        assert!(database::is_plaintext(&db_path).unwrap());
+
+        let store = open_store(temp_dir.path()).await.unwrap();
+        assert!(!database::is_plaintext(&db_path).unwrap());
+        assert_eq!(user_version(&store).await, SCHEMA_VERSION);
+        assert_eq!(store.get_messages("conv-1", 10).await.unwrap().len(), 1);
+
+        // Without the key the file is unreadable
+        let db = Connection::open(&db_path).unwrap();
+        assert!(db
+            .query_row("SELECT COUNT(*) FROM sqlite_master", [], |_| Ok(()))
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_missing_key_is_an_error() {
+        let temp_dir = TempDir::new().unwrap();
+        drop(open_store(temp_dir.path()).await.unwrap());
+
+        std::fs::remove_file(temp_dir.path().join(KEY_FILE)).unwrap();
+        assert!(open_store(temp_dir.path()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rekey() {
+        let temp_dir = TempDir::new().unwrap();
+        fixture_database(temp_dir.path(), SCHEMA_VERSION);
+        let store = open_store(temp_dir.path()).await.unwrap();
+
+        let key_store = test_key_store(temp_dir.path());
+        let old_key = key_store.load().await.unwrap().unwrap();
+        store.rekey().await.unwrap();
+        let new_key = key_store.load().await.unwrap().unwrap();
+        assert!(old_key != new_key);
+        assert_eq!(store.get_messages("conv-1", 10).await.unwrap().len(), 1);
+
+        // Reopening finds the new key
+        drop(store);
+        let store = open_store(temp_dir.path()).await.unwrap();
+        assert_eq!(store.get_messages("conv-1", 10).await.unwrap().len(), 1);
+        assert!(key_store
+            .load_slot(KeySlot::Previous)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_rekey() {
+        let temp_dir = TempDir::new().unwrap();
+        fixture_database(temp_dir.path(), SCHEMA_VERSION);
+        let store = open_store(temp_dir.path()).await.unwrap();
+        drop(store);
+
+        // Both keys were saved, but the database never switched
+        let key_store = test_key_store(temp_dir.path());
+        let old_key = key_store.load().await.unwrap().unwrap();
+        key_store
+            .save_slot(KeySlot::Previous, &old_key)
+            .await
+            .unwrap();
+        key_store.save(&DatabaseKey::generate()).await.unwrap();
+
+        let store = open_store(temp_dir.path()).await.unwrap();
+        assert_eq!(store.get_messages("conv-1", 10).await.unwrap().len(), 1);
+        assert!(key_store.load().await.unwrap() == Some(old_key));
+        assert!(key_store
+            .load_slot(KeySlot::Previous)
+            .await
+            .unwrap()
+            .is_none());
+
+        // Without the previous key there is nothing to fall back on
+        drop(store);
+        key_store.save(&DatabaseKey::generate()).await.unwrap();
+        assert!(open_store(temp_dir.path()).await.is_err());
+    }
+
     #[tokio::test]
     async fn test_store_and_get_conversation() {
         let temp_dir = TempDir::new().unwrap();
-        let store = SignalStore::new(temp_dir.path()).await.unwrap();
+        let store = open_store(temp_dir.path()).await.unwrap();
 
         let conversation = Conversation {
             id: "test-conv-1".to_string(),
             recipient: SignalIdentity {
-                uuid: uuid::Uuid::new_v4(),
+                aci: uuid::Uuid::new_v4().into(),
+                pni: None,
                 phone_number: Some("+1234567890".to_string()),
                 device_id: 1,
                 registration_id: 12345,
@@ -951,6 +4318,8 @@ mod tests {
             unread_count: 0,
             archived: false,
             muted_until: None,
+            blocked: false,
+            pinned: None,
         };
 
         store.store_conversation(&conversation).await.unwrap();
@@ -960,16 +4329,183 @@ mod tests {
         assert_eq!(retrieved.unwrap().name, "Test Contact");
     }
 
+    #[tokio::test]
+    async fn test_conversation_upkeep() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = open_store(temp_dir.path()).await.unwrap();
+
+        let me = SignalIdentity {
+            aci: uuid::Uuid::new_v4().into(),
+            pni: None,
+            phone_number: None,
+            device_id: 1,
+            registration_id: 0,
+        };
+        let alice = SignalIdentity {
+            aci: uuid::Uuid::new_v4().into(),
+            phone_number: Some("+15550100".to_string()),
+            ..me.clone()
+        };
+        let message =
+            |id: &str, sender: &SignalIdentity, conversation_id: &str, timestamp| Message {
+                id: id.to_string(),
+                conversation_id: conversation_id.to_string(),
+                sender: sender.clone(),
+                timestamp,
+                received_timestamp: None,
+                content: MessageContent::Text {
+                    body: id.to_string(),
+                },
+                status: MessageStatus::Delivered,
+                quote: None,
+                reactions: Vec::new(),
+                expires_at: None,
+                envelope: None,
+            };
+        let alice_id = alice.aci.to_string();
+
+        // The first message creates the conversation, named by phone number
+        let conversation = store
+            .store_conversation_message(
+                &message("msg-1", &alice, &alice_id, 1000),
+                &alice,
+                None,
+                true,
+            )
+            .await
+            .unwrap();
+        assert_eq!(conversation.name, "+15550100");
+        assert_eq!(conversation.unread_count, 1);
+        assert_eq!(conversation.last_message.unwrap().id, "msg-1");
+        assert!(!conversation.is_group);
+
+        // Storing the same message again does not count it twice
+        let conversation = store
+            .store_conversation_message(
+                &message("msg-1", &alice, &alice_id, 1000),
+                &alice,
+                None,
+                true,
+            )
+            .await
+            .unwrap();
+        assert_eq!(conversation.unread_count, 1);
+
+        // A contact name takes over once known
+        store
+            .db
+            .write(|db| {
+                db.execute(
+                    r#"INSERT INTO contacts (uuid, name, recipient_id, created_at, updated_at)
+                       SELECT aci, 'Alice', id, 0, 0 FROM recipients WHERE e164 = '+15550100'"#,
+                    [],
+                )?;
+                Ok(())
+            })
+            .await
+            .unwrap();
+
+        // Our replies are not unread; late older messages do not become the last message
+        let conversation = store
+            .store_conversation_message(
+                &message("msg-3", &me, &alice_id, 3000),
+                &alice,
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!(conversation.name, "Alice");
+        assert_eq!(conversation.unread_count, 1);
+        let conversation = store
+            .store_conversation_message(
+                &message("msg-2", &alice, &alice_id, 2000),
+                &alice,
+                None,
+                true,
+            )
+            .await
+            .unwrap();
+        assert_eq!(conversation.unread_count, 2);
+        assert_eq!(conversation.last_message.unwrap().id, "msg-3");
+
+        // Updating a stored message leaves it unread
+        store
+            .store_message(&message("msg-2", &alice, &alice_id, 2000))
+            .await
+            .unwrap();
+
+        let (conversation, mut read) = store
+            .mark_conversation_read(&alice_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(conversation.unread_count, 0);
+        // Only the incoming messages were unread
+        read.sort();
+        assert_eq!(read, [(alice_id.clone(), 1000), (alice_id.clone(), 2000)]);
+        assert!(store
+            .mark_conversation_read("missing")
+            .await
+            .unwrap()
+            .is_none());
+
+        // Group conversations are named after the group
+        let group = Group {
+            id: "group-1".to_string(),
+            master_key: Vec::new(),
+            revision: 0,
+            name: "Climbing".to_string(),
+            description: None,
+            avatar: None,
+            members: Vec::new(),
+            admins: Vec::new(),
+            pending_members: Vec::new(),
+            disappearing_messages_timer: None,
+            access_control: GroupAccessControl {
+                members_can_add_members: true,
+                members_can_edit_group_info: true,
+            },
+            invite_link_access: InviteLinkAccess::Disabled,
+            invite_link_password: None,
+        };
+        store.store_group(&group).await.unwrap();
+        let conversation = store
+            .store_conversation_message(
+                &message("msg-4", &alice, "group-1", 4000),
+                &alice,
+                Some("group-1"),
+                true,
+            )
+            .await
+            .unwrap();
+        assert_eq!(conversation.name, "Climbing");
+        assert!(conversation.is_group);
+        assert_eq!(conversation.group_id.as_deref(), Some("group-1"));
+
+        // Both conversations are listed
+        let ids: Vec<_> = store
+            .get_conversations()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|conversation| conversation.id)
+            .collect();
+        assert_eq!(ids.len(), 2);
+        assert!(ids.contains(&"group-1".to_string()));
+    }
+
     #[tokio::test]
     async fn test_store_and_get_message() {
         let temp_dir = TempDir::new().unwrap();
-        let store = SignalStore::new(temp_dir.path()).await.unwrap();
+        let store = open_store(temp_dir.path()).await.unwrap();
 
         // Create conversation first
         let conversation = Conversation {
             id: "test-conv-1".to_string(),
             recipient: SignalIdentity {
-                uuid: uuid::Uuid::new_v4(),
+                aci: uuid::Uuid::new_v4().into(),
+                pni: None,
                 phone_number: None,
                 device_id: 1,
                 registration_id: 0,
@@ -981,6 +4517,8 @@ mod tests {
             unread_count: 0,
             archived: false,
             muted_until: None,
+            blocked: false,
+            pinned: None,
         };
         store.store_conversation(&conversation).await.unwrap();
 
@@ -997,26 +4535,1540 @@ mod tests {
             quote: None,
             reactions: Vec::new(),
             expires_at: None,
+            envelope: None,
         };
 
         store.store_message(&message).await.unwrap();
 
         let messages = store.get_messages("test-conv-1", 10).await.unwrap();
         assert_eq!(messages.len(), 1);
+        assert!(messages[0].envelope.is_none());
 
         if let MessageContent::Text { body } = &messages[0].content {
             assert_eq!(body, "Hello, World!");
         } else {
             panic!("Expected text message");
         }
+
+        // Stored messages are found by author and timestamp
+        let (id, other) = ("test-conv-1", "test-conv-2");
+        let (sender, timestamp) = (message.sender.aci.to_string(), message.timestamp);
+        assert!(store.has_message(id, &sender, timestamp).await.unwrap());
+        assert!(!store.has_message(id, &sender, timestamp + 1).await.unwrap());
+        assert!(!store.has_message(other, &sender, timestamp).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_read_receipt_status() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = open_store(temp_dir.path()).await.unwrap();
+
+        let identity = || SignalIdentity {
+            aci: uuid::Uuid::new_v4().into(),
+            pni: None,
+            phone_number: None,
+            device_id: 1,
+            registration_id: 0,
+        };
+        let (me, bob) = (identity(), identity());
+        let conversation_id = bob.aci.to_string();
+
+        let message = |id: &str, sender: &SignalIdentity, timestamp, status| Message {
+            id: id.to_string(),
+            conversation_id: conversation_id.clone(),
+            sender: sender.clone(),
+            timestamp,
+            received_timestamp: None,
+            content: MessageContent::Text {
+                body: id.to_string(),
+            },
+            status,
+            quote: None,
+            reactions: Vec::new(),
+            expires_at: None,
+            envelope: None,
+        };
+        let messages = [
+            message("failed", &me, 500, MessageStatus::Failed),
+            message("delivered", &me, 1000, MessageStatus::Delivered),
+            message("theirs", &bob, 1500, MessageStatus::Delivered),
+            message("sent", &me, 2000, MessageStatus::Sent),
+            message("later", &me, 3000, MessageStatus::Sent),
+        ];
+        for message in &messages {
+            store
+                .store_conversation_message(message, &bob, None, false)
+                .await
+                .unwrap();
+        }
+        let sent_to = |identity: &SignalIdentity| SentRecipient {
+            identity: identity.clone(),
+            unidentified: false,
+        };
+        for id in ["sent", "later"] {
+            store
+                .store_message_recipients(id, &[sent_to(&bob)], MessageStatus::Sent)
+                .await
+                .unwrap();
+        }
+
+        let mut read = store
+            .mark_sent_messages_read(&conversation_id, me.aci, 2000)
+            .await
+            .unwrap();
+        read.sort();
+        assert_eq!(read, ["delivered", "sent"]);
+
+        let status = |id: &'static str| {
+            let store = &store;
+            async move { store.get_message(id).await.unwrap().unwrap().status }
+        };
+        assert_eq!(status("delivered").await, MessageStatus::Read);
+        assert_eq!(status("failed").await, MessageStatus::Failed);
+        assert_eq!(status("theirs").await, MessageStatus::Delivered);
+        assert_eq!(status("later").await, MessageStatus::Sent);
+
+        // The reader's own delivery state follows
+        let recipients = |id: &'static str| {
+            let store = &store;
+            async move { store.get_message_recipients(id).await.unwrap() }
+        };
+        let sent = recipients("sent").await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].recipient.aci, bob.aci);
+        assert_eq!(sent[0].status, MessageStatus::Read);
+        assert_eq!(recipients("later").await[0].status, MessageStatus::Sent);
+
+        // Recording a recipient again keeps its state
+        store
+            .store_message_recipients("sent", &[sent_to(&bob)], MessageStatus::Sent)
+            .await
+            .unwrap();
+        assert_eq!(recipients("sent").await[0].status, MessageStatus::Read);
+
+        // Receipts for messages already read change nothing
+        assert!(store
+            .mark_sent_messages_read(&conversation_id, me.aci, 2000)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_read_sync() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = open_store(temp_dir.path()).await.unwrap();
+
+        let alice = SignalIdentity {
+            aci: uuid::Uuid::new_v4().into(),
+            pni: None,
+            phone_number: None,
+            device_id: 1,
+            registration_id: 0,
+        };
+        let alice_id = alice.aci.to_string();
+        for timestamp in [1000, 2000, 3000] {
+            let message = Message {
+                id: format!("msg-{}", timestamp),
+                conversation_id: alice_id.clone(),
+                sender: alice.clone(),
+                timestamp,
+                received_timestamp: None,
+                content: MessageContent::Text {
+                    body: "Hi".to_string(),
+                },
+                status: MessageStatus::Delivered,
+                quote: None,
+                reactions: Vec::new(),
+                expires_at: None,
+                envelope: None,
+            };
+            store
+                .store_conversation_message(&message, &alice, None, true)
+                .await
+                .unwrap();
+        }
+
+        // Reading a message reads everything before it
+        let changed = store
+            .apply_read_sync(&[(alice_id.clone(), 2000), ("unknown".to_string(), 2000)])
+            .await
+            .unwrap();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].id, alice_id);
+        assert_eq!(changed[0].unread_count, 1);
+
+        // Reads already applied change nothing
+        assert!(store
+            .apply_read_sync(&[(alice_id.clone(), 1000)])
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Only the message left unread is reported when reading locally
+        let (conversation, read) = store
+            .mark_conversation_read(&alice_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(conversation.unread_count, 0);
+        assert_eq!(read, [(alice_id.clone(), 3000)]);
+    }
+
+    #[tokio::test]
+    async fn test_message_envelope_metadata() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = open_store(temp_dir.path()).await.unwrap();
+
+        let sender = SignalIdentity {
+            aci: uuid::Uuid::new_v4().into(),
+            pni: None,
+            phone_number: None,
+            device_id: 3,
+            registration_id: 0,
+        };
+        let conversation = Conversation {
+            id: sender.aci.to_string(),
+            recipient: sender.clone(),
+            is_group: false,
+            group_id: None,
+            name: "Test".to_string(),
+            last_message: None,
+            unread_count: 0,
+            archived: false,
+            muted_until: None,
+            blocked: false,
+            pinned: None,
+        };
+        store.store_conversation(&conversation).await.unwrap();
+
+        let metadata = EnvelopeMetadata {
+            server_guid: Some("guid-1".to_string()),
+            server_timestamp: Some(1_700_000_000_500),
+            destination_service_id: Some(ServiceId::Pni(uuid::Uuid::new_v4().into())),
+            urgent: false,
+            story: false,
+        };
+        let message = Message {
+            id: "msg-1".to_string(),
+            conversation_id: sender.aci.to_string(),
+            sender: sender.clone(),
+            timestamp: 1_700_000_000_000,
+            received_timestamp: Some(1_700_000_001_000),
+            content: MessageContent::Text {
+                body: "Hi".to_string(),
+            },
+            status: MessageStatus::Delivered,
+            quote: None,
+            reactions: Vec::new(),
+            expires_at: None,
+            envelope: Some(metadata.clone()),
+        };
+
+        assert!(!store.has_server_guid("guid-1").await.unwrap());
+        store.store_message(&message).await.unwrap();
+        assert!(store.has_server_guid("guid-1").await.unwrap());
+
+        let messages = store.get_messages(&sender.aci.to_string(), 10).await.unwrap();
+        assert_eq!(messages[0].sender.device_id, 3);
+        assert_eq!(messages[0].envelope.as_ref(), Some(&metadata));
+
+        // A second delivery of the same envelope never yields two rows
+        let duplicate = Message {
+            id: "msg-2".to_string(),
+            ..message
+        };
+        store.store_message(&duplicate).await.unwrap();
+        let messages = store.get_messages(&sender.aci.to_string(), 10).await.unwrap();
+        assert_eq!(messages.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_recent_send_log() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = open_store(temp_dir.path()).await.unwrap();
+
+        store.store_recent_send("bob", 1000, b"content").await.unwrap();
+
+        assert_eq!(
+            store.get_recent_send("bob", 1000).await.unwrap(),
+            Some(b"content".to_vec())
+        );
+        assert!(store.get_recent_send("bob", 1001).await.unwrap().is_none());
+        assert!(store.get_recent_send("alice", 1000).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_search_messages() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = open_store(temp_dir.path()).await.unwrap();
+
+        let sender = SignalIdentity {
+            aci: uuid::Uuid::new_v4().into(),
+            pni: None,
+            phone_number: None,
+            device_id: 1,
+            registration_id: 0,
+        };
+        for (id, name) in [("conv-1", "Alice"), ("conv-2", "Bob")] {
+            store
+                .store_conversation(&Conversation {
+                    id: id.to_string(),
+                    recipient: sender.clone(),
+                    is_group: false,
+                    group_id: None,
+                    name: name.to_string(),
+                    last_message: None,
+                    unread_count: 0,
+                    archived: false,
+                    muted_until: None,
+                    blocked: false,
+                    pinned: None,
+                })
+                .await
+                .unwrap();
+        }
+
+        let attachment = Attachment {
+            id: "att-1".to_string(),
+            content_type: "application/pdf".to_string(),
+            file_name: Some("holiday-plans.pdf".to_string()),
+            size: 1024,
+            digest: Vec::new(),
+            key: Vec::new(),
+            cdn_number: 2,
+            upload_timestamp: 0,
+            width: None,
+            height: None,
+            thumbnail: None,
+        };
+        let contents = [
+            ("msg-1", "conv-1", MessageContent::Text { body: "Lunch at noon?".to_string() }),
+            ("msg-2", "conv-1", MessageContent::Text { body: "Café closed, lunch later".to_string() }),
+            ("msg-3", "conv-2", MessageContent::Image {
+                attachment: attachment.clone(),
+                caption: Some("Lunch view".to_string()),
+            }),
+            ("msg-4", "conv-2", MessageContent::File { attachment }),
+        ];
+        for (index, (id, conversation_id, content)) in contents.into_iter().enumerate() {
+            let message = Message {
+                id: id.to_string(),
+                conversation_id: conversation_id.to_string(),
+                sender: sender.clone(),
+                timestamp: 1000 + index as i64,
+                received_timestamp: None,
+                content,
+                status: MessageStatus::Delivered,
+                quote: None,
+                reactions: Vec::new(),
+                expires_at: None,
+                envelope: None,
+            };
+            store.store_message(&message).await.unwrap();
+        }
+
+        let search = |query: &'static str, conversation_id: Option<&'static str>| {
+            let store = store.clone();
+            async move {
+                store
+                    .search_messages(query, conversation_id, SearchPaging::default())
+                    .await
+                    .unwrap()
+            }
+        };
+        let ids = |hits: &[SearchHit]| {
+            let mut ids: Vec<_> = hits.iter().map(|hit| hit.message.id.clone()).collect();
+            ids.sort();
+            ids
+        };
+
+        // Bodies and captions match, case and accents aside
+        assert_eq!(ids(&search("LUNCH", None).await), ["msg-1", "msg-2", "msg-3"]);
+        assert_eq!(ids(&search("cafe", None).await), ["msg-2"]);
+        assert_eq!(ids(&search("lunch", Some("conv-2")).await), ["msg-3"]);
+
+        // File names match, and the last word is a prefix
+        assert_eq!(ids(&search("holid", None).await), ["msg-3", "msg-4"]);
+        assert_eq!(ids(&search("lunch lat", None).await), ["msg-2"]);
+
+        // Snippets carry the matched words
+        let hits = search("noon", None).await;
+        assert_eq!(hits[0].conversation_name, "Alice");
+        assert_eq!(hits[0].snippet, "Lunch at noon?");
+        assert_eq!(&hits[0].snippet[hits[0].highlights[0].clone()], "noon");
+
+        // Query syntax in the input is taken literally
+        assert_eq!(ids(&search("\"lunch\" (noon", None).await), ["msg-1"]);
+        assert!(search("  ?! ", None).await.is_empty());
+
+        // Paging
+        let page = store
+            .search_messages("lunch", None, SearchPaging { limit: 2, offset: 2 })
+            .await
+            .unwrap();
+        assert_eq!(page.len(), 1);
+
+        // Replaced messages are reindexed
+        let mut edited = store.get_messages("conv-1", 10).await.unwrap();
+        edited.retain(|message| message.id == "msg-1");
+        edited[0].content = MessageContent::Text {
+            body: "Dinner instead".to_string(),
+        };
+        store.store_message(&edited[0]).await.unwrap();
+        assert_eq!(ids(&search("noon", None).await), Vec::<String>::new());
+        assert_eq!(ids(&search("dinner", None).await), ["msg-1"]);
+    }
+
+    #[tokio::test]
+    async fn test_message_cursors() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = open_store(temp_dir.path()).await.unwrap();
+
+        let sender = SignalIdentity {
+            aci: uuid::Uuid::new_v4().into(),
+            pni: None,
+            phone_number: None,
+            device_id: 1,
+            registration_id: 0,
+        };
+        for id in ["conv-1", "conv-2"] {
+            store
+                .store_conversation(&Conversation {
+                    id: id.to_string(),
+                    recipient: sender.clone(),
+                    is_group: false,
+                    group_id: None,
+                    name: id.to_string(),
+                    last_message: None,
+                    unread_count: 0,
+                    archived: false,
+                    muted_until: None,
+                    blocked: false,
+                    pinned: None,
+                })
+                .await
+                .unwrap();
+        }
+
+        // msg-0 .. msg-9, with msg-4 and msg-5 sent in the same millisecond
+        for index in 0..10i64 {
+            let timestamp = 1000 + if index == 5 { 4 } else { index };
+            let message = Message {
+                id: format!("msg-{}", index),
+                conversation_id: "conv-1".to_string(),
+                sender: sender.clone(),
+                timestamp,
+                received_timestamp: None,
+                content: MessageContent::Text {
+                    body: index.to_string(),
+                },
+                status: MessageStatus::Delivered,
+                quote: None,
+                reactions: Vec::new(),
+                expires_at: None,
+                envelope: None,
+            };
+            store.store_message(&message).await.unwrap();
+        }
+        let other = Message {
+            id: "other".to_string(),
+            conversation_id: "conv-2".to_string(),
+            sender: sender.clone(),
+            timestamp: 1004,
+            received_timestamp: None,
+            content: MessageContent::Text {
+                body: "other".to_string(),
+            },
+            status: MessageStatus::Delivered,
+            quote: None,
+            reactions: Vec::new(),
+            expires_at: None,
+            envelope: None,
+        };
+        store.store_message(&other).await.unwrap();
+
+        let ids = |messages: &[Message]| -> Vec<String> {
+            messages.iter().map(|message| message.id.clone()).collect()
+        };
+        let cursor = |index: i64| MessageCursor {
+            timestamp: 1000 + if index == 5 { 4 } else { index },
+            id: format!("msg-{}", index),
+        };
+
+        // Pages are oldest first and step over equal timestamps by ID
+        let older = store
+            .get_messages_before("conv-1", &cursor(6), 3)
+            .await
+            .unwrap();
+        assert_eq!(ids(&older), ["msg-3", "msg-4", "msg-5"]);
+        let older = store
+            .get_messages_before("conv-1", &cursor(5), 3)
+            .await
+            .unwrap();
+        assert_eq!(ids(&older), ["msg-2", "msg-3", "msg-4"]);
+        let newer = store
+            .get_messages_after("conv-1", &cursor(4), 2)
+            .await
+            .unwrap();
+        assert_eq!(ids(&newer), ["msg-5", "msg-6"]);
+
+        // Paging ends at either end of the conversation
+        let older = store
+            .get_messages_before("conv-1", &cursor(1), 3)
+            .await
+            .unwrap();
+        assert_eq!(ids(&older), ["msg-0"]);
+        let newer = store
+            .get_messages_after("conv-1", &cursor(9), 3)
+            .await
+            .unwrap();
+        assert!(newer.is_empty());
+
+        // A window is centred on its message
+        let window = store
+            .get_messages_around("conv-1", "msg-5", 5)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            ids(&window.messages),
+            ["msg-3", "msg-4", "msg-5", "msg-6", "msg-7"]
+        );
+        assert_eq!(window.focus_index, 2);
+        assert!(window.has_older && window.has_newer);
+
+        // ...and cut short at the ends
+        let window = store
+            .get_messages_around("conv-1", "msg-1", 5)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(ids(&window.messages), ["msg-0", "msg-1", "msg-2", "msg-3"]);
+        assert_eq!(window.focus_index, 1);
+        assert!(!window.has_older && window.has_newer);
+
+        let window = store
+            .get_messages_around("conv-1", "msg-8", 5)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(ids(&window.messages), ["msg-6", "msg-7", "msg-8", "msg-9"]);
+        assert!(window.has_older && !window.has_newer);
+
+        // Messages of other conversations are neither found nor included
+        assert!(store
+            .get_messages_around("conv-1", "other", 5)
+            .await
+            .unwrap()
+            .is_none());
+        let window = store
+            .get_messages_around("conv-2", "other", 5)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(ids(&window.messages), ["other"]);
+        assert!(!window.has_older && !window.has_newer);
+    }
+
+    #[tokio::test]
+    async fn test_message_hydration() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = open_store(temp_dir.path()).await.unwrap();
+
+        let alice = SignalIdentity {
+            aci: uuid::Uuid::new_v4().into(),
+            pni: None,
+            phone_number: None,
+            device_id: 1,
+            registration_id: 0,
+        };
+        let bob = SignalIdentity {
+            aci: uuid::Uuid::new_v4().into(),
+            ..alice.clone()
+        };
+        store
+            .store_conversation(&Conversation {
+                id: "conv-1".to_string(),
+                recipient: alice.clone(),
+                is_group: false,
+                group_id: None,
+                name: "Alice".to_string(),
+                last_message: None,
+                unread_count: 0,
+                archived: false,
+                muted_until: None,
+                blocked: false,
+                pinned: None,
+            })
+            .await
+            .unwrap();
+
+        let message = |id: &str, sender: &SignalIdentity, timestamp: i64, content| Message {
+            id: id.to_string(),
+            conversation_id: "conv-1".to_string(),
+            sender: sender.clone(),
+            timestamp,
+            received_timestamp: None,
+            content,
+            status: MessageStatus::Delivered,
+            quote: None,
+            reactions: Vec::new(),
+            expires_at: None,
+            envelope: None,
+        };
+        let text = |body: &str| MessageContent::Text {
+            body: body.to_string(),
+        };
+
+        let attachment = Attachment {
+            id: "att-1".to_string(),
+            content_type: "image/jpeg".to_string(),
+            file_name: Some("beach.jpg".to_string()),
+            size: 2048,
+            digest: vec![1, 2, 3],
+            key: vec![4, 5, 6],
+            cdn_number: 3,
+            upload_timestamp: 900,
+            width: Some(640),
+            height: Some(480),
+            thumbnail: Some(vec![7, 8]),
+        };
+        let mut photo = message(
+            "msg-1",
+            &alice,
+            1000,
+            MessageContent::Image {
+                attachment: attachment.clone(),
+                caption: Some("Beach".to_string()),
+            },
+        );
+        photo.reactions.push(Reaction {
+            emoji: "👍".to_string(),
+            sender: bob.clone(),
+            timestamp: 1001,
+        });
+        store.store_message(&photo).await.unwrap();
+
+        // Our reply knows the ID of the quoted message
+        let mut reply = message("msg-2", &bob, 1002, text("Nice"));
+        reply.quote = Some(Box::new(photo.clone()));
+        store.store_message(&reply).await.unwrap();
+
+        // Received quotes only name the author and sent timestamp
+        let mut answer = message("msg-3", &alice, 1003, text("Thanks"));
+        answer.quote = Some(Box::new(Message::quote_stub("conv-1", bob.aci, 1002)));
+        store.store_message(&answer).await.unwrap();
+
+        let mut orphan = message("msg-4", &alice, 1004, text("What?"));
+        orphan.quote = Some(Box::new(Message::quote_stub("conv-1", bob.aci, 999)));
+        store.store_message(&orphan).await.unwrap();
+
+        // Reactions replace the sender's previous one, or remove it
+        let react = |emoji: &str, remove: bool| ReactionUpdate {
+            emoji: emoji.to_string(),
+            remove,
+            target_author: alice.aci.uuid(),
+            target_timestamp: 1000,
+        };
+        let bob_uuid = bob.aci.to_string();
+        let alice_uuid = alice.aci.to_string();
+        assert_eq!(
+            store
+                .apply_reaction("conv-1", &bob_uuid, &react("❤️", false), 1005)
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("msg-1")
+        );
+        store
+            .apply_reaction("conv-1", &alice_uuid, &react("😂", false), 1006)
+            .await
+            .unwrap();
+        store
+            .apply_reaction("conv-1", &alice_uuid, &react("😂", true), 1007)
+            .await
+            .unwrap();
+        let missing = ReactionUpdate {
+            target_timestamp: 999,
+            ..react("👍", false)
+        };
+        assert!(store
+            .apply_reaction("conv-1", &bob_uuid, &missing, 1008)
+            .await
+            .unwrap()
+            .is_none());
+
+        // Everything is read back after reopening the store
+        drop(store);
+        let store = open_store(temp_dir.path()).await.unwrap();
+        let messages = store
+            .get_messages_around("conv-1", "msg-2", 10)
+            .await
+            .unwrap()
+            .unwrap();
+        let messages = messages.messages;
+
+        match &messages[0].content {
+            MessageContent::Image {
+                attachment: stored, ..
+            } => {
+                assert_eq!(stored.id, attachment.id);
+                assert_eq!(stored.digest, attachment.digest);
+                assert_eq!(stored.upload_timestamp, attachment.upload_timestamp);
+                assert_eq!(stored.thumbnail, attachment.thumbnail);
+            }
+            other => panic!("unexpected content {:?}", other),
+        }
+        let reactions: Vec<_> = messages[0]
+            .reactions
+            .iter()
+            .map(|reaction| (reaction.emoji.as_str(), reaction.sender.aci))
+            .collect();
+        assert_eq!(reactions, [("❤️", bob.aci)]);
+
+        assert_eq!(messages[1].quote.as_ref().unwrap().id, "msg-1");
+        assert_eq!(messages[2].quote.as_ref().unwrap().id, "msg-2");
+        let stub = messages[3].quote.as_ref().unwrap();
+        assert!(matches!(stub.content, MessageContent::NotFound));
+        assert_eq!((stub.sender.aci, stub.timestamp), (bob.aci, 999));
+        assert!(store.get_message("msg-5").await.unwrap().is_none());
+        assert!(store
+            .get_message("msg-4")
+            .await
+            .unwrap()
+            .unwrap()
+            .quote
+            .is_some());
+
+        // Storing the message again keeps where its attachment was saved
+        store
+            .db
+            .write(|db| Ok(db.execute("UPDATE attachments SET local_path = '/tmp/beach.jpg'", [])?))
+            .await
+            .unwrap();
+        store
+            .store_message(&store.get_message("msg-1").await.unwrap().unwrap())
+            .await
+            .unwrap();
+        let local_path: Option<String> = store
+            .db
+            .read(|db| {
+                Ok(db.query_row(
+                    "SELECT local_path FROM attachments WHERE id = 'att-1'",
+                    [],
+                    |row| row.get(0),
+                )?)
+            })
+            .await
+            .unwrap();
+        assert_eq!(local_path.as_deref(), Some("/tmp/beach.jpg"));
+
+        // Replacing a message replaces its attachment row
+        let mut edited = store.get_message("msg-1").await.unwrap().unwrap();
+        edited.content = text("Never mind");
+        store.store_message(&edited).await.unwrap();
+        let attachments: i64 = store
+            .db
+            .read(|db| Ok(db.query_row("SELECT COUNT(*) FROM attachments", [], |row| row.get(0))?))
+            .await
+            .unwrap();
+        assert_eq!(attachments, 0);
+        let edited = store.get_message("msg-1").await.unwrap().unwrap();
+        assert_eq!(edited.reactions.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_recipient_merge() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = open_store(temp_dir.path()).await.unwrap();
+
+        let aci = Aci::from(uuid::Uuid::new_v4());
+        let pni = Pni::from(uuid::Uuid::new_v4());
+        let number = "+15550001111";
+
+        // The same person, first known by PNI and number, then by ACI alone
+        let by_pni = SignalIdentity {
+            aci: Aci::from(uuid::Uuid::nil()),
+            pni: Some(pni),
+            phone_number: Some(number.to_string()),
+            device_id: 1,
+            registration_id: 0,
+        };
+        let by_aci = SignalIdentity {
+            aci,
+            pni: None,
+            phone_number: None,
+            device_id: 1,
+            registration_id: 0,
+        };
+
+        for (id, recipient) in [("conv-pni", &by_pni), ("conv-aci", &by_aci)] {
+            store
+                .store_conversation(&Conversation {
+                    id: id.to_string(),
+                    recipient: recipient.clone(),
+                    is_group: false,
+                    group_id: None,
+                    name: "Alice".to_string(),
+                    last_message: None,
+                    unread_count: 1,
+                    archived: false,
+                    muted_until: None,
+                    blocked: false,
+                    pinned: None,
+                })
+                .await
+                .unwrap();
+            store
+                .store_message(&Message {
+                    id: format!("msg-{}", id),
+                    conversation_id: id.to_string(),
+                    sender: recipient.clone(),
+                    timestamp: 1_700_000_000_000,
+                    received_timestamp: None,
+                    content: MessageContent::Text {
+                        body: "Hi".to_string(),
+                    },
+                    status: MessageStatus::Delivered,
+                    quote: None,
+                    reactions: Vec::new(),
+                    expires_at: None,
+                    envelope: None,
+                })
+                .await
+                .unwrap();
+        }
+
+        let before = store.get_recipient(&pni.into()).await.unwrap().unwrap();
+        assert_eq!(before.aci, None);
+
+        // Learning the mapping unifies both recipients and conversations
+        let merged = store
+            .merge_recipient(Some(aci), Some(pni), Some(number))
+            .await
+            .unwrap();
+        assert_ne!(merged.id, before.id);
+        assert_eq!(merged.pni, Some(pni));
+        assert_eq!(merged.e164.as_deref(), Some(number));
+        assert_eq!(store.get_recipient(&pni.into()).await.unwrap(), Some(merged.clone()));
+
+        assert!(store.get_conversation("conv-pni").await.unwrap().is_none());
+        let conversation = store.get_conversation("conv-aci").await.unwrap().unwrap();
+        assert_eq!(conversation.unread_count, 2);
+        assert_eq!(conversation.recipient.pni, Some(pni));
+
+        let messages = store.get_messages("conv-aci", 10).await.unwrap();
+        assert_eq!(messages.len(), 3);
+        assert!(messages.iter().any(|m| matches!(
+            &m.content,
+            MessageContent::NumbersMerged { phone_number: Some(n) } if n == number
+        )));
+
+        // A number taken over by another account moves without merging
+        let other = Aci::from(uuid::Uuid::new_v4());
+        let taken = store
+            .merge_recipient(Some(other), None, Some(number))
+            .await
+            .unwrap();
+        assert_ne!(taken.id, merged.id);
+        assert_eq!(taken.e164.as_deref(), Some(number));
+
+        let previous = store.get_recipient(&aci.into()).await.unwrap().unwrap();
+        assert_eq!(previous.e164, None);
+        assert_eq!(previous.pni, Some(pni));
+    }
+
+    #[tokio::test]
+    async fn test_synced_contacts() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = open_store(temp_dir.path()).await.unwrap();
+
+        let aci = Aci::from(uuid::Uuid::new_v4());
+        let recipient = SignalIdentity {
+            aci,
+            pni: None,
+            phone_number: None,
+            device_id: 1,
+            registration_id: 0,
+        };
+        store
+            .store_conversation(&Conversation {
+                id: aci.to_string(),
+                recipient: recipient.clone(),
+                is_group: false,
+                group_id: None,
+                name: aci.to_string(),
+                last_message: None,
+                unread_count: 0,
+                archived: false,
+                muted_until: None,
+                blocked: false,
+                pinned: None,
+            })
+            .await
+            .unwrap();
+
+        let mut details = ContactDetails {
+            aci: Some(aci),
+            phone_number: Some("+15550002222".to_string()),
+            name: Some("Bob".to_string()),
+            avatar: Some(ContactAvatar {
+                content_type: "image/png".to_string(),
+                data: vec![1, 2, 3],
+            }),
+            color: Some("ultramarine".to_string()),
+            expire_timer: 3600,
+            ..Default::default()
+        };
+        let identity = store.store_synced_contact(&details).await.unwrap();
+        assert_eq!(identity.aci, aci);
+        assert_eq!(identity.phone_number, details.phone_number);
+
+        // The conversation takes the synced name, and the avatar is saved
+        let conversation = store
+            .get_conversation(&aci.to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(conversation.name, "Bob");
+        let avatar = temp_dir
+            .path()
+            .join("avatars")
+            .join(format!("contact-{}", aci));
+        assert_eq!(std::fs::read(&avatar).unwrap(), vec![1, 2, 3]);
+
+        // A later sync replaces the details and drops the avatar
+        details.name = Some("Robert".to_string());
+        details.avatar = None;
+        store.store_synced_contact(&details).await.unwrap();
+
+        let conversation = store
+            .get_conversation(&aci.to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(conversation.name, "Robert");
+        assert!(!avatar.exists());
+        assert_eq!(store.get_contacts().await.unwrap().len(), 1);
+
+        // Contacts need an identifier
+        assert!(store
+            .store_synced_contact(&ContactDetails::default())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_blocked() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = open_store(temp_dir.path()).await.unwrap();
+
+        let identity = |aci: Option<Aci>, number: Option<&str>| SignalIdentity {
+            aci: aci.unwrap_or(Aci::from(uuid::Uuid::nil())),
+            pni: None,
+            phone_number: number.map(str::to_string),
+            device_id: 1,
+            registration_id: 0,
+        };
+        let alice = identity(Some(uuid::Uuid::new_v4().into()), None);
+        let bob = identity(Some(uuid::Uuid::new_v4().into()), Some("+15550004444"));
+        store
+            .store_synced_contact(&ContactDetails {
+                aci: Some(alice.aci),
+                name: Some("Alice".to_string()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        store
+            .store_conversation(&Conversation {
+                id: alice.aci.to_string(),
+                recipient: alice.clone(),
+                is_group: false,
+                group_id: None,
+                name: "Alice".to_string(),
+                last_message: None,
+                unread_count: 0,
+                archived: false,
+                muted_until: None,
+                blocked: false,
+                pinned: None,
+            })
+            .await
+            .unwrap();
+
+        // Alice is a contact; a stranger is blocked by number alone
+        let changed = store
+            .store_blocked(
+                &[alice.clone(), identity(None, Some("+15550004444"))],
+                &["group-1".to_string()],
+            )
+            .await
+            .unwrap();
+        assert_eq!(changed.len(), 1);
+        assert!(changed[0].blocked);
+        assert!(store.is_blocked(&alice).await.unwrap());
+        assert!(store.is_group_blocked("group-1").await.unwrap());
+        assert!(!store.is_group_blocked("group-2").await.unwrap());
+
+        // Learning the number's ACI keeps the block
+        assert!(!store.is_blocked(&bob).await.unwrap());
+        store
+            .merge_recipient(Some(bob.aci), None, bob.phone_number.as_deref())
+            .await
+            .unwrap();
+        assert!(store.is_blocked(&bob).await.unwrap());
+
+        // The list replaces the previous one
+        let changed = store
+            .store_blocked(std::slice::from_ref(&bob), &[])
+            .await
+            .unwrap();
+        assert_eq!(changed.len(), 1);
+        assert!(!changed[0].blocked);
+        assert!(!store.is_blocked(&alice).await.unwrap());
+        assert!(!store.is_group_blocked("group-1").await.unwrap());
+        assert_eq!(
+            store.get_conversation(&alice.aci.to_string()).await.unwrap().unwrap().name,
+            "Alice"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_account_keys() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = open_store(temp_dir.path()).await.unwrap();
+        assert_eq!(store.get_account_keys().await.unwrap(), AccountKeys::default());
+
+        let keys = AccountKeys {
+            storage_service: Some(vec![1; 32]),
+            master: Some(vec![2; 32]),
+        };
+        store.store_account_keys(&keys).await.unwrap();
+
+        // Keys left out are kept
+        store
+            .store_account_keys(&AccountKeys {
+                storage_service: None,
+                master: Some(vec![3; 32]),
+            })
+            .await
+            .unwrap();
+        let stored = store.get_account_keys().await.unwrap();
+        assert_eq!(stored.storage_service, Some(vec![1; 32]));
+        assert_eq!(stored.master, Some(vec![3; 32]));
+
+        store.clear().await.unwrap();
+        assert_eq!(store.get_account_keys().await.unwrap(), AccountKeys::default());
+    }
+
+    #[tokio::test]
+    async fn test_storage_manifest() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = open_store(temp_dir.path()).await.unwrap();
+        assert_eq!(
+            store.get_storage_manifest().await.unwrap(),
+            StorageManifest::default()
+        );
+
+        let alice: Aci = uuid::Uuid::new_v4().into();
+        store
+            .store_synced_contact(&ContactDetails {
+                aci: Some(alice),
+                name: Some("Alice Synced".to_string()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        store
+            .store_conversation(&Conversation {
+                id: alice.to_string(),
+                recipient: SignalIdentity {
+                    aci: alice,
+                    pni: None,
+                    phone_number: None,
+                    device_id: 1,
+                    registration_id: 0,
+                },
+                is_group: false,
+                group_id: None,
+                name: "Alice Synced".to_string(),
+                last_message: None,
+                unread_count: 0,
+                archived: false,
+                muted_until: None,
+                blocked: false,
+                pinned: None,
+            })
+            .await
+            .unwrap();
+
+        let id = |byte: u8, record_type: i32| StorageId {
+            raw: vec![byte; 16],
+            record_type,
+        };
+        let contact = StorageRecord::Contact(ContactRecord {
+            aci: Some(alice),
+            given_name: Some("Alice".to_string()),
+            family_name: Some("Profile".to_string()),
+            nickname_given_name: Some("Al".to_string()),
+            blocked: true,
+            archived: true,
+            ..Default::default()
+        });
+        let account = StorageRecord::Account(AccountRecord {
+            given_name: Some("Me".to_string()),
+            profile_key: Some(vec![5; 32]),
+            read_receipts: true,
+            ..Default::default()
+        });
+        let manifest = StorageManifest {
+            version: 3,
+            identifiers: vec![id(1, 1), id(2, 4), id(3, 5)],
+            record_ikm: Some(vec![9; 32]),
+        };
+        let changes = store
+            .apply_storage_manifest(
+                &manifest,
+                &[
+                    (id(1, 1), vec![1], contact),
+                    (id(2, 4), vec![2], account.clone()),
+                ],
+            )
+            .await
+            .unwrap();
+
+        // Records we do not model are listed all the same
+        assert_eq!(store.get_storage_manifest().await.unwrap(), manifest);
+        assert_eq!(
+            store.get_storage_records(4).await.unwrap(),
+            vec![(id(2, 4), vec![2])]
+        );
+        assert!(store.get_storage_records(5).await.unwrap().is_empty());
+
+        // The record's flags replace local state; names it leaves out stay,
+        // though its nickname goes before them
+        assert_eq!(changes.contacts.len(), 1);
+        assert_eq!(changes.contacts[0].aci, alice);
+        assert_eq!(changes.conversations.len(), 1);
+        let conversation = &changes.conversations[0];
+        assert!(conversation.archived && conversation.blocked);
+        assert_eq!(conversation.name, "Al");
+        let details = store.get_recipient_details(alice).await.unwrap().unwrap();
+        assert_eq!(details.system_name.as_deref(), Some("Alice Synced"));
+        assert_eq!(details.profile_name.as_deref(), Some("Alice Profile"));
+        assert_eq!(changes.account.map(StorageRecord::Account), Some(account));
+        assert_eq!(
+            store.get_own_profile_key().await.unwrap(),
+            Some(vec![5; 32])
+        );
+
+        // Records dropped from a newer manifest are forgotten
+        let manifest = StorageManifest {
+            version: 4,
+            identifiers: vec![id(3, 5)],
+            record_ikm: None,
+        };
+        store.apply_storage_manifest(&manifest, &[]).await.unwrap();
+        assert_eq!(store.get_storage_manifest().await.unwrap(), manifest);
+        assert!(store.get_storage_records(4).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_profiles() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = open_store(temp_dir.path()).await.unwrap();
+
+        // A profile key makes a stranger's profile due for fetching
+        let bob: Aci = uuid::Uuid::new_v4().into();
+        assert!(store.store_profile_key(bob, &[1; 32]).await.unwrap());
+        assert!(!store.store_profile_key(bob, &[1; 32]).await.unwrap());
+        assert_eq!(store.get_profile_key(bob).await.unwrap(), Some(vec![1; 32]));
+        assert_eq!(store.get_stale_profiles(0).await.unwrap(), vec![bob]);
+
+        store
+            .store_conversation(&Conversation {
+                id: bob.to_string(),
+                recipient: SignalIdentity {
+                    aci: bob,
+                    pni: None,
+                    phone_number: None,
+                    device_id: 1,
+                    registration_id: 0,
+                },
+                is_group: false,
+                group_id: None,
+                name: bob.to_string(),
+                last_message: None,
+                unread_count: 0,
+                archived: false,
+                muted_until: None,
+                blocked: false,
+                pinned: None,
+            })
+            .await
+            .unwrap();
+
+        let mut profile = Profile {
+            given_name: Some("Bob".to_string()),
+            family_name: Some("Builder".to_string()),
+            about: Some("Can we fix it?".to_string()),
+            about_emoji: None,
+            avatar_url: Some("profiles/abc".to_string()),
+        };
+        let changed = store
+            .store_profile(bob, &profile, Some(b"avatar".to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].name, "Bob Builder");
+        assert!(store.get_stale_profiles(0).await.unwrap().is_empty());
+        assert_eq!(
+            store.get_profile_avatar_url(bob).await.unwrap().as_deref(),
+            Some("profiles/abc")
+        );
+        let avatar_path = temp_dir
+            .path()
+            .join("avatars")
+            .join(format!("profile-{}", bob));
+        assert_eq!(std::fs::read(&avatar_path).unwrap(), b"avatar");
+
+        // An unchanged avatar is kept without downloading it again
+        store.store_profile(bob, &profile, None).await.unwrap();
+        assert!(avatar_path.exists());
+
+        // A removed one is deleted
+        profile.avatar_url = None;
+        store.store_profile(bob, &profile, None).await.unwrap();
+        assert!(!avatar_path.exists());
+        assert_eq!(store.get_profile_avatar_url(bob).await.unwrap(), None);
+
+        // A new key makes the profile due again
+        assert!(store.store_profile_key(bob, &[2; 32]).await.unwrap());
+        assert_eq!(store.get_stale_profiles(0).await.unwrap(), vec![bob]);
+    }
+
+    #[tokio::test]
+    async fn test_recipient_details() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = open_store(temp_dir.path()).await.unwrap();
+
+        let carol: Aci = uuid::Uuid::new_v4().into();
+        assert_eq!(store.get_recipient_details(carol).await.unwrap(), None);
+
+        // Known only by ACI
+        store
+            .merge_recipient(Some(carol), None, None)
+            .await
+            .unwrap();
+        let details = store.get_recipient_details(carol).await.unwrap().unwrap();
+        assert_eq!(details.display_name(), None);
+
+        // Each better name takes over as it becomes known
+        let record = |contact: ContactRecord| {
+            vec![(
+                StorageId {
+                    raw: vec![1; 16],
+                    record_type: 1,
+                },
+                vec![1],
+                StorageRecord::Contact(ContactRecord {
+                    aci: Some(carol),
+                    ..contact
+                }),
+            )]
+        };
+        let manifest = StorageManifest {
+            version: 1,
+            ..Default::default()
+        };
+        type SetName = fn(&mut ContactRecord, Option<String>);
+        let mut contact = ContactRecord::default();
+        let steps: [(SetName, &str); 5] = [
+            (|contact, name| contact.username = name, "carol.42"),
+            (|contact, name| contact.e164 = name, "+15550001111"),
+            (|contact, name| contact.given_name = name, "Carol Profile"),
+            (|contact, name| contact.system_given_name = name, "Carol Contact"),
+            (|contact, name| contact.nickname_given_name = name, "Caz"),
+        ];
+        for (set, name) in steps {
+            set(&mut contact, Some(name.to_string()));
+            store
+                .apply_storage_manifest(&manifest, &record(contact.clone()))
+                .await
+                .unwrap();
+            let details = store.get_recipient_details(carol).await.unwrap().unwrap();
+            assert_eq!(details.display_name(), Some(name));
+        }
+
+        let details = store.get_recipient_details(carol).await.unwrap().unwrap();
+        assert_eq!(details.phone_number.as_deref(), Some("+15550001111"));
+        assert_eq!(details.username.as_deref(), Some("carol.42"));
+
+        let all = store.get_all_recipient_details().await.unwrap();
+        assert_eq!(all, vec![details]);
+    }
+
+    #[tokio::test]
+    async fn test_groups() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = open_store(temp_dir.path()).await.unwrap();
+
+        // A storage record adds a group by its master key, still to be fetched
+        let params = GroupSecretParams::derive_from_master_key(&[4; 32]).unwrap();
+        let group_id = params.id();
+        let id = |byte: u8| StorageId {
+            raw: vec![byte; 16],
+            record_type: 3,
+        };
+        let record = |blocked| {
+            StorageRecord::GroupV2(GroupV2Record {
+                master_key: vec![4; 32],
+                blocked,
+                ..Default::default()
+            })
+        };
+        let manifest = StorageManifest {
+            version: 1,
+            identifiers: vec![id(1)],
+            record_ikm: None,
+        };
+        let changes = store
+            .apply_storage_manifest(&manifest, &[(id(1), vec![1], record(true))])
+            .await
+            .unwrap();
+        assert_eq!(changes.groups, vec![vec![4; 32]]);
+        assert!(store.is_group_blocked(&group_id).await.unwrap());
+        let group = store.get_group(&group_id).await.unwrap().unwrap();
+        assert_eq!(group.master_key, vec![4; 32]);
+        assert!(group.members.is_empty());
+
+        store
+            .store_conversation(&Conversation {
+                id: group_id.clone(),
+                recipient: SignalIdentity {
+                    aci: uuid::Uuid::nil().into(),
+                    pni: None,
+                    phone_number: None,
+                    device_id: 1,
+                    registration_id: 0,
+                },
+                is_group: true,
+                group_id: Some(group_id.clone()),
+                name: "Unknown group".to_string(),
+                last_message: None,
+                unread_count: 0,
+                archived: false,
+                muted_until: None,
+                blocked: false,
+                pinned: None,
+            })
+            .await
+            .unwrap();
+
+        // Its state names the conversation and keeps pending members apart
+        let (alice, bob, carol) = (
+            uuid::Uuid::new_v4(),
+            uuid::Uuid::new_v4(),
+            uuid::Uuid::new_v4(),
+        );
+        let member = |uuid, role, joined_at, invited_by| GroupMember {
+            uuid,
+            role,
+            joined_at,
+            invited_by,
+        };
+        let mut group = Group {
+            id: group_id.clone(),
+            master_key: vec![4; 32],
+            revision: 2,
+            name: "Hikers".to_string(),
+            avatar: Some("groups/avatar".to_string()),
+            members: vec![
+                member(alice, GroupRole::Administrator, 0, None),
+                member(bob, GroupRole::Member, 1, None),
+            ],
+            admins: vec![alice],
+            pending_members: vec![member(carol, GroupRole::Member, 5000, Some(alice))],
+            access_control: GroupAccessControl {
+                members_can_add_members: false,
+                members_can_edit_group_info: true,
+            },
+            invite_link_access: InviteLinkAccess::AdminApproval,
+            invite_link_password: Some(vec![6; 16]),
+            ..Default::default()
+        };
+        let conversation = store.store_group(&group).await.unwrap().unwrap();
+        assert_eq!(conversation.name, "Hikers");
+        assert!(conversation.blocked);
+
+        let stored = store.get_group(&group_id).await.unwrap().unwrap();
+        assert_eq!(stored.revision, 2);
+        assert_eq!(stored.name, "Hikers");
+        assert_eq!(stored.avatar.as_deref(), Some("groups/avatar"));
+        let members: Vec<_> = stored.members.iter().map(|m| (m.uuid, m.role)).collect();
+        assert_eq!(
+            members,
+            vec![(alice, GroupRole::Administrator), (bob, GroupRole::Member)]
+        );
+        assert_eq!(stored.admins, vec![alice]);
+        assert_eq!(stored.pending_members.len(), 1);
+        assert_eq!(stored.pending_members[0].uuid, carol);
+        assert_eq!(stored.pending_members[0].invited_by, Some(alice));
+        assert!(!stored.access_control.members_can_add_members);
+        assert!(stored.access_control.members_can_edit_group_info);
+        assert_eq!(stored.invite_link_access, InviteLinkAccess::AdminApproval);
+        assert_eq!(stored.invite_link_password, Some(vec![6; 16]));
+
+        // A later state replaces the members
+        group.revision = 3;
+        group.members.pop();
+        group.pending_members.clear();
+        store.store_group(&group).await.unwrap();
+        let stored = store.get_group(&group_id).await.unwrap().unwrap();
+        assert_eq!(stored.revision, 3);
+        assert_eq!(stored.members.len(), 1);
+        assert!(stored.pending_members.is_empty());
+
+        // Once fetched, a newer record only changes its settings
+        let manifest = StorageManifest {
+            version: 2,
+            identifiers: vec![id(2)],
+            record_ikm: None,
+        };
+        let changes = store
+            .apply_storage_manifest(&manifest, &[(id(2), vec![2], record(false))])
+            .await
+            .unwrap();
+        assert!(changes.groups.is_empty());
+        assert_eq!(changes.conversations.len(), 1);
+        assert!(!changes.conversations[0].blocked);
+        assert_eq!(changes.conversations[0].name, "Hikers");
+        let group = store.get_group(&group_id).await.unwrap().unwrap();
+        assert_eq!(group.revision, 3);
+    }
+
+    #[tokio::test]
+    async fn test_pinned_conversations() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = open_store(temp_dir.path()).await.unwrap();
+
+        let alice: Aci = uuid::Uuid::new_v4().into();
+        let bob: Aci = uuid::Uuid::new_v4().into();
+        let conversation = |id: String, aci: Aci, group_id: Option<String>| Conversation {
+            id,
+            recipient: SignalIdentity {
+                aci,
+                pni: None,
+                phone_number: None,
+                device_id: 1,
+                registration_id: 0,
+            },
+            is_group: group_id.is_some(),
+            group_id,
+            name: "Chat".to_string(),
+            last_message: None,
+            unread_count: 0,
+            archived: false,
+            muted_until: None,
+            blocked: false,
+            pinned: None,
+        };
+        for aci in [alice, bob] {
+            store
+                .store_conversation(&conversation(aci.to_string(), aci, None))
+                .await
+                .unwrap();
+        }
+
+        let master_key = vec![7u8; 32];
+        let group_id = GroupSecretParams::derive_from_master_key(&master_key)
+            .unwrap()
+            .id();
+        let account = |version: u64, pinned: Vec<PinnedConversation>| {
+            let id = StorageId {
+                raw: vec![version as u8; 16],
+                record_type: 4,
+            };
+            let manifest = StorageManifest {
+                version,
+                identifiers: vec![id.clone()],
+                record_ikm: None,
+            };
+            let record = StorageRecord::Account(AccountRecord {
+                pinned_conversations: pinned,
+                ..Default::default()
+            });
+            (manifest, vec![(id, Vec::new(), record)])
+        };
+        let pin = |aci: Aci| PinnedConversation::Contact {
+            service_id: Some(aci.into()),
+            e164: None,
+        };
+
+        let (manifest, records) = account(
+            1,
+            vec![
+                pin(bob),
+                PinnedConversation::Group {
+                    master_key: master_key.clone(),
+                },
+                pin(alice),
+            ],
+        );
+        let changes = store
+            .apply_storage_manifest(&manifest, &records)
+            .await
+            .unwrap();
+        let mut changed: Vec<String> = changes.conversations.into_iter().map(|c| c.id).collect();
+        changed.sort();
+        let mut expected = vec![alice.to_string(), bob.to_string()];
+        expected.sort();
+        assert_eq!(changed, expected);
+
+        // Pinned conversations come first, in their pinned order
+        let conversations = store.get_conversations().await.unwrap();
+        let order: Vec<(String, Option<u32>)> = conversations
+            .into_iter()
+            .map(|c| (c.id, c.pinned))
+            .collect();
+        assert_eq!(
+            order,
+            [(bob.to_string(), Some(0)), (alice.to_string(), Some(2))]
+        );
+
+        // A conversation pinned before it exists is pinned once it does
+        store
+            .store_conversation(&conversation(
+                group_id.clone(),
+                uuid::Uuid::nil().into(),
+                Some(group_id.clone()),
+            ))
+            .await
+            .unwrap();
+        let group = store.get_conversation(&group_id).await.unwrap().unwrap();
+        assert_eq!(group.pinned, Some(1));
+
+        // Conversations left out are unpinned
+        let (manifest, records) = account(2, vec![pin(alice)]);
+        let changes = store
+            .apply_storage_manifest(&manifest, &records)
+            .await
+            .unwrap();
+        assert_eq!(changes.conversations.len(), 3);
+        let conversations = store.get_conversations().await.unwrap();
+        assert_eq!(conversations[0].id, alice.to_string());
+        assert_eq!(conversations[0].pinned, Some(0));
+        assert!(conversations[1..].iter().all(|c| c.pinned.is_none()));
     }
 
     #[tokio::test]
     async fn test_session_storage() {
         let temp_dir = TempDir::new().unwrap();
-        let store = SignalStore::new(temp_dir.path()).await.unwrap();
+        let store = open_store(temp_dir.path()).await.unwrap();
 
-        let address = ProtocolAddress::new("alice", 1);
+        let address = ProtocolAddress::new(Aci::from(uuid::Uuid::new_v4()), 1);
         let session_data = b"test session data".to_vec();
 
         store.store_session(&address, &session_data).await.unwrap();
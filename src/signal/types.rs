@@ -3,15 +3,51 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+pub use super::service_id::{Aci, Pni, ServiceId, ServiceIdKind};
+
 /// Represents a Signal user identity
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SignalIdentity {
-    pub uuid: Uuid,
+    pub aci: Aci,
+    /// Phone number identity, if known
+    #[serde(default)]
+    pub pni: Option<Pni>,
     pub phone_number: Option<String>,
     pub device_id: u32,
     pub registration_id: u32,
 }
 
+impl SignalIdentity {
+    /// The service ID to address this user by: the ACI when known, else the PNI
+    pub fn service_id(&self) -> ServiceId {
+        match self.pni {
+            Some(pni) if self.aci.is_nil() => pni.into(),
+            _ => self.aci.into(),
+        }
+    }
+}
+
+/// A person as known to the store, unified across their identifiers
+///
+/// The ACI, PNI and phone number of one person may be learned separately;
+/// each is unique to a single recipient.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Recipient {
+    pub id: i64,
+    pub aci: Option<Aci>,
+    pub pni: Option<Pni>,
+    pub e164: Option<String>,
+}
+
+impl Recipient {
+    /// The service ID to address this recipient by, preferring the ACI
+    pub fn service_id(&self) -> Option<ServiceId> {
+        self.aci
+            .map(ServiceId::from)
+            .or_else(|| self.pni.map(ServiceId::from))
+    }
+}
+
 /// Represents a chat conversation
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Conversation {
@@ -24,6 +60,11 @@ pub struct Conversation {
     pub unread_count: u32,
     pub archived: bool,
     pub muted_until: Option<i64>,
+    /// Whether the other party or the group is blocked
+    pub blocked: bool,
+    /// Place in the pinned conversations, for a pinned conversation
+    #[serde(default)]
+    pub pinned: Option<u32>,
 }
 
 /// Represents a message
@@ -39,6 +80,9 @@ pub struct Message {
     pub quote: Option<Box<Message>>,
     pub reactions: Vec<Reaction>,
     pub expires_at: Option<i64>,
+    /// Server metadata of the envelope this message arrived in
+    #[serde(default)]
+    pub envelope: Option<EnvelopeMetadata>,
 }
 
 /// Message content types
@@ -54,6 +98,124 @@ pub enum MessageContent {
     Sticker { pack_id: String, sticker_id: u32 },
     Contact { contact: ContactInfo },
     Location { latitude: f64, longitude: f64, name: Option<String> },
+    /// Placeholder for a message that could not be decrypted
+    DecryptionFailed,
+    /// Timeline event left when two conversations with one person were merged
+    NumbersMerged { phone_number: Option<String> },
+    /// Stand-in for a quoted message that is not in the store
+    NotFound,
+}
+
+impl MessageContent {
+    /// The attachment carried by this content, if any
+    pub fn attachment(&self) -> Option<&Attachment> {
+        match self {
+            Self::Image { attachment, .. }
+            | Self::Video { attachment, .. }
+            | Self::Audio { attachment }
+            | Self::File { attachment }
+            | Self::Voice { attachment, .. } => Some(attachment),
+            _ => None,
+        }
+    }
+
+    /// Mutable access to the attachment carried by this content, if any
+    pub fn attachment_mut(&mut self) -> Option<&mut Attachment> {
+        match self {
+            Self::Image { attachment, .. }
+            | Self::Video { attachment, .. }
+            | Self::Audio { attachment }
+            | Self::File { attachment }
+            | Self::Voice { attachment, .. } => Some(attachment),
+            _ => None,
+        }
+    }
+}
+
+/// Position of a message in its conversation, for paging through history
+///
+/// Messages are ordered by timestamp, with the ID breaking ties between
+/// messages sent in the same millisecond.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MessageCursor {
+    pub timestamp: i64,
+    pub id: String,
+}
+
+impl Message {
+    /// Quote of a message by `author` sent at `timestamp` that is not stored
+    pub fn quote_stub(conversation_id: &str, author: Aci, timestamp: i64) -> Self {
+        Self {
+            id: String::new(),
+            conversation_id: conversation_id.to_string(),
+            sender: SignalIdentity {
+                aci: author,
+                pni: None,
+                phone_number: None,
+                device_id: 1,
+                registration_id: 0,
+            },
+            timestamp,
+            received_timestamp: None,
+            content: MessageContent::NotFound,
+            status: MessageStatus::Delivered,
+            quote: None,
+            reactions: Vec::new(),
+            expires_at: None,
+            envelope: None,
+        }
+    }
+}
+
+impl MessageCursor {
+    /// Cursor at `message`
+    pub fn of(message: &Message) -> Self {
+        Self {
+            timestamp: message.timestamp,
+            id: message.id.clone(),
+        }
+    }
+}
+
+/// Messages around one message of a conversation, oldest first
+#[derive(Debug, Clone)]
+pub struct MessageWindow {
+    pub messages: Vec<Message>,
+    /// Index of the message the window was opened at
+    pub focus_index: usize,
+    /// Whether there are older messages before the window
+    pub has_older: bool,
+    /// Whether there are newer messages after the window
+    pub has_newer: bool,
+}
+
+/// Page of search results to fetch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SearchPaging {
+    pub limit: usize,
+    pub offset: usize,
+}
+
+impl Default for SearchPaging {
+    fn default() -> Self {
+        Self {
+            limit: 50,
+            offset: 0,
+        }
+    }
+}
+
+/// Message matching a search query
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    pub message: Message,
+    pub conversation_name: String,
+    /// Excerpt of the matching text
+    pub snippet: String,
+    /// Byte ranges of `snippet` that matched the query
+    pub highlights: Vec<std::ops::Range<usize>>,
+    /// BM25 score; lower is a better match
+    pub rank: f64,
 }
 
 /// Message delivery status
@@ -99,17 +261,67 @@ pub struct ContactInfo {
 }
 
 /// Group information
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Group {
+    /// Base64 group ID, derived from the master key
     pub id: String,
+    /// Key every other group key derives from; empty while only the ID is
+    /// known
+    pub master_key: Vec<u8>,
+    /// Revision of the group state, counting every change since creation
+    pub revision: u32,
     pub name: String,
     pub description: Option<String>,
-    pub avatar: Option<Attachment>,
+    /// CDN key of the encrypted avatar
+    pub avatar: Option<String>,
     pub members: Vec<GroupMember>,
     pub admins: Vec<Uuid>,
+    /// People invited who have not accepted yet, and people asking to join
     pub pending_members: Vec<GroupMember>,
     pub disappearing_messages_timer: Option<u32>,
     pub access_control: GroupAccessControl,
+    /// Who may join with the invite link
+    pub invite_link_access: InviteLinkAccess,
+    /// Password of the invite link; a new one turns earlier links away
+    pub invite_link_password: Option<Vec<u8>>,
+}
+
+impl Group {
+    /// Role of a member, or `None` for anyone not in the group
+    pub fn role_of(&self, uuid: &Uuid) -> Option<GroupRole> {
+        self.members
+            .iter()
+            .find(|member| member.uuid == *uuid)
+            .map(|member| member.role)
+    }
+
+    pub fn is_member(&self, uuid: &Uuid) -> bool {
+        self.role_of(uuid).is_some()
+    }
+
+    pub fn is_admin(&self, uuid: &Uuid) -> bool {
+        self.role_of(uuid) == Some(GroupRole::Administrator)
+    }
+
+    /// Whether someone asked to join with the invite link and waits for an
+    /// administrator
+    pub fn is_requesting(&self, uuid: &Uuid) -> bool {
+        self.pending_members
+            .iter()
+            .any(|pending| pending.uuid == *uuid && pending.invited_by.is_none())
+    }
+
+    /// Whether a member may add people to the group
+    pub fn can_add_members(&self, uuid: &Uuid) -> bool {
+        self.is_admin(uuid) || (self.is_member(uuid) && self.access_control.members_can_add_members)
+    }
+
+    /// Whether a member may change the name, description, avatar and
+    /// disappearing timer
+    pub fn can_edit_info(&self, uuid: &Uuid) -> bool {
+        self.is_admin(uuid)
+            || (self.is_member(uuid) && self.access_control.members_can_edit_group_info)
+    }
 }
 
 /// Group member
@@ -117,7 +329,11 @@ pub struct Group {
 pub struct GroupMember {
     pub uuid: Uuid,
     pub role: GroupRole,
+    /// Revision a member joined at; for pending members, the millisecond
+    /// timestamp of their invitation or request
     pub joined_at: i64,
+    /// Who invited a pending member; unset for requests to join
+    pub invited_by: Option<Uuid>,
 }
 
 /// Group member role
@@ -128,16 +344,77 @@ pub enum GroupRole {
 }
 
 /// Group access control settings
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct GroupAccessControl {
     pub members_can_add_members: bool,
     pub members_can_edit_group_info: bool,
 }
 
+/// Who may join a group with its invite link
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InviteLinkAccess {
+    /// The link is turned off
+    #[default]
+    Disabled,
+    /// Holders of the link ask to join, and an administrator lets them in
+    AdminApproval,
+    /// Holders of the link join right away
+    Anyone,
+}
+
+/// What an invite link shows of a group before joining it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupPreview {
+    /// Base64 group ID
+    pub id: String,
+    pub name: String,
+    pub description: Option<String>,
+    /// CDN key of the encrypted avatar
+    pub avatar: Option<String>,
+    pub member_count: u32,
+    pub revision: u32,
+    /// Whether joining needs an administrator's approval
+    pub requires_approval: bool,
+    /// Whether we already asked to join
+    pub pending_approval: bool,
+}
+
+/// Change a member makes to a group
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GroupUpdate {
+    /// Add people: as members if we have their profile key credential,
+    /// otherwise as invited members who join once they accept. Adding
+    /// someone who asked to join approves them, which takes an
+    /// administrator
+    AddMembers(Vec<Aci>),
+    /// Remove members, withdraw invitations or turn down requests to join
+    RemoveMembers(Vec<Aci>),
+    /// Promote a member to administrator or demote them
+    SetRole(Aci, GroupRole),
+    SetAccessControl(GroupAccessControl),
+    SetAttributes {
+        name: String,
+        description: Option<String>,
+        disappearing_messages_timer: Option<u32>,
+    },
+    /// Let in people who asked to join with the invite link
+    ApproveRequests(Vec<Aci>),
+    /// Turn down requests to join
+    DenyRequests(Vec<Aci>),
+    /// Turn the invite link on or off, or change whether joining with it
+    /// needs approval
+    SetInviteLink(InviteLinkAccess),
+    /// Give the invite link a new password, so earlier links stop working
+    ResetInviteLink,
+}
+
 /// Device linking provisioning data
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProvisioningData {
-    pub uuid: Uuid,
+    #[serde(alias = "uuid")]
+    pub aci: Aci,
+    #[serde(default)]
+    pub pni: Option<Pni>,
     pub phone_number: String,
     pub provisioning_code: String,
     pub provisioning_cipher: Vec<u8>,
@@ -147,21 +424,423 @@ pub struct ProvisioningData {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum SyncMessage {
-    SentMessage { message: Message, destination: SignalIdentity },
+    /// Transcript of a message one of our devices sent
+    SentMessage {
+        message: Box<Message>,
+        /// The other party of a 1:1 message; unset for group messages
+        destination: SignalIdentity,
+        group_master_key: Option<Vec<u8>>,
+        /// Everyone the message was sent to
+        recipients: Vec<SentRecipient>,
+    },
+    /// Messages read on another device, by author ACI and sent timestamp
     ReadMessages { messages: Vec<(String, i64)> },
-    Contacts { contacts: Vec<SignalIdentity> },
+    /// Media messages viewed on another device, named like `ReadMessages`
+    ViewedMessages { messages: Vec<(String, i64)> },
+    /// Encrypted attachment holding a `ContactDetails` stream
+    Contacts { blob: Attachment, complete: bool },
     Groups { groups: Vec<Group> },
-    Blocked { identities: Vec<SignalIdentity> },
-    Configuration { read_receipts: bool, typing_indicators: bool },
+    /// The complete list of blocked people and base64 group IDs
+    Blocked {
+        identities: Vec<SignalIdentity>,
+        group_ids: Vec<String>,
+    },
+    /// Account settings; unset ones are left as they are
+    Configuration {
+        read_receipts: Option<bool>,
+        typing_indicators: Option<bool>,
+        link_previews: Option<bool>,
+    },
+    /// Account keys shared with linked devices
+    Keys {
+        /// Storage service key, sent by primaries predating the master key
+        storage_service: Option<Vec<u8>>,
+        master: Option<Vec<u8>>,
+    },
+    /// Ask the primary device to send its state of some kind
+    Request { kind: SyncRequest },
+    /// Another device changed state we should fetch again from the server
+    FetchLatest { kind: FetchLatest },
+}
+
+/// A recipient listed in a sent transcript
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SentRecipient {
+    pub identity: SignalIdentity,
+    /// Whether the message went to them with sealed sender
+    pub unidentified: bool,
+}
+
+/// Delivery state of an outgoing message for one of its recipients
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecipientStatus {
+    pub recipient: SignalIdentity,
+    pub status: MessageStatus,
+    /// Unix timestamp of the last status change
+    pub updated_at: i64,
+}
+
+/// Account keys received from the primary device
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccountKeys {
+    /// Storage service key, sent by primaries predating the master key
+    pub storage_service: Option<Vec<u8>>,
+    /// Master key the storage service key is derived from
+    pub master: Option<Vec<u8>>,
+}
+
+/// State a linked device can request from the primary device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SyncRequest {
+    Contacts,
+    Blocked,
+    Configuration,
+    Keys,
+}
+
+/// Server state another device asks us to fetch again
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FetchLatest {
+    LocalProfile,
+    StorageManifest,
+    SubscriptionStatus,
+}
+
+/// Identifier of a record in the storage service
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StorageId {
+    /// Random bytes naming the record, unique within the manifest
+    pub raw: Vec<u8>,
+    /// `ManifestRecord.Identifier.Type` of the record, kept as is for
+    /// types we do not model
+    pub record_type: i32,
+}
+
+/// The storage service records of an account at one version
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StorageManifest {
+    /// Incremented by every write; 0 before the first sync
+    pub version: u64,
+    pub identifiers: Vec<StorageId>,
+    /// Key material the records are encrypted with, if not the storage key
+    pub record_ikm: Option<Vec<u8>>,
+}
+
+/// A decrypted storage service record
+#[derive(Debug, Clone, PartialEq)]
+pub enum StorageRecord {
+    Contact(ContactRecord),
+    GroupV2(GroupV2Record),
+    Account(AccountRecord),
+}
+
+/// How the primary device knows a contact, and the state of their chat
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ContactRecord {
+    pub aci: Option<Aci>,
+    pub pni: Option<Pni>,
+    pub e164: Option<String>,
+    pub profile_key: Option<Vec<u8>>,
+    /// Name from the contact's profile
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+    /// Name from the address book of the primary device
+    pub system_given_name: Option<String>,
+    pub system_family_name: Option<String>,
+    /// Name the user gave the contact in Signal
+    pub nickname_given_name: Option<String>,
+    pub nickname_family_name: Option<String>,
+    pub username: Option<String>,
+    pub blocked: bool,
+    /// Whether the user accepted messages from the contact
+    pub whitelisted: bool,
+    pub archived: bool,
+    pub marked_unread: bool,
+    /// Millisecond timestamp notifications are muted until
+    pub muted_until: Option<i64>,
+}
+
+/// State of a group chat, for a group known by its master key
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GroupV2Record {
+    pub master_key: Vec<u8>,
+    pub blocked: bool,
+    /// Whether the user accepted the group's invitation
+    pub whitelisted: bool,
+    pub archived: bool,
+    pub marked_unread: bool,
+    /// Millisecond timestamp notifications are muted until
+    pub muted_until: Option<i64>,
+}
+
+/// Profile and settings of our own account
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AccountRecord {
+    pub profile_key: Option<Vec<u8>>,
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+    /// CDN path of our profile avatar
+    pub avatar_url: Option<String>,
+    pub note_to_self_archived: bool,
+    pub read_receipts: bool,
+    pub sealed_sender_indicators: bool,
+    pub typing_indicators: bool,
+    pub link_previews: bool,
+    /// Conversations pinned to the top of the chat list, in order
+    pub pinned_conversations: Vec<PinnedConversation>,
+}
+
+/// A conversation pinned in our account record
+#[derive(Debug, Clone, PartialEq)]
+pub enum PinnedConversation {
+    Contact {
+        service_id: Option<ServiceId>,
+        e164: Option<String>,
+    },
+    /// A group of the retired first version, which we cannot show
+    LegacyGroup(Vec<u8>),
+    Group {
+        master_key: Vec<u8>,
+    },
+}
+
+/// What applying storage service records changed locally
+#[derive(Debug, Clone, Default)]
+pub struct StorageChanges {
+    pub contacts: Vec<SignalIdentity>,
+    pub conversations: Vec<Conversation>,
+    /// Master keys of groups whose state is still to be fetched
+    pub groups: Vec<Vec<u8>>,
+    pub account: Option<AccountRecord>,
+}
+
+/// A contact's profile, decrypted with their profile key
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+    pub about: Option<String>,
+    pub about_emoji: Option<String>,
+    /// CDN path of the encrypted avatar
+    pub avatar_url: Option<String>,
+}
+
+impl Profile {
+    /// Given and family name joined for display
+    pub fn name(&self) -> Option<String> {
+        let name = [&self.given_name, &self.family_name]
+            .into_iter()
+            .flatten()
+            .map(|part| part.trim())
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+        (!name.is_empty()).then_some(name)
+    }
+}
+
+/// Everything we know to show a person by
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecipientDetails {
+    pub aci: Aci,
+    pub phone_number: Option<String>,
+    /// Name we gave them ourselves
+    pub nickname: Option<String>,
+    /// Name in the primary device's address book
+    pub system_name: Option<String>,
+    pub profile_name: Option<String>,
+    pub username: Option<String>,
+    /// Cached avatar, from the address book or else their profile
+    pub avatar_path: Option<std::path::PathBuf>,
+}
+
+impl RecipientDetails {
+    /// Name to show them by: their nickname, address book name, profile
+    /// name, phone number or username, whichever comes first
+    pub fn display_name(&self) -> Option<&str> {
+        [
+            &self.nickname,
+            &self.system_name,
+            &self.profile_name,
+            &self.phone_number,
+            &self.username,
+        ]
+        .into_iter()
+        .flatten()
+        .map(String::as_str)
+        .find(|name| !name.trim().is_empty())
+    }
+}
+
+/// A contact as listed by the primary device in a contact sync
+#[derive(Debug, Clone, Default)]
+pub struct ContactDetails {
+    pub aci: Option<Aci>,
+    pub phone_number: Option<String>,
+    pub name: Option<String>,
+    pub avatar: Option<ContactAvatar>,
+    pub color: Option<String>,
+    pub profile_key: Option<Vec<u8>>,
+    /// Disappearing message timer in seconds, 0 when off
+    pub expire_timer: u32,
+    pub inbox_position: Option<u32>,
+}
+
+/// Avatar image sent inline with a contact
+#[derive(Debug, Clone)]
+pub struct ContactAvatar {
+    pub content_type: String,
+    pub data: Vec<u8>,
 }
 
 /// Typing indicator status
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum TypingAction {
     Started,
     Stopped,
 }
 
+/// Typing indicator
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TypingMessage {
+    pub action: TypingAction,
+    pub timestamp: i64,
+    pub group_id: Option<String>,
+}
+
+/// Receipt kinds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReceiptType {
+    Delivery,
+    Read,
+    Viewed,
+}
+
+/// Delivery, read or viewed receipt for messages identified by sent timestamp
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReceiptMessage {
+    pub receipt_type: ReceiptType,
+    pub timestamps: Vec<i64>,
+}
+
+/// Reference to a quoted message by its sent timestamp and author
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuoteReference {
+    pub timestamp: i64,
+    pub author: Uuid,
+    pub text: Option<String>,
+}
+
+/// Reaction added to or removed from a message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReactionUpdate {
+    pub emoji: String,
+    pub remove: bool,
+    pub target_author: Uuid,
+    pub target_timestamp: i64,
+}
+
+/// Data message as exchanged on the wire
+///
+/// `content` is `None` for control messages such as reactions,
+/// timer updates and session resets.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DataMessage {
+    pub timestamp: i64,
+    pub content: Option<MessageContent>,
+    pub quote: Option<QuoteReference>,
+    pub reaction: Option<ReactionUpdate>,
+    pub group_master_key: Option<Vec<u8>>,
+    /// Revision of the group the sender saw when sending
+    pub group_revision: Option<u32>,
+    pub expire_timer: Option<u32>,
+    pub profile_key: Option<Vec<u8>>,
+    pub end_session: bool,
+}
+
+/// Request to resend a message we failed to decrypt
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecryptionErrorMessage {
+    /// Ratchet key of the failed message, if it was a regular ciphertext
+    pub ratchet_key: Option<Vec<u8>>,
+    /// Sent timestamp of the failed message
+    pub timestamp: i64,
+    /// Our device that failed to decrypt
+    pub device_id: u32,
+}
+
+/// Decoded transport content of an envelope
+#[derive(Debug, Clone)]
+pub enum SignalContent {
+    Data(Box<DataMessage>),
+    Sync(SyncMessage),
+    Typing(TypingMessage),
+    Receipt(ReceiptMessage),
+    /// Voice or video call signaling (not supported yet)
+    Call,
+    /// Padding-only message used for session maintenance
+    Null,
+    /// Resend request, only ever carried in plaintext content envelopes
+    DecryptionError(DecryptionErrorMessage),
+}
+
+/// Kind of payload carried by an envelope
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EnvelopeType {
+    /// Double Ratchet message on an existing session
+    Ciphertext,
+    /// Initial message establishing a session from a pre-key bundle
+    PreKeyBundle,
+    /// Server delivery receipt
+    Receipt,
+    /// Sealed sender message
+    UnidentifiedSender,
+    /// Unencrypted content such as decryption error reports
+    PlaintextContent,
+    Unknown,
+}
+
+/// Server-side metadata of a received envelope
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EnvelopeMetadata {
+    /// Unique ID assigned by the server, used to drop duplicate deliveries
+    pub server_guid: Option<String>,
+    /// Time the server accepted the envelope (ms)
+    pub server_timestamp: Option<i64>,
+    /// Service ID the envelope was addressed to (our ACI or PNI)
+    pub destination_service_id: Option<ServiceId>,
+    /// Whether the envelope should wake the device; true unless the sender
+    /// said otherwise
+    pub urgent: bool,
+    pub story: bool,
+}
+
+impl Default for EnvelopeMetadata {
+    fn default() -> Self {
+        Self {
+            server_guid: None,
+            server_timestamp: None,
+            destination_service_id: None,
+            urgent: true,
+            story: false,
+        }
+    }
+}
+
+/// Envelope as delivered by the server
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    pub envelope_type: EnvelopeType,
+    /// Sender's ACI or PNI, absent for sealed sender envelopes
+    pub source: Option<ServiceId>,
+    pub source_device: u32,
+    /// Sender's client timestamp (ms), also the message's sent timestamp
+    pub timestamp: i64,
+    pub metadata: EnvelopeMetadata,
+    /// Encrypted or plaintext content, depending on `envelope_type`
+    pub content: Vec<u8>,
+}
+
 /// Signal server endpoints
 pub struct SignalServers {
     pub service: &'static str,
@@ -169,6 +848,10 @@ pub struct SignalServers {
     pub cdn: &'static str,
     pub cdn2: &'static str,
     pub cdn3: &'static str,
+    /// Base64 zkgroup public params of the servers, which group and
+    /// profile credentials are checked against; set at build time with
+    /// `SIGNAL_SERVER_PUBLIC_PARAMS`
+    pub zkgroup_params: Option<&'static str>,
 }
 
 impl Default for SignalServers {
@@ -179,6 +862,7 @@ impl Default for SignalServers {
             cdn: "https://cdn.signal.org",
             cdn2: "https://cdn2.signal.org",
             cdn3: "https://cdn3.signal.org",
+            zkgroup_params: option_env!("SIGNAL_SERVER_PUBLIC_PARAMS"),
         }
     }
 }
@@ -1,12 +1,20 @@
 //! Chat list sidebar component
 
 use gtk4::prelude::*;
+use gtk4::subclass::prelude::ObjectSubclassIsExt;
 use gtk4::glib;
 use libadwaita as adw;
+use std::ops::Range;
+
+use crate::signal::types::{Aci, Conversation, SearchHit};
+use crate::ui::{ContactRow, MessageRow, RecipientResolver};
 
 mod imp {
     use super::*;
     use adw::subclass::prelude::*;
+    use glib::subclass::Signal;
+    use std::cell::RefCell;
+    use std::sync::OnceLock;
 
     #[derive(Debug, Default, gtk4::CompositeTemplate)]
     #[template(resource = "/com/signalyou/Messenger/ui/chat_list.ui")]
@@ -19,6 +27,21 @@ mod imp {
 
         #[template_child]
         pub header_bar: TemplateChild<adw::HeaderBar>,
+
+        #[template_child]
+        pub stack: TemplateChild<gtk4::Stack>,
+
+        #[template_child]
+        pub search_results: TemplateChild<gtk4::ListBox>,
+
+        /// Listed conversations, in row order
+        pub conversations: RefCell<Vec<Conversation>>,
+
+        /// Conversation and message ID of each search result row
+        pub search_hits: RefCell<Vec<(String, String)>>,
+
+        /// Names and avatars of the people chats are with
+        pub resolver: RefCell<RecipientResolver>,
     }
 
     #[glib::object_subclass]
@@ -41,22 +64,66 @@ mod imp {
     impl ChatList {
         #[template_callback]
         fn on_row_activated(&self, row: &gtk4::ListBoxRow) {
-            // TODO: Emit signal with chat ID
-            tracing::info!("Chat row activated: {:?}", row.index());
+            let chat_id = row
+                .downcast_ref::<ContactRow>()
+                .and_then(|row| row.get_chat_id());
+
+            if let Some(chat_id) = chat_id {
+                self.obj().emit_by_name::<()>("chat-activated", &[&chat_id]);
+            }
         }
 
         #[template_callback]
         fn on_search_changed(&self, entry: &gtk4::SearchEntry) {
             let text = entry.text();
-            tracing::info!("Search text: {}", text);
-            // TODO: Filter chat list
+
+            if text.trim().is_empty() {
+                self.stack.set_visible_child_name("chats");
+                self.search_results.remove_all();
+                self.search_hits.borrow_mut().clear();
+                return;
+            }
+
+            self.stack.set_visible_child_name("results");
+            self.obj().emit_by_name::<()>("search-changed", &[&text.to_string()]);
+        }
+
+        #[template_callback]
+        fn on_stop_search(&self, entry: &gtk4::SearchEntry) {
+            entry.set_text("");
+        }
+
+        #[template_callback]
+        fn on_search_result_activated(&self, row: &gtk4::ListBoxRow) {
+            let hit = usize::try_from(row.index())
+                .ok()
+                .and_then(|index| self.search_hits.borrow().get(index).cloned());
+
+            if let Some((conversation_id, message_id)) = hit {
+                self.obj().emit_by_name::<()>(
+                    "search-result-activated",
+                    &[&conversation_id, &message_id],
+                );
+            }
         }
     }
 
     impl ObjectImpl for ChatList {
-        fn constructed(&self) {
-            self.parent_constructed();
-            self.obj().setup();
+        fn signals() -> &'static [Signal] {
+            static SIGNALS: OnceLock<Vec<Signal>> = OnceLock::new();
+            SIGNALS.get_or_init(|| {
+                vec![
+                    Signal::builder("chat-activated")
+                        .param_types([String::static_type()])
+                        .build(),
+                    Signal::builder("search-changed")
+                        .param_types([String::static_type()])
+                        .build(),
+                    Signal::builder("search-result-activated")
+                        .param_types([String::static_type(), String::static_type()])
+                        .build(),
+                ]
+            })
         }
     }
 
@@ -75,18 +142,199 @@ impl ChatList {
         glib::Object::new()
     }
 
-    fn setup(&self) {
-        // TODO: Load chats from Signal service
-        self.load_chats();
+    /// Show people as `resolver` knows them, redrawing their chats as
+    /// their names and avatars change
+    pub fn set_resolver(&self, resolver: &RecipientResolver) {
+        resolver.connect_changed(glib::clone!(@weak self as chat_list => move |_, aci| {
+            chat_list.redraw_chats_with(aci);
+        }));
+        self.imp().resolver.replace(resolver.clone());
+        self.redraw_chats_with(None);
+    }
+
+    /// Replace the listed chats with `conversations`, most recent first
+    pub fn set_conversations(&self, conversations: &[Conversation]) {
+        let imp = self.imp();
+        imp.list_box.remove_all();
+
+        let resolver = imp.resolver.borrow();
+        for conversation in conversations {
+            imp.list_box
+                .append(&conversation_row(conversation, &resolver));
+        }
+        imp.conversations.replace(conversations.to_vec());
+    }
+
+    /// Redraw the rows of chats with one person, or of all chats
+    fn redraw_chats_with(&self, aci: Option<Aci>) {
+        let imp = self.imp();
+        let resolver = imp.resolver.borrow();
+
+        for (index, conversation) in imp.conversations.borrow().iter().enumerate() {
+            if conversation.is_group || aci.is_some_and(|aci| aci != conversation.recipient.aci) {
+                continue;
+            }
+            let Some(old) = imp.list_box.row_at_index(index as i32) else {
+                continue;
+            };
+
+            let row = conversation_row(conversation, &resolver);
+            let selected = old.is_selected();
+            imp.list_box.remove(&old);
+            imp.list_box.insert(&row, index as i32);
+            if selected {
+                imp.list_box.select_row(Some(&row));
+            }
+        }
+    }
+
+    /// Update the row of a changed conversation, or add it for a new one
+    ///
+    /// A conversation with a new last message moves to the top, below the
+    /// pinned conversations, which keep their pinned order.
+    pub fn update_conversation(&self, conversation: &Conversation) {
+        let imp = self.imp();
+        let mut conversations = imp.conversations.borrow_mut();
+
+        let position = conversations.iter().position(|c| c.id == conversation.id);
+        let mut was_selected = false;
+        let mut moved = true;
+        if let Some(position) = position {
+            let previous = conversations.remove(position);
+            if let Some(row) = imp.list_box.row_at_index(position as i32) {
+                was_selected = row.is_selected();
+                imp.list_box.remove(&row);
+            }
+
+            let last_id = |c: &Conversation| c.last_message.as_ref().map(|m| m.id.clone());
+            moved = last_id(&previous) != last_id(conversation)
+                || previous.pinned != conversation.pinned;
+        }
+        let index = match position {
+            Some(position) if !moved => position,
+            _ => conversations
+                .iter()
+                .take_while(|c| match (c.pinned, conversation.pinned) {
+                    (Some(other), Some(pinned)) => other < pinned,
+                    (Some(_), None) => true,
+                    (None, _) => false,
+                })
+                .count(),
+        };
+
+        conversations.insert(index, conversation.clone());
+        drop(conversations);
+
+        let row = conversation_row(conversation, &imp.resolver.borrow());
+        imp.list_box.insert(&row, index as i32);
+        if was_selected {
+            imp.list_box.select_row(Some(&row));
+        }
     }
 
-    fn load_chats(&self) {
-        // TODO: Fetch chats from Signal and populate list
-        tracing::info!("Loading chats from Signal service");
+    /// Name of a listed conversation
+    pub fn conversation_name(&self, id: &str) -> Option<String> {
+        self.imp()
+            .conversations
+            .borrow()
+            .iter()
+            .find(|conversation| conversation.id == id)
+            .map(|conversation| conversation.name.clone())
     }
 
-    pub fn refresh(&self) {
-        self.load_chats();
+    /// Whether a listed conversation is blocked
+    pub fn is_blocked(&self, id: &str) -> bool {
+        self.imp()
+            .conversations
+            .borrow()
+            .iter()
+            .any(|conversation| conversation.id == id && conversation.blocked)
+    }
+
+    /// Whether a listed conversation is a group
+    pub fn is_group(&self, id: &str) -> bool {
+        self.imp()
+            .conversations
+            .borrow()
+            .iter()
+            .any(|conversation| conversation.id == id && conversation.is_group)
+    }
+
+    /// Focus the search entry, or leave search if it is in use
+    pub fn toggle_search(&self) {
+        let imp = self.imp();
+
+        if imp.search_entry.text().is_empty() {
+            imp.search_entry.grab_focus();
+        } else {
+            imp.search_entry.set_text("");
+        }
+    }
+
+    /// Replace the search results with `hits`
+    pub fn show_search_results(&self, hits: &[SearchHit]) {
+        let imp = self.imp();
+        imp.search_results.remove_all();
+
+        for hit in hits {
+            let row = adw::ActionRow::builder()
+                .title(glib::markup_escape_text(&hit.conversation_name))
+                .subtitle(highlight_markup(&hit.snippet, &hit.highlights))
+                .subtitle_lines(2)
+                .activatable(true)
+                .build();
+            imp.search_results.append(&row);
+        }
+
+        imp.search_hits.replace(
+            hits.iter()
+                .map(|hit| (hit.message.conversation_id.clone(), hit.message.id.clone()))
+                .collect(),
+        );
+    }
+
+    /// Connect to activation of a chat, passing its conversation ID
+    pub fn connect_chat_activated<F: Fn(&Self, &str) + 'static>(
+        &self,
+        f: F,
+    ) -> glib::SignalHandlerId {
+        self.connect_closure(
+            "chat-activated",
+            false,
+            glib::closure_local!(move |chat_list: Self, chat_id: String| {
+                f(&chat_list, &chat_id);
+            }),
+        )
+    }
+
+    /// Connect to search text changes; not emitted for an empty search
+    pub fn connect_search_changed<F: Fn(&Self, &str) + 'static>(
+        &self,
+        f: F,
+    ) -> glib::SignalHandlerId {
+        self.connect_closure(
+            "search-changed",
+            false,
+            glib::closure_local!(move |chat_list: Self, query: String| {
+                f(&chat_list, &query);
+            }),
+        )
+    }
+
+    /// Connect to activation of a search result as `(conversation_id, message_id)`
+    pub fn connect_search_result_activated<F: Fn(&Self, &str, &str) + 'static>(
+        &self,
+        f: F,
+    ) -> glib::SignalHandlerId {
+        self.connect_closure(
+            "search-result-activated",
+            false,
+            glib::closure_local!(
+                move |chat_list: Self, conversation_id: String, message_id: String| {
+                    f(&chat_list, &conversation_id, &message_id);
+                }
+            ),
+        )
     }
 }
 
@@ -95,3 +343,60 @@ impl Default for ChatList {
         Self::new()
     }
 }
+
+/// Sidebar row showing a conversation
+fn conversation_row(conversation: &Conversation, resolver: &RecipientResolver) -> ContactRow {
+    let row = ContactRow::new();
+    row.set_chat_id(&conversation.id);
+    row.set_unread_count(conversation.unread_count);
+
+    // Groups, and people known only by phone number, go by the stored name
+    if conversation.is_group || conversation.recipient.aci.is_nil() {
+        row.set_name(&conversation.name);
+    } else {
+        row.set_name(&resolver.name(&conversation.recipient));
+        row.set_avatar_image(resolver.avatar(&conversation.recipient.aci).as_ref());
+    }
+
+    match &conversation.last_message {
+        _ if conversation.blocked => {
+            row.set_last_message("Blocked");
+            row.set_time("");
+        }
+        Some(message) => {
+            row.set_last_message(&MessageRow::get_content_preview(&message.content));
+            row.set_time(&format_time(message.timestamp));
+        }
+        None => {
+            row.set_last_message("");
+            row.set_time("");
+        }
+    }
+
+    row
+}
+
+/// Time of day of a millisecond timestamp, as shown next to each chat
+fn format_time(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp_millis(timestamp)
+        .unwrap_or_else(chrono::Utc::now)
+        .format("%H:%M")
+        .to_string()
+}
+
+/// Pango markup for `text` with the byte ranges in `highlights` in bold
+fn highlight_markup(text: &str, highlights: &[Range<usize>]) -> String {
+    let mut markup = String::new();
+    let mut position = 0;
+
+    for range in highlights {
+        markup.push_str(&glib::markup_escape_text(&text[position..range.start]));
+        markup.push_str("<b>");
+        markup.push_str(&glib::markup_escape_text(&text[range.clone()]));
+        markup.push_str("</b>");
+        position = range.end;
+    }
+    markup.push_str(&glib::markup_escape_text(&text[position..]));
+
+    markup
+}
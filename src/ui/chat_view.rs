@@ -2,17 +2,34 @@
 
 use gtk4::prelude::*;
 use gtk4::subclass::prelude::ObjectSubclassIsExt;
-use gtk4::glib;
+use gtk4::{gio, glib};
 use libadwaita as adw;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::Mutex;
 
-use super::ComposeBar;
+use super::{ComposeBar, MessageRow, RecipientResolver};
+use crate::application::runtime;
+use crate::signal::types::{
+    Aci, Message, MessageCursor, MessageStatus, MessageWindow, TypingAction,
+};
+use crate::signal::SignalClient;
+
+/// Seconds after which a remote typing indicator is dropped if not refreshed
+const TYPING_EXPIRY_SECS: u32 = 20;
+
+/// Messages fetched per page while scrolling
+const PAGE_SIZE: usize = 50;
 
 mod imp {
     use super::*;
     use adw::subclass::prelude::*;
-    use std::cell::RefCell;
+    use glib::subclass::Signal;
+    use std::cell::{Cell, OnceCell, RefCell};
+    use std::collections::HashMap;
+    use std::sync::OnceLock;
 
-    #[derive(Debug, Default, gtk4::CompositeTemplate)]
+    #[derive(Default, gtk4::CompositeTemplate)]
     #[template(resource = "/com/signalyou/Messenger/ui/chat_view.ui")]
     pub struct ChatView {
         #[template_child]
@@ -24,6 +41,9 @@ mod imp {
         #[template_child]
         pub status_label: TemplateChild<gtk4::Label>,
 
+        #[template_child]
+        pub group_button: TemplateChild<gtk4::Button>,
+
         #[template_child]
         pub message_list: TemplateChild<gtk4::ListView>,
 
@@ -34,6 +54,35 @@ mod imp {
         pub scrolled_window: TemplateChild<gtk4::ScrolledWindow>,
 
         pub current_chat_id: RefCell<Option<String>>,
+
+        /// Message to scroll to and highlight, such as a search hit
+        pub focused_message_id: RefCell<Option<String>>,
+
+        /// Remote senders currently typing, keyed by UUID, with their
+        /// display name and expiry timer
+        pub typing_senders: RefCell<HashMap<String, (String, glib::SourceId)>>,
+
+        /// Signal client, set once its store is open
+        pub client: RefCell<Option<Arc<Mutex<SignalClient>>>>,
+
+        /// Loaded messages of the open chat, oldest first
+        pub messages: OnceCell<gio::ListStore>,
+
+        /// Our own ACI, to tell outgoing messages apart
+        pub own_aci: Cell<Option<Aci>>,
+
+        /// Names and avatars of senders
+        pub resolver: RefCell<RecipientResolver>,
+
+        /// Whether older or newer messages than the loaded ones exist
+        pub has_older: Cell<bool>,
+        pub has_newer: Cell<bool>,
+
+        /// Whether a page is being fetched
+        pub loading: Cell<bool>,
+
+        /// Bumped per opened chat so pages fetched for another chat are dropped
+        pub load_serial: Cell<u64>,
     }
 
     #[glib::object_subclass]
@@ -57,8 +106,22 @@ mod imp {
     impl ChatView {}
 
     impl ObjectImpl for ChatView {
+        fn signals() -> &'static [Signal] {
+            static SIGNALS: OnceLock<Vec<Signal>> = OnceLock::new();
+            SIGNALS.get_or_init(|| {
+                vec![Signal::builder("typing-changed")
+                    .param_types([String::static_type(), bool::static_type()])
+                    .build()]
+            })
+        }
+
         fn constructed(&self) {
             self.parent_constructed();
+            self.obj().setup();
+        }
+
+        fn dispose(&self) {
+            self.obj().clear_typing_indicators();
         }
     }
 
@@ -77,18 +140,376 @@ impl ChatView {
         glib::Object::new()
     }
 
+    fn setup(&self) {
+        // Forward local typing state for the open chat
+        self.imp().compose_bar.connect_typing_changed(glib::clone!(
+            @weak self as chat_view => move |_, started| {
+                let chat_id = chat_view.imp().current_chat_id.borrow().clone();
+                if let Some(chat_id) = chat_id {
+                    chat_view.emit_by_name::<()>("typing-changed", &[&chat_id, &started]);
+                }
+            }
+        ));
+
+        self.setup_message_list();
+    }
+
+    fn setup_message_list(&self) {
+        let imp = self.imp();
+        let model = gio::ListStore::new::<glib::BoxedAnyObject>();
+
+        // Rows are built per bind, as a recycled row would keep the quote,
+        // reactions and styling of the message it showed before
+        let factory = gtk4::SignalListItemFactory::new();
+        factory.connect_bind(glib::clone!(@weak self as chat_view => move |_, item| {
+            let item = item
+                .downcast_ref::<gtk4::ListItem>()
+                .expect("Needs to be a ListItem");
+            let Some(object) = item.item().and_downcast::<glib::BoxedAnyObject>() else {
+                return;
+            };
+
+            let imp = chat_view.imp();
+            let message = object.borrow::<Message>();
+            let is_outgoing = imp.own_aci.get() == Some(message.sender.aci);
+            let row = MessageRow::from_message(&message, is_outgoing, &imp.resolver.borrow());
+            item.set_child(Some(&row));
+        }));
+
+        let selection = gtk4::SingleSelection::builder()
+            .model(&model)
+            .autoselect(false)
+            .can_unselect(true)
+            .build();
+        imp.message_list.set_model(Some(&selection));
+        imp.message_list.set_factory(Some(&factory));
+        imp.messages
+            .set(model)
+            .expect("Message list is set up once");
+
+        // Fetch further pages when scrolled to either end
+        imp.scrolled_window.connect_edge_reached(glib::clone!(
+            @weak self as chat_view => move |_, position| match position {
+                gtk4::PositionType::Top => chat_view.load_older(),
+                gtk4::PositionType::Bottom => chat_view.load_newer(),
+                _ => {}
+            }
+        ));
+    }
+
+    /// Hand over the Signal client once its store is open
+    pub fn set_client(&self, client: Arc<Mutex<SignalClient>>) {
+        self.imp().client.replace(Some(client));
+
+        // A chat opened before the store was ready is still empty
+        let chat_id = self.imp().current_chat_id.borrow().clone();
+        if let Some(chat_id) = chat_id {
+            self.load_messages(&chat_id);
+        }
+    }
+
+    /// Show senders as `resolver` knows them, redrawing messages as their
+    /// names and avatars change
+    pub fn set_resolver(&self, resolver: &RecipientResolver) {
+        resolver.connect_changed(glib::clone!(@weak self as chat_view => move |_, _| {
+            // Rows are built per bind, so announcing every item rebuilds them
+            let messages = chat_view.messages();
+            let n_items = messages.n_items();
+            messages.items_changed(0, n_items, n_items);
+        }));
+        self.imp().resolver.replace(resolver.clone());
+    }
+
+    fn messages(&self) -> &gio::ListStore {
+        self.imp().messages.get().expect("Message list is set up")
+    }
+
+    fn message_at(&self, position: u32) -> Option<Message> {
+        self.messages()
+            .item(position)
+            .and_downcast::<glib::BoxedAnyObject>()
+            .map(|object| object.borrow::<Message>().clone())
+    }
+
+    /// ID of the open chat
+    pub fn current_chat_id(&self) -> Option<String> {
+        self.imp().current_chat_id.borrow().clone()
+    }
+
     pub fn load_chat(&self, chat_id: &str) {
+        self.open_chat(chat_id, None);
+    }
+
+    /// Open a chat at one of its messages, e.g. a search hit
+    pub fn load_chat_at_message(&self, chat_id: &str, message_id: &str) {
+        self.open_chat(chat_id, Some(message_id));
+    }
+
+    fn open_chat(&self, chat_id: &str, message_id: Option<&str>) {
         let imp = self.imp();
+
+        // Stop typing in the previous chat before switching away from it
+        imp.compose_bar.stop_typing();
+        self.clear_typing_indicators();
+
         imp.current_chat_id.replace(Some(chat_id.to_string()));
+        imp.focused_message_id
+            .replace(message_id.map(str::to_string));
 
-        // TODO: Load messages from Signal service
         tracing::info!("Loading chat: {}", chat_id);
         self.load_messages(chat_id);
     }
 
+    /// Load the newest page of a chat, or the page around the focused message
     fn load_messages(&self, chat_id: &str) {
-        // TODO: Fetch messages from Signal service
-        tracing::info!("Loading messages for chat: {}", chat_id);
+        let imp = self.imp();
+
+        imp.load_serial.set(imp.load_serial.get() + 1);
+        imp.loading.set(false);
+        imp.has_older.set(false);
+        imp.has_newer.set(false);
+        self.messages().remove_all();
+
+        let chat_id = chat_id.to_string();
+        let focus = imp.focused_message_id.borrow().clone();
+        self.fetch(
+            move |client| async move {
+                let client = client.lock().await;
+                let own_aci = client.identity().map(|identity| identity.aci);
+
+                if let Some(focus) = focus {
+                    match client
+                        .get_messages_around(&chat_id, &focus, PAGE_SIZE)
+                        .await?
+                    {
+                        Some(window) => return Ok((own_aci, window, true)),
+                        None => tracing::warn!("Message {} is not in chat {}", focus, chat_id),
+                    }
+                }
+
+                let mut messages = client.get_messages(&chat_id, PAGE_SIZE).await?;
+                messages.reverse();
+                let window = MessageWindow {
+                    has_older: messages.len() == PAGE_SIZE,
+                    has_newer: false,
+                    focus_index: messages.len().saturating_sub(1),
+                    messages,
+                };
+                Ok((own_aci, window, false))
+            },
+            |chat_view, (own_aci, window, focused)| {
+                let imp = chat_view.imp();
+                imp.own_aci.set(own_aci);
+                imp.has_older.set(window.has_older);
+                imp.has_newer.set(window.has_newer);
+
+                if window.messages.is_empty() {
+                    return;
+                }
+                chat_view
+                    .messages()
+                    .extend_from_slice(&message_objects(window.messages));
+
+                let flags = if focused {
+                    gtk4::ListScrollFlags::FOCUS | gtk4::ListScrollFlags::SELECT
+                } else {
+                    gtk4::ListScrollFlags::NONE
+                };
+                imp.message_list
+                    .scroll_to(window.focus_index as u32, flags, None);
+            },
+        );
+    }
+
+    /// Prepend the page before the first loaded message
+    fn load_older(&self) {
+        let imp = self.imp();
+        if imp.loading.get() || !imp.has_older.get() {
+            return;
+        }
+        let Some(chat_id) = imp.current_chat_id.borrow().clone() else {
+            return;
+        };
+        let Some(first) = self.message_at(0) else {
+            return;
+        };
+
+        let cursor = MessageCursor::of(&first);
+        self.fetch(
+            move |client| async move {
+                client
+                    .lock()
+                    .await
+                    .get_messages_before(&chat_id, &cursor, PAGE_SIZE)
+                    .await
+            },
+            |chat_view, messages| {
+                let imp = chat_view.imp();
+                imp.has_older.set(messages.len() == PAGE_SIZE);
+
+                // Keep the visible messages in place as rows appear above them
+                let adjustment = imp.scrolled_window.vadjustment();
+                let from_bottom = adjustment.upper() - adjustment.value();
+
+                chat_view
+                    .messages()
+                    .splice(0, 0, &message_objects(messages));
+
+                glib::idle_add_local_once(move || {
+                    adjustment.set_value(adjustment.upper() - from_bottom);
+                });
+            },
+        );
+    }
+
+    /// Append the page after the last loaded message
+    fn load_newer(&self) {
+        let imp = self.imp();
+        if imp.loading.get() || !imp.has_newer.get() {
+            return;
+        }
+        let Some(chat_id) = imp.current_chat_id.borrow().clone() else {
+            return;
+        };
+        let n_items = self.messages().n_items();
+        let Some(last) = n_items
+            .checked_sub(1)
+            .and_then(|position| self.message_at(position))
+        else {
+            return;
+        };
+
+        let cursor = MessageCursor::of(&last);
+        self.fetch(
+            move |client| async move {
+                client
+                    .lock()
+                    .await
+                    .get_messages_after(&chat_id, &cursor, PAGE_SIZE)
+                    .await
+            },
+            |chat_view, messages| {
+                chat_view.imp().has_newer.set(messages.len() == PAGE_SIZE);
+                chat_view
+                    .messages()
+                    .extend_from_slice(&message_objects(messages));
+            },
+        );
+    }
+
+    /// Run `request` with the client on the Tokio runtime and pass its result
+    /// to `done`, unless another chat was opened in the meantime
+    fn fetch<T, F, Fut>(&self, request: F, done: impl FnOnce(&Self, T) + 'static)
+    where
+        T: Send + 'static,
+        F: FnOnce(Arc<Mutex<SignalClient>>) -> Fut,
+        Fut: Future<Output = anyhow::Result<T>> + Send + 'static,
+    {
+        let imp = self.imp();
+        let Some(client) = imp.client.borrow().clone() else {
+            return;
+        };
+
+        let serial = imp.load_serial.get();
+        imp.loading.set(true);
+
+        let request = request(client);
+        let (sender, receiver) = async_channel::bounded(1);
+        runtime().spawn(async move {
+            let _ = sender.send(request.await).await;
+        });
+
+        glib::spawn_future_local(glib::clone!(@weak self as chat_view => async move {
+            let Ok(result) = receiver.recv().await else {
+                return;
+            };
+
+            let imp = chat_view.imp();
+            if imp.load_serial.get() != serial {
+                return;
+            }
+            imp.loading.set(false);
+
+            match result {
+                Ok(value) => done(&chat_view, value),
+                Err(e) => tracing::error!("Failed to load messages: {}", e),
+            }
+        }));
+    }
+
+    /// Show a newly stored message if it belongs to the open chat
+    pub fn add_message(&self, message: &Message) {
+        let imp = self.imp();
+        if imp.current_chat_id.borrow().as_deref() != Some(message.conversation_id.as_str()) {
+            return;
+        }
+
+        // A message ends its sender's typing
+        self.show_typing_indicator(
+            &message.conversation_id,
+            &message.sender.aci.to_string(),
+            "",
+            TypingAction::Stopped,
+        );
+
+        if let Some(position) = self.position_of(&message.id) {
+            self.replace_message(position, message.clone());
+            return;
+        }
+
+        // Newer messages are not loaded yet; this one follows once they are
+        if imp.has_newer.get() {
+            return;
+        }
+
+        let adjustment = imp.scrolled_window.vadjustment();
+        let at_bottom = adjustment.value() + adjustment.page_size() >= adjustment.upper() - 1.0;
+
+        self.messages()
+            .append(&glib::BoxedAnyObject::new(message.clone()));
+
+        // Follow new messages unless scrolled back through older ones
+        if at_bottom {
+            glib::idle_add_local_once(glib::clone!(@weak self as chat_view => move || {
+                chat_view.scroll_to_bottom();
+            }));
+        }
+    }
+
+    /// Update the delivery status shown for a loaded message
+    pub fn update_message_status(&self, message_id: &str, status: MessageStatus) {
+        let Some(position) = self.position_of(message_id) else {
+            return;
+        };
+        let Some(mut message) = self.message_at(position) else {
+            return;
+        };
+
+        message.status = status;
+        self.replace_message(position, message);
+    }
+
+    /// Reload the open chat, e.g. after missing updates
+    pub fn reload(&self) {
+        let chat_id = self.imp().current_chat_id.borrow().clone();
+        if let Some(chat_id) = chat_id {
+            self.load_messages(&chat_id);
+        }
+    }
+
+    fn position_of(&self, message_id: &str) -> Option<u32> {
+        let messages = self.messages();
+        (0..messages.n_items()).rev().find(|&position| {
+            messages
+                .item(position)
+                .and_downcast::<glib::BoxedAnyObject>()
+                .is_some_and(|object| object.borrow::<Message>().id == message_id)
+        })
+    }
+
+    fn replace_message(&self, position: u32, message: Message) {
+        self.messages()
+            .splice(position, 1, &[glib::BoxedAnyObject::new(message)]);
     }
 
     pub fn scroll_to_bottom(&self) {
@@ -97,6 +518,96 @@ impl ChatView {
         adj.set_value(adj.upper() - adj.page_size());
     }
 
+    /// Connect to local typing state changes as `(chat_id, started)`
+    pub fn connect_typing_changed<F: Fn(&Self, &str, bool) + 'static>(
+        &self,
+        f: F,
+    ) -> glib::SignalHandlerId {
+        self.connect_closure(
+            "typing-changed",
+            false,
+            glib::closure_local!(move |chat_view: Self, chat_id: String, started: bool| {
+                f(&chat_view, &chat_id, started);
+            }),
+        )
+    }
+
+    /// Show or clear a remote typing indicator for the open chat
+    ///
+    /// Started indicators expire after `TYPING_EXPIRY_SECS` unless the
+    /// sender refreshes them, so a lost stop message never leaves a stale
+    /// "typing…" label behind.
+    pub fn show_typing_indicator(
+        &self,
+        conversation_id: &str,
+        sender_id: &str,
+        sender_name: &str,
+        action: TypingAction,
+    ) {
+        let imp = self.imp();
+
+        if imp.current_chat_id.borrow().as_deref() != Some(conversation_id) {
+            return;
+        }
+
+        if let Some((_, source)) = imp.typing_senders.borrow_mut().remove(sender_id) {
+            source.remove();
+        }
+
+        if action == TypingAction::Started {
+            let sender_key = sender_id.to_string();
+            let source = glib::timeout_add_seconds_local_once(
+                TYPING_EXPIRY_SECS,
+                glib::clone!(@weak self as chat_view => move || {
+                    // The source is already finished, so only forget it
+                    chat_view.imp().typing_senders.borrow_mut().remove(&sender_key);
+                    chat_view.update_typing_label();
+                }),
+            );
+            imp.typing_senders
+                .borrow_mut()
+                .insert(sender_id.to_string(), (sender_name.to_string(), source));
+        }
+
+        self.update_typing_label();
+    }
+
+    fn clear_typing_indicators(&self) {
+        let senders: Vec<_> = self.imp().typing_senders.borrow_mut().drain().collect();
+        for (_, (_, source)) in senders {
+            source.remove();
+        }
+        self.update_typing_label();
+    }
+
+    fn update_typing_label(&self) {
+        let imp = self.imp();
+        let senders = imp.typing_senders.borrow();
+
+        let label = match senders.len() {
+            0 => String::new(),
+            1 => {
+                let (name, _) = senders.values().next().unwrap();
+                format!("{} is typing…", name)
+            }
+            n => format!("{} people are typing…", n),
+        };
+
+        imp.status_label.set_text(&label);
+    }
+
+    /// Disable the compose bar while the open chat is blocked
+    pub fn set_blocked(&self, blocked: bool) {
+        let compose_bar = &self.imp().compose_bar;
+        compose_bar.set_sensitive(!blocked);
+        compose_bar.set_tooltip_text(blocked.then_some("Unblock this chat to send messages"));
+    }
+
+    /// Offer the group settings while the open chat is a group
+    pub fn set_group(&self, is_group: bool) {
+        self.imp().group_button.set_visible(is_group);
+    }
+
     pub fn send_message(&self, content: &str) {
         let imp = self.imp();
         if let Some(chat_id) = imp.current_chat_id.borrow().as_ref() {
@@ -111,3 +622,11 @@ impl Default for ChatView {
         Self::new()
     }
 }
+
+/// Wrap messages as items of the message list model
+fn message_objects(messages: Vec<Message>) -> Vec<glib::BoxedAnyObject> {
+    messages
+        .into_iter()
+        .map(glib::BoxedAnyObject::new)
+        .collect()
+}
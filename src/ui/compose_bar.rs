@@ -5,13 +5,22 @@
 //! - Disappearing message timer selection
 //! - File attachments
 //! - Emoji picker
+//! - Typing indicators (debounced, honoring the `send-typing-indicators` setting)
 
 use gtk4::prelude::*;
 use gtk4::subclass::prelude::ObjectSubclassIsExt;
-use gtk4::glib;
+use gtk4::{gio, glib};
 use libadwaita as adw;
 
+use crate::config;
 use crate::signal::types::Message;
+use crate::ui::RecipientResolver;
+
+/// Seconds without a keystroke before typing is considered stopped
+const TYPING_PAUSE_SECS: u32 = 3;
+
+/// Interval at which a started indicator is re-sent while the user keeps typing
+const TYPING_REFRESH_SECS: u32 = 15;
 
 /// Disappearing message timer durations in seconds
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
@@ -28,6 +37,25 @@ pub enum DisappearingTimer {
 }
 
 impl DisappearingTimer {
+    /// Every choice, shortest first
+    pub const ALL: [Self; 8] = [
+        Self::Off,
+        Self::Seconds30,
+        Self::Minutes5,
+        Self::Hour1,
+        Self::Hours8,
+        Self::Day1,
+        Self::Week1,
+        Self::Weeks4,
+    ];
+
+    /// Choice with a duration in seconds, if there is one
+    pub fn from_seconds(seconds: u32) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|timer| timer.duration_seconds() == seconds)
+    }
+
     /// Get duration in seconds (0 means disabled)
     pub fn duration_seconds(&self) -> u32 {
         match self {
@@ -60,7 +88,9 @@ impl DisappearingTimer {
 mod imp {
     use super::*;
     use adw::subclass::prelude::*;
-    use std::cell::RefCell;
+    use glib::subclass::Signal;
+    use std::cell::{Cell, OnceCell, RefCell};
+    use std::sync::OnceLock;
 
     /// Reply context for when replying to a message
     #[derive(Debug, Default, Clone)]
@@ -129,6 +159,11 @@ mod imp {
 
         pub reply_context: RefCell<Option<ReplyContext>>,
         pub disappearing_timer: RefCell<DisappearingTimer>,
+
+        pub settings: OnceCell<gio::Settings>,
+        pub typing_active: Cell<bool>,
+        pub typing_pause_source: RefCell<Option<glib::SourceId>>,
+        pub typing_refresh_source: RefCell<Option<glib::SourceId>>,
     }
 
     #[glib::object_subclass]
@@ -195,10 +230,23 @@ mod imp {
     }
 
     impl ObjectImpl for ComposeBar {
+        fn signals() -> &'static [Signal] {
+            static SIGNALS: OnceLock<Vec<Signal>> = OnceLock::new();
+            SIGNALS.get_or_init(|| {
+                vec![Signal::builder("typing-changed")
+                    .param_types([bool::static_type()])
+                    .build()]
+            })
+        }
+
         fn constructed(&self) {
             self.parent_constructed();
             self.obj().setup();
         }
+
+        fn dispose(&self) {
+            self.obj().stop_typing();
+        }
     }
 
     impl WidgetImpl for ComposeBar {}
@@ -237,19 +285,109 @@ impl ComposeBar {
 
         imp.text_view.add_controller(controller);
 
-        // Monitor text changes to enable/disable send button
+        // Monitor text changes to enable/disable send button and drive typing state
         let buffer = imp.text_view.buffer();
         buffer.connect_changed(glib::clone!(
             @weak self as compose_bar => move |buffer| {
                 let has_text = buffer.char_count() > 0;
                 compose_bar.imp().send_button.set_sensitive(has_text);
+
+                if has_text {
+                    compose_bar.note_typing();
+                } else {
+                    compose_bar.stop_typing();
+                }
             }
         ));
 
+        // Stop advertising typing as soon as the user turns the setting off
+        self.settings().connect_changed(
+            Some("send-typing-indicators"),
+            glib::clone!(@weak self as compose_bar => move |settings, key| {
+                if !settings.boolean(key) {
+                    compose_bar.stop_typing();
+                }
+            }),
+        );
+
         // Initial state
         imp.send_button.set_sensitive(false);
     }
 
+    fn settings(&self) -> &gio::Settings {
+        self.imp()
+            .settings
+            .get_or_init(|| gio::Settings::new(config::APP_ID))
+    }
+
+    /// Record a keystroke, emitting `typing-changed(true)` on the first one
+    /// and re-arming the pause timer that emits `typing-changed(false)`
+    fn note_typing(&self) {
+        let imp = self.imp();
+
+        if !self.settings().boolean("send-typing-indicators") {
+            return;
+        }
+
+        if !imp.typing_active.replace(true) {
+            self.emit_by_name::<()>("typing-changed", &[&true]);
+
+            // Recipients expire stale indicators, so keep refreshing while typing
+            let source = glib::timeout_add_seconds_local(
+                TYPING_REFRESH_SECS,
+                glib::clone!(@weak self as compose_bar => @default-return glib::ControlFlow::Break, move || {
+                    compose_bar.emit_by_name::<()>("typing-changed", &[&true]);
+                    glib::ControlFlow::Continue
+                }),
+            );
+            imp.typing_refresh_source.replace(Some(source));
+        }
+
+        if let Some(source) = imp.typing_pause_source.take() {
+            source.remove();
+        }
+
+        let source = glib::timeout_add_seconds_local_once(
+            TYPING_PAUSE_SECS,
+            glib::clone!(@weak self as compose_bar => move || {
+                // The source is already finished, so only forget it
+                compose_bar.imp().typing_pause_source.replace(None);
+                compose_bar.stop_typing();
+            }),
+        );
+        imp.typing_pause_source.replace(Some(source));
+    }
+
+    /// Stop the typing timers and emit `typing-changed(false)` if we were typing
+    pub fn stop_typing(&self) {
+        let imp = self.imp();
+
+        if let Some(source) = imp.typing_pause_source.take() {
+            source.remove();
+        }
+        if let Some(source) = imp.typing_refresh_source.take() {
+            source.remove();
+        }
+
+        if imp.typing_active.replace(false) {
+            self.emit_by_name::<()>("typing-changed", &[&false]);
+        }
+    }
+
+    /// Connect to typing state changes (`true` when started, `false` when stopped)
+    pub fn connect_typing_changed<F: Fn(&Self, bool) + 'static>(
+        &self,
+        f: F,
+    ) -> glib::SignalHandlerId {
+        self.connect_closure(
+            "typing-changed",
+            false,
+            glib::closure_local!(move |compose_bar: Self, started: bool| {
+                f(&compose_bar, started);
+            }),
+        )
+    }
+
     fn send_message(&self) {
         let imp = self.imp();
         let buffer = imp.text_view.buffer();
@@ -302,13 +440,10 @@ impl ComposeBar {
     }
 
     /// Set reply mode with the message being replied to
-    pub fn set_reply_to(&self, message: &Message) {
+    pub fn set_reply_to(&self, message: &Message, resolver: &RecipientResolver) {
         let imp = self.imp();
 
-        // Get sender name (use UUID as fallback)
-        let sender_name = message.sender.phone_number
-            .clone()
-            .unwrap_or_else(|| message.sender.uuid.to_string());
+        let sender_name = resolver.name(&message.sender);
 
         // Get content preview
         let content_preview = match &message.content {
@@ -329,6 +464,15 @@ impl ComposeBar {
             crate::signal::types::MessageContent::Sticker { .. } => "Sticker".to_string(),
             crate::signal::types::MessageContent::Contact { contact } => contact.name.clone(),
             crate::signal::types::MessageContent::Location { .. } => "Location".to_string(),
+            crate::signal::types::MessageContent::DecryptionFailed => {
+                "Message could not be decrypted".to_string()
+            }
+            crate::signal::types::MessageContent::NumbersMerged { .. } => {
+                "Message histories merged".to_string()
+            }
+            crate::signal::types::MessageContent::NotFound => {
+                "Original message not found".to_string()
+            }
         };
 
         // Store reply context
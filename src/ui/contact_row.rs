@@ -2,7 +2,7 @@
 
 use gtk4::prelude::*;
 use gtk4::subclass::prelude::ObjectSubclassIsExt;
-use gtk4::glib;
+use gtk4::{gdk, glib};
 use libadwaita as adw;
 
 mod imp {
@@ -97,6 +97,11 @@ impl ContactRow {
     pub fn set_avatar_icon(&self, icon_name: Option<&str>) {
         self.imp().avatar.set_icon_name(icon_name);
     }
+
+    /// Show a picture on the avatar instead of initials
+    pub fn set_avatar_image(&self, image: Option<&gdk::Texture>) {
+        self.imp().avatar.set_custom_image(image);
+    }
 }
 
 impl Default for ContactRow {
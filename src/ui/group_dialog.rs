@@ -0,0 +1,612 @@
+//! Dialogs to create, manage and join groups
+//!
+//! All are built in code, as their rows follow the people they list.
+//! Controls for changes our role does not allow are left insensitive; the
+//! client checks again before it changes the group.
+
+use gtk4::prelude::*;
+use gtk4::{gio, glib};
+use libadwaita as adw;
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use super::{DisappearingTimer, RecipientResolver};
+use crate::signal::types::{
+    Aci, Group, GroupAccessControl, GroupMember, GroupPreview, GroupRole, GroupUpdate,
+    InviteLinkAccess, SignalIdentity,
+};
+
+/// Invite link settings offered, with their labels
+const INVITE_LINK_ACCESS: [(InviteLinkAccess, &str); 3] = [
+    (InviteLinkAccess::Disabled, "Off"),
+    (InviteLinkAccess::Anyone, "On"),
+    (InviteLinkAccess::AdminApproval, "On, With Approval"),
+];
+
+/// What the user entered to create a group
+#[derive(Debug, Clone)]
+pub struct NewGroup {
+    pub name: String,
+    pub description: Option<String>,
+    /// Image file chosen as the group's avatar
+    pub avatar: Option<PathBuf>,
+    pub disappearing_timer: Option<u32>,
+    pub members: Vec<Aci>,
+}
+
+/// Dialog asking for the name, description, avatar, disappearing timer
+/// and members of a new group
+///
+/// `on_create` is called once the user confirms, and the dialog closes.
+pub fn new_group_dialog(
+    parent: &impl IsA<gtk4::Window>,
+    contacts: &[SignalIdentity],
+    resolver: &RecipientResolver,
+    on_create: impl Fn(NewGroup) + 'static,
+) -> adw::Window {
+    let (window, header, page) = dialog(parent, "New Group");
+
+    let create_button = gtk4::Button::builder()
+        .label("Create")
+        .sensitive(false)
+        .css_classes(["suggested-action"])
+        .build();
+    header.pack_end(&create_button);
+
+    let name_row = adw::EntryRow::builder().title("Name").build();
+    let description_row = adw::EntryRow::builder().title("Description").build();
+    let timer_row = timer_row(None);
+
+    let avatar: Rc<RefCell<Option<PathBuf>>> = Rc::default();
+    let avatar_button = gtk4::Button::builder()
+        .label("Choose…")
+        .valign(gtk4::Align::Center)
+        .build();
+    let avatar_row = adw::ActionRow::builder()
+        .title("Avatar")
+        .subtitle("None")
+        .activatable_widget(&avatar_button)
+        .build();
+    avatar_row.add_suffix(&avatar_button);
+    avatar_button.connect_clicked(glib::clone!(
+        @weak window, @weak avatar_row, @strong avatar => move |_| {
+            choose_avatar(&window, glib::clone!(@weak avatar_row, @strong avatar => move |path| {
+                let file_name = path.file_name().map(|name| name.to_string_lossy().to_string());
+                avatar_row.set_subtitle(file_name.as_deref().unwrap_or_default());
+                avatar.replace(Some(path));
+            }));
+        }
+    ));
+
+    let details = adw::PreferencesGroup::new();
+    details.add(&name_row);
+    details.add(&description_row);
+    details.add(&avatar_row);
+    details.add(&timer_row);
+    page.add(&details);
+
+    // People whose profile key we lack are invited rather than added
+    let members = adw::PreferencesGroup::builder()
+        .title("Members")
+        .description("People you have not exchanged messages with are invited")
+        .build();
+    let checks: Vec<(Aci, gtk4::CheckButton)> = contacts
+        .iter()
+        .map(|contact| {
+            let check = gtk4::CheckButton::new();
+            let row = person_row(contact, resolver);
+            row.add_suffix(&check);
+            row.set_activatable_widget(Some(&check));
+            members.add(&row);
+            (contact.aci, check)
+        })
+        .collect();
+    if checks.is_empty() {
+        members.add(
+            &adw::ActionRow::builder()
+                .title("No contacts yet")
+                .sensitive(false)
+                .build(),
+        );
+    }
+    page.add(&members);
+
+    name_row.connect_changed(glib::clone!(@weak create_button => move |row| {
+        create_button.set_sensitive(!row.text().trim().is_empty());
+    }));
+
+    create_button.connect_clicked(glib::clone!(
+        @weak window, @weak name_row, @weak description_row, @weak timer_row => move |_| {
+            let description = description_row.text().trim().to_string();
+            let group = NewGroup {
+                name: name_row.text().trim().to_string(),
+                description: Some(description).filter(|description| !description.is_empty()),
+                avatar: avatar.borrow().clone(),
+                disappearing_timer: selected_timer(&timer_row),
+                members: checks
+                    .iter()
+                    .filter(|(_, check)| check.is_active())
+                    .map(|(aci, _)| *aci)
+                    .collect(),
+            };
+            on_create(group);
+            window.close();
+        }
+    ));
+
+    window
+}
+
+/// Dialog showing a group's members and settings, to change them
+///
+/// `own_aci` decides which controls are sensitive. `invite_link` is shown
+/// to be shared while the link is on. `contacts` are offered to be added.
+/// `on_update` is called with each change the user makes, and the dialog
+/// closes.
+pub fn group_settings_dialog(
+    parent: &impl IsA<gtk4::Window>,
+    group: &Group,
+    own_aci: Aci,
+    invite_link: Option<&str>,
+    contacts: &[SignalIdentity],
+    resolver: &RecipientResolver,
+    on_update: impl Fn(GroupUpdate) + 'static,
+) -> adw::Window {
+    let (window, header, page) = dialog(parent, &group.name);
+    let on_update = Rc::new(on_update);
+    let own = own_aci.uuid();
+    let is_admin = group.is_admin(&own);
+    let can_edit_info = group.can_edit_info(&own);
+
+    // Attributes and permissions are changed together with Save
+    let save_button = gtk4::Button::builder()
+        .label("Save")
+        .css_classes(["suggested-action"])
+        .sensitive(can_edit_info || is_admin)
+        .build();
+    header.pack_end(&save_button);
+
+    let name_row = adw::EntryRow::builder()
+        .title("Name")
+        .sensitive(can_edit_info)
+        .build();
+    name_row.set_text(&group.name);
+    let description_row = adw::EntryRow::builder()
+        .title("Description")
+        .sensitive(can_edit_info)
+        .build();
+    description_row.set_text(group.description.as_deref().unwrap_or_default());
+    let timer_row = timer_row(group.disappearing_messages_timer);
+    timer_row.set_sensitive(can_edit_info);
+    let details = adw::PreferencesGroup::new();
+    details.add(&name_row);
+    details.add(&description_row);
+    details.add(&timer_row);
+    page.add(&details);
+
+    let add_row = adw::SwitchRow::builder()
+        .title("Members Can Add People")
+        .active(group.access_control.members_can_add_members)
+        .sensitive(is_admin)
+        .build();
+    let edit_row = adw::SwitchRow::builder()
+        .title("Members Can Edit Group Info")
+        .subtitle("Name, description, avatar and disappearing messages")
+        .active(group.access_control.members_can_edit_group_info)
+        .sensitive(is_admin)
+        .build();
+    let permissions = adw::PreferencesGroup::builder()
+        .title("Permissions")
+        .description("Administrators can always do both")
+        .build();
+    permissions.add(&add_row);
+    permissions.add(&edit_row);
+    page.add(&permissions);
+
+    let labels: Vec<&str> = INVITE_LINK_ACCESS.iter().map(|(_, label)| *label).collect();
+    let link_access_row = adw::ComboRow::builder()
+        .title("Invite Link")
+        .model(&gtk4::StringList::new(&labels))
+        .selected(
+            INVITE_LINK_ACCESS
+                .iter()
+                .position(|(access, _)| *access == group.invite_link_access)
+                .unwrap_or(0) as u32,
+        )
+        .sensitive(is_admin)
+        .build();
+    let invite_link_group = adw::PreferencesGroup::builder()
+        .title("Invite Link")
+        .description("Anyone with the link can see the group's name and join it")
+        .build();
+    invite_link_group.add(&link_access_row);
+    page.add(&invite_link_group);
+
+    let group_state = group.clone();
+    save_button.connect_clicked(glib::clone!(
+        @weak window, @weak name_row, @weak description_row, @weak timer_row,
+        @weak add_row, @weak edit_row, @weak link_access_row, @strong on_update => move |_| {
+            let name = name_row.text().trim().to_string();
+            let description = Some(description_row.text().trim().to_string())
+                .filter(|description| !description.is_empty());
+            let timer = selected_timer(&timer_row);
+            if name != group_state.name
+                || description != group_state.description
+                || timer != group_state.disappearing_messages_timer
+            {
+                on_update(GroupUpdate::SetAttributes {
+                    name,
+                    description,
+                    disappearing_messages_timer: timer,
+                });
+            }
+
+            let access_control = GroupAccessControl {
+                members_can_add_members: add_row.is_active(),
+                members_can_edit_group_info: edit_row.is_active(),
+            };
+            if access_control != group_state.access_control {
+                on_update(GroupUpdate::SetAccessControl(access_control));
+            }
+
+            if let Some((access, _)) = INVITE_LINK_ACCESS.get(link_access_row.selected() as usize) {
+                if *access != group_state.invite_link_access {
+                    on_update(GroupUpdate::SetInviteLink(*access));
+                }
+            }
+            window.close();
+        }
+    ));
+
+    // Closes the dialog on click after making `update`
+    let update_button = |label: &str, update: GroupUpdate, sensitive: bool| {
+        let button = gtk4::Button::builder()
+            .label(label)
+            .valign(gtk4::Align::Center)
+            .sensitive(sensitive)
+            .css_classes(["flat"])
+            .build();
+        button.connect_clicked(glib::clone!(@weak window, @strong on_update => move |_| {
+            on_update(update.clone());
+            window.close();
+        }));
+        button
+    };
+
+    if let Some(url) = invite_link {
+        let copy_button = gtk4::Button::builder()
+            .icon_name("edit-copy-symbolic")
+            .tooltip_text("Copy Link")
+            .valign(gtk4::Align::Center)
+            .css_classes(["flat"])
+            .build();
+        let url = url.to_string();
+        copy_button.connect_clicked(glib::clone!(@strong url => move |button| {
+            button.clipboard().set_text(&url);
+        }));
+
+        let link_row = adw::ActionRow::builder()
+            .title("Link")
+            .subtitle(url.as_str())
+            .subtitle_selectable(true)
+            .build();
+        link_row.add_suffix(&copy_button);
+        // Earlier links stop working
+        link_row.add_suffix(&update_button(
+            "Reset",
+            GroupUpdate::ResetInviteLink,
+            is_admin,
+        ));
+        invite_link_group.add(&link_row);
+    }
+
+    let members = adw::PreferencesGroup::builder()
+        .title(format!("{} Members", group.members.len()))
+        .build();
+    for member in &group.members {
+        let aci = Aci::from(member.uuid);
+        let row = person_row(&identity(member), resolver);
+        if member.role == GroupRole::Administrator {
+            row.set_subtitle("Administrator");
+        }
+        if member.uuid == own {
+            row.set_title("You");
+        } else {
+            let (label, role) = match member.role {
+                GroupRole::Administrator => ("Remove Admin", GroupRole::Member),
+                GroupRole::Member => ("Make Admin", GroupRole::Administrator),
+            };
+            row.add_suffix(&update_button(
+                label,
+                GroupUpdate::SetRole(aci, role),
+                is_admin,
+            ));
+            row.add_suffix(&update_button(
+                "Remove",
+                GroupUpdate::RemoveMembers(vec![aci]),
+                is_admin,
+            ));
+        }
+        members.add(&row);
+    }
+    page.add(&members);
+
+    let invited: Vec<&GroupMember> = group
+        .pending_members
+        .iter()
+        .filter(|pending| pending.invited_by.is_some())
+        .collect();
+    if !invited.is_empty() {
+        let pending = adw::PreferencesGroup::builder()
+            .title("Invited")
+            .description("They join once they accept")
+            .build();
+        for member in invited {
+            let row = person_row(&identity(member), resolver);
+            row.add_suffix(&update_button(
+                "Withdraw",
+                GroupUpdate::RemoveMembers(vec![member.uuid.into()]),
+                is_admin,
+            ));
+            pending.add(&row);
+        }
+        page.add(&pending);
+    }
+
+    let requesting: Vec<&GroupMember> = group
+        .pending_members
+        .iter()
+        .filter(|pending| pending.invited_by.is_none())
+        .collect();
+    if !requesting.is_empty() {
+        let requests = adw::PreferencesGroup::builder()
+            .title("Requests to Join")
+            .description("They asked to join with the invite link")
+            .build();
+        for member in requesting {
+            let aci = Aci::from(member.uuid);
+            let row = person_row(&identity(member), resolver);
+            row.add_suffix(&update_button(
+                "Approve",
+                GroupUpdate::ApproveRequests(vec![aci]),
+                is_admin,
+            ));
+            row.add_suffix(&update_button(
+                "Deny",
+                GroupUpdate::DenyRequests(vec![aci]),
+                is_admin,
+            ));
+            requests.add(&row);
+        }
+        page.add(&requests);
+    }
+
+    let outside: Vec<&SignalIdentity> = contacts
+        .iter()
+        .filter(|contact| {
+            !group.is_member(&contact.aci.uuid())
+                && !group
+                    .pending_members
+                    .iter()
+                    .any(|pending| pending.uuid == contact.aci.uuid())
+        })
+        .collect();
+    if !outside.is_empty() {
+        let add = adw::PreferencesGroup::builder()
+            .title("Add Members")
+            .build();
+        for contact in outside {
+            let row = person_row(contact, resolver);
+            row.add_suffix(&update_button(
+                "Add",
+                GroupUpdate::AddMembers(vec![contact.aci]),
+                group.can_add_members(&own),
+            ));
+            add.add(&row);
+        }
+        page.add(&add);
+    }
+
+    let leave_button = gtk4::Button::builder()
+        .label("Leave Group")
+        .halign(gtk4::Align::Center)
+        .css_classes(["destructive-action", "pill"])
+        .build();
+    leave_button.connect_clicked(glib::clone!(@weak window, @strong on_update => move |_| {
+        on_update(GroupUpdate::RemoveMembers(vec![own_aci]));
+        window.close();
+    }));
+    let leave = adw::PreferencesGroup::new();
+    leave.add(&leave_button);
+    page.add(&leave);
+
+    window
+}
+
+/// Dialog asking for a group invite link
+///
+/// `on_look_up` is called with the link once the user confirms, and the
+/// dialog closes.
+pub fn join_link_dialog(
+    parent: &impl IsA<gtk4::Window>,
+    on_look_up: impl Fn(String) + 'static,
+) -> adw::Window {
+    let (window, header, page) = dialog(parent, "Join Group");
+    window.set_default_height(280);
+
+    let next_button = gtk4::Button::builder()
+        .label("Next")
+        .sensitive(false)
+        .css_classes(["suggested-action"])
+        .build();
+    header.pack_end(&next_button);
+
+    let link_row = adw::EntryRow::builder().title("Invite Link").build();
+    let link = adw::PreferencesGroup::builder()
+        .description("Paste a link starting with https://signal.group/#")
+        .build();
+    link.add(&link_row);
+    page.add(&link);
+
+    link_row.connect_changed(glib::clone!(@weak next_button => move |row| {
+        next_button.set_sensitive(row.text().trim().starts_with("https://signal.group/#"));
+    }));
+    next_button.connect_clicked(glib::clone!(@weak window, @weak link_row => move |_| {
+        on_look_up(link_row.text().trim().to_string());
+        window.close();
+    }));
+
+    window
+}
+
+/// Dialog showing what an invite link shows of a group, offering to join
+///
+/// `on_join` is called once the user confirms, and the dialog closes.
+pub fn group_preview_dialog(
+    parent: &impl IsA<gtk4::Window>,
+    preview: &GroupPreview,
+    on_join: impl Fn() + 'static,
+) -> adw::Window {
+    let (window, header, page) = dialog(parent, &preview.name);
+    window.set_default_height(360);
+
+    let label = if preview.pending_approval {
+        "Requested"
+    } else if preview.requires_approval {
+        "Request to Join"
+    } else {
+        "Join"
+    };
+    let join_button = gtk4::Button::builder()
+        .label(label)
+        .sensitive(!preview.pending_approval)
+        .css_classes(["suggested-action"])
+        .build();
+    header.pack_end(&join_button);
+
+    let avatar = adw::Avatar::new(96, Some(preview.name.as_str()), true);
+    let about = adw::PreferencesGroup::builder()
+        .title(&preview.name)
+        .description(preview.description.as_deref().unwrap_or_default())
+        .build();
+    about.set_header_suffix(Some(&avatar));
+    let members = match preview.member_count {
+        1 => "1 member".to_string(),
+        count => format!("{} members", count),
+    };
+    about.add(&adw::ActionRow::builder().title(members).build());
+    if preview.pending_approval {
+        about.add(
+            &adw::ActionRow::builder()
+                .title("You asked to join")
+                .subtitle("An administrator has to approve your request")
+                .build(),
+        );
+    } else if preview.requires_approval {
+        about.add(
+            &adw::ActionRow::builder()
+                .title("Joining needs approval")
+                .subtitle("An administrator has to approve your request")
+                .build(),
+        );
+    }
+    page.add(&about);
+
+    join_button.connect_clicked(glib::clone!(@weak window => move |_| {
+        on_join();
+        window.close();
+    }));
+
+    window
+}
+
+/// Modal window with a header bar over a preferences page
+fn dialog(
+    parent: &impl IsA<gtk4::Window>,
+    title: &str,
+) -> (adw::Window, adw::HeaderBar, adw::PreferencesPage) {
+    let header = adw::HeaderBar::new();
+    let page = adw::PreferencesPage::new();
+    let toolbar = adw::ToolbarView::new();
+    toolbar.add_top_bar(&header);
+    toolbar.set_content(Some(&page));
+
+    let window = adw::Window::builder()
+        .title(title)
+        .modal(true)
+        .transient_for(parent)
+        .default_width(440)
+        .default_height(640)
+        .content(&toolbar)
+        .build();
+    (window, header, page)
+}
+
+/// Row choosing a disappearing timer, starting at `timer`
+fn timer_row(timer: Option<u32>) -> adw::ComboRow {
+    let labels: Vec<&str> = DisappearingTimer::ALL
+        .iter()
+        .map(|timer| timer.label())
+        .collect();
+    let selected = timer
+        .and_then(DisappearingTimer::from_seconds)
+        .and_then(|timer| DisappearingTimer::ALL.iter().position(|t| *t == timer))
+        .unwrap_or(0);
+
+    adw::ComboRow::builder()
+        .title("Disappearing Messages")
+        .model(&gtk4::StringList::new(&labels))
+        .selected(selected as u32)
+        .build()
+}
+
+/// Disappearing timer chosen in a [`timer_row`], `None` when off
+fn selected_timer(row: &adw::ComboRow) -> Option<u32> {
+    DisappearingTimer::ALL
+        .get(row.selected() as usize)
+        .map(DisappearingTimer::duration_seconds)
+        .filter(|seconds| *seconds > 0)
+}
+
+/// Row showing a person by name and avatar
+fn person_row(person: &SignalIdentity, resolver: &RecipientResolver) -> adw::ActionRow {
+    let avatar = adw::Avatar::new(32, None, true);
+    resolver.set_avatar(&avatar, person);
+
+    let row = adw::ActionRow::builder()
+        .title(resolver.name(person))
+        .build();
+    row.add_prefix(&avatar);
+    row
+}
+
+/// Identity of a group member, to show them by
+fn identity(member: &GroupMember) -> SignalIdentity {
+    SignalIdentity {
+        aci: member.uuid.into(),
+        pni: None,
+        phone_number: None,
+        device_id: 1,
+        registration_id: 0,
+    }
+}
+
+/// Let the user pick an image for a group avatar
+fn choose_avatar(parent: &adw::Window, on_chosen: impl Fn(PathBuf) + 'static) {
+    let filter = gtk4::FileFilter::new();
+    filter.set_name(Some("Images"));
+    filter.add_mime_type("image/*");
+
+    let dialog = gtk4::FileDialog::builder()
+        .title("Choose Group Avatar")
+        .modal(true)
+        .default_filter(&filter)
+        .build();
+
+    dialog.open(Some(parent), None::<&gio::Cancellable>, move |result| {
+        if let Ok(Some(path)) = result.map(|file| file.path()) {
+            on_chosen(path);
+        }
+    });
+}
@@ -12,6 +12,7 @@ use gtk4::glib;
 use libadwaita as adw;
 
 use crate::signal::types::{Message, MessageContent, MessageStatus, Reaction};
+use crate::ui::RecipientResolver;
 
 mod imp {
     use super::*;
@@ -114,10 +115,15 @@ impl MessageRow {
         glib::Object::new()
     }
 
-    /// Create a message row from a Message struct
-    pub fn from_message(message: &Message, is_outgoing: bool) -> Self {
+    /// Create a message row from a Message struct, showing people as
+    /// `resolver` knows them
+    pub fn from_message(
+        message: &Message,
+        is_outgoing: bool,
+        resolver: &RecipientResolver,
+    ) -> Self {
         let row = Self::new();
-        row.set_message(message, is_outgoing);
+        row.set_message(message, is_outgoing, resolver);
         row
     }
 
@@ -144,10 +150,13 @@ impl MessageRow {
     }
 
     /// Set message content from a Message struct
-    pub fn set_message(&self, message: &Message, is_outgoing: bool) {
+    pub fn set_message(&self, message: &Message, is_outgoing: bool, resolver: &RecipientResolver) {
         let imp = self.imp();
         imp.message_id.replace(Some(message.id.clone()));
 
+        // Placeholders are dimmed; anything else set on the row is not
+        self.remove_css_class("dim-label");
+
         // Set content based on message type
         match &message.content {
             MessageContent::Text { body } => {
@@ -181,6 +190,23 @@ impl MessageRow {
                 let label = name.as_deref().unwrap_or("Shared location");
                 imp.message_label.set_text(&format!("[Location: {}]", label));
             }
+            MessageContent::DecryptionFailed => {
+                imp.message_label
+                    .set_text("This message could not be decrypted. Asking the sender to resend it…");
+                self.add_css_class("dim-label");
+            }
+            MessageContent::NumbersMerged { phone_number } => {
+                let text = match phone_number {
+                    Some(number) => format!("Your message history with {} has been merged", number),
+                    None => "Your message histories with this person have been merged".to_string(),
+                };
+                imp.message_label.set_text(&text);
+                self.add_css_class("dim-label");
+            }
+            MessageContent::NotFound => {
+                imp.message_label.set_text("Original message not found");
+                self.add_css_class("dim-label");
+            }
         }
 
         // Set time
@@ -189,19 +215,30 @@ impl MessageRow {
         // Set outgoing status
         self.set_outgoing(is_outgoing);
 
-        // Set delivery status for outgoing messages
+        // Set delivery status for outgoing messages, and the sender of
+        // incoming ones
         if is_outgoing {
             self.set_status(message.status);
+        } else {
+            resolver.set_avatar(&imp.avatar, &message.sender);
+
+            // Only group messages need to say who sent them
+            if message.conversation_id != message.sender.aci.to_string() {
+                self.set_sender_name(&resolver.name(&message.sender));
+            }
         }
 
         // Set quote if present
         if let Some(quote) = &message.quote {
-            self.set_quote(Some(&quote.sender.uuid.to_string()), Some(&Self::get_content_preview(&quote.content)));
+            self.set_quote(
+                Some(&resolver.name(&quote.sender)),
+                Some(&Self::get_content_preview(&quote.content)),
+            );
         }
 
         // Set reactions
         if !message.reactions.is_empty() {
-            self.set_reactions(&message.reactions);
+            self.set_reactions(&message.reactions, resolver);
         }
 
         // Set expiry if disappearing message
@@ -210,7 +247,8 @@ impl MessageRow {
         }
     }
 
-    fn get_content_preview(content: &MessageContent) -> String {
+    /// Short one-line description of `content`
+    pub fn get_content_preview(content: &MessageContent) -> String {
         match content {
             MessageContent::Text { body } => {
                 if body.len() > 50 {
@@ -229,6 +267,9 @@ impl MessageRow {
             MessageContent::Sticker { .. } => "Sticker".to_string(),
             MessageContent::Contact { contact } => contact.name.clone(),
             MessageContent::Location { .. } => "Location".to_string(),
+            MessageContent::DecryptionFailed => "Message could not be decrypted".to_string(),
+            MessageContent::NumbersMerged { .. } => "Message histories merged".to_string(),
+            MessageContent::NotFound => "Original message not found".to_string(),
         }
     }
 
@@ -317,8 +358,8 @@ impl MessageRow {
         }
     }
 
-    /// Set message reactions
-    pub fn set_reactions(&self, reactions: &[Reaction]) {
+    /// Set message reactions, naming who reacted in each badge's tooltip
+    pub fn set_reactions(&self, reactions: &[Reaction], resolver: &RecipientResolver) {
         let imp = self.imp();
 
         // Clear existing reactions
@@ -331,18 +372,24 @@ impl MessageRow {
             return;
         }
 
-        // Group reactions by emoji and count
-        let mut emoji_counts: std::collections::HashMap<String, usize> = std::collections::HashMap::new();
+        // Group reactions by emoji, with who reacted
+        let mut emoji_senders: std::collections::HashMap<String, Vec<String>> =
+            std::collections::HashMap::new();
         for reaction in reactions {
-            *emoji_counts.entry(reaction.emoji.clone()).or_insert(0) += 1;
+            emoji_senders
+                .entry(reaction.emoji.clone())
+                .or_default()
+                .push(resolver.name(&reaction.sender));
         }
 
         // Create reaction badges
-        for (emoji, count) in emoji_counts {
+        for (emoji, senders) in emoji_senders {
+            let count = senders.len();
             let badge = gtk4::Box::builder()
                 .orientation(gtk4::Orientation::Horizontal)
                 .spacing(4)
                 .css_classes(["reaction-badge"])
+                .tooltip_text(senders.join(", "))
                 .build();
 
             let emoji_label = gtk4::Label::new(Some(&emoji));
@@ -4,11 +4,18 @@ mod chat_list;
 mod chat_view;
 mod compose_bar;
 mod contact_row;
+mod group_dialog;
 mod link_device_view;
 mod message_row;
+mod recipient_resolver;
 
 pub use chat_list::ChatList;
 pub use chat_view::ChatView;
 pub use compose_bar::{ComposeBar, DisappearingTimer};
+pub use contact_row::ContactRow;
+pub use group_dialog::{
+    group_preview_dialog, group_settings_dialog, join_link_dialog, new_group_dialog, NewGroup,
+};
 pub use link_device_view::LinkDeviceView;
 pub use message_row::MessageRow;
+pub use recipient_resolver::RecipientResolver;
@@ -0,0 +1,217 @@
+//! Display names and avatars of the people shown in the UI
+//!
+//! Widgets ask the resolver how to show a person instead of falling back to
+//! phone numbers or ACIs themselves. It caches what the store knows about
+//! everyone, looks up people it has not seen yet in the background, and
+//! announces when someone's name or avatar changed so widgets can redraw.
+//! Lookups read the store directly, so they never wait on the client.
+
+use gtk4::prelude::*;
+use gtk4::subclass::prelude::ObjectSubclassIsExt;
+use gtk4::{gdk, glib};
+use libadwaita as adw;
+use std::sync::Arc;
+
+use crate::application::runtime;
+use crate::signal::types::{Aci, RecipientDetails, SignalIdentity};
+use crate::signal::SignalStore;
+
+mod imp {
+    use super::*;
+    use glib::subclass::prelude::*;
+    use glib::subclass::Signal;
+    use std::cell::RefCell;
+    use std::collections::{HashMap, HashSet};
+    use std::sync::OnceLock;
+
+    #[derive(Default)]
+    pub struct RecipientResolver {
+        /// Store of the Signal client, set once it is open
+        pub store: RefCell<Option<Arc<SignalStore>>>,
+
+        /// Known people, with their avatar if they have one
+        pub recipients: RefCell<HashMap<Aci, (RecipientDetails, Option<gdk::Texture>)>>,
+
+        /// People looked up since the last reload, found or not, so
+        /// unknown people are not looked up on every redraw
+        pub requested: RefCell<HashSet<Aci>>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for RecipientResolver {
+        const NAME: &'static str = "RecipientResolver";
+        type Type = super::RecipientResolver;
+    }
+
+    impl ObjectImpl for RecipientResolver {
+        fn signals() -> &'static [Signal] {
+            static SIGNALS: OnceLock<Vec<Signal>> = OnceLock::new();
+            SIGNALS.get_or_init(|| {
+                vec![Signal::builder("changed")
+                    .param_types([String::static_type()])
+                    .build()]
+            })
+        }
+    }
+}
+
+glib::wrapper! {
+    pub struct RecipientResolver(ObjectSubclass<imp::RecipientResolver>);
+}
+
+impl RecipientResolver {
+    pub fn new() -> Self {
+        glib::Object::new()
+    }
+
+    /// Hand over the Signal client's store once it is open, and load
+    /// everyone it knows
+    pub fn set_store(&self, store: Arc<SignalStore>) {
+        self.imp().store.replace(Some(store));
+        self.reload();
+    }
+
+    /// Load everyone again, e.g. after missing updates
+    pub fn reload(&self) {
+        let Some(store) = self.imp().store.borrow().clone() else {
+            return;
+        };
+
+        let (sender, receiver) = async_channel::bounded(1);
+        runtime().spawn(async move {
+            let result = store.get_all_recipient_details().await;
+            let _ = sender.send(result).await;
+        });
+
+        glib::spawn_future_local(glib::clone!(@weak self as resolver => async move {
+            match receiver.recv().await {
+                Ok(Ok(all)) => {
+                    let imp = resolver.imp();
+                    imp.requested.borrow_mut().clear();
+                    imp.recipients.replace(
+                        all.into_iter()
+                            .map(|details| {
+                                let avatar = load_avatar(&details);
+                                (details.aci, (details, avatar))
+                            })
+                            .collect(),
+                    );
+                    resolver.emit_by_name::<()>("changed", &[&String::new()]);
+                }
+                Ok(Err(e)) => tracing::error!("Failed to load recipients: {}", e),
+                Err(_) => {}
+            }
+        }));
+    }
+
+    /// Look a person up again, e.g. after their contact or profile changed
+    pub fn refresh(&self, aci: Aci) {
+        if aci.is_nil() {
+            return;
+        }
+        let Some(store) = self.imp().store.borrow().clone() else {
+            return;
+        };
+        self.imp().requested.borrow_mut().insert(aci);
+
+        let (sender, receiver) = async_channel::bounded(1);
+        runtime().spawn(async move {
+            let result = store.get_recipient_details(aci).await;
+            let _ = sender.send(result).await;
+        });
+
+        glib::spawn_future_local(glib::clone!(@weak self as resolver => async move {
+            match receiver.recv().await {
+                Ok(Ok(Some(details))) => {
+                    let avatar = load_avatar(&details);
+                    resolver
+                        .imp()
+                        .recipients
+                        .borrow_mut()
+                        .insert(aci, (details, avatar));
+                    resolver.emit_by_name::<()>("changed", &[&aci.to_string()]);
+                }
+                Ok(Ok(None)) => {}
+                Ok(Err(e)) => tracing::error!("Failed to look up {}: {}", aci, e),
+                Err(_) => {}
+            }
+        }));
+    }
+
+    /// Name to show a person by
+    ///
+    /// Someone not known yet is shown by phone number or ACI until they
+    /// have been looked up.
+    pub fn name(&self, identity: &SignalIdentity) -> String {
+        let name = self
+            .imp()
+            .recipients
+            .borrow()
+            .get(&identity.aci)
+            .and_then(|(details, _)| details.display_name().map(str::to_string));
+        if let Some(name) = name {
+            return name;
+        }
+
+        self.look_up(identity.aci);
+        identity
+            .phone_number
+            .clone()
+            .unwrap_or_else(|| identity.aci.to_string())
+    }
+
+    /// Avatar image of a person, if they have one
+    pub fn avatar(&self, aci: &Aci) -> Option<gdk::Texture> {
+        self.imp()
+            .recipients
+            .borrow()
+            .get(aci)
+            .and_then(|(_, avatar)| avatar.clone())
+    }
+
+    /// Show a person's avatar, or their initials without one
+    pub fn set_avatar(&self, avatar: &adw::Avatar, identity: &SignalIdentity) {
+        avatar.set_text(Some(&self.name(identity)));
+        avatar.set_custom_image(self.avatar(&identity.aci).as_ref());
+    }
+
+    /// Connect to changes in how people are shown, passing the ACI of the
+    /// changed person, or `None` when anyone may have changed
+    pub fn connect_changed<F: Fn(&Self, Option<Aci>) + 'static>(
+        &self,
+        f: F,
+    ) -> glib::SignalHandlerId {
+        self.connect_closure(
+            "changed",
+            false,
+            glib::closure_local!(move |resolver: Self, aci: String| {
+                f(&resolver, aci.parse().ok());
+            }),
+        )
+    }
+
+    /// Look up a person not seen yet, once
+    fn look_up(&self, aci: Aci) {
+        if !self.imp().requested.borrow().contains(&aci) {
+            self.refresh(aci);
+        }
+    }
+}
+
+impl Default for RecipientResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Load a person's cached avatar image
+fn load_avatar(details: &RecipientDetails) -> Option<gdk::Texture> {
+    let path = details.avatar_path.as_ref()?;
+    match gdk::Texture::from_filename(path) {
+        Ok(texture) => Some(texture),
+        Err(e) => {
+            tracing::warn!("Failed to load avatar of {}: {}", details.aci, e);
+            None
+        }
+    }
+}
@@ -4,15 +4,23 @@ use gtk4::prelude::*;
 use gtk4::subclass::prelude::ObjectSubclassIsExt;
 use gtk4::{gio, glib};
 use libadwaita as adw;
+use std::sync::Arc;
+use tokio::sync::Mutex;
 
-use crate::ui::{ChatList, ChatView, LinkDeviceView};
+use crate::application::runtime;
+use crate::signal::types::{GroupUpdate, SearchPaging, TypingAction};
+use crate::signal::{BusMessage, EventBus, SignalClient, SignalEvent, SignalStore};
+use crate::ui::{
+    group_preview_dialog, group_settings_dialog, join_link_dialog, new_group_dialog, ChatList,
+    ChatView, LinkDeviceView, NewGroup, RecipientResolver,
+};
 
 mod imp {
     use super::*;
     use adw::subclass::prelude::*;
-    use std::cell::RefCell;
+    use std::cell::{Cell, RefCell};
 
-    #[derive(Debug, Default, gtk4::CompositeTemplate)]
+    #[derive(Default, gtk4::CompositeTemplate)]
     #[template(resource = "/com/signalyou/Messenger/ui/window.ui")]
     pub struct SignalYouWindow {
         #[template_child]
@@ -28,6 +36,15 @@ mod imp {
         pub toast_overlay: TemplateChild<adw::ToastOverlay>,
 
         pub is_linked: RefCell<bool>,
+
+        /// Signal client, set once its store is open
+        pub client: RefCell<Option<Arc<Mutex<SignalClient>>>>,
+
+        /// Names and avatars of people, shared by every widget
+        pub resolver: RecipientResolver,
+
+        /// Bumped per search so results of superseded queries are dropped
+        pub search_serial: Cell<u64>,
     }
 
     #[glib::object_subclass]
@@ -64,6 +81,9 @@ mod imp {
         fn constructed(&self) {
             self.parent_constructed();
             self.obj().setup_actions();
+            self.obj().setup_chat_list();
+            self.obj().setup_chat_view();
+            self.obj().setup_search();
             self.obj().check_device_linked();
         }
     }
@@ -98,6 +118,27 @@ impl SignalYouWindow {
             })
             .build();
 
+        // New group action
+        let action_new_group = gio::ActionEntry::builder("new-group")
+            .activate(move |window: &Self, _, _| {
+                window.show_new_group_dialog();
+            })
+            .build();
+
+        // Join group action
+        let action_join_group = gio::ActionEntry::builder("join-group")
+            .activate(move |window: &Self, _, _| {
+                window.show_join_group_dialog();
+            })
+            .build();
+
+        // Settings of the open group
+        let action_manage_group = gio::ActionEntry::builder("manage-group")
+            .activate(move |window: &Self, _, _| {
+                window.show_group_settings();
+            })
+            .build();
+
         // Search action
         let action_search = gio::ActionEntry::builder("search")
             .activate(move |window: &Self, _, _| {
@@ -105,7 +146,272 @@ impl SignalYouWindow {
             })
             .build();
 
-        self.add_action_entries([action_new_chat, action_search]);
+        self.add_action_entries([
+            action_new_chat,
+            action_new_group,
+            action_join_group,
+            action_manage_group,
+            action_search,
+        ]);
+    }
+
+    /// Hand over the Signal client and its store once the store is open,
+    /// and follow its events from then on
+    pub fn set_client(
+        &self,
+        client: Arc<Mutex<SignalClient>>,
+        store: Arc<SignalStore>,
+        events: &EventBus,
+    ) {
+        let imp = self.imp();
+        imp.resolver.set_store(store);
+        imp.chat_list.set_resolver(&imp.resolver);
+        imp.chat_view.set_resolver(&imp.resolver);
+        imp.chat_view.set_client(client.clone());
+        imp.client.replace(Some(client));
+
+        self.listen(events);
+        self.load_conversations();
+    }
+
+    /// Keep the chat list and chat view current with client events
+    fn listen(&self, events: &EventBus) {
+        let receiver = events.subscribe("window").forward(runtime().handle());
+        let window = self.downgrade();
+
+        glib::spawn_future_local(async move {
+            while let Ok(message) = receiver.recv().await {
+                let Some(window) = window.upgrade() else {
+                    break;
+                };
+
+                match message {
+                    BusMessage::Event(event) => window.handle_event(*event),
+                    // Updates were dropped, so start over from the store
+                    BusMessage::Lagged(_) => {
+                        window.imp().resolver.reload();
+                        window.load_conversations();
+                        window.imp().chat_view.reload();
+                    }
+                }
+            }
+        });
+    }
+
+    fn handle_event(&self, event: SignalEvent) {
+        let imp = self.imp();
+
+        match event {
+            SignalEvent::ConversationUpdated(conversation) => {
+                imp.chat_list.update_conversation(&conversation);
+
+                if imp.chat_view.current_chat_id().as_deref() == Some(conversation.id.as_str()) {
+                    imp.chat_view.set_blocked(conversation.blocked);
+                }
+            }
+            SignalEvent::MessageReceived(message) => {
+                imp.chat_view.add_message(&message);
+
+                if self.is_showing_conversation(&message.conversation_id) {
+                    self.mark_read(&message.conversation_id);
+                }
+            }
+            SignalEvent::MessageStatusChanged { message_id, status } => {
+                imp.chat_view.update_message_status(&message_id, status);
+            }
+            SignalEvent::TypingIndicator {
+                conversation_id,
+                sender,
+                action,
+            } => {
+                let name = imp.resolver.name(&sender);
+                imp.chat_view.show_typing_indicator(
+                    &conversation_id,
+                    &sender.aci.to_string(),
+                    &name,
+                    action,
+                );
+            }
+            SignalEvent::ContactUpdated(identity) => {
+                imp.resolver.refresh(identity.aci);
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether the user is looking at a conversation right now
+    pub fn is_showing_conversation(&self, conversation_id: &str) -> bool {
+        let imp = self.imp();
+        self.is_active()
+            && (!imp.split_view.is_collapsed() || imp.split_view.shows_content())
+            && imp.chat_view.current_chat_id().as_deref() == Some(conversation_id)
+    }
+
+    /// Names and avatars of people, as shown throughout the window
+    pub fn resolver(&self) -> RecipientResolver {
+        self.imp().resolver.clone()
+    }
+
+    /// Name of a conversation as listed in the sidebar
+    pub fn conversation_name(&self, conversation_id: &str) -> Option<String> {
+        self.imp().chat_list.conversation_name(conversation_id)
+    }
+
+    fn load_conversations(&self) {
+        let Some(client) = self.imp().client.borrow().clone() else {
+            return;
+        };
+
+        let (sender, receiver) = async_channel::bounded(1);
+        runtime().spawn(async move {
+            let result = client.lock().await.get_conversations().await;
+            let _ = sender.send(result).await;
+        });
+
+        glib::spawn_future_local(glib::clone!(@weak self as window => async move {
+            match receiver.recv().await {
+                Ok(Ok(conversations)) => {
+                    window.imp().chat_list.set_conversations(&conversations);
+                }
+                Ok(Err(e)) => {
+                    tracing::error!("Failed to load conversations: {}", e);
+                    window.show_toast("Could not load chats");
+                }
+                Err(_) => {}
+            }
+        }));
+    }
+
+    fn setup_chat_list(&self) {
+        self.imp().chat_list.connect_chat_activated(glib::clone!(
+            @weak self as window => move |_, chat_id| {
+                window.open_chat(chat_id);
+            }
+        ));
+    }
+
+    fn setup_chat_view(&self) {
+        self.imp().chat_view.connect_typing_changed(glib::clone!(
+            @weak self as window => move |_, chat_id, started| {
+                window.send_typing(chat_id, started);
+            }
+        ));
+    }
+
+    /// Show a chat and mark it read
+    fn open_chat(&self, chat_id: &str) {
+        let imp = self.imp();
+        imp.chat_view.load_chat(chat_id);
+        imp.chat_view.set_blocked(imp.chat_list.is_blocked(chat_id));
+        imp.chat_view.set_group(imp.chat_list.is_group(chat_id));
+        imp.split_view.set_show_content(true);
+        self.mark_read(chat_id);
+    }
+
+    /// Clear the unread count of a conversation; the chat list follows
+    /// with the resulting `ConversationUpdated` event
+    fn mark_read(&self, conversation_id: &str) {
+        let Some(client) = self.imp().client.borrow().clone() else {
+            return;
+        };
+
+        let conversation_id = conversation_id.to_string();
+        runtime().spawn(async move {
+            let result = client
+                .lock()
+                .await
+                .mark_conversation_read(&conversation_id)
+                .await;
+            if let Err(e) = result {
+                tracing::warn!(
+                    "Failed to mark conversation {} read: {}",
+                    conversation_id,
+                    e
+                );
+            }
+        });
+    }
+
+    fn send_typing(&self, chat_id: &str, started: bool) {
+        let Some(client) = self.imp().client.borrow().clone() else {
+            return;
+        };
+
+        let chat_id = chat_id.to_string();
+        let action = if started {
+            TypingAction::Started
+        } else {
+            TypingAction::Stopped
+        };
+        runtime().spawn(async move {
+            let result = client
+                .lock()
+                .await
+                .send_conversation_typing(&chat_id, action)
+                .await;
+            if let Err(e) = result {
+                tracing::debug!("Failed to send typing indicator to {}: {}", chat_id, e);
+            }
+        });
+    }
+
+    fn setup_search(&self) {
+        let imp = self.imp();
+
+        imp.chat_list.connect_search_changed(glib::clone!(
+            @weak self as window => move |_, query| {
+                window.search_messages(query);
+            }
+        ));
+
+        imp.chat_list.connect_search_result_activated(glib::clone!(
+            @weak self as window => move |_, conversation_id, message_id| {
+                let imp = window.imp();
+                imp.chat_view.load_chat_at_message(conversation_id, message_id);
+                imp.split_view.set_show_content(true);
+            }
+        ));
+    }
+
+    fn search_messages(&self, query: &str) {
+        let imp = self.imp();
+        let serial = imp.search_serial.get() + 1;
+        imp.search_serial.set(serial);
+
+        let Some(client) = imp.client.borrow().clone() else {
+            imp.chat_list.show_search_results(&[]);
+            return;
+        };
+
+        let query = query.to_string();
+        let (sender, receiver) = async_channel::bounded(1);
+        runtime().spawn(async move {
+            let result = client
+                .lock()
+                .await
+                .search_messages(&query, None, SearchPaging::default())
+                .await;
+            let _ = sender.send(result).await;
+        });
+
+        glib::spawn_future_local(glib::clone!(@weak self as window => async move {
+            let Ok(result) = receiver.recv().await else {
+                return;
+            };
+
+            // Typing went on while this search ran
+            if window.imp().search_serial.get() != serial {
+                return;
+            }
+
+            match result {
+                Ok(hits) => window.imp().chat_list.show_search_results(&hits),
+                Err(e) => {
+                    tracing::error!("Message search failed: {}", e);
+                    window.show_toast("Search failed");
+                }
+            }
+        }));
     }
 
     fn check_device_linked(&self) {
@@ -129,9 +435,241 @@ impl SignalYouWindow {
         tracing::info!("New chat dialog requested");
     }
 
+    fn show_new_group_dialog(&self) {
+        let Some(client) = self.imp().client.borrow().clone() else {
+            return;
+        };
+
+        let (sender, receiver) = async_channel::bounded(1);
+        runtime().spawn(async move {
+            let result = client.lock().await.get_contacts().await;
+            let _ = sender.send(result).await;
+        });
+
+        glib::spawn_future_local(glib::clone!(@weak self as window => async move {
+            match receiver.recv().await {
+                Ok(Ok(contacts)) => {
+                    let dialog = new_group_dialog(
+                        &window,
+                        &contacts,
+                        &window.imp().resolver,
+                        glib::clone!(@weak window => move |group| {
+                            window.create_group(group);
+                        }),
+                    );
+                    dialog.present();
+                }
+                Ok(Err(e)) => {
+                    tracing::error!("Failed to load contacts: {}", e);
+                    window.show_toast("Could not load contacts");
+                }
+                Err(_) => {}
+            }
+        }));
+    }
+
+    /// Create a group and open its chat
+    fn create_group(&self, group: NewGroup) {
+        let Some(client) = self.imp().client.borrow().clone() else {
+            return;
+        };
+
+        let (sender, receiver) = async_channel::bounded(1);
+        runtime().spawn(async move {
+            let result = async {
+                let avatar = match &group.avatar {
+                    Some(path) => Some(tokio::fs::read(path).await?),
+                    None => None,
+                };
+                client
+                    .lock()
+                    .await
+                    .create_group(
+                        &group.name,
+                        group.description.as_deref(),
+                        avatar.as_deref(),
+                        group.disappearing_timer,
+                        &group.members,
+                    )
+                    .await
+            }
+            .await;
+            let _ = sender.send(result).await;
+        });
+
+        glib::spawn_future_local(glib::clone!(@weak self as window => async move {
+            match receiver.recv().await {
+                Ok(Ok(group)) => {
+                    window.open_chat(&group.id);
+                    // The chat list may not have heard of the group yet
+                    window.imp().chat_view.set_group(true);
+                }
+                Ok(Err(e)) => {
+                    tracing::error!("Failed to create group: {}", e);
+                    window.show_toast("Could not create group");
+                }
+                Err(_) => {}
+            }
+        }));
+    }
+
+    /// Show the members and settings of the open group
+    fn show_group_settings(&self) {
+        let imp = self.imp();
+        let Some(client) = imp.client.borrow().clone() else {
+            return;
+        };
+        let Some(group_id) = imp.chat_view.current_chat_id() else {
+            return;
+        };
+
+        let (sender, receiver) = async_channel::bounded(1);
+        runtime().spawn(async move {
+            let client = client.lock().await;
+            let result = async {
+                let own_aci = client
+                    .identity()
+                    .map(|identity| identity.aci)
+                    .ok_or_else(|| anyhow::anyhow!("No local identity"))?;
+                let group = client
+                    .get_group(&group_id)
+                    .await?
+                    .filter(|group| !group.members.is_empty())
+                    .ok_or_else(|| anyhow::anyhow!("Group {} not fetched yet", group_id))?;
+                let invite_link = client.group_invite_link(&group_id).await?;
+                let contacts = client.get_contacts().await?;
+                Ok::<_, anyhow::Error>((group, own_aci, invite_link, contacts))
+            }
+            .await;
+            let _ = sender.send(result).await;
+        });
+
+        glib::spawn_future_local(glib::clone!(@weak self as window => async move {
+            match receiver.recv().await {
+                Ok(Ok((group, own_aci, invite_link, contacts))) => {
+                    let group_id = group.id.clone();
+                    let dialog = group_settings_dialog(
+                        &window,
+                        &group,
+                        own_aci,
+                        invite_link.as_deref(),
+                        &contacts,
+                        &window.imp().resolver,
+                        glib::clone!(@weak window => move |update| {
+                            window.change_group(&group_id, update);
+                        }),
+                    );
+                    dialog.present();
+                }
+                Ok(Err(e)) => {
+                    tracing::error!("Failed to load group: {}", e);
+                    window.show_toast("Could not load group");
+                }
+                Err(_) => {}
+            }
+        }));
+    }
+
+    /// Make a change to a group; the chat list follows with the resulting
+    /// `ConversationUpdated` event
+    fn change_group(&self, group_id: &str, update: GroupUpdate) {
+        let Some(client) = self.imp().client.borrow().clone() else {
+            return;
+        };
+
+        let group_id = group_id.to_string();
+        let (sender, receiver) = async_channel::bounded(1);
+        runtime().spawn(async move {
+            let result = client.lock().await.change_group(&group_id, update).await;
+            let _ = sender.send(result).await;
+        });
+
+        glib::spawn_future_local(glib::clone!(@weak self as window => async move {
+            if let Ok(Err(e)) = receiver.recv().await {
+                tracing::error!("Failed to change group: {}", e);
+                window.show_toast("Could not change group");
+            }
+        }));
+    }
+
+    fn show_join_group_dialog(&self) {
+        let dialog = join_link_dialog(
+            self,
+            glib::clone!(@weak self as window => move |url| {
+                window.preview_group_link(url);
+            }),
+        );
+        dialog.present();
+    }
+
+    /// Show the group of an invite link, offering to join it
+    fn preview_group_link(&self, url: String) {
+        let Some(client) = self.imp().client.borrow().clone() else {
+            return;
+        };
+
+        let (sender, receiver) = async_channel::bounded(1);
+        let link = url.clone();
+        runtime().spawn(async move {
+            let result = client.lock().await.preview_group_link(&link).await;
+            let _ = sender.send(result).await;
+        });
+
+        glib::spawn_future_local(glib::clone!(@weak self as window => async move {
+            match receiver.recv().await {
+                Ok(Ok(preview)) => {
+                    let dialog = group_preview_dialog(
+                        &window,
+                        &preview,
+                        glib::clone!(@weak window => move || {
+                            window.join_group_link(url.clone());
+                        }),
+                    );
+                    dialog.present();
+                }
+                Ok(Err(e)) => {
+                    tracing::error!("Failed to look up group link: {}", e);
+                    window.show_toast("Could not look up group");
+                }
+                Err(_) => {}
+            }
+        }));
+    }
+
+    /// Join the group of an invite link, opening its chat once we are in
+    fn join_group_link(&self, url: String) {
+        let Some(client) = self.imp().client.borrow().clone() else {
+            return;
+        };
+
+        let (sender, receiver) = async_channel::bounded(1);
+        runtime().spawn(async move {
+            let result = client.lock().await.join_group_link(&url).await;
+            let _ = sender.send(result).await;
+        });
+
+        glib::spawn_future_local(glib::clone!(@weak self as window => async move {
+            match receiver.recv().await {
+                Ok(Ok(preview)) if preview.requires_approval => {
+                    window.show_toast(&format!("Asked to join {}", preview.name));
+                }
+                Ok(Ok(preview)) => {
+                    window.open_chat(&preview.id);
+                    window.imp().chat_view.set_group(true);
+                }
+                Ok(Err(e)) => {
+                    tracing::error!("Failed to join group: {}", e);
+                    window.show_toast("Could not join group");
+                }
+                Err(_) => {}
+            }
+        }));
+    }
+
     fn toggle_search(&self) {
-        // TODO: Implement search toggle
-        tracing::info!("Search toggled");
+        let imp = self.imp();
+        imp.split_view.set_show_content(false);
+        imp.chat_list.toggle_search();
     }
 
     pub fn show_toast(&self, message: &str) {